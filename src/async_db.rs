@@ -0,0 +1,62 @@
+use std::io::{Error, Result};
+use std::sync::{Arc, Mutex};
+use tokio::task;
+
+use crate::core::{ByteString, KeyValue};
+use crate::service::RustDB;
+
+/// Handle to a `RustDB` for async code. Every operation runs on tokio's
+/// blocking thread pool, so file I/O never stalls the runtime's workers.
+/// Clones share the same database.
+#[derive(Clone)]
+pub struct AsyncRustDB {
+    db: Arc<Mutex<RustDB>>,
+}
+
+impl AsyncRustDB {
+    pub fn new(db: RustDB) -> AsyncRustDB {
+        AsyncRustDB::from_shared(Arc::new(Mutex::new(db)))
+    }
+
+    /// Wraps a database also used from blocking code, such as a compressor
+    /// thread.
+    pub fn from_shared(db: Arc<Mutex<RustDB>>) -> AsyncRustDB {
+        AsyncRustDB { db }
+    }
+
+    pub fn shared(&self) -> Arc<Mutex<RustDB>> {
+        Arc::clone(&self.db)
+    }
+
+    /// Runs `operation` with the database locked, on a blocking thread.
+    pub async fn run<F, T>(&self, operation: F) -> Result<T>
+    where
+        F: FnOnce(&mut RustDB) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let db = Arc::clone(&self.db);
+
+        match task::spawn_blocking(move || operation(&mut db.lock().unwrap())).await {
+            Ok(result) => result,
+            Err(err) => Err(Error::other(err)),
+        }
+    }
+
+    pub async fn get_record<K: Into<ByteString>>(&self, key: K) -> Result<Option<KeyValue>> {
+        let key = key.into();
+        self.run(move |db| db.get_record(key)).await
+    }
+
+    pub async fn save_record(&self, key_value: KeyValue) -> Result<()> {
+        self.run(move |db| db.save_record(key_value)).await
+    }
+
+    pub async fn delete_record<K: Into<ByteString>>(&self, key: K) -> Result<()> {
+        let key = key.into();
+        self.run(move |db| db.delete_record(key)).await
+    }
+
+    pub async fn flush(&self) -> Result<()> {
+        self.run(|db| db.flush()).await
+    }
+}
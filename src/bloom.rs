@@ -0,0 +1,152 @@
+use byteorder::{BigEndian, ReadBytesExt};
+use crc::crc32;
+use std::f64::consts::LN_2;
+use std::io::{BufReader, ErrorKind, Result};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::core::{ByteString, KeyValue};
+use crate::store::{read_record, write_record};
+use crate::vfs::{FileReader, Vfs};
+
+/// Set of keys answering whether a key may be present, with no false
+/// negatives and a false positive rate chosen on creation.
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    hash_count: u32,
+}
+
+impl BloomFilter {
+    pub fn new(expected_items: usize, false_positive_rate: f64) -> BloomFilter {
+        let items = expected_items.max(1) as f64;
+        let bit_count = (-items * false_positive_rate.ln() / (LN_2 * LN_2))
+            .ceil()
+            .max(8.0);
+        let hash_count = (bit_count / items * LN_2).round().max(1.0) as u32;
+
+        BloomFilter {
+            bits: vec![0; (bit_count as usize).div_ceil(8)],
+            hash_count,
+        }
+    }
+
+    pub fn from_keys<'a, I>(keys: I, false_positive_rate: f64) -> BloomFilter
+    where
+        I: ExactSizeIterator<Item = &'a ByteString>,
+    {
+        let mut filter = BloomFilter::new(keys.len(), false_positive_rate);
+        for key in keys {
+            filter.insert(key);
+        }
+        filter
+    }
+
+    pub fn insert(&mut self, key: &[u8]) {
+        for position in self.positions(key) {
+            self.bits[position / 8] |= 1 << (position % 8);
+        }
+    }
+
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.positions(key)
+            .all(|position| self.bits[position / 8] & (1 << (position % 8)) != 0)
+    }
+
+    /// Double hashing over two stable checksums, so persisted filters stay
+    /// valid across builds.
+    fn positions(&self, key: &[u8]) -> impl Iterator<Item = usize> {
+        let first = crc32::checksum_ieee(key) as u64;
+        let second = crc32::checksum_castagnoli(key) as u64 | 1;
+        let bit_count = (self.bits.len() * 8) as u64;
+
+        (0..self.hash_count as u64)
+            .map(move |i| (first.wrapping_add(i.wrapping_mul(second)) % bit_count) as usize)
+    }
+
+    /// Persists the filter as a single record, reusing the checksum of the
+    /// record structure to detect partially written files.
+    pub fn save(&self, vfs: &Arc<dyn Vfs>, path: &str) -> Result<()> {
+        let record = KeyValue::new(self.hash_count.to_be_bytes().to_vec(), self.bits.clone());
+        let mut data = Vec::new();
+        write_record(&mut data, &record)?;
+
+        let mut file = vfs.create(path)?;
+        file.append(&data)?;
+        file.sync()
+    }
+
+    pub fn load(vfs: &Arc<dyn Vfs>, path: &str) -> Result<BloomFilter> {
+        let file = vfs.open(path)?;
+        let record = read_record(&mut BufReader::new(FileReader::new(&*file, 0)))?;
+
+        Ok(BloomFilter {
+            hash_count: (&record.key[..]).read_u32::<BigEndian>()?,
+            bits: record.value,
+        })
+    }
+
+    pub fn remove(vfs: &Arc<dyn Vfs>, path: &str) -> Result<()> {
+        match vfs.remove(path) {
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BloomStats {
+    /// Lookups that consulted a filter.
+    pub checks: u64,
+    /// Lookups skipped because the filter ruled the key out.
+    pub skipped: u64,
+    /// Lookups the filter let through that found nothing.
+    pub false_positives: u64,
+}
+
+#[derive(Default)]
+pub struct BloomCounters {
+    checks: AtomicU64,
+    skipped: AtomicU64,
+    false_positives: AtomicU64,
+}
+
+impl BloomCounters {
+    pub fn record_check(&self, may_contain: bool) {
+        self.checks.fetch_add(1, Ordering::Relaxed);
+        if !may_contain {
+            self.skipped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_false_positive(&self) {
+        self.false_positives.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get_stats(&self) -> BloomStats {
+        BloomStats {
+            checks: self.checks.load(Ordering::Relaxed),
+            skipped: self.skipped.load(Ordering::Relaxed),
+            false_positives: self.false_positives.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_false_negatives_and_bounded_false_positives() {
+        let keys: Vec<ByteString> = (0..1_000)
+            .map(|i| format!("{:04}", i).into_bytes())
+            .collect();
+        let filter = BloomFilter::from_keys(keys.iter(), 0.01);
+
+        assert!(keys.iter().all(|k| filter.may_contain(k)));
+
+        let false_positives = (1_000..11_000)
+            .filter(|i| filter.may_contain(format!("{:05}", i).as_bytes()))
+            .count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+    }
+}
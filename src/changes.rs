@@ -0,0 +1,410 @@
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::io::{
+    BufReader, Error,
+    ErrorKind::{InvalidData, InvalidInput, UnexpectedEof},
+    Result,
+};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::core::{ByteString, KeyValue};
+use crate::store::{build_path, folder_path, read_record, read_u64_at};
+use crate::vfs::{FileReader, Vfs, VfsFile};
+
+static CHANGES_FILE: &str = "changes";
+/// Live events buffered for a subscriber, which is dropped once it falls
+/// further behind.
+static SUBSCRIBER_BUFFER: usize = 1_024;
+
+/// Write applied to the database, numbered from 1 in the order it was
+/// applied.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChangeEvent {
+    pub sequence: u64,
+    pub key: ByteString,
+    /// Value saved, or `None` when the key was deleted.
+    pub value: Option<ByteString>,
+}
+
+impl ChangeEvent {
+    /// Record the event is sent as, keyed by its sequence then its key, with
+    /// an empty value for a delete.
+    pub(crate) fn to_record(&self) -> KeyValue {
+        let mut key = self.sequence.to_be_bytes().to_vec();
+        key.extend_from_slice(&self.key);
+        KeyValue::new(key, self.value.clone().unwrap_or_default())
+    }
+
+    pub(crate) fn from_record(record: KeyValue) -> Option<ChangeEvent> {
+        Some(ChangeEvent {
+            sequence: parse_sequence(&record.key)?,
+            key: record.key[8..].to_vec(),
+            value: match record.value.is_empty() {
+                true => None,
+                false => Some(record.value),
+            },
+        })
+    }
+}
+
+/// Flags, content type and expiration deadline of a key, in milliseconds
+/// since the Unix epoch, as they are after a write.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct KeyMetadata {
+    pub key: ByteString,
+    pub flags: u32,
+    pub content_type: Option<String>,
+    pub expires_at: Option<u64>,
+}
+
+/// What a follower applies to keep up with a leader, in the order the
+/// leader applied it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ReplicationEvent {
+    Change(ChangeEvent),
+    /// Metadata of a key after it was set or cleared.
+    Metadata(KeyMetadata),
+    /// Metadata of every key that has some, replacing that of the follower.
+    AllMetadata(Vec<KeyMetadata>),
+    /// Names of the collections after one was created or dropped.
+    Collections(Vec<String>),
+}
+
+/// Events a subscriber is interested in.
+#[derive(Clone, Debug, Default)]
+pub struct ChangeFilter {
+    /// First sequence delivered.
+    pub from: u64,
+    /// Only keys starting with it are delivered.
+    pub prefix: ByteString,
+}
+
+impl ChangeFilter {
+    pub fn from_sequence(from: u64) -> ChangeFilter {
+        ChangeFilter {
+            from,
+            prefix: Vec::new(),
+        }
+    }
+
+    pub fn with_prefix<K: Into<ByteString>>(mut self, prefix: K) -> ChangeFilter {
+        self.prefix = prefix.into();
+        self
+    }
+
+    pub(crate) fn matches(&self, event: &ChangeEvent) -> bool {
+        event.sequence >= self.from && event.key.starts_with(&self.prefix)
+    }
+}
+
+/// Events already written when subscribing, followed by those of
+/// later writes as they are applied.
+pub struct Subscription<T = ChangeEvent> {
+    backlog: VecDeque<T>,
+    events: Receiver<T>,
+}
+
+impl<T> Subscription<T> {
+    /// Next event, waiting up to `timeout` for one. It fails with
+    /// `Disconnected` once the subscriber fell too far behind, or the
+    /// database was dropped, after which it can subscribe again from the
+    /// sequence following the last event it got.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> std::result::Result<T, RecvTimeoutError> {
+        match self.backlog.pop_front() {
+            Some(event) => Ok(event),
+            None => self.events.recv_timeout(timeout),
+        }
+    }
+}
+
+impl<T> Iterator for Subscription<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.backlog.pop_front().or_else(|| self.events.recv().ok())
+    }
+}
+
+/// Sequences of the writes, whose events are the records of the segments
+/// stamped with them, and the subscribers to the next ones. The `changes`
+/// file of the storage folder holds the first sequence still served:
+/// compaction raises it past the records it drops, so every event from it
+/// on is still in the segments.
+pub(crate) struct ChangeLog {
+    vfs: Arc<dyn Vfs>,
+    path: String,
+    last_sequence: u64,
+    subscribers: Vec<(ChangeFilter, SyncSender<ChangeEvent>)>,
+    /// Followers, which get every event along with the metadata changes.
+    replicas: Vec<SyncSender<ReplicationEvent>>,
+}
+
+static HEADER_SIZE: u64 = 8;
+
+impl ChangeLog {
+    /// Opens the log of a storage whose records are stamped up to
+    /// `last_stamped`. A log written before events were read from the
+    /// segments goes on from the sequence after its last event, which is
+    /// not in them.
+    pub fn load(folder: &str, last_stamped: u64, vfs: &Arc<dyn Vfs>) -> Result<ChangeLog> {
+        let folder = folder_path(folder);
+        let path = build_path(&folder, CHANGES_FILE);
+        vfs.create_dir_all(&folder)?;
+
+        let file = match vfs.exists(&path) {
+            true => Some(vfs.open(&path)?),
+            false => None,
+        };
+        let size = match &file {
+            Some(file) => file.len()?,
+            None => 0,
+        };
+        let first_sequence = match file {
+            Some(file) if size == HEADER_SIZE => read_u64_at(&*file, 0)?.max(1),
+            Some(file) if size > HEADER_SIZE => legacy_last_sequence(&*file)? + 1,
+            // a log created by a crash before its header was written
+            _ => 1,
+        };
+        if size != HEADER_SIZE {
+            ChangeLog::write_first_sequence(vfs, &path, first_sequence)?;
+        }
+
+        Ok(ChangeLog {
+            vfs: Arc::clone(vfs),
+            path,
+            last_sequence: last_stamped.max(first_sequence - 1),
+            subscribers: Vec::new(),
+            replicas: Vec::new(),
+        })
+    }
+
+    /// Replaces the log with one starting at `first_sequence`, through a
+    /// temporary file renamed over it.
+    fn write_first_sequence(vfs: &Arc<dyn Vfs>, path: &str, first_sequence: u64) -> Result<()> {
+        let temp_path = format!("{}.tmp", path);
+        let mut file = vfs.create(&temp_path)?;
+        file.append(&first_sequence.to_be_bytes())?;
+        file.sync()?;
+        vfs.rename(&temp_path, path)
+    }
+
+    /// Stops serving the events before `sequence` in the log of `folder`,
+    /// as compaction is about to drop some of them. Kept as it is when it
+    /// already starts after it.
+    pub fn discard_before(vfs: &Arc<dyn Vfs>, folder: &str, sequence: u64) -> Result<()> {
+        let path = build_path(&folder_path(folder), CHANGES_FILE);
+        if ChangeLog::read_first_sequence(vfs, &path)? < sequence {
+            ChangeLog::write_first_sequence(vfs, &path, sequence)?;
+        }
+        Ok(())
+    }
+
+    fn read_first_sequence(vfs: &Arc<dyn Vfs>, path: &str) -> Result<u64> {
+        match vfs.exists(path) {
+            true => read_u64_at(&*vfs.open(path)?, 0),
+            false => Ok(1),
+        }
+    }
+
+    /// Sequence of the first event still served.
+    pub fn first_sequence(&self) -> Result<u64> {
+        ChangeLog::read_first_sequence(&self.vfs, &self.path)
+    }
+
+    /// Sequence of the latest event, 0 before the first one.
+    pub fn last_sequence(&self) -> u64 {
+        self.last_sequence
+    }
+
+    /// Sequence the next write is stamped with.
+    pub fn next_sequence(&self) -> u64 {
+        self.last_sequence + 1
+    }
+
+    /// Counts the write stamped with the next sequence, once it is saved,
+    /// delivering its event to subscribers.
+    pub fn publish(&mut self, key: ByteString, value: Option<ByteString>) -> u64 {
+        self.last_sequence += 1;
+        let event = ChangeEvent {
+            sequence: self.last_sequence,
+            key,
+            value,
+        };
+
+        // drops subscribers gone or too far behind
+        self.subscribers.retain(|(filter, sender)| {
+            !filter.matches(&event) || sender.try_send(event.clone()).is_ok()
+        });
+        let sequence = event.sequence;
+        self.publish_to_replicas(ReplicationEvent::Change(event));
+        sequence
+    }
+
+    /// Whether anyone follows the log, to spare building the events of
+    /// metadata changes otherwise.
+    pub fn has_replicas(&self) -> bool {
+        !self.replicas.is_empty()
+    }
+
+    /// Delivers a change of metadata to the followers, after the events
+    /// published so far.
+    pub fn publish_to_replicas(&mut self, event: ReplicationEvent) {
+        self.replicas
+            .retain(|sender| sender.try_send(event.clone()).is_ok());
+    }
+
+    /// Empties the log, going on from `last_sequence`, as when the state at
+    /// that sequence is installed from elsewhere. Events before it can no
+    /// longer be read.
+    pub fn reset(&mut self, last_sequence: u64) -> Result<()> {
+        ChangeLog::write_first_sequence(&self.vfs, &self.path, last_sequence + 1)?;
+        self.last_sequence = last_sequence;
+        // they resume from sequences no longer there
+        self.subscribers.clear();
+        self.replicas.clear();
+        Ok(())
+    }
+
+    /// Fails when events from `from` on were already discarded.
+    pub fn check_kept(&self, from: u64) -> Result<()> {
+        let first_sequence = self.first_sequence()?;
+        match from.max(1) < first_sequence {
+            true => Err(Error::new(
+                InvalidInput,
+                format!(
+                    "Events before sequence {} were discarded, asked from {}",
+                    first_sequence, from
+                ),
+            )),
+            false => Ok(()),
+        }
+    }
+
+    /// Delivers the `backlog` of events already written matching `filter`,
+    /// then those of later writes.
+    pub fn subscribe(&mut self, filter: ChangeFilter, backlog: Vec<ChangeEvent>) -> Subscription {
+        let (sender, events) = mpsc::sync_channel(SUBSCRIBER_BUFFER);
+        self.subscribers.push((filter, sender));
+
+        Subscription {
+            backlog: backlog.into(),
+            events,
+        }
+    }
+
+    /// Delivers the `backlog` a follower needs to catch up, then every
+    /// event and metadata change from then on.
+    pub fn subscribe_replica(
+        &mut self,
+        backlog: Vec<ReplicationEvent>,
+    ) -> Subscription<ReplicationEvent> {
+        let (sender, events) = mpsc::sync_channel(SUBSCRIBER_BUFFER);
+        self.replicas.push(sender);
+
+        Subscription {
+            backlog: backlog.into(),
+            events,
+        }
+    }
+}
+
+/// Sequence of the last event of a log that held the events themselves,
+/// after the sequence of its first one.
+fn legacy_last_sequence(file: &dyn VfsFile) -> Result<u64> {
+    let mut last_sequence = read_u64_at(file, 0)?.saturating_sub(1);
+    let mut reader = BufReader::new(FileReader::new(file, HEADER_SIZE));
+    loop {
+        match read_record(&mut reader) {
+            Ok(record) => match parse_sequence(&record.key) {
+                Some(sequence) if sequence == last_sequence + 1 => last_sequence = sequence,
+                _ => break,
+            },
+            Err(err) if err.kind() == UnexpectedEof || err.kind() == InvalidData => break,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(last_sequence)
+}
+
+fn parse_sequence(key: &[u8]) -> Option<u64> {
+    let bytes: [u8; 8] = key.get(..8)?.try_into().ok()?;
+    Some(u64::from_be_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::MemoryVfs;
+
+    fn memory() -> Arc<dyn Vfs> {
+        Arc::new(MemoryVfs::new())
+    }
+
+    #[test]
+    fn keep_the_first_sequence_across_loads() {
+        let vfs = memory();
+        let mut log = ChangeLog::load("storage", 0, &vfs).unwrap();
+        log.publish(b"a".to_vec(), Some(b"1".to_vec()));
+        log.reset(5).unwrap();
+
+        let log = ChangeLog::load("storage", 3, &vfs).unwrap();
+        ChangeLog::discard_before(&vfs, "storage", 4).unwrap();
+        let kept = log.check_kept(6);
+        let discarded = log.check_kept(5);
+        ChangeLog::discard_before(&vfs, "storage", 8).unwrap();
+
+        assert_eq!(log.last_sequence(), 5);
+        assert!(kept.is_ok());
+        assert_eq!(discarded.unwrap_err().kind(), InvalidInput);
+        assert_eq!(log.first_sequence().unwrap(), 8);
+    }
+
+    #[test]
+    fn go_on_after_a_log_of_events() {
+        let vfs = memory();
+        let mut data = 4_u64.to_be_bytes().to_vec();
+        for (sequence, key) in [(4_u64, "a"), (5, "b")] {
+            let event = ChangeEvent {
+                sequence,
+                key: key.as_bytes().to_vec(),
+                value: None,
+            };
+            crate::store::write_record(&mut data, &event.to_record()).unwrap();
+        }
+        vfs.create_dir_all("./storage").unwrap();
+        vfs.create("./storage/changes")
+            .unwrap()
+            .append(&data)
+            .unwrap();
+
+        let log = ChangeLog::load("storage", 0, &vfs).unwrap();
+
+        assert_eq!(log.last_sequence(), 5);
+        assert_eq!(log.first_sequence().unwrap(), 6);
+        assert_eq!(vfs.open("./storage/changes").unwrap().len().unwrap(), 8);
+    }
+
+    #[test]
+    fn deliver_backlog_then_live_events() {
+        let mut log = ChangeLog::load("storage", 0, &memory()).unwrap();
+        let backlog = vec![ChangeEvent {
+            sequence: 1,
+            key: b"a/1".to_vec(),
+            value: Some(b"1".to_vec()),
+        }];
+        log.publish(b"a/1".to_vec(), Some(b"1".to_vec()));
+        log.publish(b"b/1".to_vec(), Some(b"2".to_vec()));
+
+        let mut subscription =
+            log.subscribe(ChangeFilter::from_sequence(0).with_prefix("a/"), backlog);
+        log.publish(b"b/2".to_vec(), None);
+        log.publish(b"a/2".to_vec(), None);
+
+        let timeout = Duration::from_millis(10);
+        assert_eq!(subscription.recv_timeout(timeout).unwrap().sequence, 1);
+        assert_eq!(subscription.recv_timeout(timeout).unwrap().sequence, 4);
+        assert!(subscription.recv_timeout(timeout).is_err());
+    }
+}
@@ -1,5 +1,8 @@
+use crc::crc64;
+
 pub type ByteString = Vec<u8>;
 
+#[derive(Clone, Debug, PartialEq)]
 pub struct KeyValue {
     pub key: ByteString,
     pub value: ByteString,
@@ -24,4 +27,11 @@ impl KeyValue {
     pub fn get_value_as_string(&self) -> String {
         String::from_utf8_lossy(&self.value).into_owned()
     }
+
+    /// Checksum of the value, changing whenever the value does, to compare
+    /// and swap records. Writing back a previous value brings back its
+    /// version.
+    pub fn get_version(&self) -> u64 {
+        crc64::checksum_ecma(&self.value)
+    }
 }
@@ -0,0 +1,112 @@
+use std::io::Result;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::core::ByteString;
+use crate::keylog::KeyLog;
+use crate::vfs::Vfs;
+
+static EXPIRATIONS_FILE: &str = "expirations";
+
+/// Milliseconds since the Unix epoch, the unit deadlines are stored in.
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Deadlines of the keys set to expire, persisted to the `expirations` log.
+pub(crate) struct Expirations {
+    deadlines: KeyLog,
+}
+
+impl Expirations {
+    pub fn in_memory() -> Expirations {
+        Expirations {
+            deadlines: KeyLog::in_memory(),
+        }
+    }
+
+    pub fn load(folder: &str, vfs: &Arc<dyn Vfs>) -> Result<Expirations> {
+        Ok(Expirations {
+            deadlines: KeyLog::load(folder, EXPIRATIONS_FILE, vfs)?,
+        })
+    }
+
+    pub fn set(&mut self, key: ByteString, deadline: u64) -> Result<()> {
+        self.deadlines.set(key, deadline)
+    }
+
+    /// Removes the deadline of `key`, returning whether it had one.
+    pub fn clear(&mut self, key: &[u8]) -> Result<bool> {
+        self.deadlines.clear(key)
+    }
+
+    pub fn deadline(&self, key: &[u8]) -> Option<u64> {
+        self.deadlines.get(key).copied()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&ByteString, &u64)> {
+        self.deadlines.iter()
+    }
+
+    pub fn is_expired(&self, key: &[u8]) -> bool {
+        match self.deadlines.get(key) {
+            Some(deadline) => *deadline <= now_millis(),
+            None => false,
+        }
+    }
+
+    /// Time left before `key` expires, zero once it has expired.
+    pub fn time_left(&self, key: &[u8]) -> Option<Duration> {
+        self.deadlines
+            .get(key)
+            .map(|deadline| Duration::from_millis(deadline.saturating_sub(now_millis())))
+    }
+
+    pub fn expired_keys(&self) -> Vec<ByteString> {
+        let now = now_millis();
+        self.deadlines
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::MemoryVfs;
+
+    #[test]
+    fn keep_deadlines_across_loads() {
+        let vfs: Arc<dyn Vfs> = Arc::new(MemoryVfs::new());
+        let mut expirations = Expirations::load("storage", &vfs).unwrap();
+        expirations.set(b"a".to_vec(), 10).unwrap();
+        expirations
+            .set(b"b".to_vec(), now_millis() + 60_000)
+            .unwrap();
+
+        let expirations = Expirations::load("storage", &vfs).unwrap();
+
+        assert!(expirations.is_expired(b"a"));
+        assert!(!expirations.is_expired(b"b"));
+        assert_eq!(expirations.expired_keys(), vec![b"a".to_vec()]);
+    }
+
+    #[test]
+    fn report_time_left() {
+        let mut expirations = Expirations::in_memory();
+        expirations
+            .set(b"a".to_vec(), now_millis() + 60_000)
+            .unwrap();
+
+        let left = expirations.time_left(b"a").unwrap();
+
+        assert!(left > Duration::from_secs(59) && left <= Duration::from_secs(60));
+        assert!(!expirations.is_expired(b"a"));
+        assert_eq!(expirations.time_left(b"b"), None);
+    }
+}
@@ -0,0 +1,324 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::{Arc, Mutex};
+
+use crate::vfs::{Vfs, VfsFile};
+
+/// Write not yet made durable by a `sync`.
+enum PendingWrite {
+    At(u64, Vec<u8>),
+    Truncate(u64),
+}
+
+/// Contents of a file as seen by readers, and as they would be found after a
+/// crash without the pending writes.
+#[derive(Default)]
+struct FileState {
+    data: Vec<u8>,
+    durable: Vec<u8>,
+    pending: Vec<PendingWrite>,
+}
+
+fn apply(data: &mut Vec<u8>, write: &PendingWrite) {
+    match write {
+        PendingWrite::At(position, bytes) => {
+            let start = *position as usize;
+            let end = start + bytes.len();
+            if data.len() < end {
+                data.resize(end, 0);
+            }
+            data[start..end].copy_from_slice(bytes);
+        }
+        PendingWrite::Truncate(size) => data.resize(*size as usize, 0),
+    }
+}
+
+type SharedFile = Arc<Mutex<FileState>>;
+
+#[derive(Default)]
+struct Faults {
+    /// Writes still allowed before the device reports it is full.
+    writes_left: Option<u64>,
+    /// Whether the device has room again after the first failed write.
+    transient: bool,
+    fail_renames: bool,
+}
+
+struct State {
+    files: HashMap<String, SharedFile>,
+    faults: Faults,
+    rng: StdRng,
+}
+
+/// In-memory file system that injects the failures of a real device, so
+/// crash recovery can be tested deterministically from a seed.
+///
+/// Writes are only durable once synced. Creating, renaming and removing
+/// files are durable right away, as on a journaled file system. A `crash`
+/// keeps the synced contents plus, in order, a random number of the
+/// unsynced writes of each file, the last of them possibly torn.
+#[derive(Clone)]
+pub struct FaultyVfs {
+    state: Arc<Mutex<State>>,
+}
+
+impl FaultyVfs {
+    pub fn new(seed: u64) -> FaultyVfs {
+        FaultyVfs {
+            state: Arc::new(Mutex::new(State {
+                files: HashMap::new(),
+                faults: Faults::default(),
+                rng: StdRng::seed_from_u64(seed),
+            })),
+        }
+    }
+
+    /// Copies a file from disk as already synced, to start from existing
+    /// storages.
+    pub fn copy_from_disk(&self, from: &str, to: &str) -> Result<()> {
+        let data = fs::read(from)?;
+        let file = FileState {
+            durable: data.clone(),
+            data,
+            pending: Vec::new(),
+        };
+        self.state
+            .lock()
+            .unwrap()
+            .files
+            .insert(String::from(to), Arc::new(Mutex::new(file)));
+        Ok(())
+    }
+
+    /// Lets `writes` more writes succeed, then fails every following one as
+    /// if the device were full. The failing write is torn, leaving a random
+    /// part of its data in the file.
+    pub fn fail_writes_after(&self, writes: u64) {
+        let faults = &mut self.state.lock().unwrap().faults;
+        faults.writes_left = Some(writes);
+        faults.transient = false;
+    }
+
+    /// Lets `writes` more writes succeed, then fails only the next one, torn
+    /// as with `fail_writes_after`, as if the device were briefly full.
+    pub fn fail_one_write_after(&self, writes: u64) {
+        let faults = &mut self.state.lock().unwrap().faults;
+        faults.writes_left = Some(writes);
+        faults.transient = true;
+    }
+
+    /// Makes every following rename fail, leaving both files untouched.
+    pub fn fail_renames(&self) {
+        self.state.lock().unwrap().faults.fail_renames = true;
+    }
+
+    /// Stops injecting faults, as when space is freed on the device.
+    pub fn clear_faults(&self) {
+        self.state.lock().unwrap().faults = Faults::default();
+    }
+
+    /// Simulates a power loss: unsynced writes are dropped or torn and every
+    /// fault is cleared. Handles opened before the crash must not be used.
+    pub fn crash(&self) {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+
+        let mut paths: Vec<&String> = state.files.keys().collect();
+        paths.sort();
+
+        for path in paths {
+            let mut file = state.files[path].lock().unwrap();
+            let mut data = file.durable.clone();
+            let pending = std::mem::take(&mut file.pending);
+            let kept = state.rng.gen_range(0, pending.len() + 1);
+
+            for write in &pending[..kept] {
+                apply(&mut data, write);
+            }
+
+            if let Some(PendingWrite::At(position, bytes)) = pending.get(kept) {
+                let torn = state.rng.gen_range(0, bytes.len() + 1);
+                apply(
+                    &mut data,
+                    &PendingWrite::At(*position, bytes[..torn].to_vec()),
+                );
+            }
+
+            file.durable = data.clone();
+            file.data = data;
+        }
+
+        state.faults = Faults::default();
+    }
+
+    fn get(&self, path: &str) -> Result<SharedFile> {
+        match self.state.lock().unwrap().files.get(path) {
+            Some(file) => Ok(Arc::clone(file)),
+            None => Err(not_found(path)),
+        }
+    }
+}
+
+fn not_found(path: &str) -> Error {
+    Error::new(ErrorKind::NotFound, format!("File not found: {}", path))
+}
+
+struct FaultyFile {
+    file: SharedFile,
+    state: Arc<Mutex<State>>,
+}
+
+impl FaultyFile {
+    fn write(&mut self, position: u64, data: &[u8]) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+
+        let full = match state.faults.writes_left.as_mut() {
+            Some(0) => true,
+            Some(left) => {
+                *left -= 1;
+                false
+            }
+            None => false,
+        };
+        if full && state.faults.transient {
+            state.faults.writes_left = None;
+        }
+
+        let size = match full {
+            true => state.rng.gen_range(0, data.len() + 1),
+            false => data.len(),
+        };
+
+        let write = PendingWrite::At(position, data[..size].to_vec());
+        let mut file = self.file.lock().unwrap();
+        apply(&mut file.data, &write);
+        file.pending.push(write);
+
+        match full {
+            true => Err(Error::new(
+                ErrorKind::StorageFull,
+                "No space left on device",
+            )),
+            false => Ok(()),
+        }
+    }
+}
+
+impl VfsFile for FaultyFile {
+    fn read_at(&self, position: u64, buffer: &mut [u8]) -> Result<usize> {
+        let file = self.file.lock().unwrap();
+        let start = (position as usize).min(file.data.len());
+        let size = buffer.len().min(file.data.len() - start);
+        buffer[..size].copy_from_slice(&file.data[start..start + size]);
+        Ok(size)
+    }
+
+    fn write_at(&mut self, position: u64, data: &[u8]) -> Result<()> {
+        self.write(position, data)
+    }
+
+    fn append(&mut self, data: &[u8]) -> Result<u64> {
+        let position = self.len()?;
+        self.write(position, data)?;
+        Ok(position)
+    }
+
+    fn len(&self) -> Result<u64> {
+        Ok(self.file.lock().unwrap().data.len() as u64)
+    }
+
+    fn set_len(&mut self, size: u64) -> Result<()> {
+        let write = PendingWrite::Truncate(size);
+        let mut file = self.file.lock().unwrap();
+        apply(&mut file.data, &write);
+        file.pending.push(write);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        let mut file = self.file.lock().unwrap();
+        file.durable = file.data.clone();
+        file.pending.clear();
+        Ok(())
+    }
+}
+
+impl Vfs for FaultyVfs {
+    fn create(&self, path: &str) -> Result<Box<dyn VfsFile>> {
+        let file = SharedFile::default();
+        self.state
+            .lock()
+            .unwrap()
+            .files
+            .insert(String::from(path), Arc::clone(&file));
+        Ok(Box::new(FaultyFile {
+            file,
+            state: Arc::clone(&self.state),
+        }))
+    }
+
+    fn open(&self, path: &str) -> Result<Box<dyn VfsFile>> {
+        Ok(Box::new(FaultyFile {
+            file: self.get(path)?,
+            state: Arc::clone(&self.state),
+        }))
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        self.state.lock().unwrap().files.contains_key(path)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.faults.fail_renames {
+            return Err(Error::other(format!("Failed to rename {} to {}", from, to)));
+        }
+
+        match state.files.remove(from) {
+            Some(file) => {
+                state.files.insert(String::from(to), file);
+                Ok(())
+            }
+            None => Err(not_found(from)),
+        }
+    }
+
+    fn remove(&self, path: &str) -> Result<()> {
+        match self.state.lock().unwrap().files.remove(path) {
+            Some(_) => Ok(()),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn create_dir_all(&self, _path: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn remove_dir_all(&self, path: &str) -> Result<()> {
+        let prefix = format!("{}/", path);
+        self.state
+            .lock()
+            .unwrap()
+            .files
+            .retain(|file, _| !file.starts_with(&prefix));
+        Ok(())
+    }
+
+    fn list(&self, folder: &str) -> Result<Vec<String>> {
+        let prefix = format!("{}/", folder);
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .files
+            .keys()
+            .filter_map(|path| path.strip_prefix(&prefix))
+            .filter(|name| !name.contains('/'))
+            .map(String::from)
+            .collect())
+    }
+}
@@ -0,0 +1,653 @@
+use std::fmt;
+use std::io::{self, prelude::*, BufReader};
+
+/// Bytes accepted for the request line and headers together.
+static MAX_HEAD_SIZE: usize = 16_384;
+
+pub struct HttpRequest {
+    pub method: String,
+    /// Request target as sent, with its query string.
+    pub target: String,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    /// Value of the first header named `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Whether the connection stays open after the response, which is the
+    /// default from HTTP/1.1 on.
+    pub fn keep_alive(&self) -> bool {
+        let options = self.header("Connection").unwrap_or("");
+        let has_option = |name: &str| {
+            options
+                .split(',')
+                .any(|option| option.trim().eq_ignore_ascii_case(name))
+        };
+
+        match self.version.as_str() {
+            "HTTP/1.0" => has_option("keep-alive"),
+            _ => !has_option("close"),
+        }
+    }
+
+    /// Target without its query string.
+    pub fn path(&self) -> &str {
+        match self.target.find('?') {
+            Some(position) => &self.target[..position],
+            None => &self.target,
+        }
+    }
+
+    /// Raw value of the first query parameter named `name`, still
+    /// percent-encoded, or an empty value when it has none.
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        let query = &self.target[self.path().len()..];
+        query
+            .strip_prefix('?')?
+            .split('&')
+            .map(|param| param.split_once('=').unwrap_or((param, "")))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    }
+}
+
+#[derive(Debug)]
+pub enum HttpError {
+    Io(io::Error),
+    /// The stream ended in the middle of a request.
+    Incomplete,
+    BadRequest(String),
+    PayloadTooLarge(usize),
+    HeadersTooLarge,
+    UnsupportedVersion(String),
+}
+
+impl HttpError {
+    /// Status of the response telling the client what went wrong, or `None`
+    /// when the connection itself failed.
+    pub fn status_code(&self) -> Option<u16> {
+        match self {
+            HttpError::Io(_) => None,
+            HttpError::Incomplete | HttpError::BadRequest(_) => Some(400),
+            HttpError::PayloadTooLarge(_) => Some(413),
+            HttpError::HeadersTooLarge => Some(431),
+            HttpError::UnsupportedVersion(_) => Some(505),
+        }
+    }
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HttpError::Io(err) => write!(f, "{}", err),
+            HttpError::Incomplete => write!(f, "Request ended unexpectedly"),
+            HttpError::BadRequest(msg) => write!(f, "{}", msg),
+            HttpError::PayloadTooLarge(limit) => {
+                write!(f, "Request body is larger than {} bytes", limit)
+            }
+            HttpError::HeadersTooLarge => {
+                write!(f, "Request headers are larger than {} bytes", MAX_HEAD_SIZE)
+            }
+            HttpError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported HTTP version: {}", version)
+            }
+        }
+    }
+}
+
+impl From<io::Error> for HttpError {
+    fn from(err: io::Error) -> HttpError {
+        match err.kind() {
+            io::ErrorKind::UnexpectedEof => HttpError::Incomplete,
+            _ => HttpError::Io(err),
+        }
+    }
+}
+
+fn bad_request(msg: &str) -> HttpError {
+    HttpError::BadRequest(String::from(msg))
+}
+
+/// Reads one request from `stream`, returning `None` when the client closed
+/// the connection before sending anything. The body is read according to
+/// `Transfer-Encoding: chunked` or `Content-Length`, and refused when longer
+/// than `max_body_size`. Clients waiting on `Expect: 100-continue` are told
+/// to send their body.
+pub fn read_request<S: Read + Write>(
+    stream: &mut BufReader<S>,
+    max_body_size: usize,
+) -> Result<Option<HttpRequest>, HttpError> {
+    parse(stream, max_body_size, |stream: &mut BufReader<S>| {
+        let writer = stream.get_mut();
+        writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+        writer.flush()
+    })
+}
+
+pub enum ParseStatus {
+    /// A request and the number of bytes it took.
+    Complete(HttpRequest, usize),
+    /// More bytes are needed, after telling the client to send its body
+    /// when it waits on `Expect: 100-continue`.
+    Partial { continue_expected: bool },
+}
+
+/// Parses a request from the bytes received so far, for servers reading
+/// from sockets on their own, as async ones do.
+pub fn parse_request(buffer: &[u8], max_body_size: usize) -> Result<ParseStatus, HttpError> {
+    let mut cursor = io::Cursor::new(buffer);
+    let mut continue_expected = false;
+
+    let result = parse(&mut cursor, max_body_size, |_: &mut io::Cursor<&[u8]>| {
+        continue_expected = true;
+        Ok(())
+    });
+
+    match result {
+        Ok(Some(request)) => Ok(ParseStatus::Complete(request, cursor.position() as usize)),
+        Ok(None) | Err(HttpError::Incomplete) => Ok(ParseStatus::Partial { continue_expected }),
+        Err(err) => Err(err),
+    }
+}
+
+fn parse<R, F>(
+    stream: &mut R,
+    max_body_size: usize,
+    mut send_continue: F,
+) -> Result<Option<HttpRequest>, HttpError>
+where
+    R: BufRead,
+    F: FnMut(&mut R) -> io::Result<()>,
+{
+    let mut head_size = 0;
+
+    let request_line = match read_line(stream, &mut head_size)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+
+    let mut parts = request_line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None)
+            if !method.is_empty() && target.starts_with('/') =>
+        {
+            (method, target, version)
+        }
+        _ => return Err(bad_request("Invalid request line")),
+    };
+
+    if version != "HTTP/1.1" && version != "HTTP/1.0" {
+        return Err(HttpError::UnsupportedVersion(String::from(version)));
+    }
+
+    let mut request = HttpRequest {
+        method: String::from(method),
+        target: String::from(target),
+        version: String::from(version),
+        headers: read_headers(stream, &mut head_size)?,
+        body: Vec::new(),
+    };
+
+    let chunked = match request.header("Transfer-Encoding") {
+        Some(encoding) if encoding.eq_ignore_ascii_case("chunked") => true,
+        Some(encoding) => {
+            return Err(HttpError::BadRequest(format!(
+                "Unsupported transfer encoding: {}",
+                encoding
+            )))
+        }
+        None => false,
+    };
+
+    let content_length = match request.header("Content-Length") {
+        Some(_) if chunked => None,
+        Some(value) => match value.parse::<usize>() {
+            Ok(length) if length > max_body_size => {
+                return Err(HttpError::PayloadTooLarge(max_body_size))
+            }
+            Ok(length) => Some(length),
+            Err(_) => return Err(bad_request("Invalid Content-Length")),
+        },
+        None => None,
+    };
+
+    if chunked || content_length.unwrap_or(0) > 0 {
+        if let Some(expect) = request.header("Expect") {
+            if expect.eq_ignore_ascii_case("100-continue") && request.version == "HTTP/1.1" {
+                send_continue(stream)?;
+            }
+        }
+    }
+
+    request.body = match content_length {
+        Some(length) => {
+            let mut body = vec![0; length];
+            stream.read_exact(&mut body)?;
+            body
+        }
+        None if chunked => read_chunked_body(stream, max_body_size)?,
+        None => Vec::new(),
+    };
+
+    Ok(Some(request))
+}
+
+/// Reads a line ended by CRLF (or a bare LF), counting it against the head
+/// size limit. Returns `None` on end of stream before any byte.
+fn read_line<R: BufRead>(
+    stream: &mut R,
+    head_size: &mut usize,
+) -> Result<Option<String>, HttpError> {
+    let mut line = Vec::new();
+    let limit = (MAX_HEAD_SIZE - *head_size) as u64 + 1;
+    let size = stream.by_ref().take(limit).read_until(b'\n', &mut line)?;
+
+    if size == 0 {
+        return Ok(None);
+    }
+
+    *head_size += size;
+    if *head_size > MAX_HEAD_SIZE {
+        return Err(HttpError::HeadersTooLarge);
+    }
+
+    if line.pop() != Some(b'\n') {
+        return Err(HttpError::Incomplete);
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+
+    match String::from_utf8(line) {
+        Ok(line) => Ok(Some(line)),
+        Err(_) => Err(bad_request("Request head is not valid UTF-8")),
+    }
+}
+
+fn read_headers<R: BufRead>(
+    stream: &mut R,
+    head_size: &mut usize,
+) -> Result<Vec<(String, String)>, HttpError> {
+    let mut headers = Vec::new();
+
+    loop {
+        let line = match read_line(stream, head_size)? {
+            Some(line) => line,
+            None => return Err(HttpError::Incomplete),
+        };
+
+        if line.is_empty() {
+            return Ok(headers);
+        }
+
+        match line.split_once(':') {
+            Some((name, value)) if !name.is_empty() && !name.contains(' ') => {
+                headers.push((String::from(name), String::from(value.trim())));
+            }
+            _ => return Err(HttpError::BadRequest(format!("Invalid header: {}", line))),
+        }
+    }
+}
+
+fn read_chunked_body<R: BufRead>(
+    stream: &mut R,
+    max_body_size: usize,
+) -> Result<Vec<u8>, HttpError> {
+    let mut body = Vec::new();
+    let mut head_size = 0;
+
+    loop {
+        let line = match read_line(stream, &mut head_size)? {
+            Some(line) => line,
+            None => return Err(HttpError::Incomplete),
+        };
+        head_size = 0;
+
+        let size = line.split(';').next().unwrap_or("").trim();
+        let size = match usize::from_str_radix(size, 16) {
+            Ok(size) => size,
+            Err(_) => return Err(bad_request("Invalid chunk size")),
+        };
+
+        if size == 0 {
+            // trailers are read and ignored
+            read_headers(stream, &mut head_size)?;
+            return Ok(body);
+        }
+
+        if body.len() + size > max_body_size {
+            return Err(HttpError::PayloadTooLarge(max_body_size));
+        }
+
+        let start = body.len();
+        body.resize(start + size, 0);
+        stream.read_exact(&mut body[start..])?;
+
+        let mut end = [0; 2];
+        stream.read_exact(&mut end)?;
+        if &end != b"\r\n" {
+            return Err(bad_request("Chunk is not followed by CRLF"));
+        }
+    }
+}
+
+/// Decodes `%XX` escapes into the bytes they stand for, returning `None` on
+/// malformed escapes.
+pub fn percent_decode(value: &str) -> Option<Vec<u8>> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut position = 0;
+
+    while position < bytes.len() {
+        match bytes[position] {
+            b'%' => {
+                let hex = bytes.get(position + 1..position + 3)?;
+                let hex = std::str::from_utf8(hex).ok()?;
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+                position += 3;
+            }
+            byte => {
+                decoded.push(byte);
+                position += 1;
+            }
+        }
+    }
+
+    Some(decoded)
+}
+
+/// Escapes every byte but unreserved characters, so `value` can be sent in
+/// a path or a query string.
+pub fn percent_encode(value: &[u8]) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(*byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn new(status_code: u16, content_type: &str, body: Vec<u8>) -> HttpResponse {
+        HttpResponse {
+            status_code,
+            headers: vec![(String::from("Content-Type"), String::from(content_type))],
+            body,
+        }
+    }
+
+    pub fn json(status_code: u16, body: String) -> HttpResponse {
+        HttpResponse::new(status_code, "application/json", body.into_bytes())
+    }
+
+    pub fn text(status_code: u16, body: String) -> HttpResponse {
+        HttpResponse::new(status_code, "text/plain; charset=utf-8", body.into_bytes())
+    }
+
+    pub fn empty(status_code: u16) -> HttpResponse {
+        HttpResponse {
+            status_code,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> HttpResponse {
+        self.headers.push((String::from(name), String::from(value)));
+        self
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.to_bytes())?;
+        writer.flush()
+    }
+
+    /// Status line, headers and body. `Content-Length` is always sent,
+    /// except on responses that cannot have a body.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = format!(
+            "HTTP/1.1 {} {}\r\n",
+            self.status_code,
+            reason_phrase(self.status_code)
+        )
+        .into_bytes();
+
+        for (name, value) in &self.headers {
+            data.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+
+        let has_body = self.status_code != 204 && self.status_code != 304;
+        if has_body {
+            data.extend_from_slice(format!("Content-Length: {}\r\n", self.body.len()).as_bytes());
+        }
+        data.extend_from_slice(b"\r\n");
+        if has_body {
+            data.extend_from_slice(&self.body);
+        }
+
+        data
+    }
+
+    /// Status line and headers of a response whose body follows in chunks,
+    /// each written with `chunk` and ended by `LAST_CHUNK`.
+    pub fn chunked_head(&self) -> Vec<u8> {
+        let mut data = format!(
+            "HTTP/1.1 {} {}\r\n",
+            self.status_code,
+            reason_phrase(self.status_code)
+        )
+        .into_bytes();
+
+        for (name, value) in &self.headers {
+            data.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+        data.extend_from_slice(b"Transfer-Encoding: chunked\r\n\r\n");
+        data
+    }
+}
+
+/// Ends a chunked body.
+pub static LAST_CHUNK: &[u8] = b"0\r\n\r\n";
+
+/// `data` framed as a chunk of a chunked body, which must not be empty as
+/// an empty chunk ends the body.
+pub fn chunk(data: &[u8]) -> Vec<u8> {
+    let mut framed = format!("{:x}\r\n", data.len()).into_bytes();
+    framed.extend_from_slice(data);
+    framed.extend_from_slice(b"\r\n");
+    framed
+}
+
+pub fn reason_phrase(status_code: u16) -> &'static str {
+    match status_code {
+        100 => "Continue",
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        411 => "Length Required",
+        412 => "Precondition Failed",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        422 => "Unprocessable Entity",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        505 => "HTTP Version Not Supported",
+        _ => "Unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn parse(data: &[u8], max_body_size: usize) -> Result<Option<HttpRequest>, HttpError> {
+        read_request(
+            &mut BufReader::new(Cursor::new(data.to_vec())),
+            max_body_size,
+        )
+    }
+
+    #[test]
+    fn read_body_with_content_length() {
+        let body = "x".repeat(2_000);
+        let data = format!(
+            "PUT /keys/1?a=b HTTP/1.1\r\nHost: localhost\r\ncontent-length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+
+        let request = parse(data.as_bytes(), 10_000).unwrap().unwrap();
+
+        assert_eq!(request.method, "PUT");
+        assert_eq!(request.target, "/keys/1?a=b");
+        assert_eq!(request.path(), "/keys/1");
+        assert_eq!(request.header("Host"), Some("localhost"));
+        assert_eq!(request.header("Content-Length"), Some("2000"));
+        assert_eq!(request.body, body.into_bytes());
+    }
+
+    #[test]
+    fn read_chunked_body() {
+        let data = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5\r\nhello\r\n7;ext=1\r\n, world\r\n0\r\nTrailer: x\r\n\r\n";
+
+        let request = parse(data, 100).unwrap().unwrap();
+
+        assert_eq!(request.body, b"hello, world".to_vec());
+    }
+
+    #[test]
+    fn reject_invalid_requests() {
+        let too_large = parse(b"POST / HTTP/1.1\r\nContent-Length: 11\r\n\r\n", 10);
+        let chunked_too_large = parse(
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n6\r\nhello!\r\n",
+            5,
+        );
+        let headerless = parse(b"GET / HTTP/1.1\r\n", 10);
+        let bad_line = parse(b"GET\r\n\r\n", 10);
+        let bad_version = parse(b"GET / HTTP/2.0\r\n\r\n", 10);
+        let bad_length = parse(b"POST / HTTP/1.1\r\nContent-Length: abc\r\n\r\n", 10);
+        let short_body = parse(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nab", 10);
+        let huge_head = parse(
+            format!("GET / HTTP/1.1\r\nX: {}\r\n\r\n", "a".repeat(MAX_HEAD_SIZE)).as_bytes(),
+            10,
+        );
+
+        assert_eq!(too_large.err().unwrap().status_code(), Some(413));
+        assert_eq!(chunked_too_large.err().unwrap().status_code(), Some(413));
+        assert_eq!(headerless.err().unwrap().status_code(), Some(400));
+        assert_eq!(bad_line.err().unwrap().status_code(), Some(400));
+        assert_eq!(bad_version.err().unwrap().status_code(), Some(505));
+        assert_eq!(bad_length.err().unwrap().status_code(), Some(400));
+        assert_eq!(short_body.err().unwrap().status_code(), Some(400));
+        assert_eq!(huge_head.err().unwrap().status_code(), Some(431));
+        assert!(parse(b"", 10).unwrap().is_none());
+    }
+
+    #[test]
+    fn parse_buffered_requests() {
+        let data =
+            b"PUT /keys/a HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 3\r\n\r\nabcGET";
+
+        let partial = parse_request(&data[..10], 10).unwrap();
+        let waiting_body = parse_request(&data[..data.len() - 6], 10).unwrap();
+        let complete = parse_request(data, 10).unwrap();
+        let next = parse_request(b"GET", 10).unwrap();
+
+        assert!(matches!(
+            partial,
+            ParseStatus::Partial {
+                continue_expected: false
+            }
+        ));
+        assert!(matches!(
+            waiting_body,
+            ParseStatus::Partial {
+                continue_expected: true
+            }
+        ));
+        match complete {
+            ParseStatus::Complete(request, size) => {
+                assert_eq!(request.body, b"abc".to_vec());
+                assert_eq!(size, data.len() - 3);
+            }
+            ParseStatus::Partial { .. } => panic!("request is complete"),
+        }
+        assert!(matches!(next, ParseStatus::Partial { .. }));
+        assert!(parse_request(b"GET\r\n\r\n", 10).is_err());
+    }
+
+    #[test]
+    fn encode_and_decode_percent_escapes() {
+        assert_eq!(percent_decode("a%2Fb%20c").unwrap(), b"a/b c".to_vec());
+        assert_eq!(percent_decode("%00%FF").unwrap(), vec![0, 255]);
+        assert_eq!(percent_decode("a+b").unwrap(), b"a+b".to_vec());
+        assert!(percent_decode("%G0").is_none());
+        assert!(percent_decode("abc%2").is_none());
+        assert_eq!(percent_encode(b"a/b c~\xff"), "a%2Fb%20c~%FF");
+    }
+
+    #[test]
+    fn find_query_params() {
+        let request = HttpRequest {
+            method: String::from("GET"),
+            target: String::from("/keys?prefix=a%2F&limit=10&values"),
+            version: String::from("HTTP/1.1"),
+            headers: Vec::new(),
+            body: Vec::new(),
+        };
+
+        assert_eq!(request.query_param("prefix"), Some("a%2F"));
+        assert_eq!(request.query_param("limit"), Some("10"));
+        assert_eq!(request.query_param("values"), Some(""));
+        assert_eq!(request.query_param("start"), None);
+    }
+
+    #[test]
+    fn write_status_line_and_headers() {
+        let mut output = Vec::new();
+        HttpResponse::json(200, String::from("{\"id\":1}"))
+            .write_to(&mut output)
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+             Content-Length: 8\r\n\r\n{\"id\":1}"
+        );
+
+        let mut output = Vec::new();
+        HttpResponse::empty(204).write_to(&mut output).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "HTTP/1.1 204 No Content\r\n\r\n"
+        );
+    }
+}
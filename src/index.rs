@@ -0,0 +1,230 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{Error, ErrorKind, Result};
+use std::sync::Arc;
+
+use serde_json::Value;
+
+use crate::core::ByteString;
+use crate::keylog::KeyLog;
+use crate::vfs::Vfs;
+
+static INDEXES_FILE: &str = "indexes";
+
+/// Keys of the JSON objects by the value at `path`, a list of field names.
+struct Index {
+    path: Vec<String>,
+    keys_by_value: BTreeMap<ByteString, BTreeSet<ByteString>>,
+    value_by_key: HashMap<ByteString, ByteString>,
+}
+
+impl Index {
+    fn new(path: &str) -> Index {
+        Index {
+            path: path.split('.').map(String::from).collect(),
+            keys_by_value: BTreeMap::new(),
+            value_by_key: HashMap::new(),
+        }
+    }
+
+    /// Indexes `key` by the value of `document` at the path, if any.
+    fn update(&mut self, key: &[u8], document: Option<&Value>) {
+        if let Some(previous) = self.value_by_key.remove(key) {
+            if let Some(keys) = self.keys_by_value.get_mut(&previous) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.keys_by_value.remove(&previous);
+                }
+            }
+        }
+
+        let value = document
+            .and_then(|document| {
+                self.path
+                    .iter()
+                    .try_fold(document, |value, field| value.get(field))
+            })
+            .and_then(indexed_value);
+        if let Some(value) = value {
+            self.keys_by_value
+                .entry(value.clone())
+                .or_default()
+                .insert(key.to_vec());
+            self.value_by_key.insert(key.to_vec(), value);
+        }
+    }
+}
+
+/// Strings are indexed by their text, numbers and booleans by their JSON
+/// text, while null, arrays and objects are not indexed.
+fn indexed_value(value: &Value) -> Option<ByteString> {
+    match value {
+        Value::String(text) => Some(text.clone().into_bytes()),
+        Value::Number(number) => Some(number.to_string().into_bytes()),
+        Value::Bool(flag) => Some(flag.to_string().into_bytes()),
+        _ => None,
+    }
+}
+
+/// Secondary indexes on fields of the values that are JSON objects. Their
+/// names and paths are persisted to the `indexes` log, while their entries
+/// are kept in memory and built again from the records on load.
+pub(crate) struct Indexes {
+    paths: KeyLog<ByteString>,
+    indexes: BTreeMap<String, Index>,
+}
+
+impl Indexes {
+    pub fn in_memory() -> Indexes {
+        Indexes {
+            paths: KeyLog::in_memory(),
+            indexes: BTreeMap::new(),
+        }
+    }
+
+    /// Loads the declared indexes, still empty.
+    pub fn load(folder: &str, vfs: &Arc<dyn Vfs>) -> Result<Indexes> {
+        let paths: KeyLog<ByteString> = KeyLog::load(folder, INDEXES_FILE, vfs)?;
+        let indexes = paths
+            .iter()
+            .map(|(name, path)| {
+                let name = String::from_utf8_lossy(name).into_owned();
+                (name, Index::new(&String::from_utf8_lossy(path)))
+            })
+            .collect();
+        Ok(Indexes { paths, indexes })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.indexes.is_empty()
+    }
+
+    /// Declares the empty index `name` on `path`, returning false when
+    /// there is one by that name already.
+    pub fn create(&mut self, name: &str, path: &str) -> Result<bool> {
+        if path.split('.').any(str::is_empty) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid JSON path: {}", path),
+            ));
+        }
+        if self.indexes.contains_key(name) {
+            return Ok(false);
+        }
+
+        self.paths
+            .set(name.as_bytes().to_vec(), path.as_bytes().to_vec())?;
+        self.indexes.insert(String::from(name), Index::new(path));
+        Ok(true)
+    }
+
+    /// Removes the index `name`, returning whether there was one.
+    pub fn remove(&mut self, name: &str) -> Result<bool> {
+        if self.indexes.remove(name).is_none() {
+            return Ok(false);
+        }
+
+        self.paths.clear(name.as_bytes())
+    }
+
+    /// Names and paths of the indexes, sorted by name.
+    pub fn list(&self) -> Vec<(String, String)> {
+        self.indexes
+            .iter()
+            .map(|(name, index)| (name.clone(), index.path.join(".")))
+            .collect()
+    }
+
+    /// Updates every index, or only `only`, with the value of `key`, which
+    /// is empty once the key is deleted.
+    pub fn update(&mut self, key: &[u8], value: &[u8], only: Option<&str>) {
+        if self.indexes.is_empty() {
+            return;
+        }
+
+        let document = serde_json::from_slice::<Value>(value).ok();
+        for (name, index) in self.indexes.iter_mut() {
+            if only.is_none_or(|only| only == name) {
+                index.update(key, document.as_ref());
+            }
+        }
+    }
+
+    /// Keys whose value at the path of the index `name` is `value`, sorted.
+    pub fn find(&self, name: &str, value: &str) -> Result<Vec<ByteString>> {
+        let index = self
+            .indexes
+            .get(name)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("Index not found: {}", name)))?;
+
+        Ok(index
+            .keys_by_value
+            .get(value.as_bytes())
+            .map(|keys| keys.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::MemoryVfs;
+
+    #[test]
+    fn follow_updates_of_indexed_fields() {
+        let mut indexes = Indexes::in_memory();
+        indexes.create("email", "email").unwrap();
+        indexes.create("city", "address.city").unwrap();
+
+        indexes.update(
+            b"1",
+            br#"{"email":"a@test.com","address":{"city":"Porto"}}"#,
+            None,
+        );
+        indexes.update(
+            b"2",
+            br#"{"email":"a@test.com","address":{"city":42}}"#,
+            None,
+        );
+        indexes.update(b"3", br#"{"email":["a@test.com"]}"#, None);
+        indexes.update(b"4", b"not json", None);
+        indexes.update(b"1", br#"{"email":"b@test.com"}"#, None);
+
+        assert_eq!(
+            indexes.find("email", "a@test.com").unwrap(),
+            vec![b"2".to_vec()]
+        );
+        assert_eq!(
+            indexes.find("email", "b@test.com").unwrap(),
+            vec![b"1".to_vec()]
+        );
+        assert_eq!(indexes.find("city", "42").unwrap(), vec![b"2".to_vec()]);
+        assert!(indexes.find("city", "Porto").unwrap().is_empty());
+
+        indexes.update(b"2", b"", None);
+        assert!(indexes.find("email", "a@test.com").unwrap().is_empty());
+        assert_eq!(
+            indexes.find("missing", "a").unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn keep_declared_indexes_across_loads() {
+        let vfs: Arc<dyn Vfs> = Arc::new(MemoryVfs::new());
+        let mut indexes = Indexes::load("storage", &vfs).unwrap();
+        assert!(indexes.create("email", "email").unwrap());
+        assert!(!indexes.create("email", "other").unwrap());
+        indexes.create("city", "address.city").unwrap();
+        indexes.remove("email").unwrap();
+        assert_eq!(
+            indexes.create("bad", "address.").unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+
+        let indexes = Indexes::load("storage", &vfs).unwrap();
+        assert_eq!(
+            indexes.list(),
+            vec![(String::from("city"), String::from("address.city"))]
+        );
+    }
+}
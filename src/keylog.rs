@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io::{
+    BufReader,
+    ErrorKind::{InvalidData, UnexpectedEof},
+    Result,
+};
+use std::sync::Arc;
+
+use crate::core::{ByteString, KeyValue};
+use crate::store::{build_path, folder_path, read_record, record_size, write_record};
+use crate::vfs::{FileReader, Vfs, VfsFile};
+
+/// Value a `KeyLog` attaches to keys, which must not encode to an empty
+/// record value, as it marks removed keys.
+pub(crate) trait LogValue: Clone + PartialEq {
+    fn encode(&self) -> ByteString;
+    fn decode(value: &[u8]) -> Option<Self>;
+}
+
+impl LogValue for u64 {
+    fn encode(&self) -> ByteString {
+        self.to_be_bytes().to_vec()
+    }
+
+    fn decode(value: &[u8]) -> Option<u64> {
+        let bytes: [u8; 8] = value.try_into().ok()?;
+        Some(u64::from_be_bytes(bytes))
+    }
+}
+
+impl LogValue for ByteString {
+    fn encode(&self) -> ByteString {
+        self.clone()
+    }
+
+    fn decode(value: &[u8]) -> Option<ByteString> {
+        match value.is_empty() {
+            true => None,
+            false => Some(value.to_vec()),
+        }
+    }
+}
+
+/// Value attached to some keys, a number by default, kept in memory and
+/// persisted to an append only log of `key -> value` records, where an
+/// empty value removes the key. The log is rewritten on load without the
+/// records that were overridden, or torn by a crash.
+pub(crate) struct KeyLog<V: LogValue = u64> {
+    values: HashMap<ByteString, V>,
+    /// Where the log is persisted, which is only created once a value is
+    /// set.
+    storage: Option<(Arc<dyn Vfs>, String)>,
+    file: Option<Box<dyn VfsFile>>,
+}
+
+impl<V: LogValue> KeyLog<V> {
+    /// Log that is never persisted, for databases only used to write
+    /// segments, as the compressor does.
+    pub fn in_memory() -> KeyLog<V> {
+        KeyLog {
+            values: HashMap::new(),
+            storage: None,
+            file: None,
+        }
+    }
+
+    pub fn load(folder: &str, name: &str, vfs: &Arc<dyn Vfs>) -> Result<KeyLog<V>> {
+        let path = build_path(&folder_path(folder), name);
+        let mut values = HashMap::new();
+        let mut records = 0;
+        let mut clean = true;
+        let file = vfs.open(&path).ok();
+
+        if let Some(file) = &file {
+            let mut end = 0;
+            let mut reader = BufReader::new(FileReader::new(&**file, 0));
+
+            loop {
+                match read_record(&mut reader) {
+                    Ok(key_value) => {
+                        records += 1;
+                        end += record_size(&key_value);
+                        match V::decode(&key_value.value) {
+                            Some(value) => values.insert(key_value.key, value),
+                            None => values.remove(&key_value.key),
+                        };
+                    }
+                    Err(err) if err.kind() == UnexpectedEof || err.kind() == InvalidData => break,
+                    Err(err) => return Err(err),
+                }
+            }
+
+            clean = file.len()? == end && records == values.len();
+        }
+
+        let file = match clean {
+            true => file,
+            false => Some(KeyLog::rewrite(vfs, &path, &values)?),
+        };
+
+        Ok(KeyLog {
+            values,
+            storage: Some((Arc::clone(vfs), path)),
+            file,
+        })
+    }
+
+    fn rewrite(
+        vfs: &Arc<dyn Vfs>,
+        path: &str,
+        values: &HashMap<ByteString, V>,
+    ) -> Result<Box<dyn VfsFile>> {
+        let temp_path = format!("{}.tmp", path);
+        let mut data = Vec::new();
+        for (key, value) in values {
+            write_record(&mut data, &KeyValue::new(key.clone(), value.encode()))?;
+        }
+
+        let mut file = vfs.create(&temp_path)?;
+        file.append(&data)?;
+        file.sync()?;
+        vfs.rename(&temp_path, path)?;
+
+        vfs.open(path)
+    }
+
+    fn append(&mut self, key_value: KeyValue) -> Result<()> {
+        if self.file.is_none() {
+            match &self.storage {
+                Some((vfs, path)) => self.file = Some(vfs.create(path)?),
+                None => return Ok(()),
+            }
+        }
+        let file = self.file.as_mut().unwrap();
+
+        let mut data = Vec::with_capacity(record_size(&key_value) as usize);
+        write_record(&mut data, &key_value)?;
+
+        let size = file.len()?;
+        if let Err(err) = file.append(&data).and_then(|_| file.sync()) {
+            // drops what was written, so the next record follows a complete one
+            file.set_len(size)?;
+            return Err(err);
+        }
+
+        Ok(())
+    }
+
+    pub fn set(&mut self, key: ByteString, value: V) -> Result<()> {
+        if self.values.get(&key) == Some(&value) {
+            return Ok(());
+        }
+
+        self.append(KeyValue::new(key.clone(), value.encode()))?;
+        self.values.insert(key, value);
+        Ok(())
+    }
+
+    /// Removes the value of `key`, returning whether it had one.
+    pub fn clear(&mut self, key: &[u8]) -> Result<bool> {
+        if !self.values.contains_key(key) {
+            return Ok(false);
+        }
+
+        self.append(KeyValue::new(key.to_vec(), Vec::new()))?;
+        self.values.remove(key);
+        Ok(true)
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&V> {
+        self.values.get(key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&ByteString, &V)> {
+        self.values.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::MemoryVfs;
+
+    fn memory() -> Arc<dyn Vfs> {
+        Arc::new(MemoryVfs::new())
+    }
+
+    #[test]
+    fn keep_numbers_across_loads() {
+        let vfs = memory();
+        let mut log: KeyLog = KeyLog::load("storage", "numbers", &vfs).unwrap();
+        log.set(b"a".to_vec(), 10).unwrap();
+        log.set(b"b".to_vec(), 20).unwrap();
+        log.set(b"a".to_vec(), 30).unwrap();
+        log.clear(b"b").unwrap();
+
+        let log: KeyLog = KeyLog::load("storage", "numbers", &vfs).unwrap();
+
+        assert_eq!(log.get(b"a"), Some(&30));
+        assert_eq!(log.get(b"b"), None);
+        assert_eq!(log.iter().count(), 1);
+    }
+
+    #[test]
+    fn rewrite_log_without_overridden_records() {
+        let vfs = memory();
+        let mut log: KeyLog = KeyLog::load("storage", "numbers", &vfs).unwrap();
+        for value in 0..10 {
+            log.set(b"a".to_vec(), value).unwrap();
+        }
+
+        KeyLog::<u64>::load("storage", "numbers", &vfs).unwrap();
+
+        let file = vfs.open("./storage/numbers").unwrap();
+        let expected = record_size(&KeyValue::new(b"a".to_vec(), vec![0; 8]));
+        assert_eq!(file.len().unwrap(), expected);
+    }
+
+    #[test]
+    fn create_log_once_a_number_is_set() {
+        let vfs = memory();
+        let mut log: KeyLog = KeyLog::load("storage", "numbers", &vfs).unwrap();
+        log.clear(b"a").unwrap();
+
+        assert!(!vfs.exists("./storage/numbers"));
+
+        log.set(b"a".to_vec(), 1).unwrap();
+
+        assert!(vfs.exists("./storage/numbers"));
+    }
+}
@@ -1,7 +1,54 @@
+#[cfg(feature = "async")]
+mod async_db;
+mod bloom;
+mod changes;
 mod core;
+mod expiry;
+mod fault;
+mod http;
+mod index;
+mod keylog;
+mod lsm;
+mod memcached;
+mod options;
+mod patch;
+mod pool;
+mod query;
+mod raft;
+mod replication;
+mod resp;
+mod schema;
 mod service;
+mod sharding;
 mod store;
+mod vfs;
 
-pub use crate::core::KeyValue;
-pub use crate::service::{LogCompressor, RustDB};
-pub use crate::store::InitialSegmentReference;
+#[cfg(feature = "async")]
+pub use crate::async_db::AsyncRustDB;
+pub use crate::bloom::BloomStats;
+pub use crate::changes::{ChangeEvent, ChangeFilter, KeyMetadata, ReplicationEvent, Subscription};
+pub use crate::core::{ByteString, KeyValue};
+pub use crate::fault::FaultyVfs;
+pub use crate::http::{
+    chunk, parse_request, percent_decode, percent_encode, read_request, reason_phrase, HttpError,
+    HttpRequest, HttpResponse, ParseStatus, LAST_CHUNK,
+};
+pub use crate::memcached::{MemcachedCommand, MemcachedError, ParsedCommand, StoreMode};
+pub use crate::options::{Durability, Options, StorageMode};
+pub use crate::patch::Patch;
+pub use crate::pool::ThreadPool;
+pub use crate::query::Query;
+pub use crate::raft::{
+    Command, Entry, Envelope, Message, NodeId, Proposal, ProposalStatus, RaftCluster, RaftNode,
+    RaftOptions, ReadIndex, Role, SnapshotChunk,
+};
+pub use crate::replication::{
+    encode_heartbeat, encode_record, encode_replicated, Follower, REPLICATION_LOG_PATH,
+    REPLICATION_SNAPSHOT_PATH, SEQUENCE_HEADER,
+};
+pub use crate::resp::{parse_command, parse_value, RespClient, RespValue};
+pub use crate::schema::{SchemaError, SchemaViolation};
+pub use crate::service::{KeyPage, LogCompressor, PointInTime, RustDB, Store, Version};
+pub use crate::sharding::ShardedDB;
+pub use crate::store::{InitialSegmentReference, SegmentStats};
+pub use crate::vfs::{DiskVfs, FileReader, FileWriter, MemoryVfs, Vfs, VfsFile};
@@ -0,0 +1,831 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use rand::random;
+use std::collections::BTreeMap;
+use std::io::{
+    prelude::*,
+    BufReader, BufWriter,
+    ErrorKind::{InvalidData, UnexpectedEof},
+    Result,
+};
+use std::ops::Bound::{Included, Unbounded};
+use std::sync::Arc;
+
+use crate::bloom::{BloomCounters, BloomFilter, BloomStats};
+use crate::core::{ByteString, KeyValue};
+use crate::options::{Durability, Options};
+use crate::store::{
+    bloom_path, build_path, folder_path, parse_file_name, read_record, read_u64_at, record_size,
+    write_record,
+};
+use crate::vfs::{FileReader, FileWriter, Vfs, VfsFile};
+
+static MANIFEST_FILE: &str = "manifest";
+static WAL_FILE: &str = "wal";
+static FOOTER_SIZE: u64 = 16;
+
+type Records<'a> = dyn Iterator<Item = Result<KeyValue>> + 'a;
+
+fn table_path(folder: &str, name: u64) -> String {
+    build_path(
+        &folder_path(folder),
+        &format!("{}.sst", parse_file_name(name)),
+    )
+}
+
+fn live(key_value: KeyValue) -> Option<KeyValue> {
+    if key_value.value.is_empty() {
+        None
+    } else {
+        Some(key_value)
+    }
+}
+
+/// Immutable file of records sorted by key. Records are grouped in blocks and
+/// only the first key of each block is kept in memory.
+pub struct SSTable {
+    pub name: u64,
+    file: Box<dyn VfsFile>,
+    index: Vec<(ByteString, u64)>,
+    index_offset: u64,
+    pub first_key: ByteString,
+    pub last_key: ByteString,
+    bloom: Option<BloomFilter>,
+    size: u64,
+}
+
+impl SSTable {
+    /// Writes sorted records until `max_size` bytes, returning `None` when
+    /// there was nothing left to write. The file holds the data blocks, the
+    /// sparse index, the key range and a footer pointing to the last two,
+    /// all of them using the same record framing of data segments. A Bloom
+    /// filter of its keys is persisted next to it.
+    pub fn create(
+        vfs: &Arc<dyn Vfs>,
+        folder: &str,
+        records: &mut Records,
+        options: &Options,
+        max_size: u64,
+    ) -> Result<Option<SSTable>> {
+        let name = random::<u64>();
+        let path = table_path(folder, name);
+        let mut file = vfs.create(&path)?;
+        let mut writer = BufWriter::new(FileWriter::new(&mut *file));
+
+        let mut index: Vec<(ByteString, u64)> = Vec::new();
+        let mut keys: Vec<ByteString> = Vec::new();
+        let mut position = 0;
+        let mut block_position = 0;
+        let mut last_key = ByteString::new();
+
+        for record in &mut *records {
+            let record = record?;
+
+            if index.is_empty() || position - block_position >= options.block_size as u64 {
+                index.push((record.key.clone(), position));
+                block_position = position;
+            }
+
+            write_record(&mut writer, &record)?;
+            position += record_size(&record);
+            keys.push(record.key.clone());
+            last_key = record.key;
+
+            if position >= max_size {
+                break;
+            }
+        }
+
+        if index.is_empty() {
+            drop(writer);
+            vfs.remove(&path)?;
+            return Ok(None);
+        }
+
+        let index_offset = position;
+        for (key, offset) in &index {
+            let entry = KeyValue::new(key.clone(), offset.to_be_bytes().to_vec());
+            write_record(&mut writer, &entry)?;
+            position += record_size(&entry);
+        }
+
+        let bounds = KeyValue::new(index[0].0.clone(), last_key);
+        write_record(&mut writer, &bounds)?;
+
+        writer.write_u64::<BigEndian>(index_offset)?;
+        writer.write_u64::<BigEndian>(position)?;
+        writer.flush()?;
+        drop(writer);
+        file.sync()?;
+
+        BloomFilter::from_keys(keys.iter(), options.bloom_false_positive_rate)
+            .save(vfs, &bloom_path(&path))?;
+
+        SSTable::open(vfs, folder, name).map(Some)
+    }
+
+    pub fn open(vfs: &Arc<dyn Vfs>, folder: &str, name: u64) -> Result<SSTable> {
+        let path = table_path(folder, name);
+        let file = vfs.open(&path)?;
+
+        let size = file.len()?;
+        let index_offset = read_u64_at(&*file, size - FOOTER_SIZE)?;
+        let bounds_offset = read_u64_at(&*file, size - FOOTER_SIZE + 8)?;
+
+        let mut index = Vec::new();
+        let mut reader = BufReader::new(FileReader::new(&*file, index_offset));
+
+        while reader.stream_position()? < bounds_offset {
+            let entry = read_record(&mut reader)?;
+            let offset = (&entry.value[..]).read_u64::<BigEndian>()?;
+            index.push((entry.key, offset));
+        }
+
+        let bounds = read_record(&mut reader)?;
+        drop(reader);
+
+        Ok(SSTable {
+            name,
+            file,
+            index,
+            index_offset,
+            first_key: bounds.key,
+            last_key: bounds.value,
+            bloom: BloomFilter::load(vfs, &bloom_path(&path)).ok(),
+            size,
+        })
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<KeyValue>> {
+        if key < &self.first_key[..] || key > &self.last_key[..] {
+            return Ok(None);
+        }
+
+        let block = self.index.partition_point(|(first, _)| &first[..] <= key) - 1;
+        let start = self.index[block].1;
+        let end = match self.index.get(block + 1) {
+            Some((_, offset)) => *offset,
+            None => self.index_offset,
+        };
+
+        let mut reader = BufReader::new(FileReader::new(&*self.file, start));
+
+        while reader.stream_position()? < end {
+            let record = read_record(&mut reader)?;
+            if record.key == key {
+                return Ok(Some(record));
+            }
+            if &record.key[..] > key {
+                break;
+            }
+        }
+
+        Ok(None)
+    }
+
+    pub fn iter(&self) -> TableIterator<'_> {
+        TableIterator {
+            reader: BufReader::new(FileReader::new(&*self.file, 0)),
+            position: 0,
+            end: self.index_offset,
+        }
+    }
+
+    /// Records from the start of the block that may hold `key` on, so
+    /// those before `key` in that block are still to be skipped.
+    pub fn iter_from(&self, key: &[u8]) -> TableIterator<'_> {
+        let block = self
+            .index
+            .partition_point(|(first, _)| &first[..] <= key)
+            .saturating_sub(1);
+        let position = self.index.get(block).map_or(0, |(_, offset)| *offset);
+        TableIterator {
+            reader: BufReader::new(FileReader::new(&*self.file, position)),
+            position,
+            end: self.index_offset,
+        }
+    }
+
+    /// Whether the table may hold `key`, or `None` when it has no filter.
+    pub fn may_contain(&self, key: &[u8]) -> Option<bool> {
+        self.bloom.as_ref().map(|filter| filter.may_contain(key))
+    }
+
+    fn overlaps(&self, first_key: &[u8], last_key: &[u8]) -> bool {
+        &self.first_key[..] <= last_key && &self.last_key[..] >= first_key
+    }
+
+    pub fn get_size(&self) -> u64 {
+        self.size
+    }
+
+    fn remove(vfs: &Arc<dyn Vfs>, folder: &str, name: u64) -> Result<()> {
+        let path = table_path(folder, name);
+        vfs.remove(&path)?;
+        BloomFilter::remove(vfs, &bloom_path(&path))
+    }
+}
+
+pub struct TableIterator<'a> {
+    reader: BufReader<FileReader<'a>>,
+    position: u64,
+    end: u64,
+}
+
+impl<'a> Iterator for TableIterator<'a> {
+    type Item = Result<KeyValue>;
+
+    fn next(&mut self) -> Option<Result<KeyValue>> {
+        if self.position >= self.end {
+            return None;
+        }
+
+        let record = read_record(&mut self.reader);
+        if let Ok(value) = &record {
+            self.position += record_size(value);
+        }
+
+        Some(record)
+    }
+}
+
+/// Merges sorted sources into a single sorted sequence. When a key shows up
+/// in more than one source, the record from the first source wins, so sources
+/// must be given from the newest to the oldest.
+pub struct MergeIterator<'a> {
+    sources: Vec<Box<Records<'a>>>,
+    heads: Vec<Option<KeyValue>>,
+    started: bool,
+}
+
+impl<'a> MergeIterator<'a> {
+    pub fn new(sources: Vec<Box<Records<'a>>>) -> MergeIterator<'a> {
+        let heads = sources.iter().map(|_| None).collect();
+
+        MergeIterator {
+            sources,
+            heads,
+            started: false,
+        }
+    }
+
+    fn advance(&mut self, source: usize) -> Result<()> {
+        self.heads[source] = self.sources[source].next().transpose()?;
+        Ok(())
+    }
+
+    fn next_record(&mut self) -> Result<Option<KeyValue>> {
+        if !self.started {
+            for source in 0..self.sources.len() {
+                self.advance(source)?;
+            }
+            self.started = true;
+        }
+
+        let mut smallest: Option<usize> = None;
+        for (source, head) in self.heads.iter().enumerate() {
+            if let Some(record) = head {
+                match smallest {
+                    Some(s) if self.heads[s].as_ref().unwrap().key <= record.key => {}
+                    _ => smallest = Some(source),
+                }
+            }
+        }
+
+        let smallest = match smallest {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+
+        let record = self.heads[smallest].take().unwrap();
+        for source in 0..self.heads.len() {
+            if self.heads[source].as_ref().map(|r| &r.key) == Some(&record.key) {
+                self.advance(source)?;
+            }
+        }
+        self.advance(smallest)?;
+
+        Ok(Some(record))
+    }
+}
+
+impl<'a> Iterator for MergeIterator<'a> {
+    type Item = Result<KeyValue>;
+
+    fn next(&mut self) -> Option<Result<KeyValue>> {
+        self.next_record().transpose()
+    }
+}
+
+/// Leveled storage: writes go to a write ahead log and a sorted memtable,
+/// which is flushed to a new table on level 0. Level 0 tables may overlap,
+/// while tables on the following levels have disjoint key ranges and each
+/// level holds `level_size_multiplier` times more data than the previous one.
+pub struct LsmStore {
+    folder: String,
+    options: Options,
+    vfs: Arc<dyn Vfs>,
+    memtable: BTreeMap<ByteString, ByteString>,
+    memtable_size: usize,
+    wal: Box<dyn VfsFile>,
+    levels: Vec<Vec<SSTable>>,
+    bloom_counters: BloomCounters,
+}
+
+impl LsmStore {
+    pub fn load(folder: &str, options: Options, vfs: &Arc<dyn Vfs>) -> LsmStore {
+        vfs.create_dir_all(&folder_path(folder)).unwrap();
+
+        let wal_path = build_path(&folder_path(folder), WAL_FILE);
+        let wal = match vfs.open(&wal_path) {
+            Ok(file) => file,
+            Err(_) => vfs.create(&wal_path).unwrap(),
+        };
+
+        let mut store = LsmStore {
+            folder: String::from(folder),
+            options,
+            vfs: Arc::clone(vfs),
+            memtable: BTreeMap::new(),
+            memtable_size: 0,
+            wal,
+            levels: LsmStore::load_manifest(folder, vfs),
+            bloom_counters: BloomCounters::default(),
+        };
+
+        store.replay_wal().unwrap();
+
+        store
+    }
+
+    fn load_manifest(folder: &str, vfs: &Arc<dyn Vfs>) -> Vec<Vec<SSTable>> {
+        let file = match vfs.open(&build_path(&folder_path(folder), MANIFEST_FILE)) {
+            Ok(f) => f,
+            Err(_) => return vec![Vec::new()],
+        };
+        let mut manifest = BufReader::new(FileReader::new(&*file, 0));
+
+        let level_count = manifest.read_u32::<BigEndian>().unwrap();
+        let mut levels = Vec::new();
+
+        for _ in 0..level_count {
+            let table_count = manifest.read_u32::<BigEndian>().unwrap();
+            let mut level = Vec::new();
+
+            for _ in 0..table_count {
+                let name = manifest.read_u64::<BigEndian>().unwrap();
+                level.push(SSTable::open(vfs, folder, name).unwrap());
+            }
+
+            levels.push(level);
+        }
+
+        levels
+    }
+
+    fn write_manifest(&self) -> Result<()> {
+        let folder_path = folder_path(&self.folder);
+        let temp_path = build_path(&folder_path, &format!("{}.tmp", MANIFEST_FILE));
+
+        let mut manifest = Vec::new();
+        manifest.write_u32::<BigEndian>(self.levels.len() as u32)?;
+
+        for level in &self.levels {
+            manifest.write_u32::<BigEndian>(level.len() as u32)?;
+            for table in level {
+                manifest.write_u64::<BigEndian>(table.name)?;
+            }
+        }
+
+        let mut file = self.vfs.create(&temp_path)?;
+        file.append(&manifest)?;
+        file.sync()?;
+
+        self.vfs
+            .rename(&temp_path, &build_path(&folder_path, MANIFEST_FILE))
+    }
+
+    /// Reads the records of the write ahead log back into the memtable,
+    /// truncating a last record torn by a crash.
+    fn replay_wal(&mut self) -> Result<()> {
+        let mut records = Vec::new();
+        let mut end = 0;
+
+        {
+            let mut reader = BufReader::new(FileReader::new(&*self.wal, 0));
+
+            loop {
+                match read_record(&mut reader) {
+                    Ok(key_value) => {
+                        end += record_size(&key_value);
+                        records.push(key_value);
+                    }
+                    Err(err) if err.kind() == UnexpectedEof || err.kind() == InvalidData => break,
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+
+        if self.wal.len()? > end {
+            self.wal.set_len(end)?;
+            self.wal.sync()?;
+        }
+
+        for key_value in records {
+            self.insert_memtable(key_value);
+        }
+
+        Ok(())
+    }
+
+    fn insert_memtable(&mut self, key_value: KeyValue) {
+        self.memtable_size += record_size(&key_value) as usize;
+        self.memtable.insert(key_value.key, key_value.value);
+    }
+
+    pub fn get_record(&self, key: &[u8]) -> Result<Option<KeyValue>> {
+        if let Some(value) = self.memtable.get(key) {
+            return Ok(live(KeyValue::new(key.to_vec(), value.clone())));
+        }
+
+        for table in &self.levels[0] {
+            if let Some(record) = self.get_from_table(table, key)? {
+                return Ok(live(record));
+            }
+        }
+
+        for level in &self.levels[1..] {
+            let position = level.partition_point(|t| &t.last_key[..] < key);
+            if let Some(table) = level.get(position) {
+                if let Some(record) = self.get_from_table(table, key)? {
+                    return Ok(live(record));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Every live key, in order, merging the memtable with every table.
+    pub fn get_keys(&self) -> Result<Vec<ByteString>> {
+        let mut sources: Vec<Box<Records>> = vec![Box::new(
+            self.memtable
+                .iter()
+                .map(|(k, v)| Ok(KeyValue::new(k.clone(), v.clone()))),
+        )];
+        for table in &self.levels[0] {
+            sources.push(Box::new(table.iter()));
+        }
+        for level in &self.levels[1..] {
+            sources.push(Box::new(level.iter().flat_map(|table| table.iter())));
+        }
+
+        let mut result = Vec::new();
+        for record in MergeIterator::new(sources) {
+            if let Some(key_value) = live(record?) {
+                result.push(key_value.key);
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Live keys from `start` on, in order, seeking to it in the memtable
+    /// and every table rather than reading the keys before it.
+    pub fn keys_from<'a>(
+        &'a self,
+        start: &'a [u8],
+    ) -> impl Iterator<Item = Result<ByteString>> + 'a {
+        let mut sources: Vec<Box<Records>> = vec![Box::new(
+            self.memtable
+                .range::<[u8], _>((Included(start), Unbounded))
+                .map(|(k, v)| Ok(KeyValue::new(k.clone(), v.clone()))),
+        )];
+        for table in &self.levels[0] {
+            sources.push(Box::new(table.iter_from(start)));
+        }
+        for level in &self.levels[1..] {
+            let first = level.partition_point(|table| &table.last_key[..] < start);
+            sources.push(Box::new(
+                level[first..]
+                    .iter()
+                    .flat_map(move |table| table.iter_from(start)),
+            ));
+        }
+
+        MergeIterator::new(sources).filter_map(move |record| match record {
+            Ok(key_value) if &key_value.key[..] < start => None,
+            Ok(key_value) => live(key_value).map(|key_value| Ok(key_value.key)),
+            Err(err) => Some(Err(err)),
+        })
+    }
+
+    fn get_from_table(&self, table: &SSTable, key: &[u8]) -> Result<Option<KeyValue>> {
+        if key < &table.first_key[..] || key > &table.last_key[..] {
+            return Ok(None);
+        }
+
+        match table.may_contain(key) {
+            Some(false) => {
+                self.bloom_counters.record_check(false);
+                Ok(None)
+            }
+            Some(true) => {
+                self.bloom_counters.record_check(true);
+                let record = table.get(key)?;
+                if record.is_none() {
+                    self.bloom_counters.record_false_positive();
+                }
+                Ok(record)
+            }
+            None => table.get(key),
+        }
+    }
+
+    pub fn get_bloom_stats(&self) -> BloomStats {
+        self.bloom_counters.get_stats()
+    }
+
+    pub fn save_record(&mut self, key_value: KeyValue) -> Result<()> {
+        let mut data = Vec::with_capacity(record_size(&key_value) as usize);
+        write_record(&mut data, &key_value)?;
+
+        let size = self.wal.len()?;
+        let sync = self.options.durability == Durability::EveryWrite;
+        let written = self
+            .wal
+            .append(&data)
+            .and_then(|_| if sync { self.wal.sync() } else { Ok(()) });
+        if let Err(err) = written {
+            // drops what was written, so the next record follows a complete one
+            self.wal.set_len(size)?;
+            return Err(err);
+        }
+
+        self.insert_memtable(key_value);
+
+        if self.memtable_size >= self.options.memtable_size {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn delete_record(&mut self, key: ByteString) -> Result<()> {
+        self.save_record(KeyValue::new(key, Vec::new()))
+    }
+
+    /// Writes the memtable as a new level 0 table and empties the write
+    /// ahead log, compacting levels that grew over their limits.
+    pub fn flush(&mut self) -> Result<()> {
+        if self.memtable.is_empty() {
+            return Ok(());
+        }
+
+        let table = {
+            let mut records = self
+                .memtable
+                .iter()
+                .map(|(k, v)| Ok(KeyValue::new(k.clone(), v.clone())));
+            SSTable::create(
+                &self.vfs,
+                &self.folder,
+                &mut records,
+                &self.options,
+                u64::MAX,
+            )?
+            .unwrap()
+        };
+
+        self.levels[0].insert(0, table);
+        self.write_manifest()?;
+
+        self.wal.set_len(0)?;
+        self.wal.sync()?;
+        self.memtable.clear();
+        self.memtable_size = 0;
+
+        self.compact()
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        while let Some(level) = self.level_to_compact() {
+            self.compact_level(level)?;
+        }
+
+        Ok(())
+    }
+
+    fn level_to_compact(&self) -> Option<usize> {
+        if self.levels[0].len() > self.options.level0_file_limit {
+            return Some(0);
+        }
+
+        (1..self.levels.len()).find(|&level| {
+            let size: u64 = self.levels[level].iter().map(|t| t.get_size()).sum();
+            size > self.max_level_size(level)
+        })
+    }
+
+    fn max_level_size(&self, level: usize) -> u64 {
+        self.options.level_base_size * self.options.level_size_multiplier.pow(level as u32 - 1)
+    }
+
+    /// Merges every level 0 table, or the first table of any other level,
+    /// with the overlapping tables of the next level. Deletes are dropped
+    /// once there is no older level where the key could still be found.
+    fn compact_level(&mut self, level: usize) -> Result<()> {
+        if self.levels.len() == level + 1 {
+            self.levels.push(Vec::new());
+        }
+
+        let inputs: Vec<SSTable> = if level == 0 {
+            self.levels[0].drain(..).collect()
+        } else {
+            vec![self.levels[level].remove(0)]
+        };
+
+        let first_key = inputs.iter().map(|t| &t.first_key).min().unwrap().clone();
+        let last_key = inputs.iter().map(|t| &t.last_key).max().unwrap().clone();
+
+        let (overlapping, mut remaining): (Vec<SSTable>, Vec<SSTable>) = self.levels[level + 1]
+            .drain(..)
+            .partition(|t| t.overlaps(&first_key, &last_key));
+
+        let bottommost = self.levels[level + 2..].iter().all(|l| l.is_empty());
+
+        let mut sources: Vec<Box<Records>> = Vec::new();
+        for table in inputs.iter().chain(overlapping.iter()) {
+            sources.push(Box::new(table.iter()));
+        }
+
+        let mut merged = MergeIterator::new(sources).filter(|record| match record {
+            Ok(key_value) => !bottommost || !key_value.value.is_empty(),
+            Err(_) => true,
+        });
+
+        while let Some(table) = SSTable::create(
+            &self.vfs,
+            &self.folder,
+            &mut merged,
+            &self.options,
+            self.options.table_size,
+        )? {
+            remaining.push(table);
+        }
+
+        remaining.sort_by(|a, b| a.first_key.cmp(&b.first_key));
+        self.levels[level + 1] = remaining;
+        self.write_manifest()?;
+
+        for table in inputs.iter().chain(overlapping.iter()) {
+            SSTable::remove(&self.vfs, &self.folder, table.name)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn get_table_counts(&self) -> Vec<usize> {
+        self.levels.iter().map(|l| l.len()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::MemoryVfs;
+
+    fn get_folder_name() -> String {
+        format!("storage_test_{}", random::<u64>())
+    }
+
+    fn memory() -> Arc<dyn Vfs> {
+        Arc::new(MemoryVfs::new())
+    }
+
+    fn small_options() -> Options {
+        Options {
+            memtable_size: 1_000,
+            block_size: 128,
+            level0_file_limit: 2,
+            level_base_size: 4_000,
+            level_size_multiplier: 4,
+            table_size: 2_000,
+            ..Options::leveled()
+        }
+    }
+
+    fn record(i: u32, version: &str) -> KeyValue {
+        KeyValue::new_from_strings(
+            format!("{:04}", i),
+            format!("{{\"id\":\"{}\",\"version\":\"{}\"}}", i, version),
+        )
+    }
+
+    #[test]
+    fn find_records_in_table_blocks() {
+        let folder_name = &get_folder_name();
+        let vfs = memory();
+
+        let mut records = (0..100).map(|i| Ok(record(i * 2, "a")));
+        let table = SSTable::create(&vfs, folder_name, &mut records, &small_options(), u64::MAX)
+            .unwrap()
+            .unwrap();
+
+        assert!(table.index.len() > 1);
+        assert_eq!(table.first_key, b"0000".to_vec());
+        assert_eq!(table.last_key, b"0198".to_vec());
+        assert_eq!(
+            table.get(b"0100").unwrap().unwrap().get_value_as_string(),
+            "{\"id\":\"100\",\"version\":\"a\"}"
+        );
+        assert!(table.get(b"0101").unwrap().is_none());
+        assert!(table.get(b"0200").unwrap().is_none());
+    }
+
+    #[test]
+    fn merge_prefers_newest_source() {
+        let newest = vec![Ok(record(1, "new")), Ok(record(3, "new"))];
+        let oldest = vec![Ok(record(1, "old")), Ok(record(2, "old"))];
+
+        let merged: Vec<KeyValue> = MergeIterator::new(vec![
+            Box::new(newest.into_iter()),
+            Box::new(oldest.into_iter()),
+        ])
+        .map(|r| r.unwrap())
+        .collect();
+
+        assert_eq!(merged.len(), 3);
+        assert_eq!(
+            merged[0].get_value_as_string(),
+            record(1, "new").get_value_as_string()
+        );
+        assert_eq!(
+            merged[1].get_value_as_string(),
+            record(2, "old").get_value_as_string()
+        );
+        assert_eq!(
+            merged[2].get_value_as_string(),
+            record(3, "new").get_value_as_string()
+        );
+    }
+
+    #[test]
+    fn compact_flushed_tables_into_levels() {
+        let folder_name = &get_folder_name();
+        let vfs = memory();
+        let mut store = LsmStore::load(folder_name, small_options(), &vfs);
+
+        for version in &["a", "b", "c"] {
+            for i in 0..200 {
+                store.save_record(record(i, version)).unwrap();
+            }
+        }
+        for i in 0..100 {
+            store
+                .delete_record(format!("{:04}", i).into_bytes())
+                .unwrap();
+        }
+
+        let counts = store.get_table_counts();
+        assert!(counts.len() > 2);
+        assert!(counts[0] <= 2);
+
+        let store = LsmStore::load(folder_name, small_options(), &vfs);
+        assert_eq!(store.get_table_counts(), counts);
+        assert!(store.get_record(b"0050").unwrap().is_none());
+        assert_eq!(
+            store
+                .get_record(b"0150")
+                .unwrap()
+                .unwrap()
+                .get_value_as_string(),
+            record(150, "c").get_value_as_string()
+        );
+    }
+
+    #[test]
+    fn seek_keys_from_a_start_across_levels() {
+        let folder_name = &get_folder_name();
+        let vfs = memory();
+        let mut store = LsmStore::load(folder_name, small_options(), &vfs);
+
+        for version in &["a", "b"] {
+            for i in 0..200 {
+                store.save_record(record(i, version)).unwrap();
+            }
+        }
+        store.delete_record(b"0151".to_vec()).unwrap();
+
+        let keys: Vec<ByteString> = store
+            .keys_from(b"0149x")
+            .take(3)
+            .map(|key| key.unwrap())
+            .collect();
+
+        assert!(store.get_table_counts().len() > 2);
+        assert_eq!(
+            keys,
+            vec![b"0150".to_vec(), b"0152".to_vec(), b"0153".to_vec()]
+        );
+    }
+}
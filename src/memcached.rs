@@ -0,0 +1,375 @@
+use std::fmt;
+
+use crate::core::ByteString;
+
+/// Longest command line accepted, as memcached does.
+static MAX_LINE_SIZE: usize = 2_048;
+static MAX_KEY_SIZE: usize = 250;
+
+#[derive(Debug, PartialEq)]
+pub enum StoreMode {
+    Set,
+    /// Stores only missing keys.
+    Add,
+    /// Stores only existing keys.
+    Replace,
+    /// Stores only if the key still has the given version.
+    Cas(u64),
+}
+
+/// Command of the memcached text protocol. Expiration times are kept as
+/// sent: seconds from now up to 30 days, a Unix time above that, none when
+/// 0 and already expired when negative.
+#[derive(Debug, PartialEq)]
+pub enum MemcachedCommand {
+    Get {
+        keys: Vec<ByteString>,
+        with_cas: bool,
+    },
+    Store {
+        mode: StoreMode,
+        key: ByteString,
+        flags: u32,
+        exptime: i64,
+        data: ByteString,
+        noreply: bool,
+    },
+    Delete {
+        key: ByteString,
+        noreply: bool,
+    },
+    Arithmetic {
+        key: ByteString,
+        delta: u64,
+        increment: bool,
+        noreply: bool,
+    },
+    Touch {
+        key: ByteString,
+        exptime: i64,
+        noreply: bool,
+    },
+    Version,
+    Quit,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MemcachedError {
+    UnknownCommand,
+    /// The command was malformed.
+    Client(String),
+    /// The command could not be served.
+    Server(String),
+}
+
+impl MemcachedError {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        format!("{}\r\n", self).into_bytes()
+    }
+}
+
+impl fmt::Display for MemcachedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MemcachedError::UnknownCommand => write!(f, "ERROR"),
+            MemcachedError::Client(message) => write!(f, "CLIENT_ERROR {}", message),
+            MemcachedError::Server(message) => write!(f, "SERVER_ERROR {}", message),
+        }
+    }
+}
+
+/// Command, or the error to answer in its place, with the number of bytes
+/// it took.
+pub type ParsedCommand = (Result<MemcachedCommand, MemcachedError>, usize);
+
+fn bad_format() -> MemcachedError {
+    MemcachedError::Client(String::from("bad command line format"))
+}
+
+fn parse_number<T: std::str::FromStr>(token: &[u8]) -> Result<T, MemcachedError> {
+    std::str::from_utf8(token)
+        .ok()
+        .and_then(|token| token.parse().ok())
+        .ok_or_else(bad_format)
+}
+
+fn parse_key(token: &[u8]) -> Result<ByteString, MemcachedError> {
+    match token.len() > MAX_KEY_SIZE || token.iter().any(|b| b.is_ascii_control()) {
+        true => Err(bad_format()),
+        false => Ok(token.to_vec()),
+    }
+}
+
+/// Whether the last token is `noreply`, removing it.
+fn take_noreply(tokens: &mut Vec<&[u8]>) -> bool {
+    match tokens.last() {
+        Some(&b"noreply") => {
+            tokens.pop();
+            true
+        }
+        _ => false,
+    }
+}
+
+impl MemcachedCommand {
+    /// Parses the command at the start of `buffer`, or returns `None` when
+    /// more bytes are needed. A malformed command is returned as the error
+    /// to answer, while a line or a data block too large to be buffered
+    /// fails the whole parse, as the connection cannot recover from it.
+    pub fn parse(buffer: &[u8], max_size: usize) -> Result<Option<ParsedCommand>, MemcachedError> {
+        let line_end = match buffer.iter().position(|b| *b == b'\n') {
+            Some(end) if end <= MAX_LINE_SIZE => end,
+            None if buffer.len() <= MAX_LINE_SIZE => return Ok(None),
+            _ => return Err(MemcachedError::Client(String::from("line too long"))),
+        };
+        let line = buffer[..line_end]
+            .strip_suffix(b"\r")
+            .unwrap_or(&buffer[..line_end]);
+        let size = line_end + 1;
+
+        let mut tokens: Vec<&[u8]> = line
+            .split(|b| *b == b' ')
+            .filter(|t| !t.is_empty())
+            .collect();
+        let name = match tokens.first() {
+            Some(name) => name.to_ascii_lowercase(),
+            None => return Ok(Some((Err(MemcachedError::UnknownCommand), size))),
+        };
+        tokens.remove(0);
+
+        let command = match name.as_slice() {
+            b"get" | b"gets" if !tokens.is_empty() => tokens
+                .iter()
+                .map(|token| parse_key(token))
+                .collect::<Result<Vec<ByteString>, MemcachedError>>()
+                .map(|keys| MemcachedCommand::Get {
+                    keys,
+                    with_cas: name == b"gets",
+                }),
+            b"set" | b"add" | b"replace" | b"cas" => {
+                return MemcachedCommand::parse_store(&name, tokens, buffer, size, max_size)
+            }
+            b"delete" => {
+                let noreply = take_noreply(&mut tokens);
+                match tokens.as_slice() {
+                    [key] | [key, b"0"] => {
+                        parse_key(key).map(|key| MemcachedCommand::Delete { key, noreply })
+                    }
+                    _ => Err(bad_format()),
+                }
+            }
+            b"incr" | b"decr" => {
+                let noreply = take_noreply(&mut tokens);
+                match tokens.as_slice() {
+                    [key, delta] => match parse_number(delta) {
+                        Ok(delta) => parse_key(key).map(|key| MemcachedCommand::Arithmetic {
+                            key,
+                            delta,
+                            increment: name == b"incr",
+                            noreply,
+                        }),
+                        Err(_) => Err(MemcachedError::Client(String::from(
+                            "invalid numeric delta argument",
+                        ))),
+                    },
+                    _ => Err(bad_format()),
+                }
+            }
+            b"touch" => {
+                let noreply = take_noreply(&mut tokens);
+                match tokens.as_slice() {
+                    [key, exptime] => parse_number(exptime).and_then(|exptime| {
+                        parse_key(key).map(|key| MemcachedCommand::Touch {
+                            key,
+                            exptime,
+                            noreply,
+                        })
+                    }),
+                    _ => Err(bad_format()),
+                }
+            }
+            b"version" if tokens.is_empty() => Ok(MemcachedCommand::Version),
+            b"quit" if tokens.is_empty() => Ok(MemcachedCommand::Quit),
+            _ => Err(MemcachedError::UnknownCommand),
+        };
+
+        Ok(Some((command, size)))
+    }
+
+    /// `<command> <key> <flags> <exptime> <bytes> [<cas unique>] [noreply]`
+    /// followed by a data block of `bytes` bytes.
+    fn parse_store(
+        name: &[u8],
+        mut tokens: Vec<&[u8]>,
+        buffer: &[u8],
+        line_size: usize,
+        max_size: usize,
+    ) -> Result<Option<ParsedCommand>, MemcachedError> {
+        let noreply = take_noreply(&mut tokens);
+        let expected = if name == b"cas" { 5 } else { 4 };
+        if tokens.len() != expected {
+            return Ok(Some((Err(bad_format()), line_size)));
+        }
+
+        let (key, flags, exptime, bytes, mode) = match parse_store_header(name, &tokens) {
+            Ok(header) => header,
+            Err(err) => return Ok(Some((Err(err), line_size))),
+        };
+
+        if bytes > max_size {
+            return Err(MemcachedError::Server(String::from(
+                "object too large for cache",
+            )));
+        }
+
+        let end = line_size + bytes;
+        if buffer.len() < end + 2 {
+            return Ok(None);
+        }
+        if &buffer[end..end + 2] != b"\r\n" {
+            return Ok(Some((
+                Err(MemcachedError::Client(String::from("bad data chunk"))),
+                end + 2,
+            )));
+        }
+
+        let command = MemcachedCommand::Store {
+            mode,
+            key,
+            flags,
+            exptime,
+            data: buffer[line_size..end].to_vec(),
+            noreply,
+        };
+
+        Ok(Some((Ok(command), end + 2)))
+    }
+
+    /// Whether the client asked not to get a reply.
+    pub fn is_noreply(&self) -> bool {
+        match self {
+            MemcachedCommand::Store { noreply, .. }
+            | MemcachedCommand::Delete { noreply, .. }
+            | MemcachedCommand::Arithmetic { noreply, .. }
+            | MemcachedCommand::Touch { noreply, .. } => *noreply,
+            _ => false,
+        }
+    }
+}
+
+type StoreHeader = (ByteString, u32, i64, usize, StoreMode);
+
+fn parse_store_header(name: &[u8], tokens: &[&[u8]]) -> Result<StoreHeader, MemcachedError> {
+    let key = parse_key(tokens[0])?;
+    let flags = parse_number(tokens[1])?;
+    let exptime = parse_number(tokens[2])?;
+    let bytes = parse_number(tokens[3])?;
+    let mode = match name {
+        b"set" => StoreMode::Set,
+        b"add" => StoreMode::Add,
+        b"replace" => StoreMode::Replace,
+        _ => StoreMode::Cas(parse_number(tokens[4])?),
+    };
+
+    Ok((key, flags, exptime, bytes, mode))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(buffer: &[u8]) -> ParsedCommand {
+        MemcachedCommand::parse(buffer, 100).unwrap().unwrap()
+    }
+
+    #[test]
+    fn parse_storage_commands() {
+        let buffer = b"cas k 5 60 3 99 noreply\r\na\r\n\r\nget k\r\n";
+
+        let (command, size) = parse(buffer);
+
+        assert_eq!(
+            command,
+            Ok(MemcachedCommand::Store {
+                mode: StoreMode::Cas(99),
+                key: b"k".to_vec(),
+                flags: 5,
+                exptime: 60,
+                data: b"a\r\n".to_vec(),
+                noreply: true,
+            })
+        );
+        assert_eq!(size, buffer.len() - 7);
+    }
+
+    #[test]
+    fn wait_for_whole_commands() {
+        let buffer = b"set k 0 0 5\r\nhello\r\n";
+
+        for end in 0..buffer.len() {
+            assert_eq!(MemcachedCommand::parse(&buffer[..end], 100), Ok(None));
+        }
+        assert!(parse(buffer).0.is_ok());
+    }
+
+    #[test]
+    fn parse_retrieval_and_other_commands() {
+        assert_eq!(
+            parse(b"gets a b\n").0,
+            Ok(MemcachedCommand::Get {
+                keys: vec![b"a".to_vec(), b"b".to_vec()],
+                with_cas: true,
+            })
+        );
+        assert_eq!(
+            parse(b"decr a 3\r\n").0,
+            Ok(MemcachedCommand::Arithmetic {
+                key: b"a".to_vec(),
+                delta: 3,
+                increment: false,
+                noreply: false,
+            })
+        );
+        assert_eq!(
+            parse(b"touch a -1 noreply\r\n").0,
+            Ok(MemcachedCommand::Touch {
+                key: b"a".to_vec(),
+                exptime: -1,
+                noreply: true,
+            })
+        );
+        assert_eq!(
+            parse(b"delete a 0\r\n").0,
+            Ok(MemcachedCommand::Delete {
+                key: b"a".to_vec(),
+                noreply: false,
+            })
+        );
+    }
+
+    #[test]
+    fn report_invalid_commands() {
+        assert_eq!(parse(b"\r\n").0, Err(MemcachedError::UnknownCommand));
+        assert_eq!(parse(b"stats\r\n").0, Err(MemcachedError::UnknownCommand));
+        assert_eq!(parse(b"get\r\n").0, Err(MemcachedError::UnknownCommand));
+        assert_eq!(parse(b"set k x 0 1\r\n"), (Err(bad_format()), 13));
+        assert_eq!(
+            parse(b"incr k x\r\n").0.unwrap_err().to_bytes(),
+            b"CLIENT_ERROR invalid numeric delta argument\r\n".to_vec()
+        );
+        assert_eq!(
+            parse(b"set k 0 0 1\r\nab\r\n"),
+            (
+                Err(MemcachedError::Client(String::from("bad data chunk"))),
+                16
+            )
+        );
+        assert!(
+            parse(&[b"get ".to_vec(), vec![b'k'; 251], b"\r\n".to_vec()].concat())
+                .0
+                .is_err()
+        );
+        assert!(MemcachedCommand::parse(b"set k 0 0 101\r\n", 100).is_err());
+        assert!(MemcachedCommand::parse(&vec![b'a'; 3_000], 100).is_err());
+    }
+}
@@ -0,0 +1,539 @@
+use rustdb::{
+    AsyncRustDB, KeyValue, LogCompressor, MemcachedCommand, MemcachedError, Options, RustDB,
+    StoreMode,
+};
+use std::env;
+use std::io::Result;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{thread, time};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Expiration times over 30 days are Unix times rather than seconds from
+/// now.
+static MAX_RELATIVE_EXPTIME: i64 = 60 * 60 * 24 * 30;
+
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(value) => match value.parse() {
+            Ok(value) => value,
+            Err(_) => panic!("Invalid {}: {}", name, value),
+        },
+        Err(_) => default,
+    }
+}
+
+fn main() {
+    println!("Loading database...");
+    let options = Options {
+        change_log_size: env_or("RUSTDB_CHANGE_LOG_SIZE", 16_777_216),
+        history_retention: Duration::from_secs(env_or("RUSTDB_HISTORY_RETENTION", 0)),
+        ..Options::default()
+    };
+    let db = Arc::new(Mutex::new(RustDB::load_with_options("storage", options)));
+    let max_size = env_or("RUSTDB_MAX_BODY_SIZE", 1_048_576);
+
+    let compress_db = Arc::clone(&db);
+    thread::spawn(move || compress_files(compress_db));
+
+    let runtime = match tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => runtime,
+        Err(err) => panic!("Failed to start runtime\n{}", err),
+    };
+
+    runtime.block_on(async move {
+        let listener = match TcpListener::bind("127.0.0.1:11212").await {
+            Ok(listener) => listener,
+            Err(err) => panic!("Failed to bind address\n{}", err),
+        };
+        println!("Database ready at 11212");
+
+        let db = AsyncRustDB::from_shared(db);
+        tokio::select! {
+            _ = serve(listener, db.clone(), max_size) => {}
+            _ = tokio::signal::ctrl_c() => println!("Shutting down..."),
+        }
+
+        if let Err(err) = db.flush().await {
+            println!("Failed to flush database\n{}", err);
+        }
+    });
+}
+
+fn compress_files(db: Arc<Mutex<RustDB>>) -> ! {
+    loop {
+        let folder = "storage";
+        if let Err(err) = db.lock().unwrap().remove_expired() {
+            println!("Failed to remove expired keys\n{}", err);
+        }
+
+        // read under one lock, so the active segment cannot roll over
+        // between picking the segments and the segment they link to
+        let compression = {
+            let db = db.lock().unwrap();
+            let segment_names = db.get_segments_to_compress();
+            match segment_names.is_empty() {
+                true => None,
+                false => Some((
+                    LogCompressor::new(folder, segment_names.clone(), db.get_active_segment_name())
+                        .with_active_keys(db.get_active_keys())
+                        .with_options(db.get_options())
+                        .with_vfs(db.get_vfs()),
+                    segment_names,
+                    db.get_vfs(),
+                )),
+            }
+        };
+
+        if let Some((compressor, segment_names, vfs)) = compression {
+            match compressor.compress() {
+                Ok((active_segment, new_segment)) => {
+                    db.lock()
+                        .unwrap()
+                        .replace_segments(active_segment, new_segment);
+                    LogCompressor::clean_with_vfs(&vfs, folder, segment_names);
+                }
+                Err(err) => println!("Failed to compress segments\n{}", err),
+            }
+        }
+
+        thread::sleep(time::Duration::from_secs(10));
+    }
+}
+
+async fn serve(listener: TcpListener, db: AsyncRustDB, max_size: usize) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(handle_connection(stream, db.clone(), max_size));
+            }
+            Err(err) => println!("Failed to process current stream\n{}", err),
+        }
+    }
+}
+
+/// Answers the commands of `stream` in order until the client quits, or
+/// sends a line or a value too large to be buffered.
+async fn handle_connection(mut stream: TcpStream, db: AsyncRustDB, max_size: usize) {
+    let mut buffer = Vec::new();
+
+    loop {
+        let (command, noreply) = match MemcachedCommand::parse(&buffer, max_size) {
+            Ok(Some((command, size))) => {
+                buffer.drain(..size);
+                let noreply = command.as_ref().is_ok_and(|c| c.is_noreply());
+                (command, noreply)
+            }
+            Ok(None) => match stream.read_buf(&mut buffer).await {
+                Ok(0) | Err(_) => return,
+                Ok(_) => continue,
+            },
+            Err(err) => {
+                let _ = stream.write_all(&err.to_bytes()).await;
+                return;
+            }
+        };
+
+        let reply = match command {
+            Ok(MemcachedCommand::Quit) => return,
+            Ok(MemcachedCommand::Version) => {
+                format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION")).into_bytes()
+            }
+            Ok(command) => match db.run(move |db| execute(command, db)).await {
+                Ok(reply) => reply,
+                Err(err) => MemcachedError::Server(err.to_string()).to_bytes(),
+            },
+            Err(err) => err.to_bytes(),
+        };
+
+        if !noreply && stream.write_all(&reply).await.is_err() {
+            return;
+        }
+    }
+}
+
+fn now_secs() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Time to live of a memcached expiration time, `None` when it never
+/// expires and zero when it is already expired.
+fn time_to_live(exptime: i64) -> Option<Duration> {
+    let seconds = match exptime {
+        0 => return None,
+        exptime if exptime > MAX_RELATIVE_EXPTIME => exptime - now_secs(),
+        exptime => exptime,
+    };
+
+    Some(Duration::from_secs(seconds.max(0) as u64))
+}
+
+/// Sets the expiration of an existing key from a memcached expiration
+/// time, deleting the key when it is already expired.
+fn apply_exptime(key: &[u8], exptime: i64, db: &mut RustDB) -> Result<()> {
+    match time_to_live(exptime) {
+        None => db.persist(key.to_vec()).map(|_| ()),
+        Some(ttl) if ttl.is_zero() => db.delete_record(key.to_vec()),
+        Some(ttl) => db.expire(key.to_vec(), ttl).map(|_| ()),
+    }
+}
+
+fn execute(command: MemcachedCommand, db: &mut RustDB) -> Result<Vec<u8>> {
+    let reply: &[u8] = match command {
+        MemcachedCommand::Get { keys, with_cas } => return get(keys, with_cas, db),
+        MemcachedCommand::Store {
+            mode,
+            key,
+            flags,
+            exptime,
+            data,
+            ..
+        } => {
+            // empty values mark deleted keys in the storage
+            if data.is_empty() {
+                let error = MemcachedError::Server(String::from("empty values are not supported"));
+                return Ok(error.to_bytes());
+            }
+
+            let current = db.get_record(key.clone())?;
+            match (mode, &current) {
+                (StoreMode::Add, Some(_)) | (StoreMode::Replace, None) => b"NOT_STORED\r\n",
+                (StoreMode::Cas(_), None) => b"NOT_FOUND\r\n",
+                (StoreMode::Cas(version), Some(kv)) if kv.get_version() != version => b"EXISTS\r\n",
+                _ => {
+                    // the value is written last, so that it is never stored
+                    // without its flags and expiration
+                    match time_to_live(exptime) {
+                        Some(ttl) if ttl.is_zero() => db.delete_record(key)?,
+                        ttl => {
+                            db.set_flags(key.clone(), flags)?;
+                            db.save_record_with_ttl(KeyValue::new(key, data), ttl)?;
+                        }
+                    }
+                    b"STORED\r\n"
+                }
+            }
+        }
+        MemcachedCommand::Delete { key, .. } => match db.get_record(key.clone())? {
+            Some(_) => {
+                db.delete_record(key)?;
+                b"DELETED\r\n"
+            }
+            None => b"NOT_FOUND\r\n",
+        },
+        MemcachedCommand::Arithmetic {
+            key,
+            delta,
+            increment,
+            ..
+        } => return arithmetic(key, delta, increment, db),
+        MemcachedCommand::Touch { key, exptime, .. } => match db.get_record(key.clone())? {
+            Some(_) => {
+                apply_exptime(&key, exptime, db)?;
+                b"TOUCHED\r\n"
+            }
+            None => b"NOT_FOUND\r\n",
+        },
+        MemcachedCommand::Version | MemcachedCommand::Quit => b"",
+    };
+
+    Ok(reply.to_vec())
+}
+
+fn get(keys: Vec<Vec<u8>>, with_cas: bool, db: &mut RustDB) -> Result<Vec<u8>> {
+    let mut reply = Vec::new();
+
+    for key in keys {
+        if let Some(kv) = db.get_record(key.clone())? {
+            let flags = db.get_flags(key.clone());
+            reply.extend_from_slice(b"VALUE ");
+            reply.extend_from_slice(&key);
+            let header = match with_cas {
+                true => format!(" {} {} {}\r\n", flags, kv.value.len(), kv.get_version()),
+                false => format!(" {} {}\r\n", flags, kv.value.len()),
+            };
+            reply.extend_from_slice(header.as_bytes());
+            reply.extend_from_slice(&kv.value);
+            reply.extend_from_slice(b"\r\n");
+        }
+    }
+
+    reply.extend_from_slice(b"END\r\n");
+    Ok(reply)
+}
+
+/// Values are decimal numbers: increments wrap around 64 bits, while
+/// decrements stop at 0. The key keeps its flags and expiration.
+fn arithmetic(key: Vec<u8>, delta: u64, increment: bool, db: &mut RustDB) -> Result<Vec<u8>> {
+    let current = match db.get_record(key.clone())? {
+        Some(kv) => kv.value,
+        None => return Ok(b"NOT_FOUND\r\n".to_vec()),
+    };
+
+    let current: u64 = match std::str::from_utf8(&current)
+        .ok()
+        .and_then(|v| v.parse().ok())
+    {
+        Some(current) => current,
+        None => {
+            let error = MemcachedError::Client(String::from(
+                "cannot increment or decrement non-numeric value",
+            ));
+            return Ok(error.to_bytes());
+        }
+    };
+
+    let value = match increment {
+        true => current.wrapping_add(delta),
+        false => current.saturating_sub(delta),
+    };
+    db.save_record(KeyValue::new(key, value.to_string().into_bytes()))?;
+
+    Ok(format!("{}\r\n", value).into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustdb::{FaultyVfs, MemoryVfs, Vfs};
+    use std::io::{BufRead, BufReader, Read, Write};
+
+    struct Client(BufReader<std::net::TcpStream>);
+
+    impl Client {
+        fn send(&mut self, data: &[u8]) {
+            self.0.get_mut().write_all(data).unwrap();
+        }
+
+        fn line(&mut self) -> String {
+            let mut line = String::new();
+            self.0.read_line(&mut line).unwrap();
+            line
+        }
+
+        fn request(&mut self, data: &[u8]) -> String {
+            self.send(data);
+            self.line()
+        }
+
+        /// Reads the values of a `get` as `(key, flags, data, cas)`.
+        fn values(&mut self) -> Vec<(String, u32, Vec<u8>, Option<u64>)> {
+            let mut values = Vec::new();
+            loop {
+                let line = self.line();
+                if line == "END\r\n" {
+                    return values;
+                }
+
+                let tokens: Vec<&str> = line.trim_end().split(' ').collect();
+                let mut data = vec![0; tokens[3].parse().unwrap()];
+                self.0.read_exact(&mut data).unwrap();
+                self.line();
+                values.push((
+                    String::from(tokens[1]),
+                    tokens[2].parse().unwrap(),
+                    data,
+                    tokens.get(4).map(|cas| cas.parse().unwrap()),
+                ));
+            }
+        }
+    }
+
+    fn start_server() -> Client {
+        start_server_with_vfs(Arc::new(MemoryVfs::new()))
+    }
+
+    fn start_server_with_vfs(vfs: Arc<dyn Vfs>) -> Client {
+        let std_listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = std_listener.local_addr().unwrap();
+        std_listener.set_nonblocking(true).unwrap();
+
+        thread::spawn(move || {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.block_on(async move {
+                let listener = TcpListener::from_std(std_listener).unwrap();
+                let db = RustDB::load_with_vfs("storage", Options::default(), vfs);
+                serve(listener, AsyncRustDB::new(db), 1_000).await;
+            });
+        });
+
+        let stream = std::net::TcpStream::connect(address).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        Client(BufReader::new(stream))
+    }
+
+    #[test]
+    fn store_and_retrieve_with_flags() {
+        // arrange
+        let mut client = start_server();
+
+        // act
+        let set = client.request(b"set a 42 0 4\r\nab\r\n\r\n");
+        let add = client.request(b"add a 0 0 1\r\nx\r\n");
+        let replace = client.request(b"replace b 0 0 1\r\nx\r\n");
+        let added = client.request(b"add b 0 0 1\r\ny\r\n");
+        client.send(b"get a b c\r\n");
+        let values = client.values();
+
+        // assert
+        assert_eq!(set, "STORED\r\n");
+        assert_eq!(add, "NOT_STORED\r\n");
+        assert_eq!(replace, "NOT_STORED\r\n");
+        assert_eq!(added, "STORED\r\n");
+        assert_eq!(
+            values,
+            vec![
+                (String::from("a"), 42, b"ab\r\n".to_vec(), None),
+                (String::from("b"), 0, b"y".to_vec(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn store_nothing_when_flags_or_expiration_fail() {
+        // arrange
+        let vfs = FaultyVfs::new(1);
+        let mut client = start_server_with_vfs(Arc::new(vfs.clone()));
+        // answered once the database is loaded
+        client.request(b"version\r\n");
+
+        // act
+        vfs.fail_one_write_after(0);
+        let flags_failed = client.request(b"set a 42 0 1\r\n1\r\n");
+        vfs.fail_one_write_after(1);
+        let expiration_failed = client.request(b"set b 42 100 1\r\n1\r\n");
+        client.send(b"get a b\r\n");
+        let values = client.values();
+
+        // assert
+        assert!(flags_failed.starts_with("SERVER_ERROR "));
+        assert!(expiration_failed.starts_with("SERVER_ERROR "));
+        assert!(values.is_empty());
+    }
+
+    #[test]
+    fn compare_and_swap() {
+        // arrange
+        let mut client = start_server();
+        client.request(b"set a 0 0 1\r\n1\r\n");
+        client.send(b"gets a\r\n");
+        let cas = client.values()[0].3.unwrap();
+
+        // act
+        let swapped = client.request(format!("cas a 0 0 1 {}\r\n2\r\n", cas).as_bytes());
+        let stale = client.request(format!("cas a 0 0 1 {}\r\n3\r\n", cas).as_bytes());
+        let missing = client.request(b"cas b 0 0 1 1\r\n3\r\n");
+        client.send(b"gets a\r\n");
+        let values = client.values();
+
+        // assert
+        assert_eq!(swapped, "STORED\r\n");
+        assert_eq!(stale, "EXISTS\r\n");
+        assert_eq!(missing, "NOT_FOUND\r\n");
+        assert_eq!(values[0].2, b"2".to_vec());
+        assert_ne!(values[0].3, Some(cas));
+    }
+
+    #[test]
+    fn increment_and_decrement() {
+        // arrange
+        let mut client = start_server();
+        client.request(b"set a 7 0 2\r\n10\r\n");
+        client.request(b"set text 0 0 3\r\nabc\r\n");
+
+        // act
+        let incremented = client.request(b"incr a 5\r\n");
+        let decremented = client.request(b"decr a 100\r\n");
+        let wrapped = client.request(format!("incr a {}\r\n", u64::MAX).as_bytes());
+        let missing = client.request(b"incr b 1\r\n");
+        let text = client.request(b"incr text 1\r\n");
+        let invalid = client.request(b"incr a -1\r\n");
+        client.send(b"get a\r\n");
+        let values = client.values();
+
+        // assert
+        assert_eq!(incremented, "15\r\n");
+        assert_eq!(decremented, "0\r\n");
+        assert_eq!(wrapped, format!("{}\r\n", u64::MAX));
+        assert_eq!(missing, "NOT_FOUND\r\n");
+        assert_eq!(
+            text,
+            "CLIENT_ERROR cannot increment or decrement non-numeric value\r\n"
+        );
+        assert_eq!(invalid, "CLIENT_ERROR invalid numeric delta argument\r\n");
+        assert_eq!(values[0].1, 7);
+    }
+
+    #[test]
+    fn expire_and_touch_keys() {
+        // arrange
+        let mut client = start_server();
+        client.request(b"set a 0 1 1\r\n1\r\n");
+        client.request(b"set b 0 1 1\r\n1\r\n");
+        client.request(b"set c 0 -1 1\r\n1\r\n");
+        let far = now_secs() + 3_600;
+        client.request(format!("set d 0 {} 1\r\n1\r\n", far).as_bytes());
+
+        // act
+        let touched = client.request(b"touch b 0\r\n");
+        let missing = client.request(b"touch x 10\r\n");
+        thread::sleep(Duration::from_millis(1_100));
+        client.send(b"get a b c d\r\n");
+        let values = client.values();
+
+        // assert
+        assert_eq!(touched, "TOUCHED\r\n");
+        assert_eq!(missing, "NOT_FOUND\r\n");
+        let keys: Vec<&str> = values.iter().map(|v| v.0.as_str()).collect();
+        assert_eq!(keys, vec!["b", "d"]);
+    }
+
+    #[test]
+    fn delete_and_skip_replies() {
+        // arrange
+        let mut client = start_server();
+
+        // act
+        client.send(b"set a 0 0 1 noreply\r\n1\r\n");
+        client.send(b"delete b noreply\r\n");
+        let deleted = client.request(b"delete a\r\n");
+        let missing = client.request(b"delete a\r\n");
+        let version = client.request(b"version\r\n");
+
+        // assert
+        assert_eq!(deleted, "DELETED\r\n");
+        assert_eq!(missing, "NOT_FOUND\r\n");
+        assert!(version.starts_with("VERSION "));
+    }
+
+    #[test]
+    fn report_errors_and_close_on_oversized_values() {
+        // arrange
+        let mut client = start_server();
+
+        // act
+        let unknown = client.request(b"stats\r\n");
+        let bad = client.request(b"set a x 0 1\r\n");
+        let data_as_command = client.request(b"1\r\n");
+        let empty = client.request(b"set a 0 0 0\r\n\r\n");
+        let too_large = client.request(b"set a 0 0 2000\r\n");
+
+        // assert
+        assert_eq!(unknown, "ERROR\r\n");
+        assert_eq!(bad, "CLIENT_ERROR bad command line format\r\n");
+        assert_eq!(data_as_command, "ERROR\r\n");
+        assert_eq!(empty, "SERVER_ERROR empty values are not supported\r\n");
+        assert_eq!(too_large, "SERVER_ERROR object too large for cache\r\n");
+        assert_eq!(client.line(), "");
+    }
+}
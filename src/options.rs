@@ -0,0 +1,82 @@
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StorageMode {
+    /// Append only data segments with every key indexed in memory.
+    Log,
+    /// Memtable flushed to sorted string tables merged by leveled compaction,
+    /// keeping only one key per block in memory.
+    Leveled,
+}
+
+/// When saved records are synced to the storage.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Durability {
+    /// Records are left to the operating system until `RustDB::flush`, or
+    /// until their segment is closed, so a crash may lose the latest ones.
+    OnFlush,
+    /// Every record is synced before its write returns.
+    EveryWrite,
+}
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub storage_mode: StorageMode,
+    pub durability: Durability,
+    /// Bytes written to the active segment before a new one is started.
+    pub segment_size: u64,
+    /// Bytes kept in the memtable before flushing it to level 0.
+    pub memtable_size: usize,
+    /// Bytes of records covered by each entry of the sparse index.
+    pub block_size: usize,
+    /// Number of tables on level 0 that triggers a compaction into level 1.
+    pub level0_file_limit: usize,
+    /// Maximum bytes on level 1, multiplied by `level_size_multiplier` on
+    /// each following level.
+    pub level_base_size: u64,
+    pub level_size_multiplier: u64,
+    /// Bytes written to each table produced by a compaction.
+    pub table_size: u64,
+    /// False positive rate of the Bloom filters kept for closed segments and
+    /// tables.
+    pub bloom_false_positive_rate: f64,
+    /// Any value but 0 keeps a change log, on log storage only: writes are
+    /// stamped with their sequence, and their events read from the records
+    /// of the segments until compaction drops them. 0 keeps no change log
+    /// and allows no subscriptions. Once the size of a separate log, it no
+    /// longer bounds anything.
+    pub change_log_size: u64,
+    /// How long compaction keeps overwritten and deleted versions for
+    /// `RustDB::history`, or zero to keep only the latest ones. Writes are
+    /// only stamped with their sequence and time while it is set, or a
+    /// change log is kept.
+    pub history_retention: Duration,
+}
+
+impl Options {
+    pub fn leveled() -> Options {
+        Options {
+            storage_mode: StorageMode::Leveled,
+            ..Options::default()
+        }
+    }
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            storage_mode: StorageMode::Log,
+            durability: Durability::OnFlush,
+            segment_size: 3_000_000,
+            memtable_size: 4_000_000,
+            block_size: 4_096,
+            level0_file_limit: 4,
+            level_base_size: 10_000_000,
+            level_size_multiplier: 10,
+            table_size: 2_000_000,
+            bloom_false_positive_rate: 0.01,
+            change_log_size: 0,
+            history_retention: Duration::ZERO,
+        }
+    }
+}
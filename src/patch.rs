@@ -0,0 +1,370 @@
+use std::io::{Error, ErrorKind, Result};
+
+use serde_json::{Map, Value};
+
+static MERGE_PATCH_TYPE: &str = "application/merge-patch+json";
+static JSON_PATCH_TYPE: &str = "application/json-patch+json";
+
+/// One operation of a JSON Patch, with its paths split into the tokens of
+/// their JSON pointers.
+#[derive(Debug, PartialEq)]
+enum Operation {
+    Add {
+        path: Vec<String>,
+        value: Value,
+    },
+    Remove {
+        path: Vec<String>,
+    },
+    Replace {
+        path: Vec<String>,
+        value: Value,
+    },
+    Move {
+        from: Vec<String>,
+        path: Vec<String>,
+    },
+    Copy {
+        from: Vec<String>,
+        path: Vec<String>,
+    },
+    Test {
+        path: Vec<String>,
+        value: Value,
+    },
+}
+
+impl Operation {
+    fn parse(operation: &Value) -> Result<Operation> {
+        let pointer = |name: &str| match operation.get(name) {
+            Some(Value::String(pointer)) => parse_pointer(pointer),
+            _ => Err(invalid_patch(&format!("{} must be a JSON pointer", name))),
+        };
+        let value = || {
+            operation
+                .get("value")
+                .cloned()
+                .ok_or_else(|| invalid_patch("value is missing"))
+        };
+
+        let operation = match operation.get("op").and_then(Value::as_str) {
+            Some("add") => Operation::Add {
+                path: pointer("path")?,
+                value: value()?,
+            },
+            Some("remove") => Operation::Remove {
+                path: pointer("path")?,
+            },
+            Some("replace") => Operation::Replace {
+                path: pointer("path")?,
+                value: value()?,
+            },
+            Some("move") => Operation::Move {
+                from: pointer("from")?,
+                path: pointer("path")?,
+            },
+            Some("copy") => Operation::Copy {
+                from: pointer("from")?,
+                path: pointer("path")?,
+            },
+            Some("test") => Operation::Test {
+                path: pointer("path")?,
+                value: value()?,
+            },
+            _ => return Err(invalid_patch("unknown op")),
+        };
+        Ok(operation)
+    }
+
+    fn apply(&self, document: &mut Value) -> Result<()> {
+        match self {
+            Operation::Add { path, value } => add(document, path, value.clone()),
+            Operation::Remove { path } => remove(document, path).map(|_| ()),
+            Operation::Replace { path, value } => {
+                *resolve(document, path)? = value.clone();
+                Ok(())
+            }
+            Operation::Move { from, path } => {
+                if path.len() > from.len() && path.starts_with(from) {
+                    return Err(conflict("cannot move a value into itself"));
+                }
+                let value = remove(document, from)?;
+                add(document, path, value)
+            }
+            Operation::Copy { from, path } => {
+                let value = resolve(document, from)?.clone();
+                add(document, path, value)
+            }
+            Operation::Test { path, value } => match *resolve(document, path)? == *value {
+                true => Ok(()),
+                false => Err(conflict("test failed")),
+            },
+        }
+    }
+}
+
+/// A partial update of a JSON document: a merge patch (RFC 7396), which
+/// sets the fields it has and removes those it has as `null`, or a JSON
+/// Patch (RFC 6902), a list of operations applied in order.
+#[derive(Debug, PartialEq)]
+pub struct Patch {
+    kind: PatchKind,
+}
+
+#[derive(Debug, PartialEq)]
+enum PatchKind {
+    Merge(Value),
+    Json(Vec<Operation>),
+}
+
+impl Patch {
+    /// Parses `body` by its content type, `application/merge-patch+json`
+    /// or `application/json-patch+json`. Other types fail as `Unsupported`,
+    /// and invalid patches as `InvalidInput`.
+    pub fn parse(content_type: &str, body: &[u8]) -> Result<Patch> {
+        let media_type = content_type.split(';').next().unwrap_or("").trim();
+        let merge = media_type.eq_ignore_ascii_case(MERGE_PATCH_TYPE);
+        if !merge && !media_type.eq_ignore_ascii_case(JSON_PATCH_TYPE) {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!(
+                    "Unsupported patch type: expected {} or {}",
+                    MERGE_PATCH_TYPE, JSON_PATCH_TYPE
+                ),
+            ));
+        }
+
+        let patch = match serde_json::from_slice::<Value>(body) {
+            Ok(patch) => patch,
+            Err(err) => return Err(invalid_patch(&err.to_string())),
+        };
+        if merge {
+            return Ok(Patch {
+                kind: PatchKind::Merge(patch),
+            });
+        }
+
+        match patch {
+            Value::Array(operations) => operations
+                .iter()
+                .map(Operation::parse)
+                .collect::<Result<_>>()
+                .map(|operations| Patch {
+                    kind: PatchKind::Json(operations),
+                }),
+            _ => Err(invalid_patch("expected an array of operations")),
+        }
+    }
+
+    /// The patched document. A JSON Patch either applies as a whole or
+    /// fails as `InvalidData`, when a path is missing or a test fails.
+    pub fn apply(&self, document: &Value) -> Result<Value> {
+        let mut patched = document.clone();
+        match &self.kind {
+            PatchKind::Merge(patch) => merge(&mut patched, patch),
+            PatchKind::Json(operations) => {
+                for operation in operations {
+                    operation.apply(&mut patched)?;
+                }
+            }
+        }
+        Ok(patched)
+    }
+}
+
+fn invalid_patch(reason: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        format!("Invalid patch: {}", reason),
+    )
+}
+
+fn conflict(reason: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("Patch does not apply: {}", reason),
+    )
+}
+
+fn merge(target: &mut Value, patch: &Value) {
+    let fields = match patch {
+        Value::Object(fields) => fields,
+        _ => {
+            *target = patch.clone();
+            return;
+        }
+    };
+
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    let target = target.as_object_mut().unwrap();
+    for (name, value) in fields {
+        match value {
+            Value::Null => {
+                target.remove(name);
+            }
+            value => merge(target.entry(name.clone()).or_insert(Value::Null), value),
+        }
+    }
+}
+
+/// Tokens of a JSON pointer (RFC 6901), none for the whole document.
+fn parse_pointer(pointer: &str) -> Result<Vec<String>> {
+    if pointer.is_empty() {
+        return Ok(Vec::new());
+    }
+    match pointer.strip_prefix('/') {
+        Some(tokens) => Ok(tokens
+            .split('/')
+            .map(|token| token.replace("~1", "/").replace("~0", "~"))
+            .collect()),
+        None => Err(invalid_patch(&format!("invalid JSON pointer {}", pointer))),
+    }
+}
+
+/// Position of an array item, without leading zeros.
+fn array_index(token: &str, len: usize) -> Result<usize> {
+    let valid = !token.is_empty()
+        && token.bytes().all(|b| b.is_ascii_digit())
+        && (token == "0" || !token.starts_with('0'));
+    match token.parse() {
+        Ok(index) if valid && index < len => Ok(index),
+        _ => Err(conflict(&format!("no item {}", token))),
+    }
+}
+
+fn resolve<'a>(document: &'a mut Value, path: &[String]) -> Result<&'a mut Value> {
+    path.iter().try_fold(document, |value, token| match value {
+        Value::Object(fields) => fields
+            .get_mut(token)
+            .ok_or_else(|| conflict(&format!("no field {}", token))),
+        Value::Array(items) => {
+            let index = array_index(token, items.len())?;
+            Ok(&mut items[index])
+        }
+        _ => Err(conflict(&format!("no field {}", token))),
+    })
+}
+
+fn add(document: &mut Value, path: &[String], value: Value) -> Result<()> {
+    let (last, parent) = match path.split_last() {
+        Some((last, parent)) => (last, parent),
+        None => {
+            *document = value;
+            return Ok(());
+        }
+    };
+
+    match resolve(document, parent)? {
+        Value::Object(fields) => {
+            fields.insert(last.clone(), value);
+        }
+        Value::Array(items) if last == "-" => items.push(value),
+        Value::Array(items) => {
+            let index = array_index(last, items.len() + 1)?;
+            items.insert(index, value);
+        }
+        _ => return Err(conflict(&format!("no field {}", last))),
+    }
+    Ok(())
+}
+
+fn remove(document: &mut Value, path: &[String]) -> Result<Value> {
+    let (last, parent) = path
+        .split_last()
+        .ok_or_else(|| conflict("cannot remove the whole document"))?;
+
+    match resolve(document, parent)? {
+        Value::Object(fields) => fields
+            .remove(last)
+            .ok_or_else(|| conflict(&format!("no field {}", last))),
+        Value::Array(items) => {
+            let index = array_index(last, items.len())?;
+            Ok(items.remove(index))
+        }
+        _ => Err(conflict(&format!("no field {}", last))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn apply_merge_patches() {
+        let document =
+            json!({"name": "Lana", "address": {"city": "Porto", "zip": "4000"}, "tags": ["a"]});
+        let patch = Patch::parse(
+            "application/merge-patch+json; charset=utf-8",
+            br#"{"address": {"zip": null, "country": "PT"}, "tags": ["b"], "age": 41}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            patch.apply(&document).unwrap(),
+            json!({"name": "Lana", "address": {"city": "Porto", "country": "PT"}, "tags": ["b"], "age": 41})
+        );
+        let patch = Patch::parse(MERGE_PATCH_TYPE, b"\"text\"").unwrap();
+        assert_eq!(patch.apply(&document).unwrap(), json!("text"));
+        assert_eq!(
+            Patch::parse("application/json", b"{}").unwrap_err().kind(),
+            ErrorKind::Unsupported
+        );
+        assert_eq!(
+            Patch::parse(MERGE_PATCH_TYPE, b"{").unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn apply_json_patches_as_a_whole() {
+        let document = json!({"name": "Lana", "a/b": 1, "tags": ["a", "c"]});
+        let patch = Patch::parse(
+            JSON_PATCH_TYPE,
+            br#"[
+                {"op": "test", "path": "/name", "value": "Lana"},
+                {"op": "add", "path": "/tags/1", "value": "b"},
+                {"op": "add", "path": "/tags/-", "value": "d"},
+                {"op": "remove", "path": "/a~1b"},
+                {"op": "copy", "from": "/name", "path": "/first_name"},
+                {"op": "move", "from": "/tags/0", "path": "/tag"},
+                {"op": "replace", "path": "/name", "value": "Lana Walter"}
+            ]"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            patch.apply(&document).unwrap(),
+            json!({"name": "Lana Walter", "first_name": "Lana", "tag": "a", "tags": ["b", "c", "d"]})
+        );
+
+        for operations in [
+            &br#"[{"op": "test", "path": "/name", "value": "Sara"}]"#[..],
+            br#"[{"op": "remove", "path": "/missing"}]"#,
+            br#"[{"op": "replace", "path": "/tags/01", "value": 1}]"#,
+            br#"[{"op": "add", "path": "/tags/3", "value": 1}]"#,
+            br#"[{"op": "move", "from": "/tags", "path": "/tags/0"}]"#,
+        ] {
+            let patch = Patch::parse(JSON_PATCH_TYPE, operations).unwrap();
+            assert_eq!(
+                patch.apply(&document).unwrap_err().kind(),
+                ErrorKind::InvalidData
+            );
+        }
+        for operations in [
+            &br#"{"op": "add", "path": "/a", "value": 1}"#[..],
+            br#"[{"op": "add", "path": "a", "value": 1}]"#,
+            br#"[{"op": "add", "path": "/a"}]"#,
+            br#"[{"op": "rename", "path": "/a"}]"#,
+        ] {
+            assert_eq!(
+                Patch::parse(JSON_PATCH_TYPE, operations)
+                    .unwrap_err()
+                    .kind(),
+                ErrorKind::InvalidInput
+            );
+        }
+    }
+}
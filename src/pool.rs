@@ -0,0 +1,144 @@
+use std::io::{Error, ErrorKind, Result};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Fixed number of threads running jobs from a bounded queue. Dropping the
+/// pool waits for the queued jobs to finish.
+pub struct ThreadPool {
+    workers: Vec<JoinHandle<()>>,
+    sender: Option<SyncSender<Job>>,
+}
+
+impl ThreadPool {
+    pub fn new(size: usize, queue_size: usize) -> ThreadPool {
+        assert!(size > 0, "A thread pool needs at least one thread");
+
+        let (sender, receiver) = mpsc::sync_channel(queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..size)
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                thread::spawn(move || ThreadPool::work(receiver))
+            })
+            .collect();
+
+        ThreadPool {
+            workers,
+            sender: Some(sender),
+        }
+    }
+
+    fn work(receiver: Arc<Mutex<Receiver<Job>>>) {
+        loop {
+            let job = receiver.lock().unwrap().recv();
+
+            match job {
+                // a failing job must not take its thread down with it
+                Ok(job) => {
+                    let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                }
+                Err(_) => return,
+            }
+        }
+    }
+
+    /// Queues `job`, failing with `WouldBlock` instead of waiting when every
+    /// thread is busy and the queue is full.
+    pub fn try_execute<F>(&self, job: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        match self.sender.as_ref().unwrap().try_send(Box::new(job)) {
+            Ok(_) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(Error::new(
+                ErrorKind::WouldBlock,
+                "Thread pool is saturated",
+            )),
+            Err(TrySendError::Disconnected(_)) => {
+                Err(Error::new(ErrorKind::BrokenPipe, "Thread pool is stopped"))
+            }
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Barrier;
+
+    #[test]
+    fn run_every_queued_job() {
+        let counter = Arc::new(AtomicUsize::new(0));
+
+        {
+            let pool = ThreadPool::new(4, 100);
+            for _ in 0..100 {
+                let counter = Arc::clone(&counter);
+                pool.try_execute(move || {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                })
+                .unwrap();
+            }
+        }
+
+        assert_eq!(counter.load(Ordering::SeqCst), 100);
+    }
+
+    #[test]
+    fn refuse_jobs_when_saturated() {
+        let pool = ThreadPool::new(1, 1);
+        let started = Arc::new(Barrier::new(2));
+        let release = Arc::new(Barrier::new(2));
+
+        {
+            let started = Arc::clone(&started);
+            let release = Arc::clone(&release);
+            pool.try_execute(move || {
+                started.wait();
+                release.wait();
+            })
+            .unwrap();
+        }
+        started.wait();
+
+        pool.try_execute(|| {}).unwrap();
+        let refused = pool.try_execute(|| {});
+        release.wait();
+
+        assert_eq!(refused.unwrap_err().kind(), ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn keep_working_after_a_job_panics() {
+        let counter = Arc::new(AtomicUsize::new(0));
+
+        {
+            let pool = ThreadPool::new(1, 10);
+            pool.try_execute(|| panic!("failing job")).unwrap();
+
+            let counter = Arc::clone(&counter);
+            pool.try_execute(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+            })
+            .unwrap();
+        }
+
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+    }
+}
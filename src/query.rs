@@ -0,0 +1,536 @@
+use std::cmp::Ordering;
+use std::io::{Error, ErrorKind, Result};
+use std::ops::Deref;
+
+use serde_json::{Map, Value};
+
+use crate::core::ByteString;
+use crate::service::Store;
+
+/// Keys taken at a time while a query scans the store.
+static SCAN_PAGE_SIZE: usize = 1_000;
+
+/// What a field has to be for a document to match. Operators on the same
+/// field must all hold.
+#[derive(Debug, PartialEq)]
+enum Condition {
+    Eq(Value),
+    Gt(Value),
+    Gte(Value),
+    Lt(Value),
+    Lte(Value),
+    In(Vec<Value>),
+    Exists(bool),
+}
+
+impl Condition {
+    fn parse(operator: &str, operand: Value) -> Result<Condition> {
+        let condition = match (operator, operand) {
+            ("$eq", operand) => Condition::Eq(operand),
+            ("$gt", operand) => Condition::Gt(operand),
+            ("$gte", operand) => Condition::Gte(operand),
+            ("$lt", operand) => Condition::Lt(operand),
+            ("$lte", operand) => Condition::Lte(operand),
+            ("$in", Value::Array(values)) => Condition::In(values),
+            ("$exists", Value::Bool(exists)) => Condition::Exists(exists),
+            ("$in", _) => return Err(invalid_query("$in takes an array")),
+            ("$exists", _) => return Err(invalid_query("$exists takes a boolean")),
+            (operator, _) => return Err(invalid_query(&format!("unknown operator {}", operator))),
+        };
+        Ok(condition)
+    }
+
+    fn holds(&self, field: Option<&Value>) -> bool {
+        let field = match (self, field) {
+            (Condition::Exists(exists), field) => return field.is_some() == *exists,
+            (_, None) => return false,
+            (_, Some(field)) => field,
+        };
+
+        match self {
+            Condition::Eq(operand) => equals(field, operand),
+            Condition::Gt(operand) => compare(field, operand) == Some(Ordering::Greater),
+            Condition::Gte(operand) => compare(field, operand).is_some_and(Ordering::is_ge),
+            Condition::Lt(operand) => compare(field, operand) == Some(Ordering::Less),
+            Condition::Lte(operand) => compare(field, operand).is_some_and(Ordering::is_le),
+            Condition::In(operands) => operands.iter().any(|operand| equals(field, operand)),
+            Condition::Exists(_) => unreachable!(),
+        }
+    }
+}
+
+/// A query on the values that are JSON objects, such as:
+///
+/// ```json
+/// {"filter": {"job_title": "Baker", "age": {"$gte": 30, "$lt": 40},
+///             "city": {"$in": ["Porto", "Lisboa"]}, "email": {"$exists": true}},
+///  "fields": ["name", "email"], "sort": ["-age", "name"], "limit": 10}
+/// ```
+///
+/// Fields are JSON paths as indexes take them, such as `address.city`. A
+/// filter value is matched as is, unless it is an object of operators:
+/// `$eq`, `$gt`, `$gte`, `$lt`, `$lte`, `$in` and `$exists`. Ranges compare
+/// numbers with numbers and strings with strings. `fields` keeps only those
+/// fields of the documents, `sort` orders them by fields, descending with a
+/// leading `-`, and `limit` caps how many there are.
+#[derive(Debug, PartialEq)]
+pub struct Query {
+    filter: Vec<(Vec<String>, Condition)>,
+    fields: Option<Vec<Vec<String>>>,
+    sort: Vec<(Vec<String>, bool)>,
+    limit: Option<usize>,
+}
+
+impl Query {
+    /// Parses the query from its JSON text, failing with `InvalidInput`.
+    pub fn parse(text: &[u8]) -> Result<Query> {
+        let query = match serde_json::from_slice::<Value>(text) {
+            Ok(Value::Object(query)) => query,
+            _ => return Err(invalid_query("expected a JSON object")),
+        };
+
+        let mut filter = Vec::new();
+        let mut fields = None;
+        let mut sort = Vec::new();
+        let mut limit = None;
+        for (name, value) in query {
+            match (name.as_str(), value) {
+                ("filter", Value::Object(conditions)) => {
+                    for (path, value) in conditions {
+                        let path = parse_path(&path)?;
+                        for condition in parse_conditions(value)? {
+                            filter.push((path.clone(), condition));
+                        }
+                    }
+                }
+                ("fields", Value::Array(paths)) => {
+                    fields = Some(
+                        paths
+                            .iter()
+                            .map(|path| {
+                                path.as_str()
+                                    .map_or_else(|| Err(invalid_field()), parse_path)
+                            })
+                            .collect::<Result<_>>()?,
+                    );
+                }
+                ("sort", Value::Array(paths)) => {
+                    for path in paths {
+                        let path = path.as_str().ok_or_else(invalid_field)?;
+                        sort.push(match path.strip_prefix('-') {
+                            Some(path) => (parse_path(path)?, true),
+                            None => (parse_path(path)?, false),
+                        });
+                    }
+                }
+                ("limit", Value::Number(number)) => match number.as_u64() {
+                    Some(number) if number > 0 => limit = Some(number as usize),
+                    _ => return Err(invalid_query("limit must be a positive integer")),
+                },
+                ("filter" | "fields" | "sort" | "limit", _) => {
+                    return Err(invalid_query(&format!("invalid {}", name)))
+                }
+                (name, _) => return Err(invalid_query(&format!("unknown field {}", name))),
+            }
+        }
+
+        Ok(Query {
+            filter,
+            fields,
+            sort,
+            limit,
+        })
+    }
+
+    /// Whether the document holds every condition of the filter.
+    pub fn matches(&self, document: &Value) -> bool {
+        self.filter
+            .iter()
+            .all(|(path, condition)| condition.holds(field(document, path)))
+    }
+
+    /// The document with only the fields asked for, or all of them.
+    pub fn project(&self, document: Value) -> Value {
+        let paths = match &self.fields {
+            Some(paths) => paths,
+            None => return document,
+        };
+
+        let mut projected = Map::new();
+        for path in paths {
+            if let Some(value) = field(&document, path) {
+                let (last, parents) = path.split_last().unwrap();
+                let mut object = &mut projected;
+                for parent in parents {
+                    let entry = object
+                        .entry(parent.clone())
+                        .or_insert_with(|| Value::Object(Map::new()));
+                    object = entry.as_object_mut().unwrap();
+                }
+                object.insert(last.clone(), value.clone());
+            }
+        }
+        Value::Object(projected)
+    }
+
+    /// Keys and projected documents that match, sorted as asked or else by
+    /// key. Records are found through an index when the filter has a string
+    /// or boolean value for the path of one, and by a scan otherwise.
+    pub fn run(&self, db: &dyn Store) -> Result<Vec<(ByteString, Value)>> {
+        let mut matches = Vec::new();
+        self.run_paged(
+            || db,
+            |key, document| {
+                matches.push((key, document));
+                Ok(())
+            },
+        )?;
+        Ok(matches)
+    }
+
+    /// Runs the query as `run` does, taking the store from `lock` for each
+    /// page of keys scanned rather than for the whole scan, and handing each
+    /// match to `emit` once its page is read, or once every match is when
+    /// they are sorted. Each key is read as it is when its page is, so
+    /// writes made during the scan may or may not be seen.
+    pub fn run_paged<'a, D>(
+        &self,
+        lock: impl Fn() -> D,
+        mut emit: impl FnMut(ByteString, Value) -> Result<()>,
+    ) -> Result<()>
+    where
+        D: Deref<Target = dyn Store + 'a>,
+    {
+        let unbounded = !self.sort.is_empty();
+        let limit = match unbounded {
+            true => usize::MAX,
+            false => self.limit.unwrap_or(usize::MAX),
+        };
+        let mut matches = Vec::new();
+        let mut emitted = 0;
+
+        let lookup = self.index_lookup(&*lock());
+        if let Some((index, value)) = lookup {
+            for record in lock().find_by(&index, &value)? {
+                self.collect(record.key, &record.value, &mut matches);
+                if matches.len() == limit {
+                    break;
+                }
+            }
+        } else {
+            let mut start = Vec::new();
+            loop {
+                let next = {
+                    let db = lock();
+                    let page = db.scan_keys(b"", &start, SCAN_PAGE_SIZE)?;
+                    for key in page.keys {
+                        if let Some(record) = db.get_record(&key)? {
+                            self.collect(record.key, &record.value, &mut matches);
+                            if emitted + matches.len() == limit {
+                                break;
+                            }
+                        }
+                    }
+                    page.next
+                };
+
+                if !unbounded {
+                    for (key, document) in matches.drain(..) {
+                        emit(key, self.project(document))?;
+                        emitted += 1;
+                    }
+                }
+                match next {
+                    Some(next) if emitted < limit => start = next,
+                    _ => break,
+                }
+            }
+        }
+
+        if unbounded {
+            matches.sort_by(|(_, a), (_, b)| self.order(a, b));
+            if let Some(limit) = self.limit {
+                matches.truncate(limit);
+            }
+        }
+        for (key, document) in matches {
+            emit(key, self.project(document))?;
+        }
+        Ok(())
+    }
+
+    fn collect(&self, key: ByteString, value: &[u8], matches: &mut Vec<(ByteString, Value)>) {
+        if let Ok(document @ Value::Object(_)) = serde_json::from_slice::<Value>(value) {
+            if self.matches(&document) {
+                matches.push((key, document));
+            }
+        }
+    }
+
+    /// Name of an index on the path of an equality in the filter, with the
+    /// value to find there.
+    fn index_lookup(&self, db: &dyn Store) -> Option<(String, String)> {
+        let indexes = db.list_indexes();
+        self.filter.iter().find_map(|(path, condition)| {
+            let value = match condition {
+                Condition::Eq(Value::String(text)) => text.clone(),
+                Condition::Eq(Value::Bool(flag)) => flag.to_string(),
+                _ => return None,
+            };
+            let path = path.join(".");
+            indexes
+                .iter()
+                .find(|(_, indexed)| *indexed == path)
+                .map(|(name, _)| (name.clone(), value))
+        })
+    }
+
+    fn order(&self, a: &Value, b: &Value) -> Ordering {
+        for (path, descending) in &self.sort {
+            let ordering = sort_order(field(a, path), field(b, path));
+            if ordering != Ordering::Equal {
+                return if *descending {
+                    ordering.reverse()
+                } else {
+                    ordering
+                };
+            }
+        }
+        Ordering::Equal
+    }
+}
+
+fn invalid_query(reason: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        format!("Invalid query: {}", reason),
+    )
+}
+
+fn invalid_field() -> Error {
+    invalid_query("fields are given as strings")
+}
+
+fn parse_path(path: &str) -> Result<Vec<String>> {
+    if path.split('.').any(str::is_empty) {
+        return Err(invalid_query(&format!("invalid JSON path {}", path)));
+    }
+    Ok(path.split('.').map(String::from).collect())
+}
+
+/// An object of operators, or else a value to be equal to.
+fn parse_conditions(value: Value) -> Result<Vec<Condition>> {
+    match value {
+        Value::Object(operators)
+            if !operators.is_empty() && operators.keys().all(|key| key.starts_with('$')) =>
+        {
+            operators
+                .into_iter()
+                .map(|(operator, operand)| Condition::parse(&operator, operand))
+                .collect()
+        }
+        Value::Object(operators) if operators.keys().any(|key| key.starts_with('$')) => {
+            Err(invalid_query("operators cannot be mixed with fields"))
+        }
+        value => Ok(vec![Condition::Eq(value)]),
+    }
+}
+
+fn field<'a>(document: &'a Value, path: &[String]) -> Option<&'a Value> {
+    path.iter()
+        .try_fold(document, |value, field| value.get(field))
+}
+
+/// Numbers are equal by value, so `30` and `30.0` are.
+fn equals(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(_), Value::Number(_)) => compare(a, b) == Some(Ordering::Equal),
+        _ => a == b,
+    }
+}
+
+/// Order of numbers or strings, which are not comparable with each other.
+fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+/// Missing fields come first, then nulls, booleans, numbers, strings,
+/// arrays and objects.
+fn sort_order(a: Option<&Value>, b: Option<&Value>) -> Ordering {
+    fn rank(value: Option<&Value>) -> u8 {
+        match value {
+            None => 0,
+            Some(Value::Null) => 1,
+            Some(Value::Bool(_)) => 2,
+            Some(Value::Number(_)) => 3,
+            Some(Value::String(_)) => 4,
+            Some(Value::Array(_)) => 5,
+            Some(Value::Object(_)) => 6,
+        }
+    }
+
+    match (a, b) {
+        (Some(Value::Bool(a)), Some(Value::Bool(b))) => a.cmp(b),
+        (Some(a), Some(b)) => compare(a, b).unwrap_or_else(|| rank(Some(a)).cmp(&rank(Some(b)))),
+        _ => rank(a).cmp(&rank(b)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::KeyValue;
+    use crate::options::Options;
+    use crate::service::RustDB;
+    use crate::vfs::MemoryVfs;
+    use serde_json::json;
+    use std::cell::Cell;
+    use std::sync::Arc;
+
+    #[test]
+    fn match_filters_on_fields() {
+        let query = Query::parse(
+            br#"{"filter": {"job": "Baker", "age": {"$gte": 30, "$lt": 40},
+                            "address.city": {"$in": ["Porto", "Lisboa"]},
+                            "email": {"$exists": false}}}"#,
+        )
+        .unwrap();
+
+        let document = json!({"job": "Baker", "age": 30.0, "address": {"city": "Porto"}});
+        assert!(query.matches(&document));
+        assert!(!query.matches(&json!({"job": "Baker", "age": 40, "address": {"city": "Porto"}})));
+        assert!(!query.matches(&json!({"job": "Baker", "age": "35", "address": {"city": "Porto"}})));
+        assert!(!query.matches(&json!({"job": "Baker", "age": 35, "address": {"city": "Braga"}})));
+        assert!(!query.matches(
+            &json!({"job": "Baker", "age": 35, "address": {"city": "Porto"}, "email": null})
+        ));
+        assert!(!query.matches(&json!({"age": 35, "address": {"city": "Porto"}})));
+
+        let query = Query::parse(br#"{"filter": {"tags": ["a", "b"]}}"#).unwrap();
+        assert!(query.matches(&json!({"tags": ["a", "b"]})));
+        assert!(!query.matches(&json!({"tags": ["b", "a"]})));
+
+        for invalid in [
+            &br#"[]"#[..],
+            br#"{"filter": {"age": {"$near": 1}}}"#,
+            br#"{"filter": {"age": {"$gt": 1, "max": 2}}}"#,
+            br#"{"filter": {"age.": 1}}"#,
+            br#"{"filter": {"age": {"$in": 1}}}"#,
+            br#"{"fields": [1]}"#,
+            br#"{"limit": 0}"#,
+            br#"{"order": []}"#,
+        ] {
+            assert_eq!(
+                Query::parse(invalid).unwrap_err().kind(),
+                ErrorKind::InvalidInput
+            );
+        }
+    }
+
+    fn document_db() -> RustDB {
+        let mut db =
+            RustDB::load_with_vfs("storage", Options::default(), Arc::new(MemoryVfs::new()));
+        let documents = [
+            r#"{"name":"Lana","job":"Baker","age":41,"address":{"city":"Porto"}}"#,
+            r#"{"name":"Adina","job":"Investment Advisor","age":29}"#,
+            r#"{"name":"Daron","job":"Baker","age":35,"address":{"city":"Braga"}}"#,
+            r#"{"name":"Sara","job":"Baker"}"#,
+            r#""Baker""#,
+        ];
+        for (id, document) in documents.iter().enumerate() {
+            db.save_record(KeyValue::new(
+                id.to_string().into_bytes(),
+                document.as_bytes().to_vec(),
+            ))
+            .unwrap();
+        }
+        db
+    }
+
+    #[test]
+    fn sort_project_and_limit_the_matches() {
+        let mut db = document_db();
+        let query = Query::parse(
+            br#"{"filter": {"job": "Baker"}, "fields": ["name", "address.city"],
+                 "sort": ["-age"], "limit": 2}"#,
+        )
+        .unwrap();
+
+        let expected = vec![
+            (
+                b"0".to_vec(),
+                json!({"name": "Lana", "address": {"city": "Porto"}}),
+            ),
+            (
+                b"2".to_vec(),
+                json!({"name": "Daron", "address": {"city": "Braga"}}),
+            ),
+        ];
+        assert_eq!(query.run(&db).unwrap(), expected);
+
+        db.create_index("job", "job").unwrap();
+        assert_eq!(query.run(&db).unwrap(), expected);
+
+        let query = Query::parse(br#"{"filter": {"job": "Baker"}, "limit": 2}"#).unwrap();
+        let keys: Vec<ByteString> = query.run(&db).unwrap().into_iter().map(|m| m.0).collect();
+        assert_eq!(keys, vec![b"0".to_vec(), b"2".to_vec()]);
+
+        let query = Query::parse(br#"{"fields": ["age"], "sort": ["age"]}"#).unwrap();
+        let ages: Vec<Value> = query.run(&db).unwrap().into_iter().map(|m| m.1).collect();
+        assert_eq!(
+            ages,
+            vec![
+                json!({}),
+                json!({"age": 29}),
+                json!({"age": 35}),
+                json!({"age": 41})
+            ]
+        );
+    }
+
+    #[test]
+    fn take_the_store_for_each_page_of_the_scan() {
+        let mut db =
+            RustDB::load_with_vfs("storage", Options::default(), Arc::new(MemoryVfs::new()));
+        for n in 0..SCAN_PAGE_SIZE * 2 + 1 {
+            let key = format!("k{:04}", n).into_bytes();
+            let document = format!(r#"{{"n":{}}}"#, n).into_bytes();
+            db.save_record(KeyValue::new(key, document)).unwrap();
+        }
+        let locks = Cell::new(0);
+        let lock = || {
+            locks.set(locks.get() + 1);
+            &db as &dyn Store
+        };
+
+        // matches are emitted once their page is read
+        let query = Query::parse(br#"{"filter": {"n": {"$gte": 999}}, "limit": 3}"#).unwrap();
+        let mut emitted = Vec::new();
+        query
+            .run_paged(lock, |_, document| {
+                emitted.push((locks.get(), document["n"].clone()));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(
+            emitted,
+            vec![(2, json!(999)), (3, json!(1000)), (3, json!(1001))]
+        );
+
+        // sorted matches are emitted once every page is
+        locks.set(0);
+        let query = Query::parse(br#"{"sort": ["-n"], "limit": 1}"#).unwrap();
+        let mut emitted = Vec::new();
+        query
+            .run_paged(lock, |_, document| {
+                emitted.push((locks.get(), document["n"].clone()));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(emitted, vec![(4, json!(SCAN_PAGE_SIZE * 2))]);
+    }
+}
@@ -0,0 +1,2256 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryInto;
+use std::io::{
+    BufReader, Error,
+    ErrorKind::{self, InvalidData, UnexpectedEof},
+    Read, Result,
+};
+use std::sync::Arc;
+
+use rand::Rng;
+
+use crate::core::{ByteString, KeyValue};
+use crate::options::Options;
+use crate::service::RustDB;
+use crate::store::{build_path, folder_path, read_record, record_size, write_record};
+use crate::vfs::{FileReader, MemoryVfs, Vfs, VfsFile};
+
+static RAFT_LOG_FILE: &str = "raft_log";
+static RAFT_STATE_FILE: &str = "raft_state";
+/// Folder the database is in, with the snapshot it was installed from.
+static RAFT_DATABASE_FILE: &str = "raft_database";
+/// Folders the database of a node alternates between, snapshots being
+/// received in the one not in use.
+static DATABASE_FOLDERS: [&str; 2] = ["db0", "db1"];
+/// Folder a leader copies its database to, to send it as a snapshot.
+static SNAPSHOT_FOLDER: &str = "snapshot";
+static SNAPSHOT_KEY: &[u8] = b"snapshot";
+static STATE_KEY: &[u8] = b"state";
+/// Ticks a `RaftCluster` waits for a leader, or for a request to go
+/// through, before giving up.
+static MAX_TICKS: usize = 1_000;
+/// Rounds of messages a `RaftCluster` delivers after a tick before deciding
+/// the nodes will not settle.
+static MAX_ROUNDS: usize = 10_000;
+
+/// Identifier of a node, from 1 on.
+pub type NodeId = u64;
+
+#[derive(Clone, Debug)]
+pub struct RaftOptions {
+    /// Ticks without hearing from a leader before a node campaigns, picked
+    /// at random up to twice as many so candidates rarely split votes.
+    pub election_ticks: u32,
+    /// Ticks between the heartbeats of a leader.
+    pub heartbeat_ticks: u32,
+    /// Entries kept in the log once applied, over which the oldest half is
+    /// dropped. Nodes missing dropped entries are sent a snapshot.
+    pub max_log_entries: usize,
+    /// Entries sent at most in one message.
+    pub max_batch: usize,
+    /// Bytes of the files of a snapshot sent at most in one message.
+    pub max_chunk_size: usize,
+}
+
+impl Default for RaftOptions {
+    fn default() -> RaftOptions {
+        RaftOptions {
+            election_ticks: 10,
+            heartbeat_ticks: 3,
+            max_log_entries: 1_000,
+            max_batch: 64,
+            max_chunk_size: 262_144,
+        }
+    }
+}
+
+/// Write agreed on by the cluster, applied in order on every node.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Put(KeyValue),
+    Delete(ByteString),
+    /// Membership changes take effect as soon as they are in the log, one
+    /// at a time.
+    AddNode(NodeId),
+    RemoveNode(NodeId),
+    /// Appended by a new leader, so the entries of earlier terms get
+    /// committed along with it.
+    Noop,
+}
+
+impl Command {
+    fn encode(&self) -> ByteString {
+        match self {
+            Command::Put(key_value) => {
+                let mut data = vec![1];
+                data.extend_from_slice(&(key_value.key.len() as u32).to_be_bytes());
+                data.extend_from_slice(&key_value.key);
+                data.extend_from_slice(&key_value.value);
+                data
+            }
+            Command::Delete(key) => [&[2][..], key].concat(),
+            Command::AddNode(id) => [&[3][..], &id.to_be_bytes()].concat(),
+            Command::RemoveNode(id) => [&[4][..], &id.to_be_bytes()].concat(),
+            Command::Noop => vec![0],
+        }
+    }
+
+    fn decode(data: &[u8]) -> Option<Command> {
+        let (tag, data) = data.split_first()?;
+        match tag {
+            0 => Some(Command::Noop),
+            1 => {
+                let key_size = u32::from_be_bytes(data.get(..4)?.try_into().ok()?) as usize;
+                let key = data.get(4..4 + key_size)?;
+                let value = &data[4 + key_size..];
+                Some(Command::Put(KeyValue::new(key.to_vec(), value.to_vec())))
+            }
+            2 => Some(Command::Delete(data.to_vec())),
+            3 => Some(Command::AddNode(parse_u64(data)?)),
+            4 => Some(Command::RemoveNode(parse_u64(data)?)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub index: u64,
+    pub term: u64,
+    pub command: Command,
+}
+
+impl Entry {
+    /// Record the entry is kept as, keyed by its index then its term.
+    fn to_record(&self) -> KeyValue {
+        let mut key = self.index.to_be_bytes().to_vec();
+        key.extend_from_slice(&self.term.to_be_bytes());
+        KeyValue::new(key, self.command.encode())
+    }
+
+    fn from_record(record: KeyValue) -> Option<Entry> {
+        Some(Entry {
+            index: parse_u64(record.key.get(..8)?)?,
+            term: parse_u64(record.key.get(8..)?)?,
+            command: Command::decode(&record.value)?,
+        })
+    }
+}
+
+/// Part of a snapshot: the files of the database of the leader once the
+/// entries up to `index` were applied, copied after compressing its
+/// segments. They are sent a chunk at a time, so a snapshot never has to
+/// fit in one message, and carry everything the database keeps, down to
+/// content types, flags and expirations.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SnapshotChunk {
+    pub index: u64,
+    pub term: u64,
+    pub members: Vec<NodeId>,
+    /// Position of the chunk in the snapshot, from 0.
+    pub number: u64,
+    /// Path of the file within the database folder, and where `data` goes
+    /// in it.
+    pub file: String,
+    pub offset: u64,
+    pub data: Vec<u8>,
+    /// Whether the snapshot is complete with this chunk.
+    pub last: bool,
+}
+
+/// Messages nodes exchange. Leaders send the round of the latest
+/// linearizable read along with their appends, which followers send back,
+/// confirming the leader was still in charge when the read began.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    RequestVote {
+        term: u64,
+        last_index: u64,
+        last_term: u64,
+    },
+    Vote {
+        term: u64,
+        granted: bool,
+    },
+    Append {
+        term: u64,
+        prev_index: u64,
+        prev_term: u64,
+        entries: Vec<Entry>,
+        commit: u64,
+        read: u64,
+    },
+    /// Answer to `Append` and to the last chunk of `InstallSnapshot`, with
+    /// the last index known to match the leader's log on success, or where
+    /// to go back to on failure.
+    AppendResult {
+        term: u64,
+        success: bool,
+        last_index: u64,
+        read: u64,
+    },
+    InstallSnapshot {
+        term: u64,
+        chunk: SnapshotChunk,
+        read: u64,
+    },
+    /// Answer to the other chunks of `InstallSnapshot`, with the number of
+    /// the chunk expected next.
+    SnapshotReceived {
+        term: u64,
+        index: u64,
+        next: u64,
+        read: u64,
+    },
+}
+
+impl Message {
+    fn term(&self) -> u64 {
+        match self {
+            Message::RequestVote { term, .. }
+            | Message::Vote { term, .. }
+            | Message::Append { term, .. }
+            | Message::AppendResult { term, .. }
+            | Message::InstallSnapshot { term, .. }
+            | Message::SnapshotReceived { term, .. } => *term,
+        }
+    }
+
+    /// Bytes the message is sent as from one node to another, read back by
+    /// `decode`.
+    pub fn encode(&self) -> ByteString {
+        let mut data = Vec::new();
+        match self {
+            Message::RequestVote {
+                term,
+                last_index,
+                last_term,
+            } => {
+                data.push(1);
+                put_u64s(&mut data, &[*term, *last_index, *last_term]);
+            }
+            Message::Vote { term, granted } => {
+                data.push(2);
+                put_u64s(&mut data, &[*term]);
+                data.push(*granted as u8);
+            }
+            Message::Append {
+                term,
+                prev_index,
+                prev_term,
+                entries,
+                commit,
+                read,
+            } => {
+                data.push(3);
+                let count = entries.len() as u64;
+                put_u64s(
+                    &mut data,
+                    &[*term, *prev_index, *prev_term, *commit, *read, count],
+                );
+                for entry in entries {
+                    put_u64s(&mut data, &[entry.index, entry.term]);
+                    put_bytes(&mut data, &entry.command.encode());
+                }
+            }
+            Message::AppendResult {
+                term,
+                success,
+                last_index,
+                read,
+            } => {
+                data.push(4);
+                put_u64s(&mut data, &[*term, *last_index, *read]);
+                data.push(*success as u8);
+            }
+            Message::InstallSnapshot { term, chunk, read } => {
+                data.push(5);
+                let count = chunk.members.len() as u64;
+                put_u64s(&mut data, &[*term, *read, chunk.index, chunk.term, count]);
+                put_u64s(&mut data, &chunk.members);
+                put_u64s(&mut data, &[chunk.number, chunk.offset]);
+                put_bytes(&mut data, chunk.file.as_bytes());
+                put_bytes(&mut data, &chunk.data);
+                data.push(chunk.last as u8);
+            }
+            Message::SnapshotReceived {
+                term,
+                index,
+                next,
+                read,
+            } => {
+                data.push(6);
+                put_u64s(&mut data, &[*term, *index, *next, *read]);
+            }
+        }
+        data
+    }
+
+    pub fn decode(data: &[u8]) -> Option<Message> {
+        let (tag, data) = data.split_first()?;
+        let mut data = Decoder { data };
+        let message = match tag {
+            1 => Message::RequestVote {
+                term: data.u64()?,
+                last_index: data.u64()?,
+                last_term: data.u64()?,
+            },
+            2 => Message::Vote {
+                term: data.u64()?,
+                granted: data.bool()?,
+            },
+            3 => {
+                let (term, prev_index, prev_term) = (data.u64()?, data.u64()?, data.u64()?);
+                let (commit, read, count) = (data.u64()?, data.u64()?, data.u64()?);
+                let mut entries = Vec::new();
+                for _ in 0..count {
+                    entries.push(Entry {
+                        index: data.u64()?,
+                        term: data.u64()?,
+                        command: Command::decode(data.bytes()?)?,
+                    });
+                }
+                Message::Append {
+                    term,
+                    prev_index,
+                    prev_term,
+                    entries,
+                    commit,
+                    read,
+                }
+            }
+            4 => Message::AppendResult {
+                term: data.u64()?,
+                last_index: data.u64()?,
+                read: data.u64()?,
+                success: data.bool()?,
+            },
+            5 => {
+                let (term, read, index, chunk_term) =
+                    (data.u64()?, data.u64()?, data.u64()?, data.u64()?);
+                let count = data.u64()?;
+                let mut members = Vec::new();
+                for _ in 0..count {
+                    members.push(data.u64()?);
+                }
+                let chunk = SnapshotChunk {
+                    index,
+                    term: chunk_term,
+                    members,
+                    number: data.u64()?,
+                    offset: data.u64()?,
+                    file: String::from_utf8(data.bytes()?.to_vec()).ok()?,
+                    data: data.bytes()?.to_vec(),
+                    last: data.bool()?,
+                };
+                Message::InstallSnapshot { term, chunk, read }
+            }
+            6 => Message::SnapshotReceived {
+                term: data.u64()?,
+                index: data.u64()?,
+                next: data.u64()?,
+                read: data.u64()?,
+            },
+            _ => return None,
+        };
+
+        match data.data.is_empty() {
+            true => Some(message),
+            false => None,
+        }
+    }
+}
+
+fn put_u64s(data: &mut ByteString, numbers: &[u64]) {
+    for number in numbers {
+        data.extend_from_slice(&number.to_be_bytes());
+    }
+}
+
+fn put_bytes(data: &mut ByteString, bytes: &[u8]) {
+    data.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    data.extend_from_slice(bytes);
+}
+
+/// Reads the fields of an encoded message in order.
+struct Decoder<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, size: usize) -> Option<&'a [u8]> {
+        if self.data.len() < size {
+            return None;
+        }
+        let (taken, rest) = self.data.split_at(size);
+        self.data = rest;
+        Some(taken)
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        parse_u64(self.take(8)?)
+    }
+
+    fn bool(&mut self) -> Option<bool> {
+        match self.take(1)? {
+            [0] => Some(false),
+            [1] => Some(true),
+            _ => None,
+        }
+    }
+
+    fn bytes(&mut self) -> Option<&'a [u8]> {
+        let size = u32::from_be_bytes(self.take(4)?.try_into().ok()?) as usize;
+        self.take(size)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Envelope {
+    pub from: NodeId,
+    pub to: NodeId,
+    pub message: Message,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// Entry proposed to a leader, to find out whether it was applied.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Proposal {
+    pub index: u64,
+    pub term: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProposalStatus {
+    Pending,
+    Applied,
+    /// Replaced by the entry of another leader, or dropped from the log
+    /// before its outcome was asked for.
+    Dropped,
+}
+
+/// Linearizable read begun on a leader, which may be served once a
+/// majority confirmed its leadership and the entries committed when it
+/// began were applied.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReadIndex {
+    term: u64,
+    round: u64,
+    index: u64,
+}
+
+/// Entries after the last snapshot, persisted to the `raft_log` file: a
+/// record with the index, term and members of the snapshot, then a record
+/// per entry.
+struct RaftLog {
+    vfs: Arc<dyn Vfs>,
+    path: String,
+    file: Box<dyn VfsFile>,
+    snapshot_index: u64,
+    snapshot_term: u64,
+    snapshot_members: Vec<NodeId>,
+    entries: Vec<Entry>,
+    /// Where each entry starts in the file.
+    positions: Vec<u64>,
+}
+
+impl RaftLog {
+    /// Opens the log, started with `members` when there is none yet, and
+    /// drops entries torn by a crash.
+    fn load(folder: &str, members: &[NodeId], vfs: &Arc<dyn Vfs>) -> Result<RaftLog> {
+        let folder = folder_path(folder);
+        let path = build_path(&folder, RAFT_LOG_FILE);
+        vfs.create_dir_all(&folder)?;
+
+        let header = match vfs.exists(&path) {
+            true => {
+                let file = vfs.open(&path)?;
+                let mut reader = BufReader::new(FileReader::new(&*file, 0));
+                read_record(&mut reader)
+                    .ok()
+                    .and_then(|record| parse_snapshot_header(&record))
+                    .map(|header| (file, header))
+            }
+            false => None,
+        };
+        let (file, (snapshot_index, snapshot_term, snapshot_members)) = match header {
+            Some(header) => header,
+            None => {
+                // a log created by a crash before its header was written
+                let header = (0, 0, members.to_vec());
+                (RaftLog::write_log(vfs, &path, &header, &[])?.0, header)
+            }
+        };
+
+        let mut log = RaftLog {
+            vfs: Arc::clone(vfs),
+            path,
+            file,
+            snapshot_index,
+            snapshot_term,
+            snapshot_members,
+            entries: Vec::new(),
+            positions: Vec::new(),
+        };
+
+        let mut end = record_size(&log.header_record());
+        {
+            let mut reader = BufReader::new(FileReader::new(&*log.file, end));
+            loop {
+                let record = match read_record(&mut reader) {
+                    Ok(record) => record,
+                    Err(err) if err.kind() == UnexpectedEof || err.kind() == InvalidData => break,
+                    Err(err) => return Err(err),
+                };
+                let size = record_size(&record);
+                match Entry::from_record(record) {
+                    Some(entry) if entry.index == log.last_index() + 1 => {
+                        log.entries.push(entry);
+                        log.positions.push(end);
+                        end += size;
+                    }
+                    _ => break,
+                }
+            }
+        }
+
+        if log.file.len()? != end {
+            log.file.set_len(end)?;
+            log.file.sync()?;
+        }
+        Ok(log)
+    }
+
+    fn header_record(&self) -> KeyValue {
+        snapshot_header(
+            self.snapshot_index,
+            self.snapshot_term,
+            &self.snapshot_members,
+        )
+    }
+
+    /// Replaces the log with one starting after the snapshot in `header`
+    /// and holding `entries`, through a temporary file renamed over it.
+    /// Returns where each entry starts.
+    fn write_log(
+        vfs: &Arc<dyn Vfs>,
+        path: &str,
+        header: &SnapshotHeader,
+        entries: &[Entry],
+    ) -> Result<(Box<dyn VfsFile>, Vec<u64>)> {
+        let mut data = Vec::new();
+        write_record(&mut data, &snapshot_header(header.0, header.1, &header.2))?;
+        let mut positions = Vec::with_capacity(entries.len());
+        for entry in entries {
+            positions.push(data.len() as u64);
+            write_record(&mut data, &entry.to_record())?;
+        }
+
+        let temp_path = format!("{}.tmp", path);
+        let mut file = vfs.create(&temp_path)?;
+        file.append(&data)?;
+        file.sync()?;
+        vfs.rename(&temp_path, path)?;
+
+        Ok((vfs.open(path)?, positions))
+    }
+
+    fn last_index(&self) -> u64 {
+        self.snapshot_index + self.entries.len() as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        self.entries
+            .last()
+            .map_or(self.snapshot_term, |entry| entry.term)
+    }
+
+    /// Term of the entry at `index`, unknown once dropped for a snapshot.
+    fn term_at(&self, index: u64) -> Option<u64> {
+        match index {
+            index if index == self.snapshot_index => Some(self.snapshot_term),
+            index if index < self.snapshot_index => None,
+            index => self.entry(index).map(|entry| entry.term),
+        }
+    }
+
+    fn entry(&self, index: u64) -> Option<&Entry> {
+        match index > self.snapshot_index {
+            true => self.entries.get((index - self.snapshot_index - 1) as usize),
+            false => None,
+        }
+    }
+
+    fn entries_from(&self, index: u64, limit: usize) -> Vec<Entry> {
+        let start = (index - self.snapshot_index - 1) as usize;
+        self.entries
+            .iter()
+            .skip(start)
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    /// Members once the entries up to `index` are in the log.
+    fn members_at(&self, index: u64) -> Vec<NodeId> {
+        let mut members = self.snapshot_members.clone();
+        for entry in self.entries.iter().take_while(|entry| entry.index <= index) {
+            match entry.command {
+                Command::AddNode(id) if !members.contains(&id) => members.push(id),
+                Command::RemoveNode(id) => members.retain(|member| *member != id),
+                _ => {}
+            }
+        }
+        members
+    }
+
+    fn append(&mut self, entries: &[Entry]) -> Result<()> {
+        let size = self.file.len()?;
+        let mut data = Vec::new();
+        let mut positions = Vec::with_capacity(entries.len());
+        for entry in entries {
+            positions.push(size + data.len() as u64);
+            write_record(&mut data, &entry.to_record())?;
+        }
+
+        if let Err(err) = self.file.append(&data).and_then(|_| self.file.sync()) {
+            self.file.set_len(size)?;
+            return Err(err);
+        }
+        self.entries.extend_from_slice(entries);
+        self.positions.extend(positions);
+        Ok(())
+    }
+
+    /// Drops the entries from `index` on, replaced by those of a leader.
+    fn truncate_from(&mut self, index: u64) -> Result<()> {
+        let kept = (index - self.snapshot_index - 1) as usize;
+        self.file.set_len(self.positions[kept])?;
+        self.file.sync()?;
+        self.entries.truncate(kept);
+        self.positions.truncate(kept);
+        Ok(())
+    }
+
+    /// Drops the entries up to `index`, which were applied.
+    fn compact(&mut self, index: u64) -> Result<()> {
+        let header = (index, self.term_at(index).unwrap(), self.members_at(index));
+        let kept = (index - self.snapshot_index) as usize;
+        let (file, positions) =
+            RaftLog::write_log(&self.vfs, &self.path, &header, &self.entries[kept..])?;
+
+        self.file = file;
+        self.positions = positions;
+        self.entries.drain(..kept);
+        self.snapshot_index = header.0;
+        self.snapshot_term = header.1;
+        self.snapshot_members = header.2;
+        Ok(())
+    }
+
+    /// Empties the log, going on after an installed snapshot.
+    fn reset(&mut self, index: u64, term: u64, members: Vec<NodeId>) -> Result<()> {
+        let header = (index, term, members);
+        self.file = RaftLog::write_log(&self.vfs, &self.path, &header, &[])?.0;
+        self.positions.clear();
+        self.entries.clear();
+        self.snapshot_index = header.0;
+        self.snapshot_term = header.1;
+        self.snapshot_members = header.2;
+        Ok(())
+    }
+}
+
+/// Index, term and members of a snapshot.
+type SnapshotHeader = (u64, u64, Vec<NodeId>);
+
+fn snapshot_header(index: u64, term: u64, members: &[NodeId]) -> KeyValue {
+    let mut value = index.to_be_bytes().to_vec();
+    value.extend_from_slice(&term.to_be_bytes());
+    for member in members {
+        value.extend_from_slice(&member.to_be_bytes());
+    }
+    KeyValue::new(SNAPSHOT_KEY.to_vec(), value)
+}
+
+fn parse_snapshot_header(record: &KeyValue) -> Option<SnapshotHeader> {
+    if record.key != SNAPSHOT_KEY || !record.value.len().is_multiple_of(8) {
+        return None;
+    }
+
+    let mut numbers = record.value.chunks(8).map(parse_u64);
+    Some((
+        numbers.next()??,
+        numbers.next()??,
+        numbers.collect::<Option<_>>()?,
+    ))
+}
+
+fn parse_u64(data: &[u8]) -> Option<u64> {
+    Some(u64::from_be_bytes(data.try_into().ok()?))
+}
+
+/// Node of a cluster agreeing on a log of writes through Raft, applying
+/// them to its database once a majority has them. It is driven from the
+/// outside: `tick` makes time pass, `step` hands it a message from another
+/// node, and the messages it sends are taken from `take_messages`, so any
+/// transport can carry them. The term, vote and log are persisted in the
+/// files `raft_state` and `raft_log` of the folder of the node, with the
+/// database in a subfolder. The RESP server runs one in cluster mode.
+pub struct RaftNode {
+    id: NodeId,
+    options: RaftOptions,
+    db: RustDB,
+    vfs: Arc<dyn Vfs>,
+    folder: String,
+    state_path: String,
+    log: RaftLog,
+    /// Which of `DATABASE_FOLDERS` the database is in.
+    database: usize,
+    term: u64,
+    voted_for: Option<NodeId>,
+    role: Role,
+    leader: Option<NodeId>,
+    members: Vec<NodeId>,
+    commit: u64,
+    applied: u64,
+    elapsed: u32,
+    election_timeout: u32,
+    votes: HashSet<NodeId>,
+    /// Of each other member, when leader.
+    progress: HashMap<NodeId, Progress>,
+    read_round: u64,
+    outbox: Vec<Envelope>,
+    /// Snapshot sent to members missing entries dropped from the log, when
+    /// leader.
+    outgoing: Option<OutgoingSnapshot>,
+    /// Index of the snapshot being received, and the number of the chunk
+    /// expected next.
+    incoming: Option<(u64, u64)>,
+}
+
+struct Progress {
+    /// Next entry to send.
+    next: u64,
+    /// Last entry known to match the leader's log.
+    matched: u64,
+    /// Latest read round acknowledged.
+    read: u64,
+    /// Index of the snapshot being sent, and the number of the chunk to
+    /// send next.
+    snapshot: Option<(u64, u64)>,
+}
+
+/// Snapshot copied to the `snapshot` folder of a leader.
+struct OutgoingSnapshot {
+    index: u64,
+    term: u64,
+    members: Vec<NodeId>,
+    /// File of each chunk, where the chunk starts in it and its size.
+    chunks: Vec<(String, u64, u64)>,
+}
+
+impl OutgoingSnapshot {
+    fn chunk(&self, vfs: &Arc<dyn Vfs>, folder: &str, number: u64) -> Result<SnapshotChunk> {
+        let (file, offset, size) = &self.chunks[number as usize];
+        let source = vfs.open(&build_path(folder, file))?;
+        let mut data = vec![0; *size as usize];
+        FileReader::new(&*source, *offset).read_exact(&mut data)?;
+
+        Ok(SnapshotChunk {
+            index: self.index,
+            term: self.term,
+            members: self.members.clone(),
+            number,
+            file: file.clone(),
+            offset: *offset,
+            data,
+            last: number as usize + 1 == self.chunks.len(),
+        })
+    }
+}
+
+impl RaftNode {
+    /// Loads node `id` from `folder`, with its database loaded with
+    /// `db_options` from a subfolder. A node without a log yet starts one
+    /// with `members`, which should list every node when bootstrapping a
+    /// cluster, and none when joining one, as it then learns them from the
+    /// leader.
+    pub fn load(
+        id: NodeId,
+        members: &[NodeId],
+        folder: &str,
+        db_options: Options,
+        vfs: Arc<dyn Vfs>,
+        options: RaftOptions,
+    ) -> Result<RaftNode> {
+        let mut log = RaftLog::load(folder, members, &vfs)?;
+        let path = folder_path(folder);
+        let state_path = build_path(&path, RAFT_STATE_FILE);
+        let (term, voted_for, applied) = load_state(&vfs, &state_path)?;
+        let (database, installed) = load_database(&vfs, &build_path(&path, RAFT_DATABASE_FILE))?;
+        // a crash may come between switching to the database of a snapshot
+        // and resetting the log after it, or saving it as applied
+        if let Some((index, term, members)) = installed {
+            if index > log.snapshot_index {
+                log.reset(index, term, members)?;
+            }
+        }
+        let applied = applied.max(log.snapshot_index);
+
+        // snapshots left unfinished by a restart are started over
+        vfs.remove_dir_all(&build_path(&path, DATABASE_FOLDERS[1 - database]))?;
+        vfs.remove_dir_all(&build_path(&path, SNAPSHOT_FOLDER))?;
+        let db = RustDB::load_with_vfs(
+            &format!("{}/{}", folder, DATABASE_FOLDERS[database]),
+            db_options,
+            Arc::clone(&vfs),
+        );
+
+        let mut node = RaftNode {
+            id,
+            options,
+            db,
+            vfs,
+            folder: String::from(folder),
+            state_path,
+            log,
+            database,
+            term,
+            voted_for,
+            role: Role::Follower,
+            leader: None,
+            members: Vec::new(),
+            commit: applied,
+            applied,
+            elapsed: 0,
+            election_timeout: 0,
+            votes: HashSet::new(),
+            progress: HashMap::new(),
+            read_round: 0,
+            outbox: Vec::new(),
+            outgoing: None,
+            incoming: None,
+        };
+        node.update_members();
+        node.reset_election_timeout();
+        Ok(node)
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    /// Leader this node last heard from, or itself when leading.
+    pub fn leader(&self) -> Option<NodeId> {
+        self.leader
+    }
+
+    pub fn members(&self) -> &[NodeId] {
+        &self.members
+    }
+
+    pub fn commit_index(&self) -> u64 {
+        self.commit
+    }
+
+    pub fn applied_index(&self) -> u64 {
+        self.applied
+    }
+
+    /// Database the committed entries are applied to. Reading it directly
+    /// may return stale values; `begin_read` and `read` do not.
+    pub fn db(&self) -> &RustDB {
+        &self.db
+    }
+
+    /// Compresses the segments of the database, which is held meanwhile.
+    pub fn compress_segments(&mut self) -> Result<()> {
+        self.db.compress_segments()
+    }
+
+    /// Makes the records applied so far durable.
+    pub fn flush(&mut self) -> Result<()> {
+        self.db.flush()
+    }
+
+    /// Messages to deliver to other nodes since the last call.
+    pub fn take_messages(&mut self) -> Vec<Envelope> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    /// Makes time pass: leaders send heartbeats, while other members
+    /// campaign once they have not heard from a leader for too long.
+    pub fn tick(&mut self) -> Result<()> {
+        self.elapsed += 1;
+        match self.role {
+            Role::Leader if self.elapsed >= self.options.heartbeat_ticks => {
+                self.elapsed = 0;
+                self.broadcast_append()
+            }
+            Role::Leader => Ok(()),
+            _ if self.elapsed >= self.election_timeout && self.members.contains(&self.id) => {
+                self.campaign()
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Appends `command` to the log of the leader, to be applied once a
+    /// majority has it. Fails on other nodes, naming the leader when known.
+    pub fn propose(&mut self, command: Command) -> Result<Proposal> {
+        self.check_leader()?;
+        match &command {
+            Command::AddNode(_) | Command::RemoveNode(_) if self.has_pending_change() => {
+                return Err(Error::new(
+                    ErrorKind::WouldBlock,
+                    "A membership change is in progress",
+                ))
+            }
+            Command::AddNode(id) if self.members.contains(id) || *id == 0 => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Invalid new member: {}", id),
+                ))
+            }
+            Command::RemoveNode(id) if !self.members.contains(id) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Not a member: {}", id),
+                ))
+            }
+            Command::Put(key_value) if key_value.value.is_empty() => {
+                return Err(Error::new(ErrorKind::InvalidInput, "Empty value"))
+            }
+            _ => {}
+        }
+
+        self.append_command(command)
+    }
+
+    pub fn proposal_status(&self, proposal: Proposal) -> ProposalStatus {
+        match self.log.term_at(proposal.index) {
+            Some(term) if term != proposal.term => ProposalStatus::Dropped,
+            Some(_) if self.applied >= proposal.index => ProposalStatus::Applied,
+            Some(_) => ProposalStatus::Pending,
+            // a leader keeps its own entries for as long as its term lasts
+            None if proposal.index > self.log.last_index() && self.term == proposal.term => {
+                ProposalStatus::Pending
+            }
+            None => ProposalStatus::Dropped,
+        }
+    }
+
+    /// Begins a linearizable read on the leader, confirming with a majority
+    /// that it still leads. Fails with `WouldBlock` until the leader
+    /// committed an entry of its term, as it may not know the latest
+    /// commit before that.
+    pub fn begin_read(&mut self) -> Result<ReadIndex> {
+        self.check_leader()?;
+        if self.log.term_at(self.commit) != Some(self.term) {
+            return Err(Error::new(
+                ErrorKind::WouldBlock,
+                "Leader has not committed in its term yet",
+            ));
+        }
+
+        self.read_round += 1;
+        let read = ReadIndex {
+            term: self.term,
+            round: self.read_round,
+            index: self.commit,
+        };
+        self.broadcast_append()?;
+        Ok(read)
+    }
+
+    pub fn is_readable(&self, read: &ReadIndex) -> bool {
+        let confirmed = self
+            .members
+            .iter()
+            .filter(|member| match self.progress.get(member) {
+                Some(progress) => progress.read >= read.round,
+                None => **member == self.id,
+            })
+            .count();
+
+        self.role == Role::Leader
+            && self.term == read.term
+            && self.applied >= read.index
+            && confirmed * 2 > self.members.len()
+    }
+
+    /// Reads `key` for a read begun with `begin_read`, failing with
+    /// `WouldBlock` while it is not readable yet.
+    pub fn read<K: Into<ByteString>>(&self, read: &ReadIndex, key: K) -> Result<Option<KeyValue>> {
+        match self.is_readable(read) {
+            true => self.db.get_record(key),
+            false => Err(Error::new(
+                ErrorKind::WouldBlock,
+                "Read not confirmed by a majority yet",
+            )),
+        }
+    }
+
+    /// Handles a message sent by node `from`.
+    pub fn step(&mut self, from: NodeId, message: Message) -> Result<()> {
+        if message.term() > self.term {
+            self.become_follower(message.term(), None)?;
+        }
+
+        match message {
+            Message::RequestVote {
+                term,
+                last_index,
+                last_term,
+            } => self.handle_vote_request(from, term, last_index, last_term),
+            Message::Vote { term, granted } => {
+                if self.role == Role::Candidate && term == self.term && granted {
+                    self.votes.insert(from);
+                    if self.has_quorum(&self.votes) {
+                        return self.become_leader();
+                    }
+                }
+                Ok(())
+            }
+            Message::Append {
+                term,
+                prev_index,
+                prev_term,
+                entries,
+                commit,
+                read,
+            } => {
+                if !self.accept_leader(from, term, read) {
+                    return Ok(());
+                }
+                self.handle_append(from, prev_index, prev_term, entries, commit, read)
+            }
+            Message::AppendResult {
+                term,
+                success,
+                last_index,
+                read,
+            } => self.handle_append_result(from, term, success, last_index, read),
+            Message::InstallSnapshot { term, chunk, read } => {
+                if !self.accept_leader(from, term, read) {
+                    return Ok(());
+                }
+                self.handle_snapshot(from, chunk, read)
+            }
+            Message::SnapshotReceived {
+                term,
+                index,
+                next,
+                read,
+            } => self.handle_snapshot_received(from, term, index, next, read),
+        }
+    }
+
+    fn check_leader(&self) -> Result<()> {
+        match (self.role, self.leader) {
+            (Role::Leader, _) => Ok(()),
+            (_, Some(leader)) => Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("Not the leader, node {} is", leader),
+            )),
+            (_, None) => Err(Error::new(ErrorKind::WouldBlock, "No leader elected yet")),
+        }
+    }
+
+    fn has_pending_change(&self) -> bool {
+        self.log
+            .entries
+            .iter()
+            .filter(|entry| entry.index > self.commit)
+            .any(|entry| matches!(entry.command, Command::AddNode(_) | Command::RemoveNode(_)))
+    }
+
+    fn has_quorum(&self, ids: &HashSet<NodeId>) -> bool {
+        let count = self
+            .members
+            .iter()
+            .filter(|member| ids.contains(member))
+            .count();
+        count * 2 > self.members.len()
+    }
+
+    fn peers(&self) -> Vec<NodeId> {
+        self.members
+            .iter()
+            .copied()
+            .filter(|member| *member != self.id)
+            .collect()
+    }
+
+    fn send(&mut self, to: NodeId, message: Message) {
+        self.outbox.push(Envelope {
+            from: self.id,
+            to,
+            message,
+        });
+    }
+
+    fn reset_election_timeout(&mut self) {
+        let ticks = self.options.election_ticks.max(1);
+        self.elapsed = 0;
+        self.election_timeout = rand::thread_rng().gen_range(ticks, ticks * 2);
+    }
+
+    fn save_state(&self) -> Result<()> {
+        let mut value = self.term.to_be_bytes().to_vec();
+        value.extend_from_slice(&self.voted_for.unwrap_or(0).to_be_bytes());
+        value.extend_from_slice(&self.applied.to_be_bytes());
+        save_record(
+            &self.vfs,
+            &self.state_path,
+            &KeyValue::new(STATE_KEY.to_vec(), value),
+        )
+    }
+
+    /// Folder of the database in `DATABASE_FOLDERS[database]`, as the
+    /// database takes it.
+    fn database_folder(&self, database: usize) -> String {
+        format!("{}/{}", self.folder, DATABASE_FOLDERS[database])
+    }
+
+    fn snapshot_folder(&self) -> String {
+        format!("{}/{}", self.folder, SNAPSHOT_FOLDER)
+    }
+
+    fn update_members(&mut self) {
+        self.members = self.log.members_at(self.log.last_index());
+        if self.role != Role::Leader {
+            return;
+        }
+
+        let next = self.log.last_index() + 1;
+        for peer in self.peers() {
+            self.progress.entry(peer).or_insert(Progress {
+                next,
+                matched: 0,
+                read: 0,
+                snapshot: None,
+            });
+        }
+        let members = &self.members;
+        self.progress.retain(|peer, _| members.contains(peer));
+    }
+
+    fn campaign(&mut self) -> Result<()> {
+        self.term += 1;
+        self.voted_for = Some(self.id);
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.votes = [self.id].iter().copied().collect();
+        self.reset_election_timeout();
+        self.save_state()?;
+
+        if self.has_quorum(&self.votes) {
+            return self.become_leader();
+        }
+
+        let message = Message::RequestVote {
+            term: self.term,
+            last_index: self.log.last_index(),
+            last_term: self.log.last_term(),
+        };
+        for peer in self.peers() {
+            self.send(peer, message.clone());
+        }
+        Ok(())
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<NodeId>) -> Result<()> {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.save_state()?;
+        }
+        self.role = Role::Follower;
+        self.leader = leader;
+        self.votes.clear();
+        self.progress.clear();
+        self.reset_election_timeout();
+        if self.outgoing.take().is_some() {
+            self.vfs
+                .remove_dir_all(&folder_path(&self.snapshot_folder()))?;
+        }
+        Ok(())
+    }
+
+    fn become_leader(&mut self) -> Result<()> {
+        self.role = Role::Leader;
+        self.leader = Some(self.id);
+        self.elapsed = 0;
+        self.progress.clear();
+        self.update_members();
+        self.append_command(Command::Noop).map(|_| ())
+    }
+
+    fn append_command(&mut self, command: Command) -> Result<Proposal> {
+        let proposal = Proposal {
+            index: self.log.last_index() + 1,
+            term: self.term,
+        };
+        self.log.append(&[Entry {
+            index: proposal.index,
+            term: proposal.term,
+            command,
+        }])?;
+        self.update_members();
+        self.maybe_commit()?;
+        self.broadcast_append()?;
+        Ok(proposal)
+    }
+
+    fn broadcast_append(&mut self) -> Result<()> {
+        for peer in self.peers() {
+            self.send_append(peer)?;
+        }
+        Ok(())
+    }
+
+    /// Sends `peer` the entries it misses, or a snapshot once they were
+    /// dropped from the log.
+    fn send_append(&mut self, peer: NodeId) -> Result<()> {
+        let next = match self.progress.get(&peer) {
+            Some(progress) => progress.next,
+            None => return Ok(()),
+        };
+
+        if next <= self.log.snapshot_index {
+            return self.send_snapshot(peer);
+        }
+
+        let message = Message::Append {
+            term: self.term,
+            prev_index: next - 1,
+            prev_term: self.log.term_at(next - 1).unwrap(),
+            entries: self.log.entries_from(next, self.options.max_batch),
+            commit: self.commit,
+            read: self.read_round,
+        };
+        self.send(peer, message);
+        Ok(())
+    }
+
+    /// Sends `peer` the chunk of the snapshot it expects next, copying the
+    /// database for a new snapshot unless the log goes on from the last
+    /// one. The last chunk is answered once installed, the next append
+    /// going on after it.
+    fn send_snapshot(&mut self, peer: NodeId) -> Result<()> {
+        let current = match &self.outgoing {
+            Some(snapshot) => snapshot.index >= self.log.snapshot_index,
+            None => false,
+        };
+        if !current {
+            self.outgoing = Some(self.copy_snapshot()?);
+        }
+
+        let folder = folder_path(&self.snapshot_folder());
+        let snapshot = self.outgoing.as_ref().unwrap();
+        let progress = self.progress.get_mut(&peer).unwrap();
+        let number = match progress.snapshot {
+            Some((index, next)) if index == snapshot.index => next,
+            _ => 0,
+        };
+        progress.snapshot = Some((snapshot.index, number));
+        let chunk = snapshot.chunk(&self.vfs, &folder, number)?;
+
+        let message = Message::InstallSnapshot {
+            term: self.term,
+            chunk,
+            read: self.read_round,
+        };
+        self.send(peer, message);
+        Ok(())
+    }
+
+    /// Copies the database as it is, with every applied entry, to the
+    /// `snapshot` folder, split in chunks of `max_chunk_size`.
+    fn copy_snapshot(&mut self) -> Result<OutgoingSnapshot> {
+        let folder = self.snapshot_folder();
+        self.vfs.remove_dir_all(&folder_path(&folder))?;
+        let files = self.db.copy_files(&folder)?;
+
+        let chunk_size = self.options.max_chunk_size.max(1) as u64;
+        let mut chunks = Vec::new();
+        for (file, size) in files {
+            let mut offset = 0;
+            loop {
+                let length = chunk_size.min(size - offset);
+                chunks.push((file.clone(), offset, length));
+                offset += length;
+                if offset >= size {
+                    break;
+                }
+            }
+        }
+
+        Ok(OutgoingSnapshot {
+            index: self.applied,
+            term: self.log.term_at(self.applied).unwrap(),
+            members: self.log.members_at(self.applied),
+            chunks,
+        })
+    }
+
+    fn handle_vote_request(
+        &mut self,
+        from: NodeId,
+        term: u64,
+        last_index: u64,
+        last_term: u64,
+    ) -> Result<()> {
+        let up_to_date = (last_term, last_index) >= (self.log.last_term(), self.log.last_index());
+        let granted = term == self.term
+            && self.voted_for.is_none_or(|voted_for| voted_for == from)
+            && up_to_date;
+
+        if granted {
+            self.voted_for = Some(from);
+            self.save_state()?;
+            self.reset_election_timeout();
+        }
+        self.send(
+            from,
+            Message::Vote {
+                term: self.term,
+                granted,
+            },
+        );
+        Ok(())
+    }
+
+    /// Whether the leader sending a message of `term` is current, following
+    /// it if so, and answering it with the current term otherwise.
+    fn accept_leader(&mut self, from: NodeId, term: u64, read: u64) -> bool {
+        if term < self.term {
+            let message = Message::AppendResult {
+                term: self.term,
+                success: false,
+                last_index: self.log.last_index(),
+                read,
+            };
+            self.send(from, message);
+            return false;
+        }
+
+        self.role = Role::Follower;
+        self.leader = Some(from);
+        self.votes.clear();
+        self.elapsed = 0;
+        true
+    }
+
+    fn handle_append(
+        &mut self,
+        from: NodeId,
+        prev_index: u64,
+        prev_term: u64,
+        entries: Vec<Entry>,
+        commit: u64,
+        read: u64,
+    ) -> Result<()> {
+        let matches = match self.log.term_at(prev_index) {
+            Some(term) => term == prev_term,
+            // entries dropped for a snapshot were committed
+            None => prev_index < self.log.snapshot_index,
+        };
+        if !matches {
+            let last_index = self.log.last_index().min(prev_index.saturating_sub(1));
+            let message = Message::AppendResult {
+                term: self.term,
+                success: false,
+                last_index,
+                read,
+            };
+            self.send(from, message);
+            return Ok(());
+        }
+
+        let last_index = prev_index + entries.len() as u64;
+        let mut new_entries = Vec::new();
+        for entry in entries {
+            if entry.index <= self.log.snapshot_index {
+                continue;
+            }
+            match self.log.term_at(entry.index) {
+                Some(term) if term == entry.term && new_entries.is_empty() => continue,
+                Some(_) if new_entries.is_empty() => self.log.truncate_from(entry.index)?,
+                _ => {}
+            }
+            new_entries.push(entry);
+        }
+        if !new_entries.is_empty() {
+            self.log.append(&new_entries)?;
+            self.update_members();
+        }
+
+        if commit > self.commit {
+            self.commit = commit.min(last_index).max(self.commit);
+            self.apply()?;
+        }
+
+        let message = Message::AppendResult {
+            term: self.term,
+            success: true,
+            last_index,
+            read,
+        };
+        self.send(from, message);
+        Ok(())
+    }
+
+    /// Writes a chunk of a snapshot to the database folder not in use,
+    /// switching to that folder once the last chunk is in. Chunks are taken
+    /// in order, any other being answered with the number of the one
+    /// expected.
+    fn handle_snapshot(&mut self, from: NodeId, chunk: SnapshotChunk, read: u64) -> Result<()> {
+        let index = chunk.index;
+        let expected = match self.incoming {
+            Some((receiving, next)) if receiving == index => next,
+            _ => 0,
+        };
+
+        if index > self.applied && chunk.number != expected {
+            let message = Message::SnapshotReceived {
+                term: self.term,
+                index,
+                next: expected,
+                read,
+            };
+            self.send(from, message);
+            return Ok(());
+        }
+
+        if index > self.applied {
+            self.write_chunk(&chunk)?;
+            if !chunk.last {
+                let message = Message::SnapshotReceived {
+                    term: self.term,
+                    index,
+                    next: chunk.number + 1,
+                    read,
+                };
+                self.send(from, message);
+                return Ok(());
+            }
+            self.install_snapshot(index, chunk.term, chunk.members)?;
+        }
+
+        let message = Message::AppendResult {
+            term: self.term,
+            success: true,
+            last_index: index,
+            read,
+        };
+        self.send(from, message);
+        Ok(())
+    }
+
+    fn write_chunk(&mut self, chunk: &SnapshotChunk) -> Result<()> {
+        let outside = chunk
+            .file
+            .split('/')
+            .any(|part| part.is_empty() || part == "." || part == "..");
+        if outside {
+            return Err(Error::new(
+                InvalidData,
+                format!("Invalid snapshot file: {}", chunk.file),
+            ));
+        }
+
+        let folder = folder_path(&self.database_folder(1 - self.database));
+        if chunk.number == 0 {
+            self.vfs.remove_dir_all(&folder)?;
+        }
+        let path = build_path(&folder, &chunk.file);
+        if let Some((parent, _)) = path.rsplit_once('/') {
+            self.vfs.create_dir_all(parent)?;
+        }
+
+        let mut file = match chunk.offset {
+            0 => self.vfs.create(&path)?,
+            _ => self.vfs.open(&path)?,
+        };
+        file.write_at(chunk.offset, &chunk.data)?;
+        file.sync()?;
+        self.incoming = Some((chunk.index, chunk.number + 1));
+        Ok(())
+    }
+
+    /// Switches to the database received in the folder not in use, holding
+    /// the entries up to `index`, then empties the log going on after it.
+    fn install_snapshot(&mut self, index: u64, term: u64, members: Vec<NodeId>) -> Result<()> {
+        let database = 1 - self.database;
+        let header = snapshot_header(index, term, &members);
+        save_record(
+            &self.vfs,
+            &build_path(&folder_path(&self.folder), RAFT_DATABASE_FILE),
+            &KeyValue::new(DATABASE_FOLDERS[database].as_bytes().to_vec(), header.value),
+        )?;
+
+        let db = RustDB::load_with_vfs(
+            &self.database_folder(database),
+            self.db.get_options(),
+            Arc::clone(&self.vfs),
+        );
+        drop(std::mem::replace(&mut self.db, db));
+        self.vfs
+            .remove_dir_all(&folder_path(&self.database_folder(self.database)))?;
+        self.database = database;
+        self.incoming = None;
+
+        self.log.reset(index, term, members)?;
+        self.commit = self.commit.max(index);
+        self.applied = index;
+        self.save_state()?;
+        self.update_members();
+        Ok(())
+    }
+
+    /// Sends the next chunk of a snapshot once `from` received the one
+    /// before. Answers to chunks sent again are left alone, as the chunk
+    /// they expect is on its way.
+    fn handle_snapshot_received(
+        &mut self,
+        from: NodeId,
+        term: u64,
+        index: u64,
+        next: u64,
+        read: u64,
+    ) -> Result<()> {
+        if self.role != Role::Leader || term != self.term {
+            return Ok(());
+        }
+        let progress = match self.progress.get_mut(&from) {
+            Some(progress) => progress,
+            None => return Ok(()),
+        };
+
+        progress.read = progress.read.max(read);
+        match progress.snapshot {
+            Some((sending, number)) if sending == index && number != next => {
+                progress.snapshot = Some((index, next));
+                self.send_snapshot(from)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn handle_append_result(
+        &mut self,
+        from: NodeId,
+        term: u64,
+        success: bool,
+        last_index: u64,
+        read: u64,
+    ) -> Result<()> {
+        if self.role != Role::Leader || term != self.term {
+            return Ok(());
+        }
+        let last_log_index = self.log.last_index();
+        let progress = match self.progress.get_mut(&from) {
+            Some(progress) => progress,
+            None => return Ok(()),
+        };
+
+        progress.read = progress.read.max(read);
+        if success {
+            if matches!(progress.snapshot, Some((index, _)) if last_index >= index) {
+                progress.snapshot = None;
+            }
+            progress.matched = progress.matched.max(last_index);
+            progress.next = progress.next.max(progress.matched + 1);
+            let behind = progress.next <= last_log_index;
+            self.maybe_commit()?;
+            if behind {
+                self.send_append(from)?;
+            }
+            Ok(())
+        } else {
+            progress.next = (last_index + 1).min(progress.next - 1).max(1);
+            self.send_append(from)
+        }
+    }
+
+    /// Commits the latest entry of the current term a majority has, along
+    /// with every entry before it.
+    fn maybe_commit(&mut self) -> Result<()> {
+        let mut index = self.log.last_index();
+        while index > self.commit && self.log.term_at(index) == Some(self.term) {
+            let count = self
+                .members
+                .iter()
+                .filter(|member| match self.progress.get(member) {
+                    Some(progress) => progress.matched >= index,
+                    None => **member == self.id,
+                })
+                .count();
+            if count * 2 > self.members.len() {
+                self.commit = index;
+                return self.apply();
+            }
+            index -= 1;
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self) -> Result<()> {
+        if self.applied >= self.commit {
+            return Ok(());
+        }
+
+        while self.applied < self.commit {
+            let entry = self.log.entry(self.applied + 1).unwrap().clone();
+            match entry.command {
+                Command::Put(key_value) => self.db.save_record(key_value)?,
+                Command::Delete(key) => self.db.delete_record(key)?,
+                // a leader removed from the cluster steps down once it is
+                // committed without it
+                Command::RemoveNode(id) if id == self.id && self.role == Role::Leader => {
+                    self.role = Role::Follower;
+                    self.leader = None;
+                    self.progress.clear();
+                }
+                _ => {}
+            }
+            self.applied += 1;
+        }
+        // the entries must be durable in the database before they are saved
+        // as applied, or a crash would skip them
+        self.db.flush()?;
+        self.save_state()?;
+
+        let compacted = self
+            .applied
+            .saturating_sub(self.options.max_log_entries as u64 / 2);
+        if self.log.entries.len() > self.options.max_log_entries
+            && compacted > self.log.snapshot_index
+        {
+            self.log.compact(compacted)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes `record` to a temporary file renamed over the file at `path`.
+fn save_record(vfs: &Arc<dyn Vfs>, path: &str, record: &KeyValue) -> Result<()> {
+    let mut data = Vec::new();
+    write_record(&mut data, record)?;
+
+    let temp_path = format!("{}.tmp", path);
+    let mut file = vfs.create(&temp_path)?;
+    file.append(&data)?;
+    file.sync()?;
+    vfs.rename(&temp_path, path)
+}
+
+/// Which of `DATABASE_FOLDERS` is in use, saved in `raft_database`, with
+/// the index, term and members of the snapshot installed there. The first
+/// folder is in use, with no snapshot, when missing.
+fn load_database(vfs: &Arc<dyn Vfs>, path: &str) -> Result<(usize, Option<SnapshotHeader>)> {
+    if !vfs.exists(path) {
+        return Ok((0, None));
+    }
+
+    let file = vfs.open(path)?;
+    let record = read_record(&mut BufReader::new(FileReader::new(&*file, 0)))?;
+    let database = DATABASE_FOLDERS
+        .iter()
+        .position(|folder| folder.as_bytes() == record.key.as_slice());
+    let header = parse_snapshot_header(&KeyValue::new(SNAPSHOT_KEY.to_vec(), record.value));
+    match (database, header) {
+        (Some(database), Some(header)) => Ok((database, Some(header))),
+        _ => Err(Error::new(InvalidData, "Invalid raft database")),
+    }
+}
+
+/// Term, vote and applied index saved in `raft_state`, all 0 when missing.
+fn load_state(vfs: &Arc<dyn Vfs>, path: &str) -> Result<(u64, Option<NodeId>, u64)> {
+    if !vfs.exists(path) {
+        return Ok((0, None, 0));
+    }
+
+    let file = vfs.open(path)?;
+    let record = read_record(&mut BufReader::new(FileReader::new(&*file, 0)))?;
+    let numbers: Vec<u64> = record.value.chunks(8).filter_map(parse_u64).collect();
+    match numbers.as_slice() {
+        [term, voted_for, applied] if record.key == STATE_KEY => {
+            Ok((*term, Some(*voted_for).filter(|id| *id != 0), *applied))
+        }
+        _ => Err(Error::new(InvalidData, "Invalid raft state")),
+    }
+}
+
+/// Nodes of a cluster in one process, exchanging messages over a simulated
+/// network where nodes can be isolated, each with its database in its own
+/// folder of a shared in memory file system. Requests go to the leader and
+/// wait for the cluster to go through with them, ticking as needed.
+pub struct RaftCluster {
+    vfs: Arc<dyn Vfs>,
+    db_options: Options,
+    options: RaftOptions,
+    nodes: BTreeMap<NodeId, RaftNode>,
+    isolated: HashSet<NodeId>,
+}
+
+impl RaftCluster {
+    pub fn new(ids: &[NodeId], db_options: Options, options: RaftOptions) -> Result<RaftCluster> {
+        RaftCluster::with_vfs(ids, db_options, options, Arc::new(MemoryVfs::new()))
+    }
+
+    /// Cluster whose nodes keep their files in `vfs`.
+    pub fn with_vfs(
+        ids: &[NodeId],
+        db_options: Options,
+        options: RaftOptions,
+        vfs: Arc<dyn Vfs>,
+    ) -> Result<RaftCluster> {
+        let mut cluster = RaftCluster {
+            vfs,
+            db_options,
+            options,
+            nodes: BTreeMap::new(),
+            isolated: HashSet::new(),
+        };
+        for id in ids {
+            let node = cluster.load_node(*id, ids)?;
+            cluster.nodes.insert(*id, node);
+        }
+        Ok(cluster)
+    }
+
+    fn load_node(&self, id: NodeId, members: &[NodeId]) -> Result<RaftNode> {
+        RaftNode::load(
+            id,
+            members,
+            &format!("node{}", id),
+            self.db_options.clone(),
+            Arc::clone(&self.vfs),
+            self.options.clone(),
+        )
+    }
+
+    pub fn node(&self, id: NodeId) -> Option<&RaftNode> {
+        self.nodes.get(&id)
+    }
+
+    pub fn node_ids(&self) -> Vec<NodeId> {
+        self.nodes.keys().copied().collect()
+    }
+
+    /// Leader of the latest term among the nodes that are not isolated.
+    pub fn leader(&self) -> Option<NodeId> {
+        self.nodes
+            .values()
+            .filter(|node| node.role() == Role::Leader && !self.isolated.contains(&node.id()))
+            .max_by_key(|node| node.term())
+            .map(|node| node.id())
+    }
+
+    /// Drops every message to and from `id` until it is healed.
+    pub fn isolate(&mut self, id: NodeId) {
+        self.isolated.insert(id);
+    }
+
+    pub fn heal(&mut self, id: NodeId) {
+        self.isolated.remove(&id);
+    }
+
+    /// Ticks every node once, then delivers messages until none are left.
+    pub fn tick(&mut self) -> Result<()> {
+        for node in self.nodes.values_mut() {
+            node.tick()?;
+        }
+        self.deliver()
+    }
+
+    pub fn run(&mut self, ticks: usize) -> Result<()> {
+        for _ in 0..ticks {
+            self.tick()?;
+        }
+        Ok(())
+    }
+
+    fn deliver(&mut self) -> Result<()> {
+        for _ in 0..MAX_ROUNDS {
+            let mut messages = Vec::new();
+            for node in self.nodes.values_mut() {
+                messages.extend(node.take_messages());
+            }
+            if messages.is_empty() {
+                return Ok(());
+            }
+
+            for envelope in messages {
+                if self.isolated.contains(&envelope.from) || self.isolated.contains(&envelope.to) {
+                    continue;
+                }
+                if let Some(node) = self.nodes.get_mut(&envelope.to) {
+                    node.step(envelope.from, envelope.message)?;
+                }
+            }
+        }
+
+        Err(Error::other("Nodes kept exchanging messages"))
+    }
+
+    /// Ticks until a leader is elected.
+    pub fn wait_for_leader(&mut self) -> Result<NodeId> {
+        for _ in 0..MAX_TICKS {
+            if let Some(leader) = self.leader() {
+                return Ok(leader);
+            }
+            self.tick()?;
+        }
+        Err(Error::new(ErrorKind::TimedOut, "No leader elected"))
+    }
+
+    /// Proposes `command` to the leader until it is applied there, again
+    /// when another leader dropped it.
+    pub fn propose(&mut self, command: Command) -> Result<()> {
+        for _ in 0..MAX_TICKS {
+            let leader = self.wait_for_leader()?;
+            let proposal = match self
+                .nodes
+                .get_mut(&leader)
+                .unwrap()
+                .propose(command.clone())
+            {
+                Ok(proposal) => proposal,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    self.tick()?;
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            self.deliver()?;
+
+            for _ in 0..MAX_TICKS {
+                match self.nodes[&leader].proposal_status(proposal) {
+                    ProposalStatus::Applied => return Ok(()),
+                    ProposalStatus::Dropped => break,
+                    ProposalStatus::Pending => self.tick()?,
+                }
+            }
+        }
+        Err(Error::new(ErrorKind::TimedOut, "Proposal not applied"))
+    }
+
+    pub fn save_record(&mut self, key_value: KeyValue) -> Result<()> {
+        self.propose(Command::Put(key_value))
+    }
+
+    pub fn delete_record<K: Into<ByteString>>(&mut self, key: K) -> Result<()> {
+        self.propose(Command::Delete(key.into()))
+    }
+
+    /// Linearizable read of `key` on the leader.
+    pub fn get_record<K: Into<ByteString>>(&mut self, key: K) -> Result<Option<KeyValue>> {
+        let key = key.into();
+        for _ in 0..MAX_TICKS {
+            let leader = self.wait_for_leader()?;
+            let read = match self.nodes.get_mut(&leader).unwrap().begin_read() {
+                Ok(read) => read,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    self.tick()?;
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            self.deliver()?;
+
+            for _ in 0..MAX_TICKS {
+                let node = &self.nodes[&leader];
+                if node.is_readable(&read) {
+                    return node.read(&read, key.clone());
+                }
+                if node.role() != Role::Leader {
+                    break;
+                }
+                self.tick()?;
+            }
+        }
+        Err(Error::new(ErrorKind::TimedOut, "Read not confirmed"))
+    }
+
+    /// Starts node `id` with an empty database and adds it to the cluster,
+    /// which sends it a snapshot then the entries after it.
+    pub fn add_node(&mut self, id: NodeId) -> Result<()> {
+        let node = self.load_node(id, &[])?;
+        self.nodes.insert(id, node);
+        self.propose(Command::AddNode(id))
+    }
+
+    /// Removes node `id` from the cluster, then stops it.
+    pub fn remove_node(&mut self, id: NodeId) -> Result<()> {
+        self.propose(Command::RemoveNode(id))?;
+        self.nodes.remove(&id);
+        Ok(())
+    }
+
+    /// Stops node `id` and loads it again from its folder, as after a
+    /// crash.
+    pub fn restart(&mut self, id: NodeId) -> Result<()> {
+        self.nodes.remove(&id);
+        let node = self.load_node(id, &[])?;
+        self.nodes.insert(id, node);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cluster(ids: &[NodeId], max_log_entries: usize) -> RaftCluster {
+        let options = RaftOptions {
+            max_log_entries,
+            max_chunk_size: 100,
+            ..RaftOptions::default()
+        };
+        let db_options = Options {
+            change_log_size: 100_000,
+            ..Options::default()
+        };
+        RaftCluster::new(ids, db_options, options).unwrap()
+    }
+
+    fn put(cluster: &mut RaftCluster, key: &str, value: &str) {
+        cluster
+            .save_record(KeyValue::new_from_strings(
+                String::from(key),
+                String::from(value),
+            ))
+            .unwrap();
+    }
+
+    fn value(cluster: &mut RaftCluster, key: &str) -> Option<String> {
+        cluster
+            .get_record(key)
+            .unwrap()
+            .map(|record| record.get_value_as_string())
+    }
+
+    /// Whether every node applied the same writes.
+    fn assert_converged(cluster: &mut RaftCluster) {
+        cluster.run(10).unwrap();
+        let ids = cluster.node_ids();
+        let first = cluster.node(ids[0]).unwrap();
+        for id in &ids[1..] {
+            let node = cluster.node(*id).unwrap();
+            assert_eq!(node.applied_index(), first.applied_index());
+            assert_eq!(
+                node.db().get_keys().unwrap(),
+                first.db().get_keys().unwrap()
+            );
+            assert_eq!(node.db().last_sequence(), first.db().last_sequence());
+        }
+    }
+
+    #[test]
+    fn encode_and_decode_entries() {
+        let entries = vec![
+            Command::Put(KeyValue::new(b"key".to_vec(), b"value".to_vec())),
+            Command::Delete(b"key".to_vec()),
+            Command::AddNode(4),
+            Command::RemoveNode(1),
+            Command::Noop,
+        ];
+
+        for (index, command) in entries.into_iter().enumerate() {
+            let entry = Entry {
+                index: index as u64 + 1,
+                term: 2,
+                command,
+            };
+            assert_eq!(Entry::from_record(entry.to_record()), Some(entry));
+        }
+        assert_eq!(Command::decode(&[1, 0, 0, 0, 9, b'k']), None);
+    }
+
+    #[test]
+    fn replicate_writes_to_every_node() {
+        let mut cluster = cluster(&[1, 2, 3], 1_000);
+
+        put(&mut cluster, "a", "1");
+        put(&mut cluster, "b", "2");
+        cluster.delete_record("a").unwrap();
+
+        assert_eq!(value(&mut cluster, "a"), None);
+        assert_eq!(value(&mut cluster, "b"), Some(String::from("2")));
+        assert_converged(&mut cluster);
+        let leader = cluster.leader().unwrap();
+        let follower = cluster.node_ids().into_iter().find(|id| *id != leader);
+        let follower = cluster.node(follower.unwrap()).unwrap();
+        assert_eq!(follower.db().last_sequence(), 3);
+        assert_eq!(follower.leader(), Some(leader));
+    }
+
+    #[test]
+    fn elect_a_new_leader_when_the_leader_is_cut_off() {
+        let mut cluster = cluster(&[1, 2, 3], 1_000);
+        put(&mut cluster, "a", "1");
+        let old_leader = cluster.leader().unwrap();
+
+        cluster.isolate(old_leader);
+        put(&mut cluster, "a", "2");
+        let new_leader = cluster.leader().unwrap();
+        let stale = cluster.nodes.get_mut(&old_leader).unwrap();
+        let refused = stale.begin_read().and_then(|read| stale.read(&read, "a"));
+        cluster.heal(old_leader);
+
+        assert_ne!(new_leader, old_leader);
+        assert_eq!(refused.unwrap_err().kind(), ErrorKind::WouldBlock);
+        assert_eq!(value(&mut cluster, "a"), Some(String::from("2")));
+        assert_converged(&mut cluster);
+        assert_eq!(cluster.node(old_leader).unwrap().role(), Role::Follower);
+    }
+
+    #[test]
+    fn send_snapshots_to_new_members() {
+        let mut cluster = cluster(&[1, 2, 3], 10);
+        for n in 0..30 {
+            put(&mut cluster, &format!("k{}", n), &n.to_string());
+        }
+        cluster.delete_record("k0").unwrap();
+
+        cluster.add_node(4).unwrap();
+        let leader = cluster.leader().unwrap();
+        let removed = cluster
+            .node_ids()
+            .into_iter()
+            .find(|id| *id != leader && *id != 4)
+            .unwrap();
+        cluster.remove_node(removed).unwrap();
+        put(&mut cluster, "after", "1");
+
+        assert_converged(&mut cluster);
+        let node = cluster.node(4).unwrap();
+        assert_eq!(node.db().get_keys().unwrap().len(), 30);
+        assert!(node.log.snapshot_index > 0);
+        let mut members = node.members().to_vec();
+        members.sort_unstable();
+        let mut expected: Vec<NodeId> = vec![1, 2, 3, 4];
+        expected.retain(|id| *id != removed);
+        assert_eq!(members, expected);
+    }
+
+    #[test]
+    fn start_snapshots_cut_off_by_a_restart_over() {
+        let mut cluster = cluster(&[1, 2, 3], 10);
+        put(&mut cluster, "a", "1");
+        let leader = cluster.leader().unwrap();
+        let lagging = if leader == 1 { 2 } else { 1 };
+        cluster.isolate(lagging);
+        for n in 0..30 {
+            put(&mut cluster, &format!("k{}", n), &n.to_string());
+        }
+        cluster.heal(lagging);
+
+        // deliver until the third chunk reaches the lagging node, which
+        // restarts instead of writing it
+        let mut restarted = false;
+        while !restarted {
+            for node in cluster.nodes.values_mut() {
+                node.tick().unwrap();
+            }
+            for _ in 0..MAX_ROUNDS {
+                let mut messages = Vec::new();
+                for node in cluster.nodes.values_mut() {
+                    messages.extend(node.take_messages());
+                }
+                let cut_off = messages
+                    .iter()
+                    .position(|envelope| match &envelope.message {
+                        Message::InstallSnapshot { chunk, .. } => chunk.number == 2,
+                        _ => false,
+                    });
+                if let Some(position) = cut_off {
+                    messages.truncate(position);
+                    restarted = true;
+                }
+                for envelope in messages {
+                    let node = cluster.nodes.get_mut(&envelope.to).unwrap();
+                    node.step(envelope.from, envelope.message).unwrap();
+                }
+                if restarted || cluster.nodes.values().all(|node| node.outbox.is_empty()) {
+                    break;
+                }
+            }
+        }
+        let partial = folder_path(&cluster.node(lagging).unwrap().database_folder(1));
+        let written = cluster.vfs.list(&partial).unwrap();
+        cluster.restart(lagging).unwrap();
+        let left = cluster.vfs.list(&partial).unwrap();
+        put(&mut cluster, "after", "1");
+
+        assert!(!written.is_empty());
+        assert!(left.is_empty());
+        assert_converged(&mut cluster);
+        let node = cluster.node(lagging).unwrap();
+        assert_eq!(node.database, 1);
+        assert_eq!(node.db().get_keys().unwrap().len(), 32);
+        assert!(node.log.snapshot_index > 2);
+    }
+
+    #[test]
+    fn remove_the_leader() {
+        let mut cluster = cluster(&[1, 2, 3], 1_000);
+        put(&mut cluster, "a", "1");
+        let leader = cluster.leader().unwrap();
+
+        cluster.remove_node(leader).unwrap();
+        put(&mut cluster, "a", "2");
+
+        assert_ne!(cluster.leader(), Some(leader));
+        assert_eq!(value(&mut cluster, "a"), Some(String::from("2")));
+        assert_converged(&mut cluster);
+    }
+
+    #[test]
+    fn restart_nodes_from_their_files() {
+        let mut cluster = cluster(&[1, 2, 3], 10);
+        for n in 0..15 {
+            put(&mut cluster, &format!("k{}", n), &n.to_string());
+        }
+        cluster.run(10).unwrap();
+        let term = cluster.node(1).unwrap().term();
+
+        for id in cluster.node_ids() {
+            cluster.restart(id).unwrap();
+        }
+        let node = cluster.node(1).unwrap();
+        let applied = node.applied_index();
+        let restarted_term = node.term();
+        put(&mut cluster, "k0", "new");
+
+        assert_eq!(restarted_term, term);
+        assert_eq!(applied, 16);
+        assert_eq!(value(&mut cluster, "k0"), Some(String::from("new")));
+        assert_eq!(value(&mut cluster, "k14"), Some(String::from("14")));
+        assert_converged(&mut cluster);
+    }
+
+    #[test]
+    fn keep_applied_entries_across_crashes() {
+        let faulty = crate::fault::FaultyVfs::new(3);
+        let options = RaftOptions {
+            max_log_entries: 10,
+            ..RaftOptions::default()
+        };
+        let mut cluster = RaftCluster::with_vfs(
+            &[1, 2, 3],
+            Options::default(),
+            options,
+            Arc::new(faulty.clone()),
+        )
+        .unwrap();
+        for n in 0..15 {
+            put(&mut cluster, &format!("k{}", n), &n.to_string());
+        }
+        cluster.run(10).unwrap();
+
+        faulty.crash();
+        for id in cluster.node_ids() {
+            cluster.restart(id).unwrap();
+        }
+
+        for id in cluster.node_ids() {
+            let node = cluster.node(id).unwrap();
+            let applied = node.applied_index();
+            // the first entry is the leader's no-op
+            for n in 0..applied.saturating_sub(1) {
+                assert!(node.db().get_record(format!("k{}", n)).unwrap().is_some());
+            }
+        }
+        assert_eq!(value(&mut cluster, "k14"), Some(String::from("14")));
+        assert_converged(&mut cluster);
+    }
+
+    #[test]
+    fn decode_encoded_messages() {
+        let chunk = SnapshotChunk {
+            index: 7,
+            term: 2,
+            members: vec![1, 2, 3],
+            number: 4,
+            file: String::from("collections/users/initial_segment"),
+            offset: 100,
+            data: b"\x00data".to_vec(),
+            last: true,
+        };
+        let entries = vec![
+            Entry {
+                index: 5,
+                term: 2,
+                command: Command::Put(KeyValue::new(b"key".to_vec(), b"value".to_vec())),
+            },
+            Entry {
+                index: 6,
+                term: 2,
+                command: Command::RemoveNode(3),
+            },
+        ];
+        let messages = vec![
+            Message::RequestVote {
+                term: 3,
+                last_index: 6,
+                last_term: 2,
+            },
+            Message::Vote {
+                term: 3,
+                granted: true,
+            },
+            Message::Append {
+                term: 2,
+                prev_index: 4,
+                prev_term: 1,
+                entries,
+                commit: 4,
+                read: 9,
+            },
+            Message::AppendResult {
+                term: 2,
+                success: false,
+                last_index: 3,
+                read: 9,
+            },
+            Message::InstallSnapshot {
+                term: 2,
+                chunk,
+                read: 1,
+            },
+            Message::SnapshotReceived {
+                term: 2,
+                index: 7,
+                next: 5,
+                read: 1,
+            },
+        ];
+
+        for message in messages {
+            let data = message.encode();
+            assert_eq!(Message::decode(&data), Some(message));
+            assert_eq!(Message::decode(&data[..data.len() - 1]), None);
+            assert_eq!(Message::decode(&[data, vec![0]].concat()), None);
+        }
+    }
+}
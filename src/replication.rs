@@ -0,0 +1,441 @@
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::io::{prelude::*, BufReader, Cursor, Error, ErrorKind, Result};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::changes::{ChangeEvent, KeyMetadata, ReplicationEvent};
+use crate::core::KeyValue;
+use crate::service::RustDB;
+use crate::store::{read_record, read_streamed_record, write_record};
+
+/// Path of the changes of a leader from `from` on, along with its changes
+/// of metadata, streamed as records until the connection is closed. Those
+/// of a collection are streamed with its name in `collection`.
+pub static REPLICATION_LOG_PATH: &str = "/_replication/log";
+/// Path of every record of a leader, or of one of its collections, along
+/// with the sequence of the last change they include in the `X-Sequence`
+/// header.
+pub static REPLICATION_SNAPSHOT_PATH: &str = "/_replication/snapshot";
+pub static SEQUENCE_HEADER: &str = "X-Sequence";
+/// How long a follower waits to hear from the leader, which sends
+/// heartbeats more often than that while idle.
+static LEADER_TIMEOUT: Duration = Duration::from_secs(30);
+static RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Event as sent on the replication log. A change is a record keyed by its
+/// sequence then its key, with an empty value for a delete, while the other
+/// events are keyed by sequence 0, which no change has, then a tag.
+pub fn encode_replicated(event: &ReplicationEvent) -> Vec<u8> {
+    let (tag, value) = match event {
+        ReplicationEvent::Change(event) => return encode_change(event),
+        ReplicationEvent::Metadata(metadata) => (METADATA_TAG, encode_metadata(metadata)),
+        ReplicationEvent::AllMetadata(all) => {
+            let mut value = Vec::new();
+            for metadata in all {
+                let data = encode_metadata(metadata);
+                value.extend_from_slice(&(data.len() as u32).to_be_bytes());
+                value.extend_from_slice(&data);
+            }
+            (ALL_METADATA_TAG, value)
+        }
+        // names are made of letters, digits, `-` and `_`
+        ReplicationEvent::Collections(names) => (COLLECTIONS_TAG, names.join("\n").into_bytes()),
+    };
+
+    let mut key = 0_u64.to_be_bytes().to_vec();
+    key.push(tag);
+    encode_record(&KeyValue::new(key, value))
+}
+
+static METADATA_TAG: u8 = b'm';
+static ALL_METADATA_TAG: u8 = b'a';
+static COLLECTIONS_TAG: u8 = b'c';
+
+fn encode_change(event: &ChangeEvent) -> Vec<u8> {
+    encode_record(&event.to_record())
+}
+
+/// Flags, deadline when there is one, and content type, empty when there
+/// is none, then the key.
+fn encode_metadata(metadata: &KeyMetadata) -> Vec<u8> {
+    let mut data = metadata.flags.to_be_bytes().to_vec();
+    match metadata.expires_at {
+        Some(deadline) => {
+            data.push(1);
+            data.extend_from_slice(&deadline.to_be_bytes());
+        }
+        None => data.push(0),
+    }
+    let content_type = metadata.content_type.as_deref().unwrap_or_default();
+    data.extend_from_slice(&(content_type.len() as u32).to_be_bytes());
+    data.extend_from_slice(content_type.as_bytes());
+    data.extend_from_slice(&metadata.key);
+    data
+}
+
+fn decode_metadata(data: &[u8]) -> Option<KeyMetadata> {
+    let flags = u32::from_be_bytes(data.get(..4)?.try_into().ok()?);
+    let (expires_at, data) = match data.get(4)? {
+        0 => (None, &data[5..]),
+        1 => (
+            Some(u64::from_be_bytes(data.get(5..13)?.try_into().ok()?)),
+            &data[13..],
+        ),
+        _ => return None,
+    };
+    let size = u32::from_be_bytes(data.get(..4)?.try_into().ok()?) as usize;
+    let content_type = String::from_utf8(data.get(4..4 + size)?.to_vec()).ok()?;
+
+    Some(KeyMetadata {
+        key: data[4 + size..].to_vec(),
+        flags,
+        content_type: Some(content_type).filter(|content_type| !content_type.is_empty()),
+        expires_at,
+    })
+}
+
+/// Event of a record of the replication log, or `None` for a heartbeat.
+fn decode_replicated(record: KeyValue) -> Result<Option<ReplicationEvent>> {
+    if record.key.is_empty() {
+        return Ok(None);
+    }
+
+    let event = match record.key.get(..8) {
+        Some(sequence) if sequence != [0; 8] => {
+            ChangeEvent::from_record(record).map(ReplicationEvent::Change)
+        }
+        _ => match record.key.get(8..) {
+            Some([tag]) if *tag == METADATA_TAG => {
+                decode_metadata(&record.value).map(ReplicationEvent::Metadata)
+            }
+            Some([tag]) if *tag == ALL_METADATA_TAG => {
+                let mut all = Vec::new();
+                let mut data = record.value.as_slice();
+                while !data.is_empty() {
+                    let size = match data.get(..4) {
+                        Some(size) => u32::from_be_bytes(size.try_into().unwrap()) as usize,
+                        None => break,
+                    };
+                    match data.get(4..4 + size).and_then(decode_metadata) {
+                        Some(metadata) => all.push(metadata),
+                        None => break,
+                    }
+                    data = &data[4 + size..];
+                }
+                Some(ReplicationEvent::AllMetadata(all)).filter(|_| data.is_empty())
+            }
+            Some([tag]) if *tag == COLLECTIONS_TAG => {
+                let names = String::from_utf8(record.value).ok();
+                names.map(|names| {
+                    ReplicationEvent::Collections(
+                        names.split_terminator('\n').map(String::from).collect(),
+                    )
+                })
+            }
+            _ => None,
+        },
+    };
+
+    match event {
+        Some(event) => Ok(Some(event)),
+        None => Err(Error::new(
+            ErrorKind::InvalidData,
+            "Invalid replication record",
+        )),
+    }
+}
+
+/// Record with an empty key, sent on an idle replication log.
+pub fn encode_heartbeat() -> Vec<u8> {
+    encode_record(&KeyValue::new(Vec::new(), Vec::new()))
+}
+
+/// Record as sent in a snapshot.
+pub fn encode_record(record: &KeyValue) -> Vec<u8> {
+    let mut data = Vec::new();
+    write_record(&mut data, record).unwrap();
+    data
+}
+
+/// Keeps a read-only database up to date with a leader, applying the
+/// changes it streams from the sequence the database is at, along with the
+/// flags, content types and expirations of keys, and creating and dropping
+/// collections as the leader does. A follower further behind than the
+/// change log of the leader starts over from a snapshot of it. Each
+/// collection has its own changes, so it is followed by a thread of its
+/// own for as long as it exists.
+pub struct Follower {
+    leader: String,
+    db: Arc<Mutex<RustDB>>,
+    /// Collection followed, or `None` for the database itself.
+    collection: Option<String>,
+    /// Collections followed by a thread.
+    followed: Arc<Mutex<HashSet<String>>>,
+}
+
+struct LeaderResponse {
+    status_code: u16,
+    headers: Vec<(String, String)>,
+    body: BufReader<TcpStream>,
+}
+
+impl LeaderResponse {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn expect_success(&self) -> Result<()> {
+        match self.status_code {
+            200 => Ok(()),
+            status_code => Err(Error::other(format!(
+                "Leader answered with status {}",
+                status_code
+            ))),
+        }
+    }
+}
+
+impl Follower {
+    /// Follows the REST server at `leader`, a `host:port`, making `db`
+    /// read-only until it is promoted.
+    pub fn new(leader: &str, db: Arc<Mutex<RustDB>>) -> Follower {
+        db.lock().unwrap().set_read_only(true);
+        Follower {
+            leader: String::from(leader),
+            db,
+            collection: None,
+            followed: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    /// Follows the leader, reconnecting whenever the connection is lost,
+    /// until the database is promoted, or the collection followed is
+    /// dropped.
+    pub fn run(&self) {
+        while self.is_following() {
+            if let Err(err) = self.follow() {
+                if self.db.lock().unwrap().is_read_only() {
+                    println!("Failed to follow the leader at {}\n{}", self.leader, err);
+                    thread::sleep(RETRY_DELAY);
+                }
+            }
+        }
+    }
+
+    /// Whether to go on following, forgetting the collection followed once
+    /// it is gone, under the lock the collections are created with, so that
+    /// a collection created again gets a thread again.
+    fn is_following(&self) -> bool {
+        let db = self.db.lock().unwrap();
+        let following = match &self.collection {
+            Some(name) => db.is_read_only() && db.collection(name).is_some(),
+            None => db.is_read_only(),
+        };
+        if let (false, Some(name)) = (following, &self.collection) {
+            self.followed.lock().unwrap().remove(name);
+        }
+        following
+    }
+
+    /// Path of the replication log, or snapshot, of what is followed.
+    fn target(&self, path: &str, from: Option<u64>) -> String {
+        let mut params = Vec::new();
+        if let Some(from) = from {
+            params.push(format!("from={}", from));
+        }
+        if let Some(name) = &self.collection {
+            params.push(format!("collection={}", name));
+        }
+        match params.is_empty() {
+            true => String::from(path),
+            false => format!("{}?{}", path, params.join("&")),
+        }
+    }
+
+    /// Database or collection followed, `None` once the collection is gone.
+    fn followed_db<'a>(&self, db: &'a mut RustDB) -> Option<&'a mut RustDB> {
+        match &self.collection {
+            Some(name) => db.collection_mut(name),
+            None => Some(db),
+        }
+    }
+
+    /// Applies what the leader streams until the connection breaks, the
+    /// database is promoted or the collection followed is dropped.
+    fn follow(&self) -> Result<()> {
+        let from = match self.followed_db(&mut self.db.lock().unwrap()) {
+            Some(db) => db.last_sequence() + 1,
+            None => return Ok(()),
+        };
+        let mut response = self.get(&self.target(REPLICATION_LOG_PATH, Some(from)))?;
+        if response.status_code == 410 {
+            return self.install_snapshot();
+        }
+        response.expect_success()?;
+
+        loop {
+            let event = match decode_replicated(read_streamed_record(&mut response.body)?)? {
+                Some(event) => event,
+                None => continue,
+            };
+            let mut db = self.db.lock().unwrap();
+            if !db.is_read_only() {
+                return Ok(());
+            }
+            let collections = matches!(event, ReplicationEvent::Collections(_));
+            match self.followed_db(&mut db) {
+                Some(followed) => followed.apply_replicated(event)?,
+                None => return Ok(()),
+            }
+            if collections {
+                self.follow_collections(&db);
+            }
+        }
+    }
+
+    /// Starts a thread for each collection not followed yet.
+    fn follow_collections(&self, db: &RustDB) {
+        let mut followed = self.followed.lock().unwrap();
+        for name in db.list_collections() {
+            if followed.insert(name.clone()) {
+                let follower = Follower {
+                    leader: self.leader.clone(),
+                    db: Arc::clone(&self.db),
+                    collection: Some(name),
+                    followed: Arc::clone(&self.followed),
+                };
+                thread::spawn(move || follower.run());
+            }
+        }
+    }
+
+    /// Replaces every record with those of the leader. Changes made on the
+    /// leader while the snapshot was taken may be in it already, which is
+    /// fine as they are applied again right after, in order.
+    fn install_snapshot(&self) -> Result<()> {
+        let mut response = self.get(&self.target(REPLICATION_SNAPSHOT_PATH, None))?;
+        response.expect_success()?;
+        let sequence = response
+            .header(SEQUENCE_HEADER)
+            .and_then(|sequence| sequence.parse().ok())
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Missing snapshot sequence"))?;
+
+        let mut body = Vec::new();
+        response.body.read_to_end(&mut body)?;
+        let size = body.len() as u64;
+        if response.header("Content-Length") != Some(&size.to_string()) {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Incomplete snapshot"));
+        }
+
+        let mut reader = Cursor::new(body);
+        let mut records = Vec::new();
+        while reader.position() < size {
+            records.push(read_record(&mut reader)?);
+        }
+
+        let mut db = self.db.lock().unwrap();
+        if !db.is_read_only() {
+            return Ok(());
+        }
+        match self.followed_db(&mut db) {
+            Some(followed) => followed.install_snapshot(sequence, records),
+            None => Ok(()),
+        }
+    }
+
+    fn get(&self, target: &str) -> Result<LeaderResponse> {
+        let mut stream = TcpStream::connect(&self.leader)?;
+        stream.set_read_timeout(Some(LEADER_TIMEOUT))?;
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            target, self.leader
+        )?;
+
+        let mut body = BufReader::new(stream);
+        let mut line = String::new();
+        body.read_line(&mut line)?;
+        let status_code = line
+            .split(' ')
+            .nth(1)
+            .and_then(|status_code| status_code.parse().ok())
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Invalid status line: {}", line.trim_end()),
+                )
+            })?;
+
+        let mut headers = Vec::new();
+        loop {
+            line.clear();
+            if body.read_line(&mut line)? == 0 {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "Connection closed before a whole response head",
+                ));
+            }
+            match line.trim_end().split_once(':') {
+                Some((name, value)) => {
+                    headers.push((String::from(name), String::from(value.trim())))
+                }
+                None => break,
+            }
+        }
+
+        Ok(LeaderResponse {
+            status_code,
+            headers,
+            body,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_encoded_events() {
+        let metadata = KeyMetadata {
+            key: b"a\x00b".to_vec(),
+            flags: 7,
+            content_type: Some(String::from("text/plain")),
+            expires_at: Some(1_000),
+        };
+        let events = vec![
+            ReplicationEvent::Change(ChangeEvent {
+                sequence: 3,
+                key: b"a".to_vec(),
+                value: None,
+            }),
+            ReplicationEvent::Metadata(metadata.clone()),
+            ReplicationEvent::AllMetadata(vec![
+                metadata,
+                KeyMetadata {
+                    key: b"b".to_vec(),
+                    ..KeyMetadata::default()
+                },
+            ]),
+            ReplicationEvent::AllMetadata(Vec::new()),
+            ReplicationEvent::Collections(vec![String::from("orders"), String::from("users")]),
+            ReplicationEvent::Collections(Vec::new()),
+        ];
+
+        for event in events {
+            let data = encode_replicated(&event);
+            let record = read_streamed_record(&mut data.as_slice()).unwrap();
+            assert_eq!(decode_replicated(record).unwrap(), Some(event));
+        }
+        let heartbeat = read_streamed_record(&mut encode_heartbeat().as_slice()).unwrap();
+        assert_eq!(decode_replicated(heartbeat).unwrap(), None);
+        let unknown = KeyValue::new([&[0; 8][..], b"x"].concat(), Vec::new());
+        assert!(decode_replicated(unknown).is_err());
+    }
+}
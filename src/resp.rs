@@ -0,0 +1,375 @@
+use std::io::{prelude::*, Error, ErrorKind, Result};
+use std::net::{TcpStream, ToSocketAddrs};
+
+use crate::core::ByteString;
+
+/// Longest line accepted for simple strings, numbers and inline commands.
+static MAX_LINE_SIZE: usize = 65_536;
+/// Deepest nesting of aggregates accepted when parsing.
+static MAX_DEPTH: usize = 32;
+
+/// Value of the Redis serialization protocol. `Null`, `Boolean` and `Map`
+/// are RESP3 types, sent as their RESP2 equivalents to RESP2 clients.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RespValue {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(ByteString),
+    Array(Vec<RespValue>),
+    Null,
+    Boolean(bool),
+    Map(Vec<(RespValue, RespValue)>),
+}
+
+impl RespValue {
+    pub fn ok() -> RespValue {
+        RespValue::Simple(String::from("OK"))
+    }
+
+    pub fn error(message: &str) -> RespValue {
+        RespValue::Error(String::from(message))
+    }
+
+    /// Bulk strings for `args`, as commands are sent.
+    pub fn command(args: &[&[u8]]) -> RespValue {
+        RespValue::Array(args.iter().map(|a| RespValue::Bulk(a.to_vec())).collect())
+    }
+
+    /// Serializes the value for a client speaking `protocol` 2 or 3.
+    pub fn encode(&self, protocol: u8) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        self.encode_into(&mut buffer, protocol);
+        buffer
+    }
+
+    fn encode_into(&self, buffer: &mut Vec<u8>, protocol: u8) {
+        match self {
+            RespValue::Simple(value) => write_line(buffer, b'+', value.as_bytes()),
+            RespValue::Error(message) => write_line(buffer, b'-', message.as_bytes()),
+            RespValue::Integer(value) => write_line(buffer, b':', value.to_string().as_bytes()),
+            RespValue::Bulk(value) => {
+                write_line(buffer, b'$', value.len().to_string().as_bytes());
+                buffer.extend_from_slice(value);
+                buffer.extend_from_slice(b"\r\n");
+            }
+            RespValue::Array(values) => {
+                write_line(buffer, b'*', values.len().to_string().as_bytes());
+                for value in values {
+                    value.encode_into(buffer, protocol);
+                }
+            }
+            RespValue::Null if protocol >= 3 => buffer.extend_from_slice(b"_\r\n"),
+            RespValue::Null => buffer.extend_from_slice(b"$-1\r\n"),
+            RespValue::Boolean(value) if protocol >= 3 => {
+                write_line(buffer, b'#', if *value { b"t" } else { b"f" })
+            }
+            RespValue::Boolean(value) => RespValue::Integer(*value as i64).encode_into(buffer, 2),
+            RespValue::Map(entries) => {
+                let (kind, size) = match protocol >= 3 {
+                    true => (b'%', entries.len()),
+                    false => (b'*', entries.len() * 2),
+                };
+                write_line(buffer, kind, size.to_string().as_bytes());
+                for (key, value) in entries {
+                    key.encode_into(buffer, protocol);
+                    value.encode_into(buffer, protocol);
+                }
+            }
+        }
+    }
+}
+
+fn write_line(buffer: &mut Vec<u8>, kind: u8, line: &[u8]) {
+    buffer.push(kind);
+    buffer.extend_from_slice(line);
+    buffer.extend_from_slice(b"\r\n");
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("Protocol error: {}", message),
+    )
+}
+
+/// Parses the value at the start of `buffer`, returning it with the number
+/// of bytes it took, or `None` when more bytes are needed. Bulk strings
+/// larger than `max_size` are refused.
+pub fn parse_value(buffer: &[u8], max_size: usize) -> Result<Option<(RespValue, usize)>> {
+    let mut position = 0;
+    Ok(parse_at(buffer, &mut position, max_size, 0)?.map(|value| (value, position)))
+}
+
+/// Parses a command, sent either as an array of bulk strings or as an
+/// inline line of words separated by spaces. Blank lines give an empty
+/// command.
+pub fn parse_command(buffer: &[u8], max_size: usize) -> Result<Option<(Vec<ByteString>, usize)>> {
+    if buffer.first() != Some(&b'*') {
+        let mut position = 0;
+        return Ok(read_line(buffer, &mut position)?.map(|line| {
+            let args = line
+                .split(|b| b.is_ascii_whitespace())
+                .filter(|word| !word.is_empty())
+                .map(|word| word.to_vec())
+                .collect();
+            (args, position)
+        }));
+    }
+
+    match parse_value(buffer, max_size)? {
+        Some((RespValue::Array(values), size)) => {
+            let mut args = Vec::with_capacity(values.len());
+            for value in values {
+                match value {
+                    RespValue::Bulk(arg) => args.push(arg),
+                    _ => return Err(invalid("commands must be arrays of bulk strings")),
+                }
+            }
+            Ok(Some((args, size)))
+        }
+        Some((RespValue::Null, size)) => Ok(Some((Vec::new(), size))),
+        Some(_) => Err(invalid("commands must be arrays of bulk strings")),
+        None => Ok(None),
+    }
+}
+
+fn read_line<'a>(buffer: &'a [u8], position: &mut usize) -> Result<Option<&'a [u8]>> {
+    let rest = &buffer[*position..];
+    match rest.windows(2).position(|w| w == b"\r\n") {
+        Some(end) if end > MAX_LINE_SIZE => Err(invalid("too long line")),
+        Some(end) => {
+            *position += end + 2;
+            Ok(Some(&rest[..end]))
+        }
+        None if rest.len() > MAX_LINE_SIZE => Err(invalid("too long line")),
+        None => Ok(None),
+    }
+}
+
+fn parse_number(line: &[u8]) -> Result<i64> {
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|line| line.parse().ok())
+        .ok_or_else(|| invalid("invalid number"))
+}
+
+/// Size of a bulk string or an aggregate, where -1 stands for null.
+fn parse_size(line: &[u8]) -> Result<Option<usize>> {
+    match parse_number(line)? {
+        -1 => Ok(None),
+        size if size >= 0 => Ok(Some(size as usize)),
+        _ => Err(invalid("invalid size")),
+    }
+}
+
+fn parse_at(
+    buffer: &[u8],
+    position: &mut usize,
+    max_size: usize,
+    depth: usize,
+) -> Result<Option<RespValue>> {
+    if depth > MAX_DEPTH {
+        return Err(invalid("too deeply nested value"));
+    }
+
+    let kind = match buffer.get(*position) {
+        Some(kind) => *kind,
+        None => return Ok(None),
+    };
+    *position += 1;
+
+    let line = match read_line(buffer, position)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+
+    let value = match kind {
+        b'+' => RespValue::Simple(String::from_utf8_lossy(line).into_owned()),
+        b'-' => RespValue::Error(String::from_utf8_lossy(line).into_owned()),
+        b':' => RespValue::Integer(parse_number(line)?),
+        b'_' => RespValue::Null,
+        b'#' => match line {
+            b"t" => RespValue::Boolean(true),
+            b"f" => RespValue::Boolean(false),
+            _ => return Err(invalid("invalid boolean")),
+        },
+        b'$' => match parse_size(line)? {
+            Some(size) if size > max_size => return Err(invalid("too large bulk string")),
+            Some(size) => {
+                let end = *position + size;
+                if buffer.len() < end + 2 {
+                    return Ok(None);
+                }
+                if &buffer[end..end + 2] != b"\r\n" {
+                    return Err(invalid("bulk string not followed by CRLF"));
+                }
+                let value = buffer[*position..end].to_vec();
+                *position = end + 2;
+                RespValue::Bulk(value)
+            }
+            None => RespValue::Null,
+        },
+        b'*' => match parse_size(line)? {
+            Some(size) => {
+                let mut values = Vec::new();
+                for _ in 0..size {
+                    match parse_at(buffer, position, max_size, depth + 1)? {
+                        Some(value) => values.push(value),
+                        None => return Ok(None),
+                    }
+                }
+                RespValue::Array(values)
+            }
+            None => RespValue::Null,
+        },
+        b'%' => {
+            let size = parse_size(line)?.ok_or_else(|| invalid("invalid map size"))?;
+            let mut entries = Vec::new();
+            for _ in 0..size {
+                let key = parse_at(buffer, position, max_size, depth + 1)?;
+                let value = parse_at(buffer, position, max_size, depth + 1)?;
+                match (key, value) {
+                    (Some(key), Some(value)) => entries.push((key, value)),
+                    _ => return Ok(None),
+                }
+            }
+            RespValue::Map(entries)
+        }
+        _ => return Err(invalid("unknown value type")),
+    };
+
+    Ok(Some(value))
+}
+
+/// Blocking RESP client sending one command at a time, or several before
+/// reading their replies to pipeline them.
+pub struct RespClient {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+}
+
+impl RespClient {
+    pub fn connect<A: ToSocketAddrs>(address: A) -> Result<RespClient> {
+        Ok(RespClient {
+            stream: TcpStream::connect(address)?,
+            buffer: Vec::new(),
+        })
+    }
+
+    pub fn get_stream(&self) -> &TcpStream {
+        &self.stream
+    }
+
+    pub fn send(&mut self, args: &[&[u8]]) -> Result<()> {
+        self.stream.write_all(&RespValue::command(args).encode(2))
+    }
+
+    pub fn read_reply(&mut self) -> Result<RespValue> {
+        loop {
+            if let Some((value, size)) = parse_value(&self.buffer, usize::MAX)? {
+                self.buffer.drain(..size);
+                return Ok(value);
+            }
+
+            let mut chunk = [0; 4_096];
+            match self.stream.read(&mut chunk)? {
+                0 => {
+                    return Err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "Connection closed before a whole reply",
+                    ))
+                }
+                size => self.buffer.extend_from_slice(&chunk[..size]),
+            }
+        }
+    }
+
+    pub fn command(&mut self, args: &[&[u8]]) -> Result<RespValue> {
+        self.send(args)?;
+        self.read_reply()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_and_parse_every_type() {
+        let value = RespValue::Array(vec![
+            RespValue::ok(),
+            RespValue::error("ERR failed"),
+            RespValue::Integer(-12),
+            RespValue::Bulk(b"a\r\nb".to_vec()),
+            RespValue::Null,
+            RespValue::Boolean(true),
+            RespValue::Map(vec![(
+                RespValue::Bulk(b"k".to_vec()),
+                RespValue::Integer(1),
+            )]),
+        ]);
+
+        let encoded = value.encode(3);
+
+        assert_eq!(
+            parse_value(&encoded, 100).unwrap(),
+            Some((value, encoded.len()))
+        );
+    }
+
+    #[test]
+    fn encode_resp3_types_for_resp2_clients() {
+        let value = RespValue::Array(vec![
+            RespValue::Null,
+            RespValue::Boolean(false),
+            RespValue::Map(vec![(
+                RespValue::Simple(String::from("k")),
+                RespValue::Integer(1),
+            )]),
+        ]);
+
+        assert_eq!(
+            value.encode(2),
+            b"*3\r\n$-1\r\n:0\r\n*2\r\n+k\r\n:1\r\n".to_vec()
+        );
+        assert_eq!(
+            value.encode(3),
+            b"*3\r\n_\r\n#f\r\n%1\r\n+k\r\n:1\r\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn wait_for_whole_values() {
+        let encoded = RespValue::command(&[b"SET", b"key", b"value"]).encode(2);
+
+        for end in 0..encoded.len() {
+            assert_eq!(parse_value(&encoded[..end], 100).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn parse_array_and_inline_commands() {
+        let mut buffer = RespValue::command(&[b"GET", b"a b"]).encode(2);
+        let size = buffer.len();
+        buffer.extend_from_slice(b"PING  hello\r\n");
+
+        let (first, first_size) = parse_command(&buffer, 100).unwrap().unwrap();
+        let (second, second_size) = parse_command(&buffer[first_size..], 100).unwrap().unwrap();
+
+        assert_eq!(first, vec![b"GET".to_vec(), b"a b".to_vec()]);
+        assert_eq!(first_size, size);
+        assert_eq!(second, vec![b"PING".to_vec(), b"hello".to_vec()]);
+        assert_eq!(first_size + second_size, buffer.len());
+    }
+
+    #[test]
+    fn reject_invalid_values() {
+        assert!(parse_value(b"$5\r\nabcdefgh\r\n", 100).is_err());
+        assert!(parse_value(b"$500\r\n", 100).is_err());
+        assert!(parse_value(b"?1\r\n", 100).is_err());
+        assert!(parse_value(b":x\r\n", 100).is_err());
+        assert!(parse_command(b"*1\r\n:1\r\n", 100).is_err());
+        assert!(parse_value(&vec![b'*'; MAX_LINE_SIZE + 2], 100).is_err());
+    }
+}
@@ -0,0 +1,1398 @@
+use rustdb::{
+    parse_command, AsyncRustDB, ByteString, Command as RaftCommand, DiskVfs, KeyValue,
+    LogCompressor, Message, NodeId, Options, ProposalStatus, RaftNode, RaftOptions, RespValue,
+    RustDB,
+};
+use std::collections::HashMap;
+use std::env;
+use std::io::{Error, ErrorKind, Result};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::{thread, time};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Notify};
+use tokio::task;
+use tokio::time::{timeout, timeout_at, Instant};
+
+/// Time between the ticks of the Raft node of a server in a cluster.
+static TICK_INTERVAL: Duration = Duration::from_millis(50);
+/// Time a command waits for the cluster before it is answered with an
+/// error.
+static REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+static CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Replies of the commands run on the database.
+type CommandCallback = fn(&[ByteString], &mut RustDB) -> Result<RespValue>;
+
+/// Command run on the database, with its number of arguments counting the
+/// command name, or the minimum number of them when negative, as Redis
+/// reports arities.
+struct Command {
+    arity: isize,
+    run: CommandCallback,
+}
+
+/// What the commands of clients run on: a database of the server's own, or
+/// the Raft node of a server in a cluster.
+#[derive(Clone)]
+enum Server {
+    Standalone(AsyncRustDB),
+    Cluster(Arc<Cluster>),
+}
+
+impl Server {
+    async fn flush(&self) -> Result<()> {
+        match self {
+            Server::Standalone(db) => db.flush().await,
+            Server::Cluster(cluster) => cluster.update(|node| node.flush()).await,
+        }
+    }
+}
+
+/// Raft node of a server in a cluster, exchanging messages with the other
+/// members as `RAFT` commands. Writes go through the leader and are
+/// answered once applied, and reads are linearizable.
+struct Cluster {
+    node: Arc<Mutex<RaftNode>>,
+    /// Address of every member, to send clients to the leader.
+    addresses: HashMap<NodeId, String>,
+    /// Messages waiting to be sent to each of the other members.
+    queues: HashMap<NodeId, mpsc::UnboundedSender<ByteString>>,
+    /// Woken each time the node handles a tick or a message, for the
+    /// commands waiting on it.
+    progress: Notify,
+    /// Size of the largest message taken from other members, which may
+    /// carry several values.
+    message_size: usize,
+}
+
+/// Protocol version negotiated with `HELLO`, RESP2 until then.
+struct Session {
+    protocol: u8,
+}
+
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(value) => match value.parse() {
+            Ok(value) => value,
+            Err(_) => panic!("Invalid {}: {}", name, value),
+        },
+        Err(_) => default,
+    }
+}
+
+/// Parses the members of a cluster, as `id=host:port` separated by commas.
+fn parse_members(value: &str) -> Option<HashMap<NodeId, String>> {
+    value
+        .split(',')
+        .map(|member| {
+            let (id, address) = member.trim().split_once('=')?;
+            let id = id.parse().ok().filter(|id| *id > 0)?;
+            match address.is_empty() {
+                true => None,
+                false => Some((id, String::from(address))),
+            }
+        })
+        .collect()
+}
+
+fn main() {
+    println!("Loading database...");
+    let options = Options {
+        change_log_size: env_or("RUSTDB_CHANGE_LOG_SIZE", 16_777_216),
+        history_retention: Duration::from_secs(env_or("RUSTDB_HISTORY_RETENTION", 0)),
+        ..Options::default()
+    };
+    let folder = env_or("RUSTDB_STORAGE", String::from("storage"));
+    let host = env_or("RUSTDB_HOST", String::from("127.0.0.1"));
+    let port: u16 = env_or("RUSTDB_PORT", 6380);
+    let max_size = env_or("RUSTDB_MAX_BODY_SIZE", 1_048_576);
+    let raft_id: NodeId = env_or("RUSTDB_RAFT_ID", 0);
+
+    let runtime = match tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => runtime,
+        Err(err) => panic!("Failed to start runtime\n{}", err),
+    };
+
+    runtime.block_on(async move {
+        let server = match raft_id {
+            0 => {
+                let db = Arc::new(Mutex::new(RustDB::load_with_options(&folder, options)));
+                let compress_db = Arc::clone(&db);
+                thread::spawn(move || compress_files(compress_db, folder));
+                Server::Standalone(AsyncRustDB::from_shared(db))
+            }
+            id => {
+                let cluster = start_cluster(id, &folder, options, max_size);
+                let node = Arc::clone(&cluster.node);
+                thread::spawn(move || compress_node_files(node));
+                Server::Cluster(cluster)
+            }
+        };
+
+        let listener = match TcpListener::bind((host.as_str(), port)).await {
+            Ok(listener) => listener,
+            Err(err) => panic!("Failed to bind address\n{}", err),
+        };
+        println!("Database ready at {}", port);
+
+        tokio::select! {
+            _ = serve(listener, server.clone(), max_size) => {}
+            _ = tokio::signal::ctrl_c() => println!("Shutting down..."),
+        }
+
+        if let Err(err) = server.flush().await {
+            println!("Failed to flush database\n{}", err);
+        }
+    });
+}
+
+/// Loads node `id` of the cluster whose members are listed in
+/// `RUSTDB_RAFT_PEERS`, this node included.
+fn start_cluster(id: NodeId, folder: &str, options: Options, max_size: usize) -> Arc<Cluster> {
+    let peers = env_or("RUSTDB_RAFT_PEERS", String::new());
+    let addresses = match parse_members(&peers) {
+        Some(addresses) if addresses.contains_key(&id) => addresses,
+        _ => panic!("Invalid RUSTDB_RAFT_PEERS: {}", peers),
+    };
+    let mut members: Vec<NodeId> = addresses.keys().copied().collect();
+    members.sort_unstable();
+
+    let raft_options = RaftOptions::default();
+    let message_size = max_size * (raft_options.max_batch + 1) + raft_options.max_chunk_size;
+    let vfs = Arc::new(DiskVfs);
+    match RaftNode::load(id, &members, folder, options, vfs, raft_options) {
+        Ok(node) => Cluster::start(node, addresses, message_size),
+        Err(err) => panic!("Failed to load raft node\n{}", err),
+    }
+}
+
+/// Compresses the segments of a node every 10 seconds, holding it
+/// meanwhile, as the node may send them as a snapshot at any time.
+fn compress_node_files(node: Arc<Mutex<RaftNode>>) -> ! {
+    loop {
+        if let Err(err) = node.lock().unwrap().compress_segments() {
+            println!("Failed to compress segments\n{}", err);
+        }
+
+        thread::sleep(time::Duration::from_secs(10));
+    }
+}
+
+fn compress_files(db: Arc<Mutex<RustDB>>, folder: String) -> ! {
+    loop {
+        if let Err(err) = db.lock().unwrap().remove_expired() {
+            println!("Failed to remove expired keys\n{}", err);
+        }
+
+        // read under one lock, so the active segment cannot roll over
+        // between picking the segments and the segment they link to
+        let compression = {
+            let db = db.lock().unwrap();
+            let segment_names = db.get_segments_to_compress();
+            match segment_names.is_empty() {
+                true => None,
+                false => Some((
+                    LogCompressor::new(
+                        &folder,
+                        segment_names.clone(),
+                        db.get_active_segment_name(),
+                    )
+                    .with_active_keys(db.get_active_keys())
+                    .with_options(db.get_options())
+                    .with_vfs(db.get_vfs()),
+                    segment_names,
+                    db.get_vfs(),
+                )),
+            }
+        };
+
+        if let Some((compressor, segment_names, vfs)) = compression {
+            match compressor.compress() {
+                Ok((active_segment, new_segment)) => {
+                    db.lock()
+                        .unwrap()
+                        .replace_segments(active_segment, new_segment);
+                    LogCompressor::clean_with_vfs(&vfs, &folder, segment_names);
+                }
+                Err(err) => println!("Failed to compress segments\n{}", err),
+            }
+        }
+
+        thread::sleep(time::Duration::from_secs(10));
+    }
+}
+
+async fn serve(listener: TcpListener, server: Server, max_size: usize) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(handle_connection(stream, server.clone(), max_size));
+            }
+            Err(err) => println!("Failed to process current stream\n{}", err),
+        }
+    }
+}
+
+/// Answers the commands of `stream` in order until the client quits or
+/// sends something that is not RESP, which is answered with an error before
+/// closing the connection.
+async fn handle_connection(mut stream: TcpStream, server: Server, max_size: usize) {
+    let mut buffer = Vec::new();
+    let mut session = Session { protocol: 2 };
+    let limit = match &server {
+        Server::Standalone(_) => max_size,
+        Server::Cluster(cluster) => cluster.message_size.max(max_size),
+    };
+
+    loop {
+        let args = match parse_command(&buffer, limit) {
+            Ok(Some((args, size))) => {
+                buffer.drain(..size);
+                args
+            }
+            Ok(None) => match stream.read_buf(&mut buffer).await {
+                Ok(0) | Err(_) => return,
+                Ok(_) => continue,
+            },
+            Err(err) => {
+                let reply = RespValue::Error(format!("ERR {}", err));
+                let _ = stream.write_all(&reply.encode(session.protocol)).await;
+                return;
+            }
+        };
+
+        if args.is_empty() {
+            continue;
+        }
+
+        let name = String::from_utf8_lossy(&args[0]).to_uppercase();
+        let reply = match execute_session(&name, &args, &mut session) {
+            Some(reply) => reply,
+            None => match &server {
+                Server::Standalone(db) => {
+                    let command = name.clone();
+                    let result = db.run(move |db| Ok(execute(&command, &args, db))).await;
+                    result.unwrap_or_else(|err| RespValue::Error(format!("ERR {}", err)))
+                }
+                Server::Cluster(cluster) => {
+                    execute_in_cluster(&name, &args, cluster, max_size).await
+                }
+            },
+        };
+
+        if stream
+            .write_all(&reply.encode(session.protocol))
+            .await
+            .is_err()
+        {
+            return;
+        }
+
+        if name == "QUIT" {
+            return;
+        }
+    }
+}
+
+fn wrong_arity(name: &str) -> RespValue {
+    RespValue::Error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        name.to_lowercase()
+    ))
+}
+
+fn not_an_integer() -> RespValue {
+    RespValue::error("ERR value is not an integer or out of range")
+}
+
+fn parse_integer(arg: &[u8]) -> Option<i64> {
+    std::str::from_utf8(arg).ok()?.parse().ok()
+}
+
+/// Runs the commands about the connection itself, which do not touch the
+/// database.
+fn execute_session(name: &str, args: &[ByteString], session: &mut Session) -> Option<RespValue> {
+    let reply = match name {
+        "PING" => match args.len() {
+            1 => RespValue::Simple(String::from("PONG")),
+            2 => RespValue::Bulk(args[1].clone()),
+            _ => wrong_arity(name),
+        },
+        "HELLO" => {
+            if let Some(version) = args.get(1) {
+                match parse_integer(version) {
+                    Some(version) if version == 2 || version == 3 => {
+                        session.protocol = version as u8
+                    }
+                    _ => return Some(RespValue::error("NOPROTO unsupported protocol version")),
+                }
+            }
+
+            let field = |name: &str| RespValue::Bulk(name.as_bytes().to_vec());
+            RespValue::Map(vec![
+                (field("server"), field("rustdb")),
+                (field("version"), field(env!("CARGO_PKG_VERSION"))),
+                (field("proto"), RespValue::Integer(session.protocol as i64)),
+                (field("mode"), field("standalone")),
+                (field("role"), field("master")),
+                (field("modules"), RespValue::Array(Vec::new())),
+            ])
+        }
+        "SELECT" => match args.get(1).map(|index| parse_integer(index)) {
+            Some(Some(0)) if args.len() == 2 => RespValue::ok(),
+            _ if args.len() != 2 => wrong_arity(name),
+            _ => RespValue::error("ERR DB index is out of range"),
+        },
+        // clients send these on connect, to learn commands and name themselves
+        "COMMAND" => RespValue::Array(Vec::new()),
+        "CLIENT" => RespValue::ok(),
+        "QUIT" => RespValue::ok(),
+        _ => return None,
+    };
+
+    Some(reply)
+}
+
+fn build_commands() -> HashMap<&'static str, Command> {
+    let mut commands: HashMap<&str, Command> = HashMap::new();
+    commands.insert("GET", Command { arity: 2, run: get });
+    commands.insert(
+        "SET",
+        Command {
+            arity: -3,
+            run: set,
+        },
+    );
+    commands.insert(
+        "DEL",
+        Command {
+            arity: -2,
+            run: del,
+        },
+    );
+    commands.insert(
+        "EXISTS",
+        Command {
+            arity: -2,
+            run: exists,
+        },
+    );
+    commands.insert(
+        "MGET",
+        Command {
+            arity: -2,
+            run: mget,
+        },
+    );
+    commands.insert(
+        "MSET",
+        Command {
+            arity: -3,
+            run: mset,
+        },
+    );
+    commands.insert(
+        "SCAN",
+        Command {
+            arity: -2,
+            run: scan,
+        },
+    );
+    commands.insert(
+        "EXPIRE",
+        Command {
+            arity: 3,
+            run: expire,
+        },
+    );
+    commands.insert("TTL", Command { arity: 2, run: ttl });
+    commands.insert(
+        "INCR",
+        Command {
+            arity: 2,
+            run: incr,
+        },
+    );
+    commands.insert(
+        "INCRBY",
+        Command {
+            arity: 3,
+            run: incrby,
+        },
+    );
+    commands.insert(
+        "DECR",
+        Command {
+            arity: 2,
+            run: decr,
+        },
+    );
+    commands.insert(
+        "DECRBY",
+        Command {
+            arity: 3,
+            run: decrby,
+        },
+    );
+
+    commands
+}
+
+fn execute(name: &str, args: &[ByteString], db: &mut RustDB) -> RespValue {
+    let command = match build_commands().remove(name) {
+        Some(command) => command,
+        None => {
+            return RespValue::Error(format!(
+                "ERR unknown command '{}'",
+                String::from_utf8_lossy(&args[0])
+            ))
+        }
+    };
+
+    let arity_matches = match command.arity {
+        arity if arity < 0 => args.len() >= arity.unsigned_abs(),
+        arity => args.len() == arity as usize,
+    };
+    if !arity_matches {
+        return wrong_arity(name);
+    }
+
+    (command.run)(&args[1..], db).unwrap_or_else(|err| RespValue::Error(format!("ERR {}", err)))
+}
+
+impl Cluster {
+    /// Starts sending the messages of `node` to the other members at
+    /// `addresses`, and making time pass for it.
+    fn start(
+        node: RaftNode,
+        addresses: HashMap<NodeId, String>,
+        message_size: usize,
+    ) -> Arc<Cluster> {
+        let id = node.id();
+        let mut queues = HashMap::new();
+        for (member, address) in &addresses {
+            if *member != id {
+                let (sender, receiver) = mpsc::unbounded_channel();
+                tokio::spawn(send_messages(id, address.clone(), receiver));
+                queues.insert(*member, sender);
+            }
+        }
+
+        let cluster = Arc::new(Cluster {
+            node: Arc::new(Mutex::new(node)),
+            addresses,
+            queues,
+            progress: Notify::new(),
+            message_size,
+        });
+        tokio::spawn(tick(Arc::clone(&cluster)));
+        cluster
+    }
+
+    /// Runs `operation` with the node locked, on a blocking thread, then
+    /// queues the messages it sent and wakes the commands waiting on it.
+    async fn update<F, T>(self: &Arc<Self>, operation: F) -> Result<T>
+    where
+        F: FnOnce(&mut RaftNode) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let cluster = Arc::clone(self);
+        let result = task::spawn_blocking(move || {
+            let mut node = cluster.node.lock().unwrap();
+            let result = operation(&mut node);
+            for envelope in node.take_messages() {
+                if let Some(queue) = cluster.queues.get(&envelope.to) {
+                    let _ = queue.send(envelope.message.encode());
+                }
+            }
+            result
+        })
+        .await;
+        self.progress.notify_waiters();
+
+        match result {
+            Ok(result) => result,
+            Err(err) => Err(Error::other(err)),
+        }
+    }
+
+    /// Waits for `check` to give a value, checking the node again each time
+    /// it makes progress, up to `REQUEST_TIMEOUT`.
+    async fn wait_for<F, T>(&self, check: F) -> Result<T>
+    where
+        F: Fn(&RaftNode) -> Option<T>,
+    {
+        let deadline = Instant::now() + REQUEST_TIMEOUT;
+        loop {
+            // registered before checking, so progress made meanwhile wakes it
+            let progress = self.progress.notified();
+            let value = check(&self.node.lock().unwrap());
+            if let Some(value) = value {
+                return Ok(value);
+            }
+            if timeout_at(deadline, progress).await.is_err() {
+                return Err(Error::new(
+                    ErrorKind::TimedOut,
+                    "Timed out waiting for the cluster",
+                ));
+            }
+        }
+    }
+
+    /// Applies `command` on the leader, once a majority has it.
+    async fn apply(self: &Arc<Self>, command: RaftCommand) -> Result<()> {
+        let proposal = self.update(move |node| node.propose(command)).await?;
+        let status = self
+            .wait_for(move |node| match node.proposal_status(proposal) {
+                ProposalStatus::Pending => None,
+                status => Some(status),
+            })
+            .await?;
+
+        match status {
+            ProposalStatus::Applied => Ok(()),
+            _ => Err(Error::new(
+                ErrorKind::Interrupted,
+                "Write dropped by a new leader",
+            )),
+        }
+    }
+
+    /// Reads `key` on the leader, once a majority confirmed it still leads.
+    async fn get(self: &Arc<Self>, key: ByteString) -> Result<Option<KeyValue>> {
+        let read = self.update(|node| node.begin_read()).await?;
+        self.wait_for(|node| Some(()).filter(|_| node.is_readable(&read)))
+            .await?;
+        self.update(move |node| node.read(&read, key)).await
+    }
+
+    /// Error answered for `err`, naming the leader and its address when this
+    /// node does not lead.
+    fn error(&self, err: Error) -> RespValue {
+        let leader = self.node.lock().unwrap().leader();
+        match (err.kind(), leader) {
+            (ErrorKind::PermissionDenied, Some(leader)) => RespValue::Error(format!(
+                "ERR not the leader, node {} is at {}",
+                leader,
+                self.addresses
+                    .get(&leader)
+                    .map_or("an unknown address", String::as_str)
+            )),
+            _ => RespValue::Error(format!("ERR {}", err)),
+        }
+    }
+}
+
+/// Makes time pass for the node of the cluster, every `TICK_INTERVAL`.
+async fn tick(cluster: Arc<Cluster>) {
+    let mut interval = tokio::time::interval(TICK_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(err) = cluster.update(|node| node.tick()).await {
+            println!("Failed to tick raft node\n{}", err);
+        }
+    }
+}
+
+/// Sends the messages queued for the member at `address` as `RAFT`
+/// commands over one connection, connecting again once it fails. Messages
+/// that could not be sent are dropped, as the node sends again whatever
+/// was not acknowledged.
+async fn send_messages(
+    from: NodeId,
+    address: String,
+    mut queue: mpsc::UnboundedReceiver<ByteString>,
+) {
+    let from = from.to_string();
+    let mut connection: Option<OwnedWriteHalf> = None;
+
+    while let Some(message) = queue.recv().await {
+        if connection.is_none() {
+            connection = match timeout(CONNECT_TIMEOUT, TcpStream::connect(&address)).await {
+                Ok(Ok(stream)) => {
+                    let (mut replies, writer) = stream.into_split();
+                    tokio::spawn(async move {
+                        let _ = tokio::io::copy(&mut replies, &mut tokio::io::sink()).await;
+                    });
+                    Some(writer)
+                }
+                _ => continue,
+            };
+        }
+
+        let command = RespValue::command(&[b"RAFT", from.as_bytes(), &message]).encode(2);
+        if let Some(writer) = connection.as_mut() {
+            if writer.write_all(&command).await.is_err() {
+                connection = None;
+            }
+        }
+    }
+}
+
+/// Runs the commands a cluster supports, reads and writes of single keys,
+/// along with the `RAFT` commands carrying the messages of other members.
+/// Nodes other than the leader answer with an error naming it.
+async fn execute_in_cluster(
+    name: &str,
+    args: &[ByteString],
+    cluster: &Arc<Cluster>,
+    max_size: usize,
+) -> RespValue {
+    let result = match name {
+        "RAFT" if args.len() == 3 => step(&args[1..], cluster).await,
+        "GET" if args.len() == 2 => cluster.get(args[1].clone()).await.map(bulk_or_null),
+        "SET" if args.len() > 3 => {
+            return RespValue::error("ERR SET options are not supported in cluster mode")
+        }
+        "SET" if args.len() == 3 => {
+            let key_value = KeyValue::new(args[1].clone(), args[2].clone());
+            if key_value.value.is_empty() {
+                return empty_value();
+            }
+            if key_value.value.len() > max_size {
+                return RespValue::error("ERR value is too large");
+            }
+            let command = RaftCommand::Put(key_value);
+            cluster.apply(command).await.map(|_| RespValue::ok())
+        }
+        "DEL" | "EXISTS" if args.len() > 1 => count_keys(name, &args[1..], cluster).await,
+        "RAFT" | "GET" | "SET" | "DEL" | "EXISTS" => return wrong_arity(name),
+        _ => {
+            return RespValue::Error(format!(
+                "ERR '{}' is not supported in cluster mode",
+                String::from_utf8_lossy(&args[0])
+            ))
+        }
+    };
+
+    result.unwrap_or_else(|err| cluster.error(err))
+}
+
+/// `RAFT from message`, handing the node a message of another member.
+async fn step(args: &[ByteString], cluster: &Arc<Cluster>) -> Result<RespValue> {
+    let from = parse_integer(&args[0]).filter(|id| *id > 0);
+    let message = Message::decode(&args[1]);
+    match (from, message) {
+        (Some(from), Some(message)) => {
+            let from = from as NodeId;
+            cluster.update(move |node| node.step(from, message)).await?;
+            Ok(RespValue::ok())
+        }
+        _ => Ok(RespValue::error("ERR invalid raft message")),
+    }
+}
+
+/// Counts the keys that exist, deleting them for `DEL`. Each key is read
+/// then deleted on its own, not all of them at once.
+async fn count_keys(name: &str, keys: &[ByteString], cluster: &Arc<Cluster>) -> Result<RespValue> {
+    let mut count = 0;
+    for key in keys {
+        if cluster.get(key.clone()).await?.is_some() {
+            if name == "DEL" {
+                cluster.apply(RaftCommand::Delete(key.clone())).await?;
+            }
+            count += 1;
+        }
+    }
+
+    Ok(RespValue::Integer(count))
+}
+
+fn bulk_or_null(record: Option<KeyValue>) -> RespValue {
+    match record {
+        Some(kv) => RespValue::Bulk(kv.value),
+        None => RespValue::Null,
+    }
+}
+
+fn get(args: &[ByteString], db: &mut RustDB) -> Result<RespValue> {
+    Ok(bulk_or_null(db.get_record(args[0].clone())?))
+}
+
+/// Empty values mark deleted keys in the storage, so they cannot be stored.
+fn empty_value() -> RespValue {
+    RespValue::error("ERR empty values are not supported")
+}
+
+/// `SET key value [NX | XX] [EX seconds | PX milliseconds | KEEPTTL]`
+fn set(args: &[ByteString], db: &mut RustDB) -> Result<RespValue> {
+    let (key, value) = (&args[0], &args[1]);
+    let (mut only_new, mut only_existing) = (false, false);
+    let mut ttl: Option<Duration> = None;
+    let mut keep_ttl = false;
+
+    let mut options = args[2..].iter();
+    while let Some(option) = options.next() {
+        let option = String::from_utf8_lossy(option).to_uppercase();
+        match option.as_str() {
+            "NX" if !only_existing => only_new = true,
+            "XX" if !only_new => only_existing = true,
+            "EX" | "PX" if ttl.is_none() && !keep_ttl => {
+                let amount = match options.next().map(|amount| parse_integer(amount)) {
+                    Some(Some(amount)) => amount,
+                    Some(None) => return Ok(not_an_integer()),
+                    None => return Ok(RespValue::error("ERR syntax error")),
+                };
+                if amount <= 0 {
+                    return Ok(RespValue::error("ERR invalid expire time in 'set' command"));
+                }
+                ttl = Some(match option.as_str() {
+                    "EX" => Duration::from_secs(amount as u64),
+                    _ => Duration::from_millis(amount as u64),
+                });
+            }
+            "KEEPTTL" if ttl.is_none() => keep_ttl = true,
+            _ => return Ok(RespValue::error("ERR syntax error")),
+        }
+    }
+
+    if value.is_empty() {
+        return Ok(empty_value());
+    }
+
+    let exists = db.get_record(key.clone())?.is_some();
+    if (only_new && exists) || (only_existing && !exists) {
+        return Ok(RespValue::Null);
+    }
+
+    db.save_record(KeyValue::new(key.clone(), value.clone()))?;
+    match ttl {
+        Some(ttl) => {
+            db.expire(key.clone(), ttl)?;
+        }
+        None if !keep_ttl => {
+            db.persist(key.clone())?;
+        }
+        None => {}
+    }
+
+    Ok(RespValue::ok())
+}
+
+fn del(args: &[ByteString], db: &mut RustDB) -> Result<RespValue> {
+    let mut deleted = 0;
+    for key in args {
+        if db.get_record(key.clone())?.is_some() {
+            db.delete_record(key.clone())?;
+            deleted += 1;
+        }
+    }
+
+    Ok(RespValue::Integer(deleted))
+}
+
+fn exists(args: &[ByteString], db: &mut RustDB) -> Result<RespValue> {
+    let mut found = 0;
+    for key in args {
+        if db.get_record(key.clone())?.is_some() {
+            found += 1;
+        }
+    }
+
+    Ok(RespValue::Integer(found))
+}
+
+fn mget(args: &[ByteString], db: &mut RustDB) -> Result<RespValue> {
+    let mut values = Vec::with_capacity(args.len());
+    for key in args {
+        values.push(bulk_or_null(db.get_record(key.clone())?));
+    }
+
+    Ok(RespValue::Array(values))
+}
+
+fn mset(args: &[ByteString], db: &mut RustDB) -> Result<RespValue> {
+    if !args.len().is_multiple_of(2) {
+        return Ok(wrong_arity("MSET"));
+    }
+    if args.chunks(2).any(|pair| pair[1].is_empty()) {
+        return Ok(empty_value());
+    }
+
+    for pair in args.chunks(2) {
+        db.save_record(KeyValue::new(pair[0].clone(), pair[1].clone()))?;
+        db.persist(pair[0].clone())?;
+    }
+
+    Ok(RespValue::ok())
+}
+
+/// `SCAN cursor [MATCH pattern] [COUNT count]`, where the cursor is the
+/// position in the sorted keys. As with Redis, `MATCH` filters the keys
+/// after they are picked, so a page may be empty while the cursor is not 0.
+fn scan(args: &[ByteString], db: &mut RustDB) -> Result<RespValue> {
+    let cursor = match parse_integer(&args[0]) {
+        Some(cursor) if cursor >= 0 => cursor as usize,
+        _ => return Ok(RespValue::error("ERR invalid cursor")),
+    };
+    let mut pattern: Option<&[u8]> = None;
+    let mut count = 10;
+
+    let mut options = args[1..].iter();
+    while let Some(option) = options.next() {
+        let value = match options.next() {
+            Some(value) => value,
+            None => return Ok(RespValue::error("ERR syntax error")),
+        };
+        match String::from_utf8_lossy(option).to_uppercase().as_str() {
+            "MATCH" => pattern = Some(value),
+            "COUNT" => match parse_integer(value) {
+                Some(value) if value > 0 => count = value as usize,
+                _ => return Ok(RespValue::error("ERR syntax error")),
+            },
+            _ => return Ok(RespValue::error("ERR syntax error")),
+        }
+    }
+
+    let keys = db.get_keys()?;
+    let end = cursor.saturating_add(count).min(keys.len());
+    let page = keys
+        .get(cursor..end)
+        .unwrap_or_default()
+        .iter()
+        .filter(|key| pattern.is_none_or(|pattern| glob_match(pattern, key)))
+        .map(|key| RespValue::Bulk(key.clone()))
+        .collect();
+    let next = if end < keys.len() { end } else { 0 };
+
+    Ok(RespValue::Array(vec![
+        RespValue::Bulk(next.to_string().into_bytes()),
+        RespValue::Array(page),
+    ]))
+}
+
+/// Matches Redis glob patterns: `*`, `?`, `[abc]`, `[^a]`, `[a-z]` and `\`
+/// to escape the next character.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some(b'*') => (0..=text.len()).any(|skip| glob_match(&pattern[1..], &text[skip..])),
+        Some(b'?') => !text.is_empty() && glob_match(&pattern[1..], &text[1..]),
+        Some(b'[') => {
+            let end = match pattern.iter().skip(2).position(|b| *b == b']') {
+                Some(end) => end + 2,
+                None => {
+                    return text.first() == Some(&b'[') && glob_match(&pattern[1..], &text[1..])
+                }
+            };
+            let (negated, class) = match pattern[1] {
+                b'^' => (true, &pattern[2..end]),
+                _ => (false, &pattern[1..end]),
+            };
+            match text.first() {
+                Some(c) => {
+                    let found = class_contains(class, *c);
+                    found != negated && glob_match(&pattern[end + 1..], &text[1..])
+                }
+                None => false,
+            }
+        }
+        Some(b'\\') if pattern.len() > 1 => {
+            text.first() == Some(&pattern[1]) && glob_match(&pattern[2..], &text[1..])
+        }
+        Some(c) => text.first() == Some(c) && glob_match(&pattern[1..], &text[1..]),
+    }
+}
+
+fn class_contains(class: &[u8], c: u8) -> bool {
+    let mut i = 0;
+    while i < class.len() {
+        if i + 2 < class.len() && class[i + 1] == b'-' {
+            if class[i] <= c && c <= class[i + 2] {
+                return true;
+            }
+            i += 3;
+        } else {
+            if class[i] == c {
+                return true;
+            }
+            i += 1;
+        }
+    }
+    false
+}
+
+/// `EXPIRE key seconds`, deleting the key right away when `seconds` is not
+/// positive.
+fn expire(args: &[ByteString], db: &mut RustDB) -> Result<RespValue> {
+    let seconds = match parse_integer(&args[1]) {
+        Some(seconds) => seconds,
+        None => return Ok(not_an_integer()),
+    };
+
+    if seconds <= 0 {
+        return del(&args[..1], db);
+    }
+
+    let expiring = db.expire(args[0].clone(), Duration::from_secs(seconds as u64))?;
+    Ok(RespValue::Integer(expiring as i64))
+}
+
+/// Seconds left before the key expires, -1 when it does not expire and -2
+/// when it does not exist.
+fn ttl(args: &[ByteString], db: &mut RustDB) -> Result<RespValue> {
+    if db.get_record(args[0].clone())?.is_none() {
+        return Ok(RespValue::Integer(-2));
+    }
+
+    Ok(RespValue::Integer(match db.time_to_live(args[0].clone()) {
+        Some(left) => ((left.as_millis() + 500) / 1000) as i64,
+        None => -1,
+    }))
+}
+
+/// Adds one to the decimal value of the key, starting from 0 when missing.
+/// The key keeps its expiration.
+fn incr(args: &[ByteString], db: &mut RustDB) -> Result<RespValue> {
+    increment_by(&args[0], 1, db)
+}
+
+fn incrby(args: &[ByteString], db: &mut RustDB) -> Result<RespValue> {
+    match parse_integer(&args[1]) {
+        Some(delta) => increment_by(&args[0], delta, db),
+        None => Ok(not_an_integer()),
+    }
+}
+
+fn decr(args: &[ByteString], db: &mut RustDB) -> Result<RespValue> {
+    increment_by(&args[0], -1, db)
+}
+
+fn decrby(args: &[ByteString], db: &mut RustDB) -> Result<RespValue> {
+    match parse_integer(&args[1]).and_then(i64::checked_neg) {
+        Some(delta) => increment_by(&args[0], delta, db),
+        None => Ok(not_an_integer()),
+    }
+}
+
+fn increment_by(key: &[u8], delta: i64, db: &mut RustDB) -> Result<RespValue> {
+    Ok(RespValue::Integer(db.increment(key, delta)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustdb::{MemoryVfs, RespClient};
+
+    fn start_server() -> RespClient {
+        let std_listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = std_listener.local_addr().unwrap();
+        std_listener.set_nonblocking(true).unwrap();
+
+        thread::spawn(move || {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.block_on(async move {
+                let listener = TcpListener::from_std(std_listener).unwrap();
+                let db = RustDB::load_with_vfs(
+                    "storage",
+                    Options::default(),
+                    Arc::new(MemoryVfs::new()),
+                );
+                serve(listener, Server::Standalone(AsyncRustDB::new(db)), 1_000).await;
+            });
+        });
+
+        let client = RespClient::connect(address).unwrap();
+        client
+            .get_stream()
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client
+    }
+
+    /// Starts a server per node of a cluster of `size` nodes, returning a
+    /// client of each with its node.
+    fn start_cluster_servers(size: u64) -> Vec<(RespClient, Arc<Mutex<RaftNode>>)> {
+        let listeners: Vec<_> = (1..=size)
+            .map(|_| std::net::TcpListener::bind("127.0.0.1:0").unwrap())
+            .collect();
+        let addresses: HashMap<NodeId, String> = listeners
+            .iter()
+            .enumerate()
+            .map(|(i, listener)| (i as NodeId + 1, listener.local_addr().unwrap().to_string()))
+            .collect();
+        let members: Vec<NodeId> = (1..=size).collect();
+
+        let mut servers = Vec::new();
+        for (i, std_listener) in listeners.into_iter().enumerate() {
+            let id = i as NodeId + 1;
+            let address = std_listener.local_addr().unwrap();
+            std_listener.set_nonblocking(true).unwrap();
+            let (addresses, members) = (addresses.clone(), members.clone());
+            let (sender, receiver) = std::sync::mpsc::channel();
+
+            thread::spawn(move || {
+                let runtime = tokio::runtime::Runtime::new().unwrap();
+                runtime.block_on(async move {
+                    let listener = TcpListener::from_std(std_listener).unwrap();
+                    let vfs = Arc::new(MemoryVfs::new());
+                    let options = RaftOptions::default();
+                    let node =
+                        RaftNode::load(id, &members, "node", Options::default(), vfs, options)
+                            .unwrap();
+                    let cluster = Cluster::start(node, addresses, 1_000_000);
+                    sender.send(Arc::clone(&cluster.node)).unwrap();
+                    serve(listener, Server::Cluster(cluster), 1_000).await;
+                });
+            });
+
+            let client = RespClient::connect(address).unwrap();
+            client
+                .get_stream()
+                .set_read_timeout(Some(Duration::from_secs(10)))
+                .unwrap();
+            servers.push((client, receiver.recv().unwrap()));
+        }
+        servers
+    }
+
+    fn bulk(value: &str) -> RespValue {
+        RespValue::Bulk(value.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn set_get_and_delete() {
+        // arrange
+        let mut client = start_server();
+
+        // act
+        let set = client.command(&[b"SET", b"a\r\nb", b"\x00\xff"]).unwrap();
+        let get = client.command(&[b"GET", b"a\r\nb"]).unwrap();
+        let exists = client
+            .command(&[b"EXISTS", b"a\r\nb", b"a\r\nb", b"c"])
+            .unwrap();
+        let del = client.command(&[b"DEL", b"a\r\nb", b"c"]).unwrap();
+        let missing = client.command(&[b"GET", b"a\r\nb"]).unwrap();
+
+        // assert
+        assert_eq!(set, RespValue::ok());
+        assert_eq!(get, RespValue::Bulk(b"\x00\xff".to_vec()));
+        assert_eq!(exists, RespValue::Integer(2));
+        assert_eq!(del, RespValue::Integer(1));
+        assert_eq!(missing, RespValue::Null);
+    }
+
+    #[test]
+    fn set_and_get_many_keys() {
+        // arrange
+        let mut client = start_server();
+
+        // act
+        let mset = client.command(&[b"MSET", b"a", b"1", b"b", b"2"]).unwrap();
+        let mget = client.command(&[b"MGET", b"a", b"x", b"b"]).unwrap();
+        let odd = client.command(&[b"MSET", b"a", b"1", b"b"]).unwrap();
+
+        // assert
+        assert_eq!(mset, RespValue::ok());
+        assert_eq!(
+            mget,
+            RespValue::Array(vec![bulk("1"), RespValue::Null, bulk("2")])
+        );
+        assert!(matches!(odd, RespValue::Error(_)));
+    }
+
+    #[test]
+    fn apply_set_conditions() {
+        // arrange
+        let mut client = start_server();
+
+        // act
+        let created = client.command(&[b"SET", b"a", b"1", b"NX"]).unwrap();
+        let not_created = client.command(&[b"SET", b"a", b"2", b"NX"]).unwrap();
+        let not_updated = client.command(&[b"SET", b"b", b"1", b"XX"]).unwrap();
+        let updated = client.command(&[b"SET", b"a", b"3", b"xx"]).unwrap();
+        let empty = client.command(&[b"SET", b"a", b""]).unwrap();
+        let invalid = client.command(&[b"SET", b"a", b"1", b"EX"]).unwrap();
+
+        // assert
+        assert_eq!(created, RespValue::ok());
+        assert_eq!(not_created, RespValue::Null);
+        assert_eq!(not_updated, RespValue::Null);
+        assert_eq!(updated, RespValue::ok());
+        assert_eq!(client.command(&[b"GET", b"a"]).unwrap(), bulk("3"));
+        assert!(matches!(empty, RespValue::Error(_)));
+        assert!(matches!(invalid, RespValue::Error(_)));
+    }
+
+    #[test]
+    fn expire_keys() {
+        // arrange
+        let mut client = start_server();
+        client.command(&[b"SET", b"a", b"1"]).unwrap();
+        client.command(&[b"SET", b"b", b"1", b"PX", b"50"]).unwrap();
+
+        // act
+        let no_ttl = client.command(&[b"TTL", b"a"]).unwrap();
+        let expire = client.command(&[b"EXPIRE", b"a", b"100"]).unwrap();
+        let ttl = client.command(&[b"TTL", b"a"]).unwrap();
+        let incremented = client.command(&[b"INCR", b"a"]).unwrap();
+        let kept_ttl = client.command(&[b"TTL", b"a"]).unwrap();
+        let reset = client.command(&[b"SET", b"a", b"1"]).unwrap();
+        let reset_ttl = client.command(&[b"TTL", b"a"]).unwrap();
+        let missing = client.command(&[b"EXPIRE", b"x", b"100"]).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let expired = client.command(&[b"GET", b"b"]).unwrap();
+        let expired_ttl = client.command(&[b"TTL", b"b"]).unwrap();
+
+        // assert
+        assert_eq!(no_ttl, RespValue::Integer(-1));
+        assert_eq!(expire, RespValue::Integer(1));
+        assert_eq!(ttl, RespValue::Integer(100));
+        assert_eq!(incremented, RespValue::Integer(2));
+        assert_eq!(kept_ttl, RespValue::Integer(100));
+        assert_eq!(reset, RespValue::ok());
+        assert_eq!(reset_ttl, RespValue::Integer(-1));
+        assert_eq!(missing, RespValue::Integer(0));
+        assert_eq!(expired, RespValue::Null);
+        assert_eq!(expired_ttl, RespValue::Integer(-2));
+    }
+
+    #[test]
+    fn increment_integers_only() {
+        // arrange
+        let mut client = start_server();
+        client.command(&[b"SET", b"text", b"abc"]).unwrap();
+        client
+            .command(&[b"SET", b"max", i64::MAX.to_string().as_bytes()])
+            .unwrap();
+
+        // act
+        let first = client.command(&[b"INCR", b"counter"]).unwrap();
+        let second = client.command(&[b"INCR", b"counter"]).unwrap();
+        let text = client.command(&[b"INCR", b"text"]).unwrap();
+        let overflow = client.command(&[b"INCR", b"max"]).unwrap();
+        let added = client.command(&[b"INCRBY", b"counter", b"10"]).unwrap();
+        let decremented = client.command(&[b"DECR", b"counter"]).unwrap();
+        let subtracted = client.command(&[b"DECRBY", b"counter", b"20"]).unwrap();
+        let invalid = client.command(&[b"INCRBY", b"counter", b"x"]).unwrap();
+
+        // assert
+        assert_eq!(first, RespValue::Integer(1));
+        assert_eq!(second, RespValue::Integer(2));
+        assert!(matches!(text, RespValue::Error(_)));
+        assert!(matches!(overflow, RespValue::Error(_)));
+        assert_eq!(added, RespValue::Integer(12));
+        assert_eq!(decremented, RespValue::Integer(11));
+        assert_eq!(subtracted, RespValue::Integer(-9));
+        assert!(matches!(invalid, RespValue::Error(_)));
+        assert_eq!(client.command(&[b"GET", b"counter"]).unwrap(), bulk("-9"));
+    }
+
+    #[test]
+    fn scan_every_key_with_cursor() {
+        // arrange
+        let mut client = start_server();
+        for i in 0..25 {
+            let key = format!("key:{:02}", i);
+            client.command(&[b"SET", key.as_bytes(), b"1"]).unwrap();
+        }
+        client.command(&[b"SET", b"other", b"1"]).unwrap();
+
+        // act
+        let mut cursor = String::from("0");
+        let mut keys = Vec::new();
+        let mut pages = 0;
+        loop {
+            let reply = client
+                .command(&[
+                    b"SCAN",
+                    cursor.as_bytes(),
+                    b"MATCH",
+                    b"key:*",
+                    b"COUNT",
+                    b"10",
+                ])
+                .unwrap();
+            pages += 1;
+            match reply {
+                RespValue::Array(mut values) => {
+                    if let RespValue::Array(page) = values.pop().unwrap() {
+                        keys.extend(page);
+                    }
+                    if let RespValue::Bulk(next) = values.pop().unwrap() {
+                        cursor = String::from_utf8(next).unwrap();
+                    }
+                }
+                reply => panic!("unexpected reply {:?}", reply),
+            }
+            if cursor == "0" {
+                break;
+            }
+        }
+
+        // assert
+        assert_eq!(pages, 3);
+        assert_eq!(keys.len(), 25);
+        assert_eq!(keys[0], bulk("key:00"));
+        assert_eq!(keys[24], bulk("key:24"));
+    }
+
+    #[test]
+    fn negotiate_protocol_with_hello() {
+        // arrange
+        let mut client = start_server();
+
+        // act
+        let resp2_null = client.command(&[b"GET", b"missing"]).unwrap();
+        let hello = client.command(&[b"HELLO", b"3"]).unwrap();
+        let resp3_null = client.command(&[b"GET", b"missing"]).unwrap();
+        let unsupported = client.command(&[b"HELLO", b"4"]).unwrap();
+
+        // assert
+        assert_eq!(resp2_null, RespValue::Null);
+        match hello {
+            RespValue::Map(entries) => {
+                assert!(entries.contains(&(bulk("proto"), RespValue::Integer(3))));
+            }
+            reply => panic!("expected a map, got {:?}", reply),
+        }
+        assert_eq!(resp3_null, RespValue::Null);
+        assert!(matches!(unsupported, RespValue::Error(_)));
+    }
+
+    #[test]
+    fn answer_pipelined_and_inline_commands() {
+        // arrange
+        let mut client = start_server();
+
+        // act
+        client.send(&[b"PING"]).unwrap();
+        client.send(&[b"SET", b"a", b"1"]).unwrap();
+        client.send(&[b"PING", b"hello"]).unwrap();
+        client.send(&[b"GET", b"a"]).unwrap();
+        std::io::Write::write_all(&mut client.get_stream(), b"EXISTS a\r\n").unwrap();
+        client.send(&[b"GET"]).unwrap();
+        client.send(&[b"NOPE"]).unwrap();
+
+        // assert
+        assert_eq!(
+            client.read_reply().unwrap(),
+            RespValue::Simple(String::from("PONG"))
+        );
+        assert_eq!(client.read_reply().unwrap(), RespValue::ok());
+        assert_eq!(client.read_reply().unwrap(), bulk("hello"));
+        assert_eq!(client.read_reply().unwrap(), bulk("1"));
+        assert_eq!(client.read_reply().unwrap(), RespValue::Integer(1));
+        assert_eq!(
+            client.read_reply().unwrap(),
+            RespValue::error("ERR wrong number of arguments for 'get' command")
+        );
+        assert_eq!(
+            client.read_reply().unwrap(),
+            RespValue::error("ERR unknown command 'NOPE'")
+        );
+    }
+
+    #[test]
+    fn close_connection_after_protocol_error() {
+        // arrange
+        let mut client = start_server();
+
+        // act
+        client.send(&[b"SET", b"a", &[b'x'; 2_000]]).unwrap();
+
+        // assert
+        assert!(matches!(client.read_reply().unwrap(), RespValue::Error(_)));
+        assert!(client.read_reply().is_err());
+    }
+
+    #[test]
+    fn write_through_the_leader_of_a_cluster() {
+        // arrange
+        let mut servers = start_cluster_servers(3);
+        let mut leader = None;
+        for _ in 0..100 {
+            leader = servers.iter_mut().position(|(client, _)| {
+                client.command(&[b"SET", b"a", b"1"]).unwrap() == RespValue::ok()
+            });
+            if leader.is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        let leader = leader.expect("no leader elected");
+        let follower = (leader + 1) % servers.len();
+        let leader_id = servers[leader].1.lock().unwrap().id();
+        while servers[follower].1.lock().unwrap().leader() != Some(leader_id) {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // act
+        let set = servers[leader].0.command(&[b"SET", b"b", b"2"]).unwrap();
+        let get = servers[leader].0.command(&[b"GET", b"a"]).unwrap();
+        let del = servers[leader].0.command(&[b"DEL", b"a", b"c"]).unwrap();
+        let exists = servers[leader].0.command(&[b"EXISTS", b"a", b"b"]).unwrap();
+        let options = servers[leader]
+            .0
+            .command(&[b"SET", b"a", b"1", b"NX"])
+            .unwrap();
+        let unsupported = servers[leader].0.command(&[b"INCR", b"a"]).unwrap();
+        let redirected = servers[follower].0.command(&[b"GET", b"b"]).unwrap();
+        let invalid = servers[follower]
+            .0
+            .command(&[b"RAFT", b"1", b"nope"])
+            .unwrap();
+
+        // assert
+        assert_eq!(set, RespValue::ok());
+        assert_eq!(get, bulk("1"));
+        assert_eq!(del, RespValue::Integer(1));
+        assert_eq!(exists, RespValue::Integer(1));
+        assert!(matches!(options, RespValue::Error(_)));
+        assert!(matches!(unsupported, RespValue::Error(_)));
+        let leader_address = servers[leader].0.get_stream().peer_addr().unwrap();
+        assert_eq!(
+            redirected,
+            RespValue::Error(format!(
+                "ERR not the leader, node {} is at {}",
+                leader_id, leader_address
+            ))
+        );
+        assert_eq!(invalid, RespValue::error("ERR invalid raft message"));
+        let applied = servers[leader].1.lock().unwrap().applied_index();
+        for (_, node) in &servers {
+            while node.lock().unwrap().applied_index() < applied {
+                thread::sleep(Duration::from_millis(10));
+            }
+            let node = node.lock().unwrap();
+            assert_eq!(node.db().get_record(b"a".to_vec()).unwrap(), None);
+            assert_eq!(
+                node.db().get_record(b"b".to_vec()).unwrap().unwrap().value,
+                b"2".to_vec()
+            );
+        }
+    }
+
+    #[test]
+    fn parse_cluster_members() {
+        let members = parse_members("1=127.0.0.1:6380, 2=db2:6380").unwrap();
+
+        assert_eq!(members.len(), 2);
+        assert_eq!(members[&1], "127.0.0.1:6380");
+        assert_eq!(members[&2], "db2:6380");
+        assert_eq!(parse_members(""), None);
+        assert_eq!(parse_members("0=db:6380"), None);
+        assert_eq!(parse_members("1=db:6380,2"), None);
+        assert_eq!(parse_members("1="), None);
+    }
+
+    #[test]
+    fn match_glob_patterns() {
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"key:*", b"key:12"));
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(glob_match(b"h[ae]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"h[a-c]llo", b"hbllo"));
+        assert!(glob_match(b"a\\*", b"a*"));
+        assert!(!glob_match(b"a\\*", b"ab"));
+        assert!(!glob_match(b"key:*", b"other"));
+    }
+}
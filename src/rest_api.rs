@@ -1,15 +1,81 @@
-use rustdb::{KeyValue, LogCompressor, RustDB};
-use serde_json::Value;
+use rustdb::{
+    chunk, encode_heartbeat, encode_record, encode_replicated, parse_request, percent_decode,
+    percent_encode, read_request, ByteString, ChangeEvent, ChangeFilter, Follower, HttpError,
+    HttpRequest, HttpResponse, KeyValue, LogCompressor, Options, ParseStatus, Patch, Query,
+    ReplicationEvent, RustDB, SchemaViolation, ShardedDB, Store, Subscription, ThreadPool,
+    LAST_CHUNK, REPLICATION_LOG_PATH, REPLICATION_SNAPSHOT_PATH, SEQUENCE_HEADER,
+};
+use serde_json::{value::RawValue, Value};
 use std::collections::HashMap;
-use std::io::prelude::*;
+use std::env;
+use std::io::{self, prelude::*, BufReader, ErrorKind};
 use std::net::{TcpListener, TcpStream};
+use std::str::FromStr;
+use std::sync::mpsc::RecvTimeoutError;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use std::{thread, time};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{mpsc, watch};
+use tokio::task::{self, JoinSet};
 
-const INSERT_DATA: &[u8; 17] = b"POST / HTTP/1.1\r\n";
-const UPDATE_DATA: &[u8; 16] = b"PUT / HTTP/1.1\r\n";
-const DELETE_DATA: &[u8; 19] = b"DELETE / HTTP/1.1\r\n";
-const READ_DATA: &[u8; 16] = b"GET / HTTP/1.1\r\n";
+#[derive(Clone, Copy)]
+enum ServerMode {
+    /// Blocking sockets served by a fixed pool of threads.
+    Threads,
+    /// Connections served as tasks of a tokio runtime.
+    Async,
+}
+
+impl FromStr for ServerMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<ServerMode, String> {
+        match value {
+            "threads" => Ok(ServerMode::Threads),
+            "async" => Ok(ServerMode::Async),
+            _ => Err(format!("Unknown server mode: {}", value)),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct ServerConfig {
+    mode: ServerMode,
+    max_body_size: usize,
+    /// Threads serving connections, in threads mode.
+    workers: usize,
+    /// Accepted connections waiting for a thread, over which new ones are
+    /// refused with a 503.
+    queue_size: usize,
+    /// How long an idle connection is kept open waiting for a new request.
+    keep_alive_timeout: Duration,
+    /// How long reading a request, or writing its response, may take.
+    request_timeout: Duration,
+}
+
+impl ServerConfig {
+    fn from_env() -> ServerConfig {
+        ServerConfig {
+            mode: env_or("RUSTDB_SERVER_MODE", ServerMode::Threads),
+            max_body_size: env_or("RUSTDB_MAX_BODY_SIZE", 1_048_576),
+            workers: env_or("RUSTDB_WORKERS", 8),
+            queue_size: env_or("RUSTDB_QUEUE_SIZE", 32),
+            keep_alive_timeout: Duration::from_secs(env_or("RUSTDB_KEEP_ALIVE_TIMEOUT", 5)),
+            request_timeout: Duration::from_secs(env_or("RUSTDB_REQUEST_TIMEOUT", 30)),
+        }
+    }
+}
+
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(value) => match value.parse() {
+            Ok(value) => value,
+            Err(_) => panic!("Invalid {}: {}", name, value),
+        },
+        Err(_) => default,
+    }
+}
 
 #[cfg(debug_assertions)]
 fn debug(msg: &str) {
@@ -21,204 +87,3369 @@ fn debug(_msg: &str) {
     // do nothing
 }
 
+/// Database served, either a `RustDB` or a `ShardedDB`.
+type SharedStore = Arc<Mutex<dyn Store>>;
+
 fn main() {
     println!("Loading database...");
-    let db = RustDB::load("storage");
-    let listener = match TcpListener::bind("127.0.0.1:7887") {
+    let options = Options {
+        change_log_size: env_or("RUSTDB_CHANGE_LOG_SIZE", 16_777_216),
+        history_retention: Duration::from_secs(env_or("RUSTDB_HISTORY_RETENTION", 0)),
+        ..Options::default()
+    };
+    let folder = env_or("RUSTDB_STORAGE", String::from("storage"));
+    let port: u16 = env_or("RUSTDB_PORT", 7887);
+    let shard_count: u32 = env_or("RUSTDB_SHARDS", 0);
+    if shard_count > 0 && env::var("RUSTDB_LEADER").is_ok() {
+        panic!("RUSTDB_LEADER cannot be set along with RUSTDB_SHARDS, as sharded stores do not replicate");
+    }
+    let listener = match TcpListener::bind(("127.0.0.1", port)) {
         Ok(listener) => listener,
         Err(err) => panic!("Failed to bind address\n{}", err),
     };
-    let db = Arc::new(Mutex::new(db));
-    println!("Database ready at 7887");
 
-    let compress_db = Arc::clone(&db);
-    thread::spawn(move || compress_files(compress_db));
+    let db: SharedStore = match shard_count {
+        0 => {
+            let db = Arc::new(Mutex::new(RustDB::load_with_options(&folder, options)));
+            if let Ok(leader) = env::var("RUSTDB_LEADER") {
+                let follower = Follower::new(&leader, Arc::clone(&db));
+                thread::spawn(move || follower.run());
+                println!("Following the leader at {}", leader);
+            }
+
+            let compress_db = Arc::clone(&db);
+            thread::spawn(move || compress_files(compress_db, folder));
+            db
+        }
+        shard_count => {
+            let mut db = match ShardedDB::load_with_options(&folder, shard_count, options) {
+                Ok(db) => db,
+                Err(err) => panic!("Failed to load shards\n{}", err),
+            };
+            if let Err(err) = db.resize(shard_count) {
+                panic!("Failed to resize shards\n{}", err);
+            }
+            let db = Arc::new(Mutex::new(db));
+
+            let maintained_db = Arc::clone(&db);
+            thread::spawn(move || maintain_shards(maintained_db));
+            db
+        }
+    };
+    println!("Database ready at {}", port);
+
+    let config = ServerConfig::from_env();
+    match config.mode {
+        ServerMode::Threads => serve(listener, db, config),
+        ServerMode::Async => serve_on_runtime(listener, db, config),
+    }
+}
+
+/// Hands every accepted connection to the thread pool, answering 503 right
+/// away when the pool is saturated.
+fn serve(listener: TcpListener, db: SharedStore, config: ServerConfig) {
+    let pool = ThreadPool::new(config.workers, config.queue_size);
 
     for stream in listener.incoming() {
-        match stream {
-            Ok(result) => handle_connection(result, Arc::clone(&db)),
-            Err(err) => println!("Failed to process current stream\n{}", err),
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                println!("Failed to process current stream\n{}", err);
+                continue;
+            }
+        };
+
+        let rejected = stream.try_clone();
+        let db = Arc::clone(&db);
+
+        if let Err(err) = pool.try_execute(move || handle_connection(stream, db, config)) {
+            if let Ok(mut rejected) = rejected {
+                let response = HttpResponse::text(503, err.to_string())
+                    .with_header("Retry-After", "1")
+                    .with_header("Connection", "close");
+                let _ = rejected.set_write_timeout(Some(config.request_timeout));
+                let _ = response.write_to(&mut rejected);
+            }
+        }
+    }
+}
+
+/// Serves connections on a tokio runtime until SIGTERM or Ctrl-C.
+fn serve_on_runtime(listener: TcpListener, db: SharedStore, config: ServerConfig) {
+    let runtime = match tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => runtime,
+        Err(err) => panic!("Failed to start runtime\n{}", err),
+    };
+
+    runtime.block_on(async move {
+        let listener = match listener
+            .set_nonblocking(true)
+            .and_then(|_| tokio::net::TcpListener::from_std(listener))
+        {
+            Ok(listener) => listener,
+            Err(err) => panic!("Failed to listen asynchronously\n{}", err),
         };
+
+        let (shutdown, shutdown_requested) = watch::channel(false);
+        tokio::spawn(async move {
+            wait_for_termination().await;
+            println!("Shutting down...");
+            let _ = shutdown.send(true);
+        });
+
+        serve_async(listener, db, config, shutdown_requested).await;
+    });
+}
+
+#[cfg(unix)]
+async fn wait_for_termination() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(err) => panic!("Failed to listen to SIGTERM\n{}", err),
+    };
+
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
     }
 }
 
-fn compress_files(db: Arc<Mutex<RustDB>>) -> ! {
+#[cfg(not(unix))]
+async fn wait_for_termination() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+async fn wait_for_shutdown(shutdown: &mut watch::Receiver<bool>) {
+    let _ = shutdown.wait_for(|requested| *requested).await;
+}
+
+/// Serves every connection as a task until a shutdown is requested. Then no
+/// connection is accepted anymore, idle ones are closed and those in the
+/// middle of a request get their response, before the database is flushed.
+async fn serve_async(
+    listener: tokio::net::TcpListener,
+    db: SharedStore,
+    config: ServerConfig,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let mut connections = JoinSet::new();
+
     loop {
-        let folder = "storage";
-        let segment_names = db.lock().unwrap().get_closed_segment_names();
-        let current_segment_name = db.lock().unwrap().get_active_segment_name();
-        let compressor = LogCompressor::new(folder, segment_names.clone(), current_segment_name);
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    connections.spawn(handle_connection_async(
+                        stream,
+                        Arc::clone(&db),
+                        config,
+                        shutdown.clone(),
+                    ));
+                }
+                Err(err) => println!("Failed to process current stream\n{}", err),
+            },
+            _ = wait_for_shutdown(&mut shutdown) => break,
+        }
 
-        let (active_segment, new_segment) = compressor.compress();
+        while connections.try_join_next().is_some() {}
+    }
 
-        db.lock()
-            .unwrap()
-            .replace_segments(active_segment, new_segment);
-        LogCompressor::clean(&folder, segment_names);
+    drop(listener);
 
-        thread::sleep(time::Duration::from_secs(10));
+    let drained = tokio::time::timeout(config.request_timeout, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+
+    if drained.is_err() {
+        println!("Closing connections still busy after shutdown");
+        connections.abort_all();
+    }
+
+    match task::spawn_blocking(move || db.lock().unwrap().flush()).await {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => println!("Failed to flush database\n{}", err),
+        Err(err) => println!("Failed to flush database\n{}", err),
     }
 }
 
-fn handle_connection(mut stream: TcpStream, db: Arc<Mutex<RustDB>>) {
-    let mut buffer = [0; 512];
-    let size = match stream.read(&mut buffer) {
-        Ok(value) => value,
-        Err(err) => {
-            println!("Failed to read stream\n{}", err);
+/// Async counterpart of `handle_connection`, buffering reads until they hold
+/// a whole request. Requests are routed on a blocking thread, as they do file
+/// I/O.
+async fn handle_connection_async(
+    mut stream: tokio::net::TcpStream,
+    db: SharedStore,
+    config: ServerConfig,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let mut buffer = Vec::new();
+    let mut continue_sent = false;
+
+    loop {
+        let request = match parse_request(&buffer, config.max_body_size) {
+            Ok(ParseStatus::Complete(request, size)) => {
+                buffer.drain(..size);
+                continue_sent = false;
+                Ok(request)
+            }
+            Ok(ParseStatus::Partial { continue_expected }) => {
+                if continue_expected && !continue_sent {
+                    continue_sent = true;
+                    if stream
+                        .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+                        .await
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+
+                // an idle connection is closed without a response
+                let idle = buffer.is_empty();
+                let timeout = match idle {
+                    true => config.keep_alive_timeout,
+                    false => config.request_timeout,
+                };
+
+                let read = tokio::select! {
+                    read = tokio::time::timeout(timeout, stream.read_buf(&mut buffer)) => read,
+                    _ = wait_for_shutdown(&mut shutdown), if idle => return,
+                };
+
+                match read {
+                    Ok(Ok(0)) | Ok(Err(_)) | Err(_) if idle => return,
+                    Ok(Ok(0)) => Err(HttpError::Incomplete),
+                    Ok(Ok(_)) => continue,
+                    Ok(Err(_)) => return,
+                    Err(_) => Err(HttpError::Io(io::Error::new(
+                        ErrorKind::TimedOut,
+                        "Timed out reading request",
+                    ))),
+                }
+            }
+            Err(err) => Err(err),
+        };
+
+        let (response, keep_alive) = match request {
+            Ok(request) if EventStream::of(&request).is_some() => {
+                let format = EventStream::of(&request).unwrap();
+                return stream_events_async(
+                    format,
+                    request,
+                    &db,
+                    &mut stream,
+                    &config,
+                    &mut shutdown,
+                )
+                .await;
+            }
+            Ok(request) if is_query(&request) => {
+                let keep_alive = request.keep_alive() && !*shutdown.borrow();
+                match stream_query_async(request, &db, &mut stream, keep_alive, &config).await {
+                    Ok(()) if keep_alive => continue,
+                    _ => return,
+                }
+            }
+            Ok(request) => {
+                let keep_alive = request.keep_alive() && !*shutdown.borrow();
+                let db = Arc::clone(&db);
+                match task::spawn_blocking(move || route(&request, &db)).await {
+                    Ok(response) => (response, keep_alive),
+                    Err(err) => (HttpResponse::text(500, err.to_string()), false),
+                }
+            }
+            Err(HttpError::Io(_)) => (
+                HttpResponse::text(408, String::from("Timed out reading request")),
+                false,
+            ),
+            Err(err) => (
+                HttpResponse::text(err.status_code().unwrap_or(400), err.to_string()),
+                false,
+            ),
+        };
+
+        let response = with_connection_headers(response, keep_alive, &config);
+        let written = tokio::time::timeout(
+            config.request_timeout,
+            stream.write_all(&response.to_bytes()),
+        )
+        .await;
+
+        if !matches!(written, Ok(Ok(_))) || !keep_alive {
             return;
         }
-    };
+    }
+}
 
-    let content = String::from_utf8_lossy(&buffer[..size]);
-    let content: Vec<&str> = content.split("\r\n\r\n").collect();
-    let content = content[1];
+/// How change events are streamed to a client that keeps the connection.
+#[derive(Clone, Copy)]
+enum EventStream {
+    /// Server-Sent Events of `/changes`.
+    ServerSent,
+    /// Records of the replication log, for followers.
+    Replication,
+}
 
-    let mut response = Response::new(400, String::new());
+impl EventStream {
+    fn of(request: &HttpRequest) -> Option<EventStream> {
+        if request.method != "GET" {
+            return None;
+        }
 
-    for (action_type, action) in build_actions().into_iter() {
-        if buffer.starts_with(action_type) {
-            response = action(content, &db);
+        match request.path() {
+            path if path == REPLICATION_LOG_PATH => Some(EventStream::Replication),
+            path if path == CHANGES_PATH
+                && request
+                    .header("Accept")
+                    .is_some_and(|accept| accept.contains("text/event-stream")) =>
+            {
+                Some(EventStream::ServerSent)
+            }
+            _ => None,
         }
     }
 
-    debug(&format!(
-        "status_code: {} - response: {}",
-        response.status_code, response.response
-    ));
+    fn head(self) -> &'static [u8] {
+        match self {
+            EventStream::ServerSent => EVENT_STREAM_HEAD,
+            EventStream::Replication => REPLICATION_STREAM_HEAD,
+        }
+    }
 
-    match stream.write(build_response(response).as_bytes()) {
-        Ok(_) => {
-            if let Err(err) = stream.flush() {
-                println!("Failed to flush stream\n{}", err);
-            }
+    fn subscribe(self, request: &HttpRequest, db: &SharedStore) -> Result<Events, HttpResponse> {
+        match self {
+            EventStream::ServerSent => subscribe_changes(request, db).map(Events::Changes),
+            EventStream::Replication => subscribe_replica(request, db).map(Events::Replication),
         }
-        Err(err) => println!("Failed to write to stream\n{}", err),
-    };
+    }
+
+    /// Sent after `ping_interval` without events.
+    fn ping(self) -> Vec<u8> {
+        match self {
+            EventStream::ServerSent => b": ping\n\n".to_vec(),
+            EventStream::Replication => encode_heartbeat(),
+        }
+    }
+
+    fn ping_interval(self) -> Duration {
+        match self {
+            EventStream::ServerSent => EVENT_STREAM_PING,
+            EventStream::Replication => REPLICATION_HEARTBEAT,
+        }
+    }
 }
 
-fn build_response(response: Response) -> String {
-    let status_code = match response.status_code {
-        200 => "200 OK",
-        204 => "204 NO CONTENT",
-        400 => "400 BAD REQUEST",
-        _ => "500 INTERNAL SERVER ERROR",
-    };
-    format!("HTTP/1.1 {}\r\n\r\n{}", status_code, response.response)
+/// Events subscribed to by a stream.
+enum Events {
+    Changes(Subscription),
+    Replication(Subscription<ReplicationEvent>),
 }
 
-struct Response {
-    status_code: u16,
-    response: String,
+impl Events {
+    /// Next event, as the stream sends it.
+    fn recv_timeout(&mut self, timeout: Duration) -> Result<Vec<u8>, RecvTimeoutError> {
+        match self {
+            Events::Changes(events) => events
+                .recv_timeout(timeout)
+                .map(|event| format_event(&event)),
+            Events::Replication(events) => events
+                .recv_timeout(timeout)
+                .map(|event| encode_replicated(&event)),
+        }
+    }
 }
 
-impl Response {
-    fn new(status_code: u16, response: String) -> Response {
-        Response {
-            status_code,
-            response,
+/// Sends change events until the client goes away, taking the connection
+/// over.
+fn stream_events(
+    format: EventStream,
+    request: &HttpRequest,
+    db: &SharedStore,
+    stream: &mut TcpStream,
+    config: &ServerConfig,
+) {
+    let mut subscription = match format.subscribe(request, db) {
+        Ok(subscription) => subscription,
+        Err(response) => {
+            let _ = with_connection_headers(response, false, config).write_to(stream);
+            return;
+        }
+    };
+
+    if stream.write_all(format.head()).is_err() {
+        return;
+    }
+
+    loop {
+        let data = match subscription.recv_timeout(format.ping_interval()) {
+            Ok(data) => data,
+            Err(RecvTimeoutError::Timeout) => format.ping(),
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+
+        if stream.write_all(&data).is_err() {
+            return;
         }
     }
 }
 
-type Callback = fn(&str, &Arc<Mutex<RustDB>>) -> Response;
+/// Async counterpart of `stream_events`. Events are awaited on a blocking
+/// thread, and the stream is closed on shutdown.
+async fn stream_events_async(
+    format: EventStream,
+    request: HttpRequest,
+    db: &SharedStore,
+    stream: &mut tokio::net::TcpStream,
+    config: &ServerConfig,
+    shutdown: &mut watch::Receiver<bool>,
+) {
+    let shared = Arc::clone(db);
+    let mut subscription =
+        match task::spawn_blocking(move || format.subscribe(&request, &shared)).await {
+            Ok(Ok(subscription)) => subscription,
+            Ok(Err(response)) => {
+                let response = with_connection_headers(response, false, config);
+                let _ = stream.write_all(&response.to_bytes()).await;
+                return;
+            }
+            Err(err) => {
+                let response = HttpResponse::text(500, err.to_string());
+                let response = with_connection_headers(response, false, config);
+                let _ = stream.write_all(&response.to_bytes()).await;
+                return;
+            }
+        };
+
+    let (sender, mut events) = mpsc::channel(16);
+    task::spawn_blocking(move || loop {
+        match subscription.recv_timeout(Duration::from_secs(1)) {
+            Ok(data) => {
+                if sender.blocking_send(data).is_err() {
+                    return;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) if !sender.is_closed() => {}
+            Err(_) => return,
+        }
+    });
 
-fn build_actions() -> HashMap<&'static [u8], Callback> {
-    let mut actions: HashMap<&[u8], Callback> = HashMap::new();
-    actions.insert(READ_DATA, read_content);
-    actions.insert(DELETE_DATA, delete_content);
-    actions.insert(INSERT_DATA, update_content);
-    actions.insert(UPDATE_DATA, update_content);
+    if stream.write_all(format.head()).await.is_err() {
+        return;
+    }
 
-    actions
+    let mut ping = tokio::time::interval(format.ping_interval());
+    ping.tick().await;
+    loop {
+        let data = tokio::select! {
+            event = events.recv() => match event {
+                Some(data) => data,
+                None => return,
+            },
+            _ = ping.tick() => format.ping(),
+            _ = wait_for_shutdown(shutdown) => return,
+        };
+
+        let written = tokio::time::timeout(config.request_timeout, stream.write_all(&data)).await;
+        if !matches!(written, Ok(Ok(_))) {
+            return;
+        }
+    }
 }
 
-fn read_content(content: &str, db: &Arc<Mutex<RustDB>>) -> Response {
-    let key = match get_key(content) {
-        Ok(v) => v,
-        Err(err) => return Response::new(400, err),
-    };
+fn is_query(request: &HttpRequest) -> bool {
+    request.method == "POST" && request.path() == QUERY_PATH
+}
 
-    let (response_code, result) = match db.lock().unwrap().get_record(key) {
-        Ok(key_value) => match key_value {
-            Some(kv) => (200, kv.get_value_as_string()),
-            None => (204, String::new()),
-        },
-        Err(err) => (500, err.to_string()),
+/// Answers a query with its matches as a chunked body, sent while the keys
+/// are scanned, so the store is only held for a page of them at a time. The
+/// head goes out with the first matches, so a query failing before any gets
+/// an error response; one failing after is cut short by closing the
+/// connection without the last chunk.
+fn stream_query(
+    request: &HttpRequest,
+    db: &SharedStore,
+    stream: &mut TcpStream,
+    keep_alive: bool,
+    config: &ServerConfig,
+) -> io::Result<()> {
+    let query = match Query::parse(&request.body) {
+        Ok(query) => query,
+        Err(err) => {
+            let response = HttpResponse::text(400, err.to_string());
+            return with_connection_headers(response, keep_alive, config).write_to(stream);
+        }
     };
 
-    Response::new(response_code, result)
+    let mut head = Some(query_head(keep_alive, config));
+    let result = query_batches(&query, db, |batch| {
+        if let Some(head) = head.take() {
+            stream.write_all(&head)?;
+        }
+        stream.write_all(&chunk(&batch))
+    });
+
+    match (result, head) {
+        (Ok(()), Some(head)) => {
+            stream.write_all(&head)?;
+            stream.write_all(LAST_CHUNK)
+        }
+        (Ok(()), None) => stream.write_all(LAST_CHUNK),
+        (Err(err), Some(_)) => {
+            let response = HttpResponse::text(500, err.to_string());
+            with_connection_headers(response, false, config).write_to(stream)?;
+            Err(err)
+        }
+        (Err(err), None) => Err(err),
+    }
 }
 
-fn delete_content(content: &str, db: &Arc<Mutex<RustDB>>) -> Response {
-    let key = match get_key(content) {
-        Ok(v) => v,
-        Err(err) => return Response::new(400, err),
-    };
+/// Async counterpart of `stream_query`. The query runs on a blocking thread,
+/// handing over its batches through a channel, and stops once the client
+/// goes away.
+async fn stream_query_async(
+    request: HttpRequest,
+    db: &SharedStore,
+    stream: &mut tokio::net::TcpStream,
+    keep_alive: bool,
+    config: &ServerConfig,
+) -> io::Result<()> {
+    let (sender, mut batches) = mpsc::channel(4);
+    let shared = Arc::clone(db);
+    let scan = task::spawn_blocking(move || {
+        let query = Query::parse(&request.body).map_err(|err| (400, err.to_string()))?;
+        query_batches(&query, &shared, |batch| {
+            sender
+                .blocking_send(batch)
+                .map_err(|_| io::Error::new(ErrorKind::BrokenPipe, "Query stream closed"))
+        })
+        .map_err(|err| (500, err.to_string()))
+    });
+
+    let mut head = Some(query_head(keep_alive, config));
+    while let Some(batch) = batches.recv().await {
+        if let Some(head) = head.take() {
+            write_timed(stream, &head, config).await?;
+        }
+        write_timed(stream, &chunk(&batch), config).await?;
+    }
 
-    let (response_code, result) = match db.lock().unwrap().delete_record(key) {
-        Ok(_) => (200, String::new()),
-        Err(err) => (500, err.to_string()),
+    let result = match scan.await {
+        Ok(result) => result,
+        Err(err) => Err((500, err.to_string())),
     };
+    match (result, head) {
+        (Ok(()), Some(head)) => {
+            write_timed(stream, &head, config).await?;
+            write_timed(stream, LAST_CHUNK, config).await
+        }
+        (Ok(()), None) => write_timed(stream, LAST_CHUNK, config).await,
+        (Err((status_code, message)), Some(_)) => {
+            let keep_alive = keep_alive && status_code == 400;
+            let response = HttpResponse::text(status_code, message.clone());
+            let response = with_connection_headers(response, keep_alive, config);
+            write_timed(stream, &response.to_bytes(), config).await?;
+            match keep_alive {
+                true => Ok(()),
+                false => Err(io::Error::other(message)),
+            }
+        }
+        (Err((_, message)), None) => Err(io::Error::other(message)),
+    }
+}
 
-    Response::new(response_code, result)
+async fn write_timed(
+    stream: &mut tokio::net::TcpStream,
+    data: &[u8],
+    config: &ServerConfig,
+) -> io::Result<()> {
+    match tokio::time::timeout(config.request_timeout, stream.write_all(data)).await {
+        Ok(written) => written,
+        Err(_) => Err(io::Error::new(
+            ErrorKind::TimedOut,
+            "Timed out writing response",
+        )),
+    }
 }
 
-fn update_content(content: &str, db: &Arc<Mutex<RustDB>>) -> Response {
-    let key_value = match get_keyvalue(content) {
-        Ok(v) => v,
-        Err(err) => return Response::new(400, err),
-    };
+fn query_head(keep_alive: bool, config: &ServerConfig) -> Vec<u8> {
+    let response = HttpResponse::new(200, "application/x-ndjson", Vec::new());
+    with_connection_headers(response, keep_alive, config).chunked_head()
+}
 
-    let (response_code, result) = match db.lock().unwrap().save_record(key_value) {
-        Ok(_) => (200, String::new()),
-        Err(err) => (500, err.to_string()),
-    };
+/// Runs `query`, locking the store a page of keys at a time, and hands
+/// `send` its matches as lines of NDJSON, in batches of about
+/// `QUERY_BATCH_SIZE` bytes.
+fn query_batches(
+    query: &Query,
+    db: &SharedStore,
+    mut send: impl FnMut(Vec<u8>) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut batch = Vec::new();
+    query.run_paged(
+        || db.lock().unwrap(),
+        |key, document| {
+            batch.extend_from_slice(
+                format!("{{{},\"value\":{}}}\n", encode_key(&key), document).as_bytes(),
+            );
+            if batch.len() >= QUERY_BATCH_SIZE {
+                send(std::mem::take(&mut batch))?;
+            }
+            Ok(())
+        },
+    )?;
 
-    Response::new(response_code, result)
+    match batch.is_empty() {
+        true => Ok(()),
+        false => send(batch),
+    }
 }
 
-fn get_key(content: &str) -> Result<String, String> {
-    let json_content: Value = match serde_json::from_str(content) {
-        Ok(value) => value,
-        Err(error) => return Err(error.to_string()),
-    };
+fn with_connection_headers(
+    response: HttpResponse,
+    keep_alive: bool,
+    config: &ServerConfig,
+) -> HttpResponse {
+    match keep_alive {
+        true => response
+            .with_header("Connection", "keep-alive")
+            .with_header(
+                "Keep-Alive",
+                &format!("timeout={}", config.keep_alive_timeout.as_secs()),
+            ),
+        false => response.with_header("Connection", "close"),
+    }
+}
 
-    let key = match json_content {
-        Value::Null => return Err(String::from("Invalid input: null")),
-        Value::Bool(_) => return Err(String::from("Invalid input: boolean")),
-        Value::Object(obj) if !obj.contains_key("id") => {
-            return Err(String::from("Missing field 'id'"))
+fn compress_files(db: Arc<Mutex<RustDB>>, folder: String) -> ! {
+    loop {
+        let folder = folder.as_str();
+        if let Err(err) = db.lock().unwrap().remove_expired() {
+            println!("Failed to remove expired keys\n{}", err);
         }
-        Value::Array(_) => return Err(String::from("Invalid input: array")),
-        Value::Number(id) => id.to_string(),
-        Value::String(id) => id,
-        Value::Object(obj) => obj["id"].to_string().replace("\"", ""),
-    };
 
-    Ok(key)
+        // read under one lock, so the active segment cannot roll over
+        // between picking the segments and the segment they link to
+        let compression = {
+            let db = db.lock().unwrap();
+            let segment_names = db.get_segments_to_compress();
+            match segment_names.is_empty() {
+                true => None,
+                false => Some((
+                    LogCompressor::new(folder, segment_names.clone(), db.get_active_segment_name())
+                        .with_active_keys(db.get_active_keys())
+                        .with_options(db.get_options())
+                        .with_vfs(db.get_vfs()),
+                    segment_names,
+                    db.get_vfs(),
+                )),
+            }
+        };
+
+        if let Some((compressor, segment_names, vfs)) = compression {
+            match compressor.compress() {
+                Ok((active_segment, new_segment)) => {
+                    db.lock()
+                        .unwrap()
+                        .replace_segments(active_segment, new_segment);
+                    LogCompressor::clean_with_vfs(&vfs, folder, segment_names);
+                }
+                Err(err) => println!("Failed to compress segments\n{}", err),
+            }
+        }
+
+        // collections are compressed one at a time, holding the lock
+        let names = db.lock().unwrap().list_collections();
+        for name in names {
+            let mut db = db.lock().unwrap();
+            if let Some(Err(err)) = db.collection_mut(&name).map(|c| c.compress_segments()) {
+                println!("Failed to compress collection {}\n{}", name, err);
+            }
+        }
+
+        thread::sleep(time::Duration::from_secs(10));
+    }
 }
 
-fn get_keyvalue(content: &str) -> Result<KeyValue, String> {
-    let key = get_key(content)?;
+/// Keys moved between shards at once, so that requests get the lock in
+/// between.
+static MIGRATION_BATCH: usize = 100;
 
-    let v: Value = match serde_json::from_str(content) {
-        Ok(value) => value,
-        Err(error) => return Err(error.to_string()),
-    };
+/// Background work of a sharded store: removing expired keys, moving keys
+/// to new shards a batch at a time while requests are served, and
+/// compressing segments.
+fn maintain_shards(db: Arc<Mutex<ShardedDB>>) -> ! {
+    loop {
+        if let Err(err) = db.lock().unwrap().remove_expired() {
+            println!("Failed to remove expired keys\n{}", err);
+        }
+
+        while db.lock().unwrap().is_migrating() {
+            match db.lock().unwrap().migrate(MIGRATION_BATCH) {
+                Ok(moved) => debug(&format!("moved {} keys between shards", moved)),
+                Err(err) => {
+                    println!("Failed to move keys between shards\n{}", err);
+                    break;
+                }
+            }
+        }
+
+        if let Err(err) = db.lock().unwrap().compress_segments() {
+            println!("Failed to compress segments\n{}", err);
+        }
+
+        thread::sleep(time::Duration::from_secs(10));
+    }
+}
+
+/// Serves requests from `stream` until the client closes it, asks to close
+/// it, or stays idle longer than the keep alive timeout. Pipelined requests
+/// are answered in order, as they are read one at a time.
+fn handle_connection(stream: TcpStream, db: SharedStore, config: ServerConfig) {
+    if let Err(err) = stream.set_write_timeout(Some(config.request_timeout)) {
+        println!("Failed to configure stream\n{}", err);
+        return;
+    }
+    let mut stream = BufReader::new(stream);
+
+    loop {
+        // an idle connection is closed without a response
+        if stream
+            .get_ref()
+            .set_read_timeout(Some(config.keep_alive_timeout))
+            .is_err()
+        {
+            return;
+        }
+        match stream.fill_buf() {
+            Ok(buffer) if !buffer.is_empty() => {}
+            _ => return,
+        }
+        if stream
+            .get_ref()
+            .set_read_timeout(Some(config.request_timeout))
+            .is_err()
+        {
+            return;
+        }
+
+        let (response, keep_alive) = match read_request(&mut stream, config.max_body_size) {
+            Ok(Some(request)) if EventStream::of(&request).is_some() => {
+                let format = EventStream::of(&request).unwrap();
+                return stream_events(format, &request, &db, stream.get_mut(), &config);
+            }
+            Ok(Some(request)) if is_query(&request) => {
+                let keep_alive = request.keep_alive();
+                match stream_query(&request, &db, stream.get_mut(), keep_alive, &config) {
+                    Ok(()) if keep_alive => continue,
+                    Ok(()) => return,
+                    Err(err) => {
+                        println!("Failed to stream query\n{}", err);
+                        return;
+                    }
+                }
+            }
+            Ok(Some(request)) => (route(&request, &db), request.keep_alive()),
+            Ok(None) => return,
+            Err(HttpError::Io(err)) if is_timeout(&err) => (
+                HttpResponse::text(408, String::from("Timed out reading request")),
+                false,
+            ),
+            Err(err) => match err.status_code() {
+                Some(status_code) => (HttpResponse::text(status_code, err.to_string()), false),
+                None => {
+                    println!("Failed to read stream\n{}", err);
+                    return;
+                }
+            },
+        };
+
+        debug(&format!(
+            "status_code: {} - response: {}",
+            response.status_code,
+            String::from_utf8_lossy(&response.body)
+        ));
+
+        let response = with_connection_headers(response, keep_alive, &config);
+
+        if let Err(err) = response.write_to(stream.get_mut()) {
+            println!("Failed to write to stream\n{}", err);
+            return;
+        }
+
+        if !keep_alive {
+            return;
+        }
+    }
+}
+
+fn is_timeout(err: &io::Error) -> bool {
+    err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut
+}
+
+static KEYS_PATH: &str = "/keys/";
+static CHANGES_PATH: &str = "/changes";
+static DEFAULT_PAGE_SIZE: usize = 100;
+static MAX_PAGE_SIZE: usize = 1_000;
+/// Seconds a long-poll waits for a change by default, and at most.
+static DEFAULT_POLL_TIMEOUT: u64 = 30;
+static MAX_POLL_TIMEOUT: u64 = 60;
+/// How often an idle event stream gets a comment, to find out whether the
+/// client is still there.
+static EVENT_STREAM_PING: Duration = Duration::from_secs(15);
+static EVENT_STREAM_HEAD: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\
+    Cache-Control: no-cache\r\nConnection: close\r\n\r\n";
+/// How often an idle replication log gets a heartbeat, well within the
+/// time followers wait to hear from the leader.
+static REPLICATION_HEARTBEAT: Duration = Duration::from_secs(5);
+static REPLICATION_STREAM_HEAD: &[u8] = b"HTTP/1.1 200 OK\r\n\
+    Content-Type: application/octet-stream\r\nCache-Control: no-cache\r\n\
+    Connection: close\r\n\r\n";
+static PROMOTE_PATH: &str = "/_promote";
+static QUERY_PATH: &str = "/_query";
+/// Bytes of NDJSON gathered before a chunk of query matches is sent.
+static QUERY_BATCH_SIZE: usize = 16 * 1024;
+static COLLECTIONS_PATH: &str = "/c";
+static COLLECTION_PREFIX: &str = "/c/";
+static INDEXES_PATH: &str = "/index";
+static INDEX_PREFIX: &str = "/index/";
+static SCHEMA_PATH: &str = "_schema";
+static READ_ONLY_MESSAGE: &str = "Read-only replica, writes go to the leader";
+
+fn route(request: &HttpRequest, db: &SharedStore) -> HttpResponse {
+    let path = request.path();
+
+    if path == "/keys" {
+        return match request.method.as_str() {
+            "GET" => list_keys(request, &*db.lock().unwrap()),
+            _ => method_not_allowed(request, "GET"),
+        };
+    }
 
-    let value = match v {
-        Value::Object(obj) => {
-            if obj.keys().len() > 1 {
-                Value::Object(obj).to_string()
-            } else {
-                return Err(String::from(
-                    "Invalid input: Missing fields different from id",
-                ));
+    if path == COLLECTIONS_PATH {
+        return match request.method.as_str() {
+            "GET" => list_collections(db),
+            _ => method_not_allowed(request, "GET"),
+        };
+    }
+
+    if let Some(path) = path.strip_prefix(COLLECTION_PREFIX) {
+        return route_collection(path, request, db);
+    }
+
+    if path == INDEXES_PATH {
+        return match request.method.as_str() {
+            "GET" => list_indexes(db),
+            _ => method_not_allowed(request, "GET"),
+        };
+    }
+
+    if let Some(path) = path.strip_prefix(INDEX_PREFIX) {
+        return route_index(path, request, &mut *db.lock().unwrap());
+    }
+
+    if let Some(path) = path.strip_prefix('/').and_then(|path| schema_path(path)) {
+        return route_schema(path, request, &mut *db.lock().unwrap());
+    }
+
+    if path == CHANGES_PATH {
+        return match request.method.as_str() {
+            "GET" => poll_changes(request, db),
+            _ => method_not_allowed(request, "GET"),
+        };
+    }
+
+    if path == REPLICATION_SNAPSHOT_PATH {
+        return match request.method.as_str() {
+            "GET" => snapshot(request, db),
+            _ => method_not_allowed(request, "GET"),
+        };
+    }
+
+    if path == PROMOTE_PATH {
+        return match request.method.as_str() {
+            "POST" => promote(db),
+            _ => method_not_allowed(request, "POST"),
+        };
+    }
+
+    if let Some(key) = path.strip_prefix(KEYS_PATH) {
+        return key_action(key, request, &mut *db.lock().unwrap());
+    }
+
+    if let Some(action) = build_batch_actions().get(path) {
+        return match request.method.as_str() {
+            "POST" => action(request, db),
+            _ => method_not_allowed(request, "POST"),
+        };
+    }
+
+    if path != "/" {
+        return HttpResponse::text(404, format!("Path not found: {}", path));
+    }
+
+    match build_actions().get(request.method.as_str()) {
+        Some(action) => action(request, db),
+        None => method_not_allowed(request, "GET, POST, PUT, DELETE"),
+    }
+}
+
+/// Routes `/c/{collection}`, to create or drop the collection, and
+/// `/c/{collection}/keys` along with the keys under it as `/keys` is
+/// routed, within the collection.
+fn route_collection(path: &str, request: &HttpRequest, db: &SharedStore) -> HttpResponse {
+    let mut db = db.lock().unwrap();
+    let (name, path) = match path.split_once('/') {
+        Some((name, path)) => (name, path),
+        None => {
+            return match request.method.as_str() {
+                "PUT" => create_collection(path, &mut *db),
+                "DELETE" => drop_collection(path, &mut *db),
+                _ => method_not_allowed(request, "PUT, DELETE"),
             }
         }
-        _ => return Err(String::from("Invalid input: Missing value")),
     };
 
-    Ok(KeyValue::new_from_strings(key, value))
+    let collection = match db.collection_mut(name) {
+        Some(collection) => collection,
+        None => return HttpResponse::text(404, format!("Collection not found: {}", name)),
+    };
+
+    if let Some(path) = schema_path(path) {
+        return route_schema(path, request, collection);
+    }
+
+    if path == "keys" {
+        return match request.method.as_str() {
+            "GET" => list_keys(request, collection),
+            _ => method_not_allowed(request, "GET"),
+        };
+    }
+
+    match path.strip_prefix("keys/") {
+        Some(key) => key_action(key, request, collection),
+        None => HttpResponse::text(404, format!("Path not found: {}", request.path())),
+    }
+}
+
+/// Routes `/index/{name}`, to create the index on the JSON path sent as the
+/// body or drop it, and `/index/{name}/{value}` to find the records with
+/// that value.
+fn route_index(path: &str, request: &HttpRequest, db: &mut dyn Store) -> HttpResponse {
+    let (name, value) = match path.split_once('/') {
+        Some((name, value)) => (name, value),
+        None => {
+            return match request.method.as_str() {
+                "PUT" => create_index(path, request, db),
+                "DELETE" => drop_index(path, db),
+                _ => method_not_allowed(request, "PUT, DELETE"),
+            }
+        }
+    };
+
+    if request.method != "GET" {
+        return method_not_allowed(request, "GET");
+    }
+    let value = match percent_decode(value).map(String::from_utf8) {
+        Some(Ok(value)) => value,
+        _ => return HttpResponse::text(400, format!("Invalid value: {}", value)),
+    };
+
+    match db.find_by(name, &value) {
+        Ok(records) => {
+            let items: Vec<String> = records
+                .into_iter()
+                .map(|record| {
+                    let content_type = db
+                        .get_content_type(&record.key)
+                        .unwrap_or_else(|| String::from("application/json"));
+                    format!(
+                        "{{{},\"content_type\":{},{}}}",
+                        encode_key(&record.key),
+                        serde_json::to_string(&content_type).unwrap(),
+                        encode_value(&content_type, record.value)
+                    )
+                })
+                .collect();
+            HttpResponse::json(200, format!("{{\"items\":[{}]}}", items.join(",")))
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => HttpResponse::text(404, err.to_string()),
+        Err(err) => collection_error(err),
+    }
+}
+
+/// What follows `_schema` in `path`, without the leading `/`.
+fn schema_path(path: &str) -> Option<&str> {
+    match path.strip_prefix(SCHEMA_PATH)? {
+        "" => Some(""),
+        path => path.strip_prefix('/'),
+    }
+}
+
+/// Routes `_schema`, to read, set or remove the schema in force, along with
+/// `_schema/versions` and `_schema/{version}` to read past versions.
+fn route_schema(path: &str, request: &HttpRequest, db: &mut dyn Store) -> HttpResponse {
+    let versions = db.schema_versions();
+    let version_json = |(version, schema): &(u64, Option<Value>)| {
+        let schema = schema.as_ref().unwrap_or(&Value::Null);
+        format!("{{\"version\":{},\"schema\":{}}}", version, schema)
+    };
+
+    match (request.method.as_str(), path) {
+        ("GET", "") => match versions.last() {
+            Some(version @ (_, Some(_))) => HttpResponse::json(200, version_json(version)),
+            _ => HttpResponse::text(404, String::from("No schema")),
+        },
+        ("PUT", "") => {
+            let schema = match serde_json::from_slice::<Value>(&request.body) {
+                Ok(schema) => schema,
+                Err(err) => return HttpResponse::text(400, format!("Invalid schema: {}", err)),
+            };
+            match db.set_schema(Some(schema)) {
+                Ok(version) => HttpResponse::json(201, format!("{{\"version\":{}}}", version)),
+                Err(err) => collection_error(err),
+            }
+        }
+        ("DELETE", "") => match versions.last() {
+            Some((_, Some(_))) => match db.set_schema(None) {
+                Ok(version) => HttpResponse::json(200, format!("{{\"version\":{}}}", version)),
+                Err(err) => collection_error(err),
+            },
+            _ => HttpResponse::text(404, String::from("No schema")),
+        },
+        (_, "") => method_not_allowed(request, "GET, PUT, DELETE"),
+        ("GET", "versions") => {
+            let versions: Vec<String> = versions.iter().map(version_json).collect();
+            HttpResponse::json(200, format!("{{\"versions\":[{}]}}", versions.join(",")))
+        }
+        ("GET", version) => {
+            let found = version
+                .parse::<u64>()
+                .ok()
+                .and_then(|number| versions.iter().find(|(version, _)| *version == number));
+            match found {
+                Some(version) => HttpResponse::json(200, version_json(version)),
+                None => HttpResponse::text(404, format!("Schema version not found: {}", version)),
+            }
+        }
+        _ => method_not_allowed(request, "GET"),
+    }
+}
+
+fn key_action(key: &str, request: &HttpRequest, db: &mut dyn Store) -> HttpResponse {
+    let key = match percent_decode(key) {
+        Some(key) if !key.is_empty() => key,
+        _ => return HttpResponse::text(400, format!("Invalid key: {}", key)),
+    };
+
+    match build_key_actions().get(request.method.as_str()) {
+        Some(action) => action(key, request, db),
+        None => method_not_allowed(request, "GET, PUT, PATCH, POST, DELETE"),
+    }
+}
+
+fn method_not_allowed(request: &HttpRequest, allow: &str) -> HttpResponse {
+    HttpResponse::text(405, format!("Method not allowed: {}", request.method))
+        .with_header("Allow", allow)
+}
+
+type Callback = fn(&HttpRequest, &SharedStore) -> HttpResponse;
+
+fn build_actions() -> HashMap<&'static str, Callback> {
+    let mut actions: HashMap<&str, Callback> = HashMap::new();
+    actions.insert("GET", read_content);
+    actions.insert("DELETE", delete_content);
+    actions.insert("POST", update_content);
+    actions.insert("PUT", update_content);
+
+    actions
+}
+
+fn build_batch_actions() -> HashMap<&'static str, Callback> {
+    let mut actions: HashMap<&str, Callback> = HashMap::new();
+    actions.insert("/_bulk", bulk);
+    actions.insert("/_mget", mget);
+    actions.insert(QUERY_PATH, query);
+
+    actions
+}
+
+type KeyCallback = fn(ByteString, &HttpRequest, &mut dyn Store) -> HttpResponse;
+
+fn build_key_actions() -> HashMap<&'static str, KeyCallback> {
+    let mut actions: HashMap<&str, KeyCallback> = HashMap::new();
+    actions.insert("GET", read_key);
+    actions.insert("DELETE", delete_key);
+    actions.insert("PUT", put_key);
+    actions.insert("PATCH", patch_key);
+    actions.insert("POST", increment_key);
+
+    actions
+}
+
+fn read_key(key: ByteString, _request: &HttpRequest, db: &mut dyn Store) -> HttpResponse {
+    match read_value(key, db) {
+        Ok(Some(response)) => response,
+        Ok(None) => HttpResponse::text(404, String::from("Key not found")),
+        Err(err) => HttpResponse::text(500, err.to_string()),
+    }
+}
+
+fn delete_key(key: ByteString, _request: &HttpRequest, db: &mut dyn Store) -> HttpResponse {
+    match db.delete_record(&key) {
+        Ok(_) => HttpResponse::empty(204),
+        Err(err) => storage_error(err),
+    }
+}
+
+/// Stores the body as is. Empty values mark deleted keys in the storage, so
+/// they cannot be stored.
+fn put_key(key: ByteString, request: &HttpRequest, db: &mut dyn Store) -> HttpResponse {
+    if request.body.is_empty() {
+        return HttpResponse::text(400, String::from("Invalid input: empty value"));
+    }
+
+    match save_value(key, request, db) {
+        Ok(_) => HttpResponse::empty(204),
+        Err(err) => storage_error(err),
+    }
+}
+
+/// Applies the body to the stored JSON document, as a merge patch or a JSON
+/// Patch by its content type, and answers the patched document. The lock
+/// is held from the read to the write, so no other write comes in between.
+/// Patches that do not apply to the document, such as a failed `test`, get
+/// a `409` and leave it as it was.
+fn patch_key(key: ByteString, request: &HttpRequest, db: &mut dyn Store) -> HttpResponse {
+    let content_type = request.header("Content-Type").unwrap_or("");
+    let patch = match Patch::parse(content_type, &request.body) {
+        Ok(patch) => patch,
+        Err(err) if err.kind() == ErrorKind::Unsupported => {
+            return HttpResponse::text(415, err.to_string())
+        }
+        Err(err) => return HttpResponse::text(400, err.to_string()),
+    };
+
+    let record = match db.get_record(&key) {
+        Ok(Some(record)) => record,
+        Ok(None) => return HttpResponse::text(404, String::from("Key not found")),
+        Err(err) => return HttpResponse::text(500, err.to_string()),
+    };
+    let document = match serde_json::from_slice::<Value>(&record.value) {
+        Ok(document) => document,
+        Err(_) => return HttpResponse::text(409, String::from("Stored value is not JSON")),
+    };
+    let patched = match patch.apply(&document) {
+        Ok(patched) => patched.to_string(),
+        Err(err) => return HttpResponse::text(409, err.to_string()),
+    };
+
+    match db.save_record(KeyValue::new(key, patched.clone().into_bytes())) {
+        Ok(_) => HttpResponse::json(200, patched),
+        Err(err) => storage_error(err),
+    }
+}
+
+/// Adds the `delta` query parameter, 1 by default, to the counter at the
+/// key and answers its new value as a JSON number. Values that are not
+/// integers, and results that would overflow, get a `409`.
+fn increment_key(key: ByteString, request: &HttpRequest, db: &mut dyn Store) -> HttpResponse {
+    let delta = match request.query_param("delta").map(str::parse::<i64>) {
+        None => 1,
+        Some(Ok(delta)) => delta,
+        Some(Err(_)) => return HttpResponse::text(400, String::from("Invalid delta")),
+    };
+
+    match db.increment(&key, delta) {
+        Ok(value) => HttpResponse::json(200, value.to_string()),
+        Err(err) if err.kind() == ErrorKind::InvalidData && schema_violation(&err).is_none() => {
+            HttpResponse::text(409, err.to_string())
+        }
+        Err(err) => storage_error(err),
+    }
+}
+
+fn read_value(key: ByteString, db: &dyn Store) -> io::Result<Option<HttpResponse>> {
+    Ok(stored_value(db, key)?
+        .map(|(content_type, value)| HttpResponse::new(200, &content_type, value)))
+}
+
+/// Content type and stored bytes of `key`. Values written without a
+/// content type, or through other protocols, are typed as JSON when they
+/// parse as such and as raw bytes otherwise.
+fn stored_value(db: &dyn Store, key: ByteString) -> io::Result<Option<(String, ByteString)>> {
+    let kv = match db.get_record(&key)? {
+        Some(kv) => kv,
+        None => return Ok(None),
+    };
+
+    let content_type = db.get_content_type(&key).unwrap_or_else(|| {
+        match serde_json::from_slice::<Value>(&kv.value) {
+            Ok(_) => String::from("application/json"),
+            Err(_) => String::from("application/octet-stream"),
+        }
+    });
+
+    Ok(Some((content_type, kv.value)))
+}
+
+/// Stores the body of `request` verbatim, along with its content type.
+fn save_value(key: ByteString, request: &HttpRequest, db: &mut dyn Store) -> io::Result<()> {
+    let key_value = KeyValue::new(key, request.body.clone());
+    save_with_content_type(db, key_value, request.header("Content-Type"))
+}
+
+/// Saves the value, then its content type, which are kept apart. When the
+/// content type fails to be saved, the previous value is put back, so the
+/// value is never left with the content type of another.
+fn save_with_content_type(
+    db: &mut dyn Store,
+    key_value: KeyValue,
+    content_type: Option<&str>,
+) -> io::Result<()> {
+    let key = key_value.key.clone();
+    let previous = db.get_record(&key)?;
+    db.save_record(key_value)?;
+
+    if let Err(err) = db.set_content_type(&key, content_type) {
+        match previous {
+            Some(previous) => db.save_record(previous)?,
+            None => db.delete_record(&key)?,
+        }
+        return Err(err);
+    }
+    Ok(())
+}
+
+enum BulkOperation {
+    Put {
+        key: String,
+        value: ByteString,
+        content_type: String,
+    },
+    Delete {
+        key: String,
+    },
+}
+
+impl BulkOperation {
+    fn key(&self) -> &str {
+        match self {
+            BulkOperation::Put { key, .. } | BulkOperation::Delete { key } => key,
+        }
+    }
+}
+
+/// Applies NDJSON operations, one per line:
+///
+/// ```text
+/// {"op":"put","key":"a","value":{"name":"test"}}
+/// {"op":"put","key":"b","value_base64":"/wA=","content_type":"image/png"}
+/// {"op":"delete","key":"c"}
+/// ```
+///
+/// The whole body is validated before anything is written, and operations
+/// are applied in order under a single lock, so other requests see none or
+/// all of them. Each one is answered with its status: `201` for new keys,
+/// `200` for replaced or deleted ones, `404` for missing keys and `422` for
+/// values the schema rejects, which are skipped. Once the storage fails,
+/// the remaining operations are not applied.
+fn bulk(request: &HttpRequest, db: &SharedStore) -> HttpResponse {
+    let mut operations = Vec::new();
+    for (index, line) in request.body.split(|b| *b == b'\n').enumerate() {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.iter().all(|b| b.is_ascii_whitespace()) {
+            continue;
+        }
+
+        match parse_bulk_operation(line) {
+            Ok(operation) => operations.push(operation),
+            Err(err) => {
+                return HttpResponse::text(
+                    400,
+                    format!("Invalid operation on line {}: {}", index + 1, err),
+                )
+            }
+        }
+    }
+
+    if operations.is_empty() {
+        return HttpResponse::text(400, String::from("Invalid input: no operations"));
+    }
+
+    let mut db = db.lock().unwrap();
+    if db.is_read_only() {
+        return HttpResponse::text(403, String::from(READ_ONLY_MESSAGE));
+    }
+    let mut failed = false;
+    let mut rejected = false;
+    let mut items = Vec::with_capacity(operations.len());
+
+    for operation in operations {
+        let key = serde_json::to_string(operation.key()).unwrap();
+        if failed {
+            items.push(format!(
+                "{{\"key\":{},\"status\":503,\"error\":\"Not applied\"}}",
+                key
+            ));
+            continue;
+        }
+
+        match apply_bulk_operation(&mut *db, operation) {
+            Ok(status) => items.push(format!("{{\"key\":{},\"status\":{}}}", key, status)),
+            Err(err) => match schema_violation(&err) {
+                Some(violation) => {
+                    rejected = true;
+                    items.push(format!(
+                        "{{\"key\":{},\"status\":422,{}}}",
+                        key,
+                        violation_fields(violation)
+                    ));
+                }
+                None => {
+                    failed = true;
+                    items.push(format!(
+                        "{{\"key\":{},\"status\":500,\"error\":{}}}",
+                        key,
+                        serde_json::to_string(&err.to_string()).unwrap()
+                    ));
+                }
+            },
+        }
+    }
+
+    HttpResponse::json(
+        200,
+        format!(
+            "{{\"errors\":{},\"items\":[{}]}}",
+            failed || rejected,
+            items.join(",")
+        ),
+    )
+}
+
+/// Values are taken verbatim from the line: JSON strings are stored as
+/// text, other JSON values as the JSON text sent and `value_base64` as the
+/// bytes it decodes to.
+fn parse_bulk_operation(line: &[u8]) -> Result<BulkOperation, String> {
+    let fields: HashMap<String, Box<RawValue>> =
+        serde_json::from_slice(line).map_err(|err| err.to_string())?;
+    let text_field = |name: &str| -> Result<Option<String>, String> {
+        match fields.get(name) {
+            Some(raw) => serde_json::from_str(raw.get())
+                .map(Some)
+                .map_err(|_| format!("Field '{}' must be a string", name)),
+            None => Ok(None),
+        }
+    };
+
+    let key = match text_field("key")? {
+        Some(key) if !key.is_empty() => key,
+        _ => return Err(String::from("Missing field 'key'")),
+    };
+
+    match text_field("op")?.as_deref() {
+        Some("delete") => return Ok(BulkOperation::Delete { key }),
+        Some("put") => {}
+        _ => return Err(String::from("Field 'op' must be \"put\" or \"delete\"")),
+    }
+
+    let (value, content_type) = match (fields.get("value"), text_field("value_base64")?) {
+        (Some(raw), None) => match serde_json::from_str::<String>(raw.get()) {
+            Ok(text) => (text.into_bytes(), "text/plain; charset=utf-8"),
+            Err(_) => (raw.get().as_bytes().to_vec(), "application/json"),
+        },
+        (None, Some(encoded)) => match base64_decode(&encoded) {
+            Some(value) => (value, "application/octet-stream"),
+            None => return Err(String::from("Invalid base64 in 'value_base64'")),
+        },
+        _ => {
+            return Err(String::from(
+                "Expected one of the fields 'value' and 'value_base64'",
+            ))
+        }
+    };
+
+    if value.is_empty() {
+        return Err(String::from("Invalid input: empty value"));
+    }
+
+    Ok(BulkOperation::Put {
+        key,
+        value,
+        content_type: text_field("content_type")?.unwrap_or_else(|| String::from(content_type)),
+    })
+}
+
+fn apply_bulk_operation(db: &mut dyn Store, operation: BulkOperation) -> io::Result<u16> {
+    match operation {
+        BulkOperation::Put {
+            key,
+            value,
+            content_type,
+        } => {
+            let key = key.into_bytes();
+            let existed = db.get_record(&key)?.is_some();
+            save_with_content_type(db, KeyValue::new(key, value), Some(&content_type))?;
+            Ok(if existed { 200 } else { 201 })
+        }
+        BulkOperation::Delete { key } => {
+            if db.get_record(key.as_bytes())?.is_none() {
+                return Ok(404);
+            }
+            db.delete_record(key.as_bytes())?;
+            Ok(200)
+        }
+    }
+}
+
+/// Lists keys in order, `limit` at a time, with only those starting with
+/// `prefix` and from `start` on. The value of each key comes along, encoded
+/// as `/_mget` does, with `values=true`. `next` is the `start` of the next
+/// page, already percent-encoded, or `null` on the last page.
+fn list_keys(request: &HttpRequest, db: &dyn Store) -> HttpResponse {
+    let (prefix, start, limit) = match (
+        decoded_param(request, "prefix"),
+        decoded_param(request, "start"),
+        page_limit(request),
+    ) {
+        (Ok(prefix), Ok(start), Ok(limit)) => (prefix, start, limit),
+        (Err(err), _, _) | (_, Err(err), _) | (_, _, Err(err)) => return err,
+    };
+    let with_values = match request.query_param("values") {
+        None | Some("false") => false,
+        Some("true") => true,
+        Some(value) => return HttpResponse::text(400, format!("Invalid values: {}", value)),
+    };
+
+    let page = match db.scan_keys(&prefix, &start, limit) {
+        Ok(page) => page,
+        Err(err) => return HttpResponse::text(500, err.to_string()),
+    };
+
+    let mut items = Vec::with_capacity(page.keys.len());
+    for key in page.keys {
+        let encoded_key = encode_key(&key);
+        if !with_values {
+            items.push(format!("{{{}}}", encoded_key));
+            continue;
+        }
+
+        match stored_value(db, key) {
+            Ok(Some((content_type, value))) => items.push(format!(
+                "{{{},\"content_type\":{},{}}}",
+                encoded_key,
+                serde_json::to_string(&content_type).unwrap(),
+                encode_value(&content_type, value)
+            )),
+            Ok(None) => {}
+            Err(err) => return HttpResponse::text(500, err.to_string()),
+        }
+    }
+
+    let next = match page.next {
+        Some(next) => serde_json::to_string(&percent_encode(&next)).unwrap(),
+        None => String::from("null"),
+    };
+    HttpResponse::json(
+        200,
+        format!("{{\"items\":[{}],\"next\":{}}}", items.join(","), next),
+    )
+}
+
+fn list_collections(db: &SharedStore) -> HttpResponse {
+    let names = db.lock().unwrap().list_collections();
+    HttpResponse::json(
+        200,
+        format!(
+            "{{\"collections\":{}}}",
+            serde_json::to_string(&names).unwrap()
+        ),
+    )
+}
+
+/// Answers `201` when the collection is new and `204` when it was there.
+fn create_collection(name: &str, db: &mut dyn Store) -> HttpResponse {
+    match db.create_collection(name) {
+        Ok(true) => HttpResponse::empty(201),
+        Ok(false) => HttpResponse::empty(204),
+        Err(err) => collection_error(err),
+    }
+}
+
+fn drop_collection(name: &str, db: &mut dyn Store) -> HttpResponse {
+    match db.drop_collection(name) {
+        Ok(true) => HttpResponse::empty(204),
+        Ok(false) => HttpResponse::text(404, format!("Collection not found: {}", name)),
+        Err(err) => collection_error(err),
+    }
+}
+
+fn list_indexes(db: &SharedStore) -> HttpResponse {
+    let indexes: Vec<String> = db
+        .lock()
+        .unwrap()
+        .list_indexes()
+        .into_iter()
+        .map(|(name, path)| {
+            format!(
+                "{{\"name\":{},\"path\":{}}}",
+                serde_json::to_string(&name).unwrap(),
+                serde_json::to_string(&path).unwrap()
+            )
+        })
+        .collect();
+    HttpResponse::json(200, format!("{{\"indexes\":[{}]}}", indexes.join(",")))
+}
+
+/// Answers `201` when the index is new and `204` when it was there.
+fn create_index(name: &str, request: &HttpRequest, db: &mut dyn Store) -> HttpResponse {
+    let path = match std::str::from_utf8(&request.body) {
+        Ok(path) => path.trim(),
+        Err(_) => return HttpResponse::text(400, String::from("Invalid JSON path")),
+    };
+
+    match db.create_index(name, path) {
+        Ok(true) => HttpResponse::empty(201),
+        Ok(false) => HttpResponse::empty(204),
+        Err(err) => collection_error(err),
+    }
+}
+
+fn drop_index(name: &str, db: &mut dyn Store) -> HttpResponse {
+    match db.drop_index(name) {
+        Ok(true) => HttpResponse::empty(204),
+        Ok(false) => HttpResponse::text(404, format!("Index not found: {}", name)),
+        Err(err) => collection_error(err),
+    }
+}
+
+/// Invalid names get a `400`, and what a sharded store does not support a
+/// `501`.
+fn collection_error(err: io::Error) -> HttpResponse {
+    match err.kind() {
+        ErrorKind::InvalidInput => HttpResponse::text(400, err.to_string()),
+        ErrorKind::Unsupported => HttpResponse::text(501, err.to_string()),
+        _ => storage_error(err),
+    }
+}
+
+/// The database, or the collection named in the `collection` parameter,
+/// which a follower replicates.
+fn replicated_store<'a>(
+    request: &HttpRequest,
+    db: &'a mut dyn Store,
+) -> Result<&'a mut dyn Store, HttpResponse> {
+    match request.query_param("collection") {
+        Some(name) => db
+            .collection_mut(name)
+            .ok_or_else(|| HttpResponse::text(404, format!("Collection not found: {}", name))),
+        None => Ok(db),
+    }
+}
+
+/// Every record, taken a page at a time so writes go on meanwhile, and the
+/// sequence of the last change before it was started. Changes made while it
+/// is taken may already be in it, which followers are fine with as they
+/// apply them again afterwards.
+fn snapshot(request: &HttpRequest, db: &SharedStore) -> HttpResponse {
+    let sequence = match replicated_store(request, &mut *db.lock().unwrap()) {
+        Ok(db) => db.last_sequence(),
+        Err(response) => return response,
+    };
+    let mut body = Vec::new();
+    let mut start = Vec::new();
+
+    loop {
+        let mut db = db.lock().unwrap();
+        let db = match replicated_store(request, &mut *db) {
+            Ok(db) => db,
+            Err(response) => return response,
+        };
+        let page = match db.scan_keys(b"", &start, MAX_PAGE_SIZE) {
+            Ok(page) => page,
+            Err(err) => return HttpResponse::text(500, err.to_string()),
+        };
+
+        for key in page.keys {
+            match db.get_record(&key) {
+                Ok(Some(record)) => body.extend_from_slice(&encode_record(&record)),
+                Ok(None) => {}
+                Err(err) => return HttpResponse::text(500, err.to_string()),
+            }
+        }
+
+        match page.next {
+            Some(next) => start = next,
+            None => break,
+        }
+    }
+
+    HttpResponse::new(200, "application/octet-stream", body)
+        .with_header(SEQUENCE_HEADER, &sequence.to_string())
+}
+
+/// Stops following the leader, taking writes from then on.
+fn promote(db: &SharedStore) -> HttpResponse {
+    let mut db = db.lock().unwrap();
+    if !db.is_read_only() {
+        return HttpResponse::text(409, String::from("Not a follower"));
+    }
+
+    db.set_read_only(false);
+    HttpResponse::text(200, format!("Promoted at sequence {}", db.last_sequence()))
+}
+
+/// Writes refused by a read-only follower are forbidden, other errors are
+/// failures of the storage.
+fn storage_error(err: io::Error) -> HttpResponse {
+    if let Some(violation) = schema_violation(&err) {
+        return HttpResponse::json(422, format!("{{{}}}", violation_fields(violation)));
+    }
+
+    match err.kind() {
+        ErrorKind::PermissionDenied => HttpResponse::text(403, String::from(READ_ONLY_MESSAGE)),
+        _ => HttpResponse::text(500, err.to_string()),
+    }
+}
+
+fn schema_violation(err: &io::Error) -> Option<&SchemaViolation> {
+    err.get_ref()
+        .and_then(|inner| inner.downcast_ref::<SchemaViolation>())
+}
+
+/// `"schema_version": 2, "errors": [{"path": "/age", "message": ...}]` as
+/// fields of a JSON object, where paths are JSON pointers into the value.
+fn violation_fields(violation: &SchemaViolation) -> String {
+    let errors: Vec<String> = violation
+        .errors
+        .iter()
+        .map(|error| {
+            format!(
+                "{{\"path\":{},\"message\":{}}}",
+                serde_json::to_string(&error.path).unwrap(),
+                serde_json::to_string(&error.message).unwrap()
+            )
+        })
+        .collect();
+    format!(
+        "\"schema_version\":{},\"errors\":[{}]",
+        violation.version,
+        errors.join(",")
+    )
+}
+
+/// Filter of the changes asked by `request`: those of keys starting with
+/// `prefix`, from the sequence after `Last-Event-ID` when an event stream
+/// resumes, or `from`. Without either, only changes still to come.
+fn changes_filter(request: &HttpRequest, db: &dyn Store) -> Result<ChangeFilter, HttpResponse> {
+    let prefix = decoded_param(request, "prefix")?;
+    let from = match (request.header("Last-Event-ID"), request.query_param("from")) {
+        (Some(id), _) => id.trim().parse().map(|id: u64| id + 1),
+        (None, Some(from)) => from.parse(),
+        (None, None) => Ok(db.last_sequence() + 1),
+    };
+
+    match from {
+        Ok(from) => Ok(ChangeFilter::from_sequence(from).with_prefix(prefix)),
+        Err(_) => Err(HttpResponse::text(
+            400,
+            String::from("Invalid sequence to start from"),
+        )),
+    }
+}
+
+fn changes_error(err: io::Error) -> HttpResponse {
+    let status_code = match err.kind() {
+        // the events asked for were dropped by compaction
+        ErrorKind::InvalidInput => 410,
+        ErrorKind::Unsupported => 501,
+        _ => 500,
+    };
+    HttpResponse::text(status_code, err.to_string())
+}
+
+/// Subscribes a follower from the sequence in `from`, or from the next one
+/// when missing.
+fn subscribe_replica(
+    request: &HttpRequest,
+    db: &SharedStore,
+) -> Result<Subscription<ReplicationEvent>, HttpResponse> {
+    let mut db = db.lock().unwrap();
+    let db = replicated_store(request, &mut *db)?;
+    let from = match request.query_param("from").map(str::parse) {
+        Some(Ok(from)) => from,
+        None => db.last_sequence() + 1,
+        Some(Err(_)) => {
+            return Err(HttpResponse::text(
+                400,
+                String::from("Invalid sequence to start from"),
+            ))
+        }
+    };
+    db.replicate(from).map_err(changes_error)
+}
+
+fn subscribe_changes(
+    request: &HttpRequest,
+    db: &SharedStore,
+) -> Result<Subscription, HttpResponse> {
+    let mut db = db.lock().unwrap();
+    let filter = changes_filter(request, &*db)?;
+    db.subscribe(filter).map_err(changes_error)
+}
+
+/// Answers the changes from `from` on, up to `limit`, waiting up to
+/// `timeout` seconds for one when there are none yet. `next` is the `from`
+/// of the following poll.
+fn poll_changes(request: &HttpRequest, db: &SharedStore) -> HttpResponse {
+    let limit = match page_limit(request) {
+        Ok(limit) => limit,
+        Err(response) => return response,
+    };
+    let timeout = match request.query_param("timeout").map(str::parse) {
+        None => DEFAULT_POLL_TIMEOUT,
+        Some(Ok(timeout)) if timeout <= MAX_POLL_TIMEOUT => timeout,
+        Some(_) => {
+            return HttpResponse::text(
+                400,
+                format!("Invalid timeout: expected 0 to {}", MAX_POLL_TIMEOUT),
+            )
+        }
+    };
+
+    let (filter, mut events) = {
+        let db = db.lock().unwrap();
+        let filter = match changes_filter(request, &*db) {
+            Ok(filter) => filter,
+            Err(response) => return response,
+        };
+        match db.changes(&filter, limit) {
+            Ok(events) => (filter, events),
+            Err(err) => return changes_error(err),
+        }
+    };
+
+    if events.is_empty() && timeout > 0 {
+        let mut subscription = match db.lock().unwrap().subscribe(filter.clone()) {
+            Ok(subscription) => subscription,
+            Err(err) => return changes_error(err),
+        };
+
+        let mut wait = Duration::from_secs(timeout);
+        while events.len() < limit {
+            match subscription.recv_timeout(wait) {
+                Ok(event) => events.push(event),
+                Err(_) => break,
+            }
+            // takes the changes already there along with the first one
+            wait = Duration::ZERO;
+        }
+    }
+
+    let next = events
+        .last()
+        .map_or(filter.from, |event| event.sequence + 1);
+    let events: Vec<String> = events.iter().map(event_json).collect();
+    HttpResponse::json(
+        200,
+        format!("{{\"events\":[{}],\"next\":{}}}", events.join(","), next),
+    )
+}
+
+/// Event in the format of `/_bulk` operations, with its sequence.
+fn event_json(event: &ChangeEvent) -> String {
+    match &event.value {
+        Some(value) => format!(
+            "{{\"sequence\":{},\"op\":\"put\",{},{}}}",
+            event.sequence,
+            encode_key(&event.key),
+            encode_value("application/json", value.clone())
+        ),
+        None => format!(
+            "{{\"sequence\":{},\"op\":\"delete\",{}}}",
+            event.sequence,
+            encode_key(&event.key)
+        ),
+    }
+}
+
+fn format_event(event: &ChangeEvent) -> Vec<u8> {
+    let op = match event.value {
+        Some(_) => "put",
+        None => "delete",
+    };
+    format!(
+        "id: {}\nevent: {}\ndata: {}\n\n",
+        event.sequence,
+        op,
+        event_json(event)
+    )
+    .into_bytes()
+}
+
+/// Percent-decoded value of the query parameter `name`, empty when missing.
+fn decoded_param(request: &HttpRequest, name: &str) -> Result<ByteString, HttpResponse> {
+    match request.query_param(name) {
+        Some(value) => percent_decode(value)
+            .ok_or_else(|| HttpResponse::text(400, format!("Invalid {}: {}", name, value))),
+        None => Ok(Vec::new()),
+    }
+}
+
+fn page_limit(request: &HttpRequest) -> Result<usize, HttpResponse> {
+    match request.query_param("limit").map(str::parse) {
+        None => Ok(DEFAULT_PAGE_SIZE),
+        Some(Ok(limit)) if limit > 0 && limit <= MAX_PAGE_SIZE => Ok(limit),
+        Some(_) => Err(HttpResponse::text(
+            400,
+            format!("Invalid limit: expected 1 to {}", MAX_PAGE_SIZE),
+        )),
+    }
+}
+
+/// `key` as a JSON field, or `key_base64` for keys that are not UTF-8.
+fn encode_key(key: &[u8]) -> String {
+    match std::str::from_utf8(key) {
+        Ok(key) => format!("\"key\":{}", serde_json::to_string(key).unwrap()),
+        Err(_) => format!("\"key_base64\":\"{}\"", base64_encode(key)),
+    }
+}
+
+/// Reads the keys of a `{"keys": [...]}` body at once. Values come back as
+/// `value` the way `/_bulk` takes them: JSON values as is, text as a string
+/// and other bytes as `value_base64`.
+fn mget(request: &HttpRequest, db: &SharedStore) -> HttpResponse {
+    let keys: Option<Vec<String>> = match serde_json::from_slice::<Value>(&request.body) {
+        Ok(Value::Object(mut obj)) => obj
+            .remove("keys")
+            .and_then(|keys| serde_json::from_value(keys).ok()),
+        _ => None,
+    };
+    let keys = match keys {
+        Some(keys) => keys,
+        None => {
+            return HttpResponse::text(
+                400,
+                String::from("Invalid input: expected {\"keys\": [...]} with string keys"),
+            )
+        }
+    };
+
+    let db = db.lock().unwrap();
+    let mut items = Vec::with_capacity(keys.len());
+    for key in keys {
+        let key_json = serde_json::to_string(&key).unwrap();
+        let item = match stored_value(&*db, key.into_bytes()) {
+            Ok(Some((content_type, value))) => format!(
+                "{{\"key\":{},\"status\":200,\"content_type\":{},{}}}",
+                key_json,
+                serde_json::to_string(&content_type).unwrap(),
+                encode_value(&content_type, value)
+            ),
+            Ok(None) => format!("{{\"key\":{},\"status\":404}}", key_json),
+            Err(err) => format!(
+                "{{\"key\":{},\"status\":500,\"error\":{}}}",
+                key_json,
+                serde_json::to_string(&err.to_string()).unwrap()
+            ),
+        };
+        items.push(item);
+    }
+
+    HttpResponse::json(200, format!("{{\"items\":[{}]}}", items.join(",")))
+}
+
+/// Runs the query in the body over the documents, as `Query` takes it, and
+/// answers one line of NDJSON per match: `{"key": ..., "value": ...}` with
+/// the document projected to the fields asked for. The servers stream these
+/// lines with `stream_query` instead; this answers a request routed on its
+/// own, with the body built whole.
+fn query(request: &HttpRequest, db: &SharedStore) -> HttpResponse {
+    let query = match Query::parse(&request.body) {
+        Ok(query) => query,
+        Err(err) => return HttpResponse::text(400, err.to_string()),
+    };
+
+    let mut body = Vec::new();
+    let result = query_batches(&query, db, |batch| {
+        body.extend_from_slice(&batch);
+        Ok(())
+    });
+    match result {
+        Ok(()) => HttpResponse::new(200, "application/x-ndjson", body),
+        Err(err) => HttpResponse::text(500, err.to_string()),
+    }
+}
+
+fn encode_value(content_type: &str, value: ByteString) -> String {
+    if content_type.starts_with("application/json")
+        && serde_json::from_slice::<Value>(&value).is_ok()
+    {
+        return format!("\"value\":{}", String::from_utf8(value).unwrap());
+    }
+
+    match String::from_utf8(value) {
+        Ok(text) => format!("\"value\":{}", serde_json::to_string(&text).unwrap()),
+        Err(err) => format!("\"value_base64\":\"{}\"", base64_encode(err.as_bytes())),
+    }
+}
+
+static BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let group = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for index in 0..4 {
+            match index <= chunk.len() {
+                true => {
+                    let sextet = (group >> (18 - 6 * index)) & 0x3f;
+                    encoded.push(BASE64_ALPHABET[sextet as usize] as char);
+                }
+                false => encoded.push('='),
+            }
+        }
+    }
+    encoded
+}
+
+fn base64_decode(encoded: &str) -> Option<ByteString> {
+    let encoded = encoded.as_bytes();
+    if !encoded.len().is_multiple_of(4) {
+        return None;
+    }
+
+    let groups = encoded.len() / 4;
+    let mut decoded = Vec::with_capacity(groups * 3);
+    for (index, chunk) in encoded.chunks(4).enumerate() {
+        // only the last group may be padded
+        let padding = chunk.iter().rev().take_while(|b| **b == b'=').count();
+        if padding > 2 || (padding > 0 && index + 1 < groups) {
+            return None;
+        }
+
+        let mut group = 0u32;
+        for byte in &chunk[..4 - padding] {
+            let sextet = BASE64_ALPHABET.iter().position(|b| b == byte)?;
+            group = (group << 6) | sextet as u32;
+        }
+        group <<= 6 * padding;
+
+        decoded.extend_from_slice(&group.to_be_bytes()[1..4 - padding]);
+    }
+    Some(decoded)
+}
+
+fn read_content(request: &HttpRequest, db: &SharedStore) -> HttpResponse {
+    let key = match get_key(&request.body) {
+        Ok(v) => v,
+        Err(err) => return HttpResponse::text(400, err),
+    };
+
+    match read_value(key.into_bytes(), &*db.lock().unwrap()) {
+        Ok(Some(response)) => response,
+        Ok(None) => HttpResponse::empty(204),
+        Err(err) => HttpResponse::text(500, err.to_string()),
+    }
+}
+
+fn delete_content(request: &HttpRequest, db: &SharedStore) -> HttpResponse {
+    let key = match get_key(&request.body) {
+        Ok(v) => v,
+        Err(err) => return HttpResponse::text(400, err),
+    };
+
+    match db.lock().unwrap().delete_record(key.as_bytes()) {
+        Ok(_) => HttpResponse::empty(200),
+        Err(err) => storage_error(err),
+    }
+}
+
+fn update_content(request: &HttpRequest, db: &SharedStore) -> HttpResponse {
+    let key = match get_document_key(&request.body) {
+        Ok(v) => v,
+        Err(err) => return HttpResponse::text(400, err),
+    };
+
+    match save_value(key.into_bytes(), request, &mut *db.lock().unwrap()) {
+        Ok(_) => HttpResponse::empty(200),
+        Err(err) => storage_error(err),
+    }
+}
+
+fn get_key(content: &[u8]) -> Result<String, String> {
+    let json_content: Value = match serde_json::from_slice(content) {
+        Ok(value) => value,
+        Err(error) => return Err(error.to_string()),
+    };
+
+    let key = match json_content {
+        Value::Null => return Err(String::from("Invalid input: null")),
+        Value::Bool(_) => return Err(String::from("Invalid input: boolean")),
+        Value::Object(obj) if !obj.contains_key("id") => {
+            return Err(String::from("Missing field 'id'"))
+        }
+        Value::Array(_) => return Err(String::from("Invalid input: array")),
+        Value::Number(id) => id.to_string(),
+        Value::String(id) => id,
+        Value::Object(obj) => obj["id"].to_string().replace("\"", ""),
+    };
+
+    Ok(key)
+}
+
+/// Key of a document to store, which must be an object with an `id` and
+/// other fields. The document itself is stored as sent.
+fn get_document_key(content: &[u8]) -> Result<String, String> {
+    let key = get_key(content)?;
+
+    match serde_json::from_slice(content) {
+        Ok(Value::Object(obj)) if obj.keys().len() > 1 => Ok(key),
+        Ok(Value::Object(_)) => Err(String::from(
+            "Invalid input: Missing fields different from id",
+        )),
+        Ok(_) => Err(String::from("Invalid input: Missing value")),
+        Err(error) => Err(error.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustdb::{FaultyVfs, MemoryVfs, Options};
+
+    fn memory_db() -> SharedStore {
+        memory_db_with_change_log(100_000)
+    }
+
+    fn memory_db_with_change_log(change_log_size: u64) -> Arc<Mutex<RustDB>> {
+        let options = Options {
+            change_log_size,
+            ..Options::default()
+        };
+        let db = RustDB::load_with_vfs("storage", options, Arc::new(MemoryVfs::new()));
+        Arc::new(Mutex::new(db))
+    }
+
+    fn start_server(workers: usize, queue_size: usize) -> TestServer {
+        start_server_with_db(memory_db(), workers, queue_size)
+    }
+
+    fn start_server_with_db(db: SharedStore, workers: usize, queue_size: usize) -> TestServer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let config = ServerConfig {
+            mode: ServerMode::Threads,
+            max_body_size: 1_000,
+            workers,
+            queue_size,
+            keep_alive_timeout: Duration::from_secs(5),
+            request_timeout: Duration::from_secs(5),
+        };
+
+        thread::spawn(move || serve(listener, db, config));
+
+        TestServer(address)
+    }
+
+    struct TestServer(std::net::SocketAddr);
+
+    impl TestServer {
+        fn connect(&self) -> BufReader<TcpStream> {
+            let stream = TcpStream::connect(self.0).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            BufReader::new(stream)
+        }
+    }
+
+    /// Reads a response as `(status code, headers, body)`.
+    fn read_response(stream: &mut BufReader<TcpStream>) -> (u16, String, Vec<u8>) {
+        let mut head = String::new();
+        loop {
+            let mut line = String::new();
+            stream.read_line(&mut line).unwrap();
+            if line == "\r\n" || line.is_empty() {
+                break;
+            }
+            head.push_str(&line);
+        }
+
+        let status_code = head[9..12].parse().unwrap();
+        let length = head
+            .lines()
+            .find_map(|line| line.strip_prefix("Content-Length: "))
+            .map(|length| length.parse().unwrap())
+            .unwrap_or(0);
+
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).unwrap();
+        if head.contains("Transfer-Encoding: chunked") {
+            body = read_chunks(stream);
+        }
+
+        (status_code, head, body)
+    }
+
+    fn read_chunks(stream: &mut BufReader<TcpStream>) -> Vec<u8> {
+        let mut body = Vec::new();
+        loop {
+            let mut size = String::new();
+            stream.read_line(&mut size).unwrap();
+            let size = usize::from_str_radix(size.trim_end(), 16).unwrap();
+            let mut data = vec![0; size + 2];
+            stream.read_exact(&mut data).unwrap();
+            assert!(data.ends_with(b"\r\n"));
+            if size == 0 {
+                return body;
+            }
+            body.extend_from_slice(&data[..size]);
+        }
+    }
+
+    /// A store of `count` documents `{"n": ...}` under keys `k0000`, ...
+    fn numbered_documents(count: usize) -> SharedStore {
+        let db = memory_db();
+        for n in 0..count {
+            let document = format!("{{\"n\":{}}}", n);
+            let target = format!("/keys/k{:04}", n);
+            route(&request("PUT", &target, document.as_bytes()), &db);
+        }
+        db
+    }
+
+    /// Sends queries with matches, without any and invalid, then a request
+    /// on the same connection, and checks both answers.
+    fn stream_query_then_keep_alive(server: &TestServer) {
+        let mut stream = server.connect();
+        stream
+            .get_mut()
+            .write_all(
+                b"POST /_query HTTP/1.1\r\nContent-Length: 40\r\n\r\n\
+                  {\"filter\":{\"n\":{\"$gte\":1001}},\"limit\":3}\
+                  POST /_query HTTP/1.1\r\nContent-Length: 19\r\n\r\n{\"filter\":{\"n\":-1}}\
+                  POST /_query HTTP/1.1\r\nContent-Length: 1\r\n\r\n[\
+                  GET /keys/k0001 HTTP/1.1\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+
+        let (status_code, head, body) = read_response(&mut stream);
+        assert_eq!(status_code, 200);
+        assert!(head.contains("Content-Type: application/x-ndjson"));
+        assert!(!head.contains("Content-Length"));
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            "{\"key\":\"k1001\",\"value\":{\"n\":1001}}\n\
+             {\"key\":\"k1002\",\"value\":{\"n\":1002}}\n\
+             {\"key\":\"k1003\",\"value\":{\"n\":1003}}\n"
+        );
+        let (status_code, head, body) = read_response(&mut stream);
+        assert_eq!(status_code, 200);
+        assert!(head.contains("Transfer-Encoding: chunked"));
+        assert!(body.is_empty());
+        assert_eq!(read_response(&mut stream).0, 400);
+        let (status_code, _, body) = read_response(&mut stream);
+        assert_eq!(status_code, 200);
+        assert_eq!(body, b"{\"n\":1}".to_vec());
+    }
+
+    #[test]
+    fn stream_query_matches_in_chunks() {
+        // arrange
+        let db = numbered_documents(1_200);
+        let server = start_server_with_db(db, 2, 2);
+        let mut stream = server.connect();
+
+        // act
+        stream
+            .get_mut()
+            .write_all(b"POST /_query HTTP/1.1\r\nContent-Length: 2\r\n\r\n{}")
+            .unwrap();
+        let (status_code, head, body) = read_response(&mut stream);
+
+        // assert
+        assert_eq!(status_code, 200);
+        assert!(head.contains("Transfer-Encoding: chunked"));
+        let lines: Vec<Value> = body
+            .split(|byte| *byte == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 1_200);
+        assert_eq!(lines[1_199]["value"]["n"], 1_199);
+        stream_query_then_keep_alive(&server);
+    }
+
+    #[test]
+    fn serve_pipelined_requests_on_one_connection() {
+        // arrange
+        let server = start_server(2, 2);
+        let mut stream = server.connect();
+
+        // act
+        stream
+            .get_mut()
+            .write_all(
+                b"PUT /keys/a HTTP/1.1\r\nContent-Length: 1\r\n\r\n1\
+                  GET /keys/a HTTP/1.1\r\n\r\n\
+                  GET /keys/b HTTP/1.1\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+
+        // assert
+        let (put, put_head, _) = read_response(&mut stream);
+        let (get, _, body) = read_response(&mut stream);
+        let (missing, missing_head, _) = read_response(&mut stream);
+
+        assert_eq!(put, 204);
+        assert!(put_head.contains("Connection: keep-alive"));
+        assert_eq!(get, 200);
+        assert_eq!(body, b"1".to_vec());
+        assert_eq!(missing, 404);
+        assert!(missing_head.contains("Connection: close"));
+
+        let mut rest = Vec::new();
+        assert_eq!(stream.read_to_end(&mut rest).unwrap(), 0);
+    }
+
+    #[test]
+    fn close_connection_after_invalid_request() {
+        // arrange
+        let server = start_server(1, 1);
+        let mut stream = server.connect();
+
+        // act
+        stream
+            .get_mut()
+            .write_all(b"PUT /keys/a HTTP/1.1\r\nContent-Length: 5000\r\n\r\n")
+            .unwrap();
+
+        // assert
+        let (status_code, head, _) = read_response(&mut stream);
+        assert_eq!(status_code, 413);
+        assert!(head.contains("Connection: close"));
+    }
+
+    #[test]
+    fn refuse_connections_when_saturated() {
+        // arrange
+        let server = start_server(1, 1);
+        let mut busy = server.connect();
+        busy.get_mut()
+            .write_all(b"GET /keys/a HTTP/1.1\r\n\r\n")
+            .unwrap();
+        assert_eq!(read_response(&mut busy).0, 404);
+        let _queued = server.connect();
+
+        // act
+        let mut refused = server.connect();
+
+        // assert
+        let (status_code, head, _) = read_response(&mut refused);
+        assert_eq!(status_code, 503);
+        assert!(head.contains("Retry-After: 1"));
+    }
+
+    fn request(method: &str, target: &str, body: &[u8]) -> HttpRequest {
+        HttpRequest {
+            method: String::from(method),
+            target: String::from(target),
+            version: String::from("HTTP/1.1"),
+            headers: Vec::new(),
+            body: body.to_vec(),
+        }
+    }
+
+    #[test]
+    fn put_get_and_delete_by_key() {
+        // arrange
+        let db = memory_db();
+
+        // act
+        let put = route(&request("PUT", "/keys/a%2Fb%00", b"raw value"), &db);
+        let get = route(&request("GET", "/keys/a%2Fb%00", b""), &db);
+        let delete = route(&request("DELETE", "/keys/a%2Fb%00", b""), &db);
+        let missing = route(&request("GET", "/keys/a%2Fb%00", b""), &db);
+
+        // assert
+        assert_eq!(put.status_code, 204);
+        assert_eq!(get.status_code, 200);
+        assert_eq!(get.body, b"raw value".to_vec());
+        assert_eq!(delete.status_code, 204);
+        assert_eq!(missing.status_code, 404);
+
+        let key = b"a/b\0".to_vec();
+        assert!(db.lock().unwrap().get_record(&key).unwrap().is_none());
+    }
+
+    #[test]
+    fn key_routes_share_records_with_body_routes() {
+        // arrange
+        let db = memory_db();
+        route(
+            &request("POST", "/", b"{\"id\":\"7\",\"name\":\"test\"}"),
+            &db,
+        );
+
+        // act
+        let get = route(&request("GET", "/keys/7", b""), &db);
+
+        // assert
+        assert_eq!(get.status_code, 200);
+        assert_eq!(get.body, b"{\"id\":\"7\",\"name\":\"test\"}".to_vec());
+        assert!(get.headers.contains(&(
+            String::from("Content-Type"),
+            String::from("application/json")
+        )));
+    }
+
+    fn typed_request(method: &str, target: &str, body: &[u8], content_type: &str) -> HttpRequest {
+        let mut request = request(method, target, body);
+        request
+            .headers
+            .push((String::from("Content-Type"), String::from(content_type)));
+        request
+    }
+
+    fn content_type(response: &HttpResponse) -> Option<&str> {
+        response
+            .headers
+            .iter()
+            .find(|(name, _)| name == "Content-Type")
+            .map(|(_, value)| value.as_str())
+    }
+
+    #[test]
+    fn return_values_verbatim_with_their_content_type() {
+        // arrange
+        let db = memory_db();
+        let document = b"{\"name\":\"test\",  \"id\":\"7\"}";
+        let binary = [0xff, 0x00, 0xfe, b'\n'];
+        let json_bytes = b"{\"id\":1}";
+
+        // act
+        route(
+            &typed_request("PUT", "/", document, "application/json; charset=utf-8"),
+            &db,
+        );
+        route(
+            &typed_request("PUT", "/keys/bin", &binary, "application/octet-stream"),
+            &db,
+        );
+        route(
+            &typed_request("PUT", "/keys/raw", json_bytes, "application/octet-stream"),
+            &db,
+        );
+        let get_document = route(&request("GET", "/", b"\"7\""), &db);
+        let get_binary = route(&request("GET", "/keys/bin", b""), &db);
+        let get_raw = route(&request("GET", "/keys/raw", b""), &db);
+
+        // assert
+        assert_eq!(get_document.body, document.to_vec());
+        assert_eq!(
+            content_type(&get_document),
+            Some("application/json; charset=utf-8")
+        );
+        assert_eq!(get_binary.body, binary.to_vec());
+        assert_eq!(content_type(&get_binary), Some("application/octet-stream"));
+        assert_eq!(get_raw.body, json_bytes.to_vec());
+        assert_eq!(content_type(&get_raw), Some("application/octet-stream"));
+    }
+
+    #[test]
+    fn put_the_previous_value_back_when_its_content_type_fails() {
+        // arrange
+        let vfs = FaultyVfs::new(1);
+        let db = RustDB::load_with_vfs("storage", Options::default(), Arc::new(vfs.clone()));
+        let db: SharedStore = Arc::new(Mutex::new(db));
+        route(&typed_request("PUT", "/keys/a", b"old", "text/plain"), &db);
+
+        // act
+        // a record takes one write, so that of its content type fails
+        vfs.fail_one_write_after(1);
+        let put = route(
+            &typed_request("PUT", "/keys/a", b"{}", "application/json"),
+            &db,
+        );
+        vfs.fail_one_write_after(1);
+        let put_new = route(
+            &typed_request("PUT", "/keys/b", b"{}", "application/json"),
+            &db,
+        );
+        let get = route(&request("GET", "/keys/a", b""), &db);
+        let get_new = route(&request("GET", "/keys/b", b""), &db);
+
+        // assert
+        assert_eq!(put.status_code, 500);
+        assert_eq!(put_new.status_code, 500);
+        assert_eq!(get.body, b"old".to_vec());
+        assert_eq!(content_type(&get), Some("text/plain"));
+        assert_eq!(get_new.status_code, 404);
+    }
+
+    #[test]
+    fn apply_bulk_operations_and_read_them_back() {
+        // arrange
+        let db = memory_db();
+        route(&request("PUT", "/keys/c", b"old"), &db);
+        let body = b"{\"op\":\"put\",\"key\":\"a\",\"value\":{\"z\":1, \"id\":\"a\"}}\r\n\
+            \n\
+            {\"op\":\"put\",\"key\":\"b\",\"value_base64\":\"/wB7\"}\n\
+            {\"op\":\"put\",\"key\":\"c\",\"value\":\"text\"}\n\
+            {\"op\":\"delete\",\"key\":\"d\"}\n";
+
+        // act
+        let bulk = route(&request("POST", "/_bulk", body), &db);
+        let mget = route(
+            &request("POST", "/_mget", b"{\"keys\":[\"a\",\"b\",\"c\",\"d\"]}"),
+            &db,
+        );
+        let get = route(&request("GET", "/keys/b", b""), &db);
+
+        // assert
+        assert_eq!(bulk.status_code, 200);
+        assert_eq!(
+            String::from_utf8(bulk.body).unwrap(),
+            "{\"errors\":false,\"items\":[{\"key\":\"a\",\"status\":201},\
+            {\"key\":\"b\",\"status\":201},{\"key\":\"c\",\"status\":200},\
+            {\"key\":\"d\",\"status\":404}]}"
+        );
+        assert_eq!(
+            String::from_utf8(mget.body).unwrap(),
+            "{\"items\":[\
+            {\"key\":\"a\",\"status\":200,\"content_type\":\"application/json\",\"value\":{\"z\":1, \"id\":\"a\"}},\
+            {\"key\":\"b\",\"status\":200,\"content_type\":\"application/octet-stream\",\"value_base64\":\"/wB7\"},\
+            {\"key\":\"c\",\"status\":200,\"content_type\":\"text/plain; charset=utf-8\",\"value\":\"text\"},\
+            {\"key\":\"d\",\"status\":404}]}"
+        );
+        assert_eq!(get.body, vec![0xff, 0x00, b'{']);
+    }
+
+    #[test]
+    fn reject_whole_bulk_with_an_invalid_operation() {
+        let db = memory_db();
+        let body = b"{\"op\":\"put\",\"key\":\"a\",\"value\":1}\n{\"op\":\"put\",\"key\":\"b\"}\n";
+
+        let bulk = route(&request("POST", "/_bulk", body), &db);
+
+        assert_eq!(bulk.status_code, 400);
+        assert!(String::from_utf8(bulk.body).unwrap().contains("line 2"));
+        assert!(db.lock().unwrap().get_record(b"a").unwrap().is_none());
+        assert_eq!(
+            route(&request("POST", "/_bulk", b"\n"), &db).status_code,
+            400
+        );
+        assert_eq!(route(&request("GET", "/_mget", b""), &db).status_code, 405);
+        assert_eq!(
+            route(&request("POST", "/_mget", b"{\"keys\":[1]}"), &db).status_code,
+            400
+        );
+        assert_eq!(
+            route(&request("POST", "/_mget", b"{}"), &db).status_code,
+            400
+        );
+    }
+
+    #[test]
+    fn list_keys_by_page() {
+        // arrange
+        let db = memory_db();
+        for key in ["user/1", "user/2", "user/3", "order/1", "user%2F4"] {
+            route(
+                &request("PUT", &format!("/keys/{}", key), b"{\"n\":1}"),
+                &db,
+            );
+        }
+        route(&request("PUT", "/keys/user%2F%FF", b"\xff"), &db);
+        route(&request("DELETE", "/keys/user%2F3", b""), &db);
+
+        // act
+        let first = route(&request("GET", "/keys?prefix=user%2F&limit=2", b""), &db);
+        let second = route(
+            &request(
+                "GET",
+                "/keys?prefix=user%2F&limit=2&start=user%2F4&values=true",
+                b"",
+            ),
+            &db,
+        );
+        let all = route(&request("GET", "/keys", b""), &db);
+
+        // assert
+        assert_eq!(
+            String::from_utf8(first.body).unwrap(),
+            "{\"items\":[{\"key\":\"user/1\"},{\"key\":\"user/2\"}],\"next\":\"user%2F4\"}"
+        );
+        assert_eq!(
+            String::from_utf8(second.body).unwrap(),
+            "{\"items\":[\
+            {\"key\":\"user/4\",\"content_type\":\"application/json\",\"value\":{\"n\":1}},\
+            {\"key_base64\":\"dXNlci//\",\"content_type\":\"application/octet-stream\",\"value_base64\":\"/w==\"}],\
+            \"next\":null}"
+        );
+        assert!(String::from_utf8(all.body)
+            .unwrap()
+            .starts_with("{\"items\":[{\"key\":\"order/1\"},{\"key\":\"user/1\"}"));
+        assert_eq!(
+            route(&request("GET", "/keys?limit=0", b""), &db).status_code,
+            400
+        );
+        assert_eq!(
+            route(&request("GET", "/keys?prefix=%zz", b""), &db).status_code,
+            400
+        );
+        assert_eq!(route(&request("PUT", "/keys", b""), &db).status_code, 405);
+    }
+
+    #[test]
+    fn poll_changes_from_a_sequence() {
+        // arrange
+        let db = memory_db();
+        route(&request("PUT", "/keys/a", b"{\"n\":1}"), &db);
+        route(&request("PUT", "/keys/b%2F1", b"text"), &db);
+        route(&request("DELETE", "/keys/a", b""), &db);
+
+        // act
+        let all = route(&request("GET", "/changes?from=1&timeout=0", b""), &db);
+        let filtered = route(
+            &request("GET", "/changes?from=1&prefix=b%2F&timeout=0", b""),
+            &db,
+        );
+        let writer_db = Arc::clone(&db);
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            route(&request("PUT", "/keys/c", b"\xff"), &writer_db);
+        });
+        let waited = route(&request("GET", "/changes?timeout=5", b""), &db);
+        writer.join().unwrap();
+
+        // assert
+        assert_eq!(
+            String::from_utf8(all.body).unwrap(),
+            "{\"events\":[\
+            {\"sequence\":1,\"op\":\"put\",\"key\":\"a\",\"value\":{\"n\":1}},\
+            {\"sequence\":2,\"op\":\"put\",\"key\":\"b/1\",\"value\":\"text\"},\
+            {\"sequence\":3,\"op\":\"delete\",\"key\":\"a\"}],\"next\":4}"
+        );
+        assert_eq!(
+            String::from_utf8(filtered.body).unwrap(),
+            "{\"events\":[\
+            {\"sequence\":2,\"op\":\"put\",\"key\":\"b/1\",\"value\":\"text\"}],\"next\":3}"
+        );
+        assert_eq!(
+            String::from_utf8(waited.body).unwrap(),
+            "{\"events\":[\
+            {\"sequence\":4,\"op\":\"put\",\"key\":\"c\",\"value_base64\":\"/w==\"}],\"next\":5}"
+        );
+        assert_eq!(
+            route(&request("GET", "/changes?from=x", b""), &db).status_code,
+            400
+        );
+    }
+
+    fn wait_for_record(db: &Arc<Mutex<RustDB>>, key: &[u8], value: Option<&[u8]>) {
+        for _ in 0..100 {
+            let record = db.lock().unwrap().get_record(key).unwrap();
+            if record.as_ref().map(|record| record.value.as_slice()) == value {
+                return;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        panic!("Record not replicated: {}", String::from_utf8_lossy(key));
+    }
+
+    #[test]
+    fn read_and_write_keys_of_collections() {
+        // arrange
+        let db = memory_db();
+        route(&request("PUT", "/keys/a", b"root"), &db);
+
+        // act
+        let created = route(&request("PUT", "/c/users", b""), &db);
+        let existing = route(&request("PUT", "/c/users", b""), &db);
+        let invalid = route(&request("PUT", "/c/a.b", b""), &db);
+        let put = route(&request("PUT", "/c/users/keys/a", b"user"), &db);
+        let get = route(&request("GET", "/c/users/keys/a", b""), &db);
+        let list = route(&request("GET", "/c/users/keys?values=true", b""), &db);
+        let names = route(&request("GET", "/c", b""), &db);
+        let root = route(&request("GET", "/keys/a", b""), &db);
+        let dropped = route(&request("DELETE", "/c/users", b""), &db);
+        let missing = route(&request("GET", "/c/users/keys/a", b""), &db);
+
+        // assert
+        assert_eq!(created.status_code, 201);
+        assert_eq!(existing.status_code, 204);
+        assert_eq!(invalid.status_code, 400);
+        assert_eq!(put.status_code, 204);
+        assert_eq!(get.body, b"user".to_vec());
+        assert_eq!(
+            String::from_utf8(list.body).unwrap(),
+            "{\"items\":[{\"key\":\"a\",\"content_type\":\"application/octet-stream\",\"value\":\"user\"}],\"next\":null}"
+        );
+        assert_eq!(names.body, b"{\"collections\":[\"users\"]}".to_vec());
+        assert_eq!(root.body, b"root".to_vec());
+        assert_eq!(dropped.status_code, 204);
+        assert_eq!(missing.status_code, 404);
+    }
+
+    #[test]
+    fn find_documents_through_an_index() {
+        // arrange
+        let db = memory_db();
+        for (id, city) in [("1", "Porto"), ("2", "São Paulo"), ("3", "Porto")] {
+            let document = format!(
+                "{{\"id\":\"{}\",\"address\":{{\"city\":\"{}\"}}}}",
+                id, city
+            );
+            route(&request("POST", "/", document.as_bytes()), &db);
+        }
+
+        // act
+        let created = route(&request("PUT", "/index/city", b"address.city"), &db);
+        let invalid = route(&request("PUT", "/index/bad", b"address."), &db);
+        route(&request("DELETE", "/keys/3", b""), &db);
+        let found = route(&request("GET", "/index/city/S%C3%A3o%20Paulo", b""), &db);
+        let porto = route(&request("GET", "/index/city/Porto", b""), &db);
+        let listed = route(&request("GET", "/index", b""), &db);
+        let dropped = route(&request("DELETE", "/index/city", b""), &db);
+        let missing = route(&request("GET", "/index/city/Porto", b""), &db);
+
+        // assert
+        assert_eq!(created.status_code, 201);
+        assert_eq!(invalid.status_code, 400);
+        let found: Value = serde_json::from_slice(&found.body).unwrap();
+        assert_eq!(found["items"][0]["key"], "2");
+        assert_eq!(found["items"][0]["value"]["address"]["city"], "São Paulo");
+        let porto: Value = serde_json::from_slice(&porto.body).unwrap();
+        assert_eq!(porto["items"].as_array().unwrap().len(), 1);
+        assert_eq!(
+            listed.body,
+            b"{\"indexes\":[{\"name\":\"city\",\"path\":\"address.city\"}]}".to_vec()
+        );
+        assert_eq!(dropped.status_code, 204);
+        assert_eq!(missing.status_code, 404);
+    }
+
+    #[test]
+    fn query_documents_as_ndjson() {
+        // arrange
+        let db = memory_db();
+        for line in [
+            "1,Baker,Lana_Walter951@famism.biz,Lana Walter",
+            "2,Investment Advisor,Adina_Holmes6609@fuliss.net,Adina Holmes",
+            "3,Baker,Daron_Watson256@bungar.biz,Daron Watson",
+        ] {
+            let fields: Vec<&str> = line.split(',').collect();
+            let document = serde_json::json!({
+                "id": fields[0], "job_title": fields[1], "email": fields[2], "name": fields[3]
+            });
+            route(&request("POST", "/", document.to_string().as_bytes()), &db);
+        }
+
+        // act
+        let bakers = route(
+            &request(
+                "POST",
+                "/_query",
+                br#"{"filter":{"job_title":"Baker"},"fields":["name"],"sort":["-name"]}"#,
+            ),
+            &db,
+        );
+        let invalid = route(
+            &request("POST", "/_query", br#"{"filter":{"id":{"$near":1}}}"#),
+            &db,
+        );
+        let not_allowed = route(&request("GET", "/_query", b""), &db);
+
+        // assert
+        assert_eq!(bakers.status_code, 200);
+        assert_eq!(content_type(&bakers), Some("application/x-ndjson"));
+        assert_eq!(
+            String::from_utf8(bakers.body).unwrap(),
+            "{\"key\":\"1\",\"value\":{\"name\":\"Lana Walter\"}}\n\
+             {\"key\":\"3\",\"value\":{\"name\":\"Daron Watson\"}}\n"
+        );
+        assert_eq!(invalid.status_code, 400);
+        assert_eq!(not_allowed.status_code, 405);
+    }
+
+    #[test]
+    fn patch_stored_documents() {
+        // arrange
+        let db = memory_db();
+        route(
+            &request(
+                "PUT",
+                "/keys/1",
+                br#"{"name":"Lana","address":{"city":"Porto","zip":"4000"}}"#,
+            ),
+            &db,
+        );
+        route(&request("PUT", "/keys/text", b"plain text"), &db);
+        let merge = "application/merge-patch+json";
+        let json_patch = "application/json-patch+json";
+
+        // act
+        let merged = route(
+            &typed_request(
+                "PATCH",
+                "/keys/1",
+                br#"{"address":{"zip":null},"job":"Baker"}"#,
+                merge,
+            ),
+            &db,
+        );
+        let patched = route(
+            &typed_request(
+                "PATCH",
+                "/keys/1",
+                br#"[{"op":"test","path":"/job","value":"Baker"},{"op":"replace","path":"/name","value":"Lana Walter"}]"#,
+                json_patch,
+            ),
+            &db,
+        );
+        let failed = route(
+            &typed_request(
+                "PATCH",
+                "/keys/1",
+                br#"[{"op":"replace","path":"/name","value":"Sara"},{"op":"test","path":"/job","value":"Cook"}]"#,
+                json_patch,
+            ),
+            &db,
+        );
+        let stored = route(&request("GET", "/keys/1", b""), &db);
+        let unsupported = route(
+            &typed_request("PATCH", "/keys/1", b"{}", "application/json"),
+            &db,
+        );
+        let invalid = route(
+            &typed_request("PATCH", "/keys/1", b"{\"op\"}", json_patch),
+            &db,
+        );
+        let missing = route(&typed_request("PATCH", "/keys/2", b"{}", merge), &db);
+        let not_json = route(&typed_request("PATCH", "/keys/text", b"{}", merge), &db);
+
+        // assert
+        assert_eq!(merged.status_code, 200);
+        assert_eq!(
+            serde_json::from_slice::<Value>(&merged.body).unwrap(),
+            serde_json::json!({"name":"Lana","address":{"city":"Porto"},"job":"Baker"})
+        );
+        assert_eq!(patched.status_code, 200);
+        assert_eq!(failed.status_code, 409);
+        assert_eq!(
+            serde_json::from_slice::<Value>(&stored.body).unwrap(),
+            serde_json::json!({"name":"Lana Walter","address":{"city":"Porto"},"job":"Baker"})
+        );
+        assert_eq!(unsupported.status_code, 415);
+        assert_eq!(invalid.status_code, 400);
+        assert_eq!(missing.status_code, 404);
+        assert_eq!(not_json.status_code, 409);
+    }
+
+    #[test]
+    fn increment_counters() {
+        // arrange
+        let db = memory_db();
+        route(&request("PUT", "/keys/text", b"abc"), &db);
+        route(
+            &request("PUT", "/keys/max", i64::MAX.to_string().as_bytes()),
+            &db,
+        );
+
+        // act
+        let first = route(&request("POST", "/keys/visits", b""), &db);
+        let added = route(&request("POST", "/keys/visits?delta=10", b""), &db);
+        let subtracted = route(&request("POST", "/keys/visits?delta=-20", b""), &db);
+        let stored = route(&request("GET", "/keys/visits", b""), &db);
+        let text = route(&request("POST", "/keys/text", b""), &db);
+        let overflow = route(&request("POST", "/keys/max", b""), &db);
+        let invalid = route(&request("POST", "/keys/visits?delta=x", b""), &db);
+
+        // assert
+        assert_eq!(first.body, b"1".to_vec());
+        assert_eq!(added.body, b"11".to_vec());
+        assert_eq!(subtracted.status_code, 200);
+        assert_eq!(subtracted.body, b"-9".to_vec());
+        assert_eq!(stored.body, b"-9".to_vec());
+        assert_eq!(content_type(&stored), Some("application/json"));
+        assert_eq!(text.status_code, 409);
+        assert_eq!(overflow.status_code, 409);
+        assert_eq!(invalid.status_code, 400);
+    }
+
+    #[test]
+    fn validate_writes_against_versioned_schemas() {
+        // arrange
+        let db = memory_db();
+        route(&request("PUT", "/c/users", b""), &db);
+        let schema = br#"{"type":"object","required":["id","name"],
+            "properties":{"age":{"type":"integer","minimum":0}}}"#;
+
+        // act
+        let created = route(&request("PUT", "/_schema", schema), &db);
+        let unsupported = route(&request("PUT", "/_schema", br#"{"pattern":"^a"}"#), &db);
+        let valid = route(&request("POST", "/", br#"{"id":"1","name":"Lana"}"#), &db);
+        let invalid = route(&request("PUT", "/keys/2", br#"{"id":"2","age":-1}"#), &db);
+        let bulk = route(
+            &request(
+                "POST",
+                "/_bulk",
+                b"{\"op\":\"put\",\"key\":\"3\",\"value\":\"text\"}\n\
+                  {\"op\":\"put\",\"key\":\"4\",\"value\":{\"id\":\"4\",\"name\":\"Sara\"}}",
+            ),
+            &db,
+        );
+        let patched = route(
+            &typed_request(
+                "PATCH",
+                "/keys/1",
+                br#"{"name":null}"#,
+                "application/merge-patch+json",
+            ),
+            &db,
+        );
+        let in_collection = route(&request("PUT", "/c/users/keys/2", b"any value"), &db);
+        let current = route(&request("GET", "/_schema", b""), &db);
+        let removed = route(&request("DELETE", "/_schema", b""), &db);
+        let unvalidated = route(&request("PUT", "/keys/2", b"any value"), &db);
+        let versions = route(&request("GET", "/_schema/versions", b""), &db);
+        let first = route(&request("GET", "/_schema/1", b""), &db);
+        let missing = route(&request("GET", "/_schema", b""), &db);
+        let collection = route(
+            &request("PUT", "/c/users/_schema", b"{\"type\":\"string\"}"),
+            &db,
+        );
+
+        // assert
+        assert_eq!(created.status_code, 201);
+        assert_eq!(created.body, b"{\"version\":1}".to_vec());
+        assert_eq!(unsupported.status_code, 400);
+        assert_eq!(valid.status_code, 200);
+        assert_eq!(invalid.status_code, 422);
+        assert_eq!(
+            serde_json::from_slice::<Value>(&invalid.body).unwrap(),
+            serde_json::json!({"schema_version": 1, "errors": [
+                {"path": "", "message": "missing required property name"},
+                {"path": "/age", "message": "-1 breaks minimum 0"}
+            ]})
+        );
+        let bulk: Value = serde_json::from_slice(&bulk.body).unwrap();
+        assert_eq!(bulk["errors"], true);
+        assert_eq!(bulk["items"][0]["status"], 422);
+        assert_eq!(bulk["items"][1]["status"], 201);
+        assert_eq!(patched.status_code, 422);
+        assert_eq!(in_collection.status_code, 204);
+        assert_eq!(
+            serde_json::from_slice::<Value>(&current.body).unwrap()["version"],
+            1
+        );
+        assert_eq!(removed.body, b"{\"version\":2}".to_vec());
+        assert_eq!(unvalidated.status_code, 204);
+        let versions: Value = serde_json::from_slice(&versions.body).unwrap();
+        assert_eq!(
+            versions["versions"][1],
+            serde_json::json!({"version": 2, "schema": null})
+        );
+        assert_eq!(
+            serde_json::from_slice::<Value>(&first.body).unwrap()["schema"]["required"],
+            serde_json::json!(["id", "name"])
+        );
+        assert_eq!(missing.status_code, 404);
+        assert_eq!(collection.status_code, 201);
+        assert_eq!(
+            route(&request("PUT", "/c/users/keys/3", b"{}"), &db).status_code,
+            422
+        );
+    }
+
+    #[test]
+    fn serve_a_sharded_store_while_it_is_rebalanced() {
+        // arrange
+        let sharded =
+            ShardedDB::load_with_vfs("storage", 2, Options::default(), Arc::new(MemoryVfs::new()))
+                .unwrap();
+        let sharded = Arc::new(Mutex::new(sharded));
+        let db: SharedStore = sharded.clone();
+        for n in 0..30 {
+            let target = format!("/keys/k{:02}", n);
+            route(&request("PUT", &target, n.to_string().as_bytes()), &db);
+        }
+
+        // act
+        sharded.lock().unwrap().add_shard().unwrap();
+        sharded.lock().unwrap().migrate(5).unwrap();
+        let get = route(&request("GET", "/keys/k07", b""), &db);
+        let put = route(&request("PUT", "/keys/k08", b"new"), &db);
+        let delete = route(&request("DELETE", "/keys/k09", b""), &db);
+        while sharded.lock().unwrap().is_migrating() {
+            sharded.lock().unwrap().migrate(5).unwrap();
+        }
+        let list = route(&request("GET", "/keys?limit=10&start=k05", b""), &db);
+        let mget = route(
+            &request("POST", "/_mget", b"{\"keys\":[\"k08\",\"k09\"]}"),
+            &db,
+        );
+        let changes = route(&request("GET", "/changes", b""), &db);
+        let unsupported = [
+            route(&request("PUT", "/c/users", b""), &db),
+            route(&request("PUT", "/index/email", b"email"), &db),
+            route(&request("PUT", "/_schema", b"{\"type\":\"object\"}"), &db),
+        ];
+
+        // assert
+        assert_eq!(get.body, b"7".to_vec());
+        assert_eq!(put.status_code, 204);
+        assert_eq!(delete.status_code, 204);
+        let list: Value = serde_json::from_slice(&list.body).unwrap();
+        assert_eq!(list["items"][0]["key"], "k05");
+        assert_eq!(list["items"][4]["key"], "k10");
+        assert_eq!(list["next"], "k16");
+        let mget: Value = serde_json::from_slice(&mget.body).unwrap();
+        assert_eq!(mget["items"][0]["value"], "new");
+        assert_eq!(mget["items"][1]["status"], 404);
+        assert_eq!(changes.status_code, 501);
+        for response in unsupported {
+            assert_eq!(response.status_code, 501);
+        }
+    }
+
+    #[test]
+    fn replicate_to_a_follower_until_promoted() {
+        // arrange
+        let options = Options {
+            change_log_size: 100_000,
+            segment_size: 100,
+            ..Options::default()
+        };
+        let compacted = Arc::new(Mutex::new(RustDB::load_with_vfs(
+            "storage",
+            options,
+            Arc::new(MemoryVfs::new()),
+        )));
+        let leader_db: SharedStore = compacted.clone();
+        let leader = start_server_with_db(Arc::clone(&leader_db), 4, 4);
+        for target in ["/keys/k0", "/keys/k1"] {
+            route(&request("PUT", target, b"old"), &leader_db);
+        }
+        for n in 0..20 {
+            let target = format!("/keys/k{}", n);
+            route(
+                &request("PUT", &target, n.to_string().as_bytes()),
+                &leader_db,
+            );
+        }
+        route(&request("DELETE", "/keys/k0", b""), &leader_db);
+        // drops the first writes, so the follower starts from a snapshot
+        compacted.lock().unwrap().compress_segments().unwrap();
+        let db = memory_db_with_change_log(100_000);
+        let served: SharedStore = db.clone();
+        route(&request("PUT", "/keys/stale", b"1"), &served);
+
+        // act
+        let follower = Follower::new(&leader.0.to_string(), Arc::clone(&db));
+        thread::spawn(move || follower.run());
+        wait_for_record(&db, b"k19", Some(b"19"));
+        route(&request("PUT", "/keys/live", b"new"), &leader_db);
+        route(&request("DELETE", "/keys/k1", b""), &leader_db);
+        wait_for_record(&db, b"live", Some(b"new"));
+        wait_for_record(&db, b"k1", None);
+        let sequence = db.lock().unwrap().last_sequence();
+        let refused = route(&request("PUT", "/keys/a", b"1"), &served);
+        let promoted = route(&request("POST", "/_promote", b""), &served);
+        let written = route(&request("PUT", "/keys/a", b"1"), &served);
+
+        // assert
+        let keys = db.lock().unwrap().get_keys().unwrap();
+        assert_eq!(keys.len(), 20);
+        assert!(!keys.contains(&b"k0".to_vec()));
+        assert!(!keys.contains(&b"stale".to_vec()));
+        assert_eq!(sequence, leader_db.lock().unwrap().last_sequence());
+        assert_eq!(refused.status_code, 403);
+        assert_eq!(promoted.status_code, 200);
+        assert_eq!(written.status_code, 204);
+        assert_eq!(
+            route(&request("POST", "/_promote", b""), &served).status_code,
+            409
+        );
+    }
+
+    fn wait_until(db: &Arc<Mutex<RustDB>>, replicated: impl Fn(&RustDB) -> bool) {
+        for _ in 0..100 {
+            if replicated(&db.lock().unwrap()) {
+                return;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        panic!("Not replicated");
+    }
+
+    #[test]
+    fn replicate_metadata_and_collections() {
+        // arrange
+        let leader_db = memory_db_with_change_log(100_000);
+        let leader = start_server_with_db(leader_db.clone(), 4, 4);
+        let hour = Duration::from_secs(3_600);
+        {
+            let mut db = leader_db.lock().unwrap();
+            db.save_record(KeyValue::new(b"a".to_vec(), b"1".to_vec()))
+                .unwrap();
+            db.set_content_type(b"a".to_vec(), Some("text/plain"))
+                .unwrap();
+            db.set_flags(b"a".to_vec(), 7).unwrap();
+            db.save_record_with_ttl(KeyValue::new(b"b".to_vec(), b"2".to_vec()), Some(hour))
+                .unwrap();
+            db.create_collection("users").unwrap();
+            db.create_collection("old").unwrap();
+            let users = db.collection_mut("users").unwrap();
+            users
+                .save_record(KeyValue::new(b"u1".to_vec(), b"{}".to_vec()))
+                .unwrap();
+            users
+                .set_content_type(b"u1".to_vec(), Some("application/json"))
+                .unwrap();
+        }
+        let db = memory_db_with_change_log(100_000);
+        db.lock()
+            .unwrap()
+            .set_content_type(b"stale".to_vec(), Some("text/html"))
+            .unwrap();
+
+        // act
+        let follower = Follower::new(&leader.0.to_string(), Arc::clone(&db));
+        thread::spawn(move || follower.run());
+        wait_until(&db, |db| {
+            db.collection("users")
+                .is_some_and(|users| users.get_content_type(b"u1".to_vec()).is_some())
+        });
+        let caught_up = {
+            let db = db.lock().unwrap();
+            (
+                db.get_content_type(b"a".to_vec()),
+                db.get_flags(b"a".to_vec()),
+                db.time_to_live(b"b".to_vec()).is_some(),
+                db.get_content_type(b"stale".to_vec()),
+                db.list_collections(),
+            )
+        };
+        {
+            let mut db = leader_db.lock().unwrap();
+            db.set_content_type(b"a".to_vec(), None).unwrap();
+            db.expire(b"a".to_vec(), hour).unwrap();
+            db.persist(b"b".to_vec()).unwrap();
+            db.drop_collection("old").unwrap();
+            db.create_collection("orders").unwrap();
+            db.collection_mut("orders")
+                .unwrap()
+                .save_record(KeyValue::new(b"o1".to_vec(), b"1".to_vec()))
+                .unwrap();
+        }
+        wait_until(&db, |db| {
+            db.collection("orders")
+                .is_some_and(|orders| orders.get_record(b"o1".to_vec()).unwrap().is_some())
+        });
+        wait_until(&db, |db| db.time_to_live(b"b".to_vec()).is_none());
+
+        // assert
+        assert_eq!(caught_up.0, Some(String::from("text/plain")));
+        assert_eq!(caught_up.1, 7);
+        assert!(caught_up.2);
+        assert_eq!(caught_up.3, None);
+        assert_eq!(caught_up.4, vec!["old", "users"]);
+        let db = db.lock().unwrap();
+        assert_eq!(db.get_content_type(b"a".to_vec()), None);
+        assert!(db.time_to_live(b"a".to_vec()).is_some());
+        assert_eq!(db.list_collections(), vec!["orders", "users"]);
+        assert!(db.collection("orders").unwrap().is_read_only());
+    }
+
+    fn read_event(stream: &mut BufReader<TcpStream>) -> String {
+        let mut event = String::new();
+        loop {
+            let mut line = String::new();
+            stream.read_line(&mut line).unwrap();
+            if line == "\n" || line == "\r\n" || line.is_empty() {
+                return event;
+            }
+            event.push_str(&line);
+        }
+    }
+
+    #[test]
+    fn stream_changes_as_server_sent_events() {
+        // arrange
+        let server = start_server(3, 1);
+        let mut writer = server.connect();
+        writer
+            .get_mut()
+            .write_all(b"PUT /keys/a HTTP/1.1\r\nContent-Length: 1\r\n\r\n1")
+            .unwrap();
+        read_response(&mut writer);
+        let mut events = server.connect();
+
+        // act
+        events
+            .get_mut()
+            .write_all(
+                b"GET /changes HTTP/1.1\r\nAccept: text/event-stream\r\n\
+                  Last-Event-ID: 0\r\n\r\n",
+            )
+            .unwrap();
+        let head = read_event(&mut events);
+        writer
+            .get_mut()
+            .write_all(b"DELETE /keys/a HTTP/1.1\r\n\r\n")
+            .unwrap();
+        read_response(&mut writer);
+
+        // assert
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n"));
+        assert_eq!(
+            read_event(&mut events),
+            "id: 1\nevent: put\ndata: {\"sequence\":1,\"op\":\"put\",\"key\":\"a\",\"value\":1}\n"
+        );
+        assert_eq!(
+            read_event(&mut events),
+            "id: 2\nevent: delete\ndata: {\"sequence\":2,\"op\":\"delete\",\"key\":\"a\"}\n"
+        );
+    }
+
+    #[test]
+    fn encode_and_decode_base64() {
+        for data in [
+            &b""[..],
+            b"f",
+            b"fo",
+            b"foo",
+            b"foob",
+            &[0xff, 0xfe, 0x00, 0x01],
+        ] {
+            let encoded = base64_encode(data);
+            assert_eq!(base64_decode(&encoded), Some(data.to_vec()));
+        }
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_decode("Zg==Zm8="), None);
+        assert_eq!(base64_decode("Zm8"), None);
+        assert_eq!(base64_decode("Z!8="), None);
+    }
+
+    #[test]
+    fn reject_invalid_key_requests() {
+        let db = memory_db();
+
+        assert_eq!(route(&request("PUT", "/keys/a", b""), &db).status_code, 400);
+        assert_eq!(
+            route(&request("GET", "/keys/%zz", b""), &db).status_code,
+            400
+        );
+        assert_eq!(route(&request("GET", "/keys/", b""), &db).status_code, 400);
+        assert_eq!(
+            route(&request("OPTIONS", "/keys/a", b""), &db).status_code,
+            405
+        );
+        assert_eq!(route(&request("GET", "/other", b""), &db).status_code, 404);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn drain_requests_in_flight_on_shutdown() {
+        // arrange
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = TestServer(listener.local_addr().unwrap());
+        listener.set_nonblocking(true).unwrap();
+        let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+        let config = ServerConfig {
+            mode: ServerMode::Async,
+            max_body_size: 1_000,
+            workers: 1,
+            queue_size: 1,
+            keep_alive_timeout: Duration::from_secs(5),
+            request_timeout: Duration::from_secs(5),
+        };
+        let db = memory_db();
+        let (shutdown, shutdown_requested) = watch::channel(false);
+        let serving = tokio::spawn(serve_async(
+            listener,
+            Arc::clone(&db),
+            config,
+            shutdown_requested,
+        ));
+
+        let mut idle = server.connect();
+        idle.get_mut()
+            .write_all(b"PUT /keys/a HTTP/1.1\r\nContent-Length: 1\r\n\r\n1")
+            .unwrap();
+        let (put, put_head, _) = read_response(&mut idle);
+
+        let mut busy = server.connect();
+        busy.get_mut()
+            .write_all(b"PUT /keys/b HTTP/1.1\r\nContent-Length: 3\r\n\r\nab")
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        // act
+        shutdown.send(true).unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        busy.get_mut().write_all(b"c").unwrap();
+        let (busy_put, busy_head, _) = read_response(&mut busy);
+
+        // assert
+        assert_eq!(put, 204);
+        assert!(put_head.contains("Connection: keep-alive"));
+        assert_eq!(busy_put, 204);
+        assert!(busy_head.contains("Connection: close"));
+        assert_eq!(idle.read(&mut [0; 1]).unwrap(), 0);
+
+        tokio::time::timeout(Duration::from_secs(5), serving)
+            .await
+            .unwrap()
+            .unwrap();
+        let value = db.lock().unwrap().get_record(b"b").unwrap().unwrap();
+        assert_eq!(value.value, b"abc".to_vec());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn serve_requests_asynchronously() {
+        // arrange
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = TestServer(listener.local_addr().unwrap());
+        listener.set_nonblocking(true).unwrap();
+        let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+        let config = ServerConfig {
+            mode: ServerMode::Async,
+            max_body_size: 4,
+            workers: 1,
+            queue_size: 1,
+            keep_alive_timeout: Duration::from_secs(5),
+            request_timeout: Duration::from_secs(5),
+        };
+        let (_shutdown, shutdown_requested) = watch::channel(false);
+        tokio::spawn(serve_async(
+            listener,
+            memory_db(),
+            config,
+            shutdown_requested,
+        ));
+        let mut stream = server.connect();
+        let mut too_large = server.connect();
+
+        // act
+        stream
+            .get_mut()
+            .write_all(b"PUT /keys/a HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 2\r\n\r\n")
+            .unwrap();
+        let (continued, _, _) = read_response(&mut stream);
+        stream
+            .get_mut()
+            .write_all(
+                b"12GET /keys/a HTTP/1.1\r\n\r\n\
+                  GET /keys/b HTTP/1.1\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+        too_large
+            .get_mut()
+            .write_all(b"PUT /keys/a HTTP/1.1\r\nContent-Length: 5\r\n\r\n12345")
+            .unwrap();
+
+        // assert
+        assert_eq!(continued, 100);
+        assert_eq!(read_response(&mut stream).0, 204);
+        let (get, _, body) = read_response(&mut stream);
+        assert_eq!(get, 200);
+        assert_eq!(body, b"12".to_vec());
+        let (missing, head, _) = read_response(&mut stream);
+        assert_eq!(missing, 404);
+        assert!(head.contains("Connection: close"));
+        assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+        assert_eq!(read_response(&mut too_large).0, 413);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn stream_query_matches_asynchronously() {
+        // arrange
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = TestServer(listener.local_addr().unwrap());
+        listener.set_nonblocking(true).unwrap();
+        let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+        let config = ServerConfig {
+            mode: ServerMode::Async,
+            max_body_size: 1_000,
+            workers: 1,
+            queue_size: 1,
+            keep_alive_timeout: Duration::from_secs(5),
+            request_timeout: Duration::from_secs(5),
+        };
+        let db = numbered_documents(1_200);
+        let (_shutdown, shutdown_requested) = watch::channel(false);
+        tokio::spawn(serve_async(listener, db, config, shutdown_requested));
+
+        // act and assert
+        task::spawn_blocking(move || stream_query_then_keep_alive(&server))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn close_event_streams_on_shutdown() {
+        // arrange
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = TestServer(listener.local_addr().unwrap());
+        listener.set_nonblocking(true).unwrap();
+        let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+        let config = ServerConfig {
+            mode: ServerMode::Async,
+            max_body_size: 1_000,
+            workers: 1,
+            queue_size: 1,
+            keep_alive_timeout: Duration::from_secs(5),
+            request_timeout: Duration::from_secs(5),
+        };
+        let db = memory_db();
+        let (shutdown, shutdown_requested) = watch::channel(false);
+        let serving = tokio::spawn(serve_async(
+            listener,
+            Arc::clone(&db),
+            config,
+            shutdown_requested,
+        ));
+        let mut events = server.connect();
+        events
+            .get_mut()
+            .write_all(b"GET /changes?prefix=b HTTP/1.1\r\nAccept: text/event-stream\r\n\r\n")
+            .unwrap();
+        let head = read_event(&mut events);
+
+        // act
+        for key in ["a", "b"] {
+            let mut writer = server.connect();
+            writer
+                .get_mut()
+                .write_all(
+                    format!("PUT /keys/{} HTTP/1.1\r\nContent-Length: 1\r\n\r\n1", key).as_bytes(),
+                )
+                .unwrap();
+            read_response(&mut writer);
+        }
+        let event = read_event(&mut events);
+        shutdown.send(true).unwrap();
+
+        // assert
+        assert!(head.contains("text/event-stream"));
+        assert!(event.starts_with("id: 2\nevent: put\n"));
+        assert_eq!(events.read(&mut [0; 1]).unwrap(), 0);
+        tokio::time::timeout(Duration::from_secs(5), serving)
+            .await
+            .unwrap()
+            .unwrap();
+    }
 }
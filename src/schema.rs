@@ -0,0 +1,607 @@
+use std::convert::TryInto;
+use std::error;
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::Arc;
+
+use serde_json::{Map, Value};
+
+use crate::core::ByteString;
+use crate::keylog::KeyLog;
+use crate::vfs::Vfs;
+
+static SCHEMAS_FILE: &str = "schemas";
+
+/// Keywords that only describe a schema, which validation ignores.
+static ANNOTATIONS: &[&str] = &[
+    "$schema",
+    "$id",
+    "$comment",
+    "title",
+    "description",
+    "default",
+    "examples",
+    "format",
+    "deprecated",
+    "readOnly",
+    "writeOnly",
+];
+
+static TYPES: &[&str] = &[
+    "null", "boolean", "object", "array", "number", "integer", "string",
+];
+
+/// Where a document breaks its schema: the JSON pointer of the value, empty
+/// for the document itself, and what is wrong with it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SchemaError {
+    pub path: String,
+    pub message: String,
+}
+
+/// Error a write fails with, as `InvalidData`, when the value does not
+/// match the schema of the database.
+#[derive(Debug, PartialEq)]
+pub struct SchemaViolation {
+    pub version: u64,
+    pub errors: Vec<SchemaError>,
+}
+
+impl fmt::Display for SchemaViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Value does not match schema version {}", self.version)?;
+        for error in &self.errors {
+            let path = if error.path.is_empty() {
+                "/"
+            } else {
+                &error.path
+            };
+            write!(f, "; {}: {}", path, error.message)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for SchemaViolation {}
+
+/// A JSON Schema, checked to use only the keywords validation supports:
+/// `type`, `enum`, `const`, `properties`, `required`,
+/// `additionalProperties`, `minProperties`, `maxProperties`, `items`,
+/// `minItems`, `maxItems`, `uniqueItems`, `minimum`, `maximum`,
+/// `exclusiveMinimum`, `exclusiveMaximum`, `multipleOf`, `minLength`,
+/// `maxLength`, `allOf`, `anyOf`, `oneOf` and `not`, besides annotations
+/// such as `title`.
+pub(crate) struct Schema {
+    root: Value,
+}
+
+impl Schema {
+    /// Fails with `InvalidInput` for keywords that are unknown, and so
+    /// would not be enforced, or malformed.
+    pub fn compile(schema: Value) -> Result<Schema> {
+        check_schema(&schema, "")?;
+        Ok(Schema { root: schema })
+    }
+
+    pub fn validate(&self, document: &Value) -> Vec<SchemaError> {
+        let mut errors = Vec::new();
+        validate(&self.root, document, &mut String::new(), &mut errors);
+        errors
+    }
+}
+
+fn invalid_schema(path: &str, reason: &str) -> Error {
+    let path = if path.is_empty() { "/" } else { path };
+    Error::new(
+        ErrorKind::InvalidInput,
+        format!("Invalid schema at {}: {}", path, reason),
+    )
+}
+
+fn check_schema(schema: &Value, path: &str) -> Result<()> {
+    let keywords = match schema {
+        Value::Bool(_) => return Ok(()),
+        Value::Object(keywords) => keywords,
+        _ => return Err(invalid_schema(path, "expected an object or a boolean")),
+    };
+
+    for (keyword, value) in keywords {
+        let at = format!("{}/{}", path, keyword);
+        let valid = match keyword.as_str() {
+            "type" => match value {
+                Value::String(name) => TYPES.contains(&name.as_str()),
+                Value::Array(names) => names
+                    .iter()
+                    .all(|name| name.as_str().is_some_and(|name| TYPES.contains(&name))),
+                _ => false,
+            },
+            "enum" => value.is_array(),
+            "const" => true,
+            "properties" => match value {
+                Value::Object(properties) => {
+                    for (name, schema) in properties {
+                        check_schema(schema, &format!("{}/{}", at, name))?;
+                    }
+                    true
+                }
+                _ => false,
+            },
+            "required" => value
+                .as_array()
+                .is_some_and(|names| names.iter().all(Value::is_string)),
+            "additionalProperties" | "items" | "not" => {
+                check_schema(value, &at)?;
+                true
+            }
+            "allOf" | "anyOf" | "oneOf" => match value {
+                Value::Array(schemas) if !schemas.is_empty() => {
+                    for (index, schema) in schemas.iter().enumerate() {
+                        check_schema(schema, &format!("{}/{}", at, index))?;
+                    }
+                    true
+                }
+                _ => false,
+            },
+            "minProperties" | "maxProperties" | "minItems" | "maxItems" | "minLength"
+            | "maxLength" => value.is_u64(),
+            "minimum" | "maximum" | "exclusiveMinimum" | "exclusiveMaximum" => value.is_number(),
+            "multipleOf" => value.as_f64().is_some_and(|value| value > 0.0),
+            "uniqueItems" => value.is_boolean(),
+            keyword if ANNOTATIONS.contains(&keyword) => true,
+            _ => return Err(invalid_schema(&at, "unsupported keyword")),
+        };
+
+        if !valid {
+            return Err(invalid_schema(&at, "malformed keyword"));
+        }
+    }
+    Ok(())
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn has_type(value: &Value, name: &str) -> bool {
+    match name {
+        "integer" => value.as_f64().is_some_and(|number| number.fract() == 0.0),
+        name => type_name(value) == name,
+    }
+}
+
+/// Numbers are equal by value, so `1` and `1.0` are.
+fn equals(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64() == b.as_f64(),
+        (Value::Array(a), Value::Array(b)) => {
+            a.len() == b.len() && a.iter().zip(b).all(|(a, b)| equals(a, b))
+        }
+        (Value::Object(a), Value::Object(b)) => {
+            a.len() == b.len()
+                && a.iter()
+                    .all(|(name, a)| b.get(name).is_some_and(|b| equals(a, b)))
+        }
+        (a, b) => a == b,
+    }
+}
+
+fn push_error(errors: &mut Vec<SchemaError>, path: &str, message: String) {
+    errors.push(SchemaError {
+        path: String::from(path),
+        message,
+    });
+}
+
+/// Appends to `errors` what is wrong with `value`, at `path`, for `schema`,
+/// which was checked by `check_schema`.
+fn validate(schema: &Value, value: &Value, path: &mut String, errors: &mut Vec<SchemaError>) {
+    let keywords = match schema {
+        Value::Bool(true) => return,
+        Value::Bool(false) => {
+            return push_error(errors, path, String::from("no value is allowed"));
+        }
+        Value::Object(keywords) => keywords,
+        _ => return,
+    };
+
+    let number = |keyword: &str| keywords.get(keyword).and_then(Value::as_f64);
+    let count = |keyword: &str| keywords.get(keyword).and_then(Value::as_u64);
+
+    match keywords.get("type") {
+        Some(Value::String(name)) if !has_type(value, name) => push_error(
+            errors,
+            path,
+            format!("expected {}, found {}", name, type_name(value)),
+        ),
+        Some(Value::Array(names))
+            if !names
+                .iter()
+                .any(|name| has_type(value, name.as_str().unwrap_or(""))) =>
+        {
+            let names: Vec<&str> = names.iter().filter_map(Value::as_str).collect();
+            push_error(
+                errors,
+                path,
+                format!(
+                    "expected {}, found {}",
+                    names.join(" or "),
+                    type_name(value)
+                ),
+            )
+        }
+        _ => {}
+    }
+
+    if let Some(Value::Array(allowed)) = keywords.get("enum") {
+        if !allowed.iter().any(|allowed| equals(value, allowed)) {
+            push_error(errors, path, String::from("not one of the allowed values"));
+        }
+    }
+    if let Some(constant) = keywords.get("const") {
+        if !equals(value, constant) {
+            push_error(errors, path, format!("expected {}", constant));
+        }
+    }
+
+    match value {
+        Value::Object(fields) => validate_object(keywords, fields, path, errors),
+        Value::Array(items) => {
+            if let Some(schema) = keywords.get("items") {
+                for (index, item) in items.iter().enumerate() {
+                    let len = path.len();
+                    path.push_str(&format!("/{}", index));
+                    validate(schema, item, path, errors);
+                    path.truncate(len);
+                }
+            }
+            if count("minItems").is_some_and(|min| (items.len() as u64) < min) {
+                push_error(errors, path, String::from("too few items"));
+            }
+            if count("maxItems").is_some_and(|max| items.len() as u64 > max) {
+                push_error(errors, path, String::from("too many items"));
+            }
+            let unique = keywords.get("uniqueItems") == Some(&Value::Bool(true));
+            if unique
+                && items
+                    .iter()
+                    .enumerate()
+                    .any(|(index, a)| items[..index].iter().any(|b| equals(a, b)))
+            {
+                push_error(errors, path, String::from("items are not unique"));
+            }
+        }
+        Value::Number(value) => {
+            let value = value.as_f64().unwrap_or(f64::NAN);
+            let limits = [
+                ("minimum", value < number("minimum").unwrap_or(f64::MIN)),
+                ("maximum", value > number("maximum").unwrap_or(f64::MAX)),
+                (
+                    "exclusiveMinimum",
+                    number("exclusiveMinimum").is_some_and(|min| value <= min),
+                ),
+                (
+                    "exclusiveMaximum",
+                    number("exclusiveMaximum").is_some_and(|max| value >= max),
+                ),
+                (
+                    "multipleOf",
+                    number("multipleOf").is_some_and(|factor| (value / factor).fract() != 0.0),
+                ),
+            ];
+            for (keyword, broken) in limits {
+                if broken {
+                    push_error(
+                        errors,
+                        path,
+                        format!("{} breaks {} {}", value, keyword, keywords[keyword]),
+                    );
+                }
+            }
+        }
+        Value::String(text) => {
+            let len = text.chars().count() as u64;
+            if count("minLength").is_some_and(|min| len < min) {
+                push_error(errors, path, String::from("too short"));
+            }
+            if count("maxLength").is_some_and(|max| len > max) {
+                push_error(errors, path, String::from("too long"));
+            }
+        }
+        _ => {}
+    }
+
+    if let Some(Value::Array(schemas)) = keywords.get("allOf") {
+        for schema in schemas {
+            validate(schema, value, path, errors);
+        }
+    }
+    let matching = |schemas: &Vec<Value>| {
+        schemas
+            .iter()
+            .filter(|schema| {
+                let mut errors = Vec::new();
+                validate(schema, value, &mut path.clone(), &mut errors);
+                errors.is_empty()
+            })
+            .count()
+    };
+    if let Some(Value::Array(schemas)) = keywords.get("anyOf") {
+        if matching(schemas) == 0 {
+            push_error(errors, path, String::from("matches none of anyOf"));
+        }
+    }
+    if let Some(Value::Array(schemas)) = keywords.get("oneOf") {
+        let matches = matching(schemas);
+        if matches != 1 {
+            push_error(
+                errors,
+                path,
+                format!("matches {} of oneOf, expected one", matches),
+            );
+        }
+    }
+    if let Some(schema) = keywords.get("not") {
+        let mut not_errors = Vec::new();
+        validate(schema, value, &mut path.clone(), &mut not_errors);
+        if not_errors.is_empty() {
+            push_error(errors, path, String::from("matches the schema in not"));
+        }
+    }
+}
+
+fn validate_object(
+    keywords: &Map<String, Value>,
+    fields: &Map<String, Value>,
+    path: &mut String,
+    errors: &mut Vec<SchemaError>,
+) {
+    if let Some(Value::Array(names)) = keywords.get("required") {
+        for name in names.iter().filter_map(Value::as_str) {
+            if !fields.contains_key(name) {
+                push_error(errors, path, format!("missing required property {}", name));
+            }
+        }
+    }
+
+    let properties = keywords.get("properties").and_then(Value::as_object);
+    for (name, field) in fields {
+        let schema = match properties.and_then(|properties| properties.get(name)) {
+            Some(schema) => schema,
+            None => match keywords.get("additionalProperties") {
+                Some(schema) => schema,
+                None => continue,
+            },
+        };
+
+        let len = path.len();
+        path.push('/');
+        path.push_str(&name.replace('~', "~0").replace('/', "~1"));
+        validate(schema, field, path, errors);
+        path.truncate(len);
+    }
+
+    let len = fields.len() as u64;
+    if let Some(min) = keywords.get("minProperties").and_then(Value::as_u64) {
+        if len < min {
+            push_error(errors, path, String::from("too few properties"));
+        }
+    }
+    if let Some(max) = keywords.get("maxProperties").and_then(Value::as_u64) {
+        if len > max {
+            push_error(errors, path, String::from("too many properties"));
+        }
+    }
+}
+
+/// Versions of the schema of a database, numbered from 1 and persisted to
+/// the `schemas` log. A version without a schema removes validation, and
+/// the last version is the one writes are validated against.
+pub(crate) struct Schemas {
+    versions: KeyLog<ByteString>,
+    current: Option<(u64, Schema)>,
+}
+
+impl Schemas {
+    pub fn in_memory() -> Schemas {
+        Schemas {
+            versions: KeyLog::in_memory(),
+            current: None,
+        }
+    }
+
+    pub fn load(folder: &str, vfs: &Arc<dyn Vfs>) -> Result<Schemas> {
+        let mut schemas = Schemas {
+            versions: KeyLog::load(folder, SCHEMAS_FILE, vfs)?,
+            current: None,
+        };
+        if let Some((version, Some(schema))) = schemas.list().pop() {
+            schemas.current = Some((version, Schema::compile(schema)?));
+        }
+        Ok(schemas)
+    }
+
+    /// Every version with its schema, `None` where it was removed, in order.
+    pub fn list(&self) -> Vec<(u64, Option<Value>)> {
+        let mut versions: Vec<(u64, Option<Value>)> = self
+            .versions
+            .iter()
+            .filter_map(|(version, schema)| {
+                let version = u64::from_be_bytes(version[..].try_into().ok()?);
+                let schema = serde_json::from_slice(schema).ok();
+                Some((version, schema.filter(|schema: &Value| !schema.is_null())))
+            })
+            .collect();
+        versions.sort_by_key(|(version, _)| *version);
+        versions
+    }
+
+    /// Adds the version `schema`, or one without a schema, returning its
+    /// number.
+    pub fn set(&mut self, schema: Option<Value>) -> Result<u64> {
+        let compiled = schema.clone().map(Schema::compile).transpose()?;
+        let version = self.list().last().map_or(1, |(version, _)| version + 1);
+        let text = match &schema {
+            Some(schema) => schema.to_string(),
+            None => String::from("null"),
+        };
+
+        self.versions
+            .set(version.to_be_bytes().to_vec(), text.into_bytes())?;
+        self.current = compiled.map(|schema| (version, schema));
+        Ok(version)
+    }
+
+    /// Checks `value` against the current schema, if any.
+    pub fn check(&self, value: &[u8]) -> Result<()> {
+        let (version, schema) = match &self.current {
+            Some(current) => current,
+            None => return Ok(()),
+        };
+
+        let errors = match serde_json::from_slice::<Value>(value) {
+            Ok(document) => schema.validate(&document),
+            Err(_) => vec![SchemaError {
+                path: String::new(),
+                message: String::from("not a JSON value"),
+            }],
+        };
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(Error::new(
+                ErrorKind::InvalidData,
+                SchemaViolation {
+                    version: *version,
+                    errors,
+                },
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::MemoryVfs;
+    use serde_json::json;
+
+    fn errors(schema: Value, document: Value) -> Vec<(String, String)> {
+        Schema::compile(schema)
+            .unwrap()
+            .validate(&document)
+            .into_iter()
+            .map(|error| (error.path, error.message))
+            .collect()
+    }
+
+    #[test]
+    fn validate_documents_against_schemas() {
+        let schema = json!({
+            "type": "object",
+            "required": ["id", "name"],
+            "properties": {
+                "id": {"type": "string", "minLength": 1},
+                "name": {"type": "string"},
+                "age": {"type": "integer", "minimum": 0, "exclusiveMaximum": 150},
+                "job_title": {"enum": ["Baker", "Investment Advisor"]},
+                "tags": {"type": "array", "items": {"type": "string"}, "uniqueItems": true},
+                "email": {"anyOf": [{"type": "string"}, {"type": "null"}]}
+            },
+            "additionalProperties": false
+        });
+
+        assert!(errors(
+            schema.clone(),
+            json!({"id": "1", "name": "Lana", "age": 41.0, "job_title": "Baker", "tags": ["a"]})
+        )
+        .is_empty());
+        assert_eq!(
+            errors(
+                schema,
+                json!({"id": "", "age": 150, "job_title": "Cook", "tags": ["a", 1, "a"],
+                       "email": 1, "a/b": true})
+            ),
+            vec![
+                (
+                    String::from(""),
+                    String::from("missing required property name")
+                ),
+                (String::from("/a~1b"), String::from("no value is allowed")),
+                (
+                    String::from("/age"),
+                    String::from("150 breaks exclusiveMaximum 150")
+                ),
+                (
+                    String::from("/email"),
+                    String::from("matches none of anyOf")
+                ),
+                (String::from("/id"), String::from("too short")),
+                (
+                    String::from("/job_title"),
+                    String::from("not one of the allowed values")
+                ),
+                (
+                    String::from("/tags/1"),
+                    String::from("expected string, found number")
+                ),
+                (String::from("/tags"), String::from("items are not unique")),
+            ]
+        );
+        assert_eq!(
+            errors(json!({"type": ["string", "null"]}), json!(1)),
+            vec![(
+                String::new(),
+                String::from("expected string or null, found number")
+            )]
+        );
+
+        for invalid in [
+            json!({"pattern": "^a"}),
+            json!({"type": "text"}),
+            json!({"properties": {"a": {"$ref": "#"}}}),
+            json!({"minLength": -1}),
+            json!([]),
+        ] {
+            assert_eq!(
+                Schema::compile(invalid).err().unwrap().kind(),
+                ErrorKind::InvalidInput
+            );
+        }
+    }
+
+    #[test]
+    fn keep_schema_versions_across_loads() {
+        let vfs: Arc<dyn Vfs> = Arc::new(MemoryVfs::new());
+        let mut schemas = Schemas::load("storage", &vfs).unwrap();
+        assert!(schemas.check(b"not json").is_ok());
+        assert_eq!(schemas.set(Some(json!({"type": "object"}))).unwrap(), 1);
+        assert_eq!(schemas.set(None).unwrap(), 2);
+        assert_eq!(schemas.set(Some(json!({"required": ["id"]}))).unwrap(), 3);
+        assert!(schemas.set(Some(json!({"type": 1}))).is_err());
+
+        let schemas = Schemas::load("storage", &vfs).unwrap();
+        assert_eq!(
+            schemas.list(),
+            vec![
+                (1, Some(json!({"type": "object"}))),
+                (2, None),
+                (3, Some(json!({"required": ["id"]}))),
+            ]
+        );
+        assert!(schemas.check(br#"{"id":1}"#).is_ok());
+        let err = schemas.check(b"{}").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        let violation = err.get_ref().unwrap().downcast_ref::<SchemaViolation>();
+        assert_eq!(violation.unwrap().version, 3);
+        assert_eq!(
+            err.to_string(),
+            "Value does not match schema version 3; /: missing required property id"
+        );
+    }
+}
@@ -1,76 +1,952 @@
-use std::io::Result;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{Error, ErrorKind, Result};
+use std::sync::Arc;
+use std::time::Duration;
 
-use crate::core::KeyValue;
-use crate::store::{DataSgment, InitialSegmentReference};
+use serde_json::Value;
 
-static MAX_SIZE_FILE: u64 = 3_000_000;
+use crate::bloom::{BloomCounters, BloomStats};
+use crate::changes::{
+    ChangeEvent, ChangeFilter, ChangeLog, KeyMetadata, ReplicationEvent, Subscription,
+};
+use crate::core::{ByteString, KeyValue};
+use crate::expiry::{now_millis, Expirations};
+use crate::index::Indexes;
+use crate::keylog::KeyLog;
+use crate::lsm::LsmStore;
+use crate::options::{Options, StorageMode};
+use crate::schema::Schemas;
+use crate::store::{
+    build_path, folder_path, parse_file_name, DataSgment, InitialSegmentReference, SegmentStats,
+    VersionStamp,
+};
+use crate::vfs::{copy_file, DiskVfs, Vfs};
+
+static MIN_DEAD_RATIO: f64 = 0.5;
+static FLAGS_FILE: &str = "flags";
+static CONTENT_TYPES_FILE: &str = "content_types";
+static COLLECTIONS_FILE: &str = "collection_names";
+static COLLECTIONS_FOLDER: &str = "collections";
+static MAX_NAME_SIZE: usize = 64;
+
+/// Keys returned by `RustDB::scan_keys`.
+#[derive(Debug, PartialEq)]
+pub struct KeyPage {
+    pub keys: Vec<ByteString>,
+    /// Key the next page starts from, when there are more keys.
+    pub next: Option<ByteString>,
+}
+
+/// A value a key had, as returned by `RustDB::history`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Version {
+    /// Sequence of the write, the one of its change event when a change log
+    /// is kept, or 0 when unknown.
+    pub sequence: u64,
+    /// Time of the write in milliseconds since the Unix epoch, or 0 when
+    /// unknown.
+    pub time: u64,
+    /// Value written, `None` for a delete.
+    pub value: Option<ByteString>,
+}
+
+/// Point in the history of a key read by `RustDB::get_record_at`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PointInTime {
+    /// Right after the write with this sequence.
+    Sequence(u64),
+    /// At this time, in milliseconds since the Unix epoch.
+    Time(u64),
+}
 
 pub struct RustDB {
     pub segment: Option<DataSgment>,
+    leveled: Option<LsmStore>,
     folder: String,
+    options: Options,
+    vfs: Arc<dyn Vfs>,
+    bloom_counters: BloomCounters,
+    expirations: Expirations,
+    flags: KeyLog,
+    content_types: KeyLog<ByteString>,
+    changes: Option<ChangeLog>,
+    /// Whether writes are refused, as they come from a leader instead.
+    read_only: bool,
+    /// Named collections, each a database of its own in a subfolder, with
+    /// their names kept in the file `collection_names`.
+    collections: BTreeMap<String, RustDB>,
+    collection_names: KeyLog,
+    indexes: Indexes,
+    schemas: Schemas,
+    /// Sequence stamped on the latest write, followed when no change log is
+    /// kept.
+    sequence: u64,
 }
 
 impl RustDB {
     pub fn load(folder: &str) -> RustDB {
-        RustDB {
-            segment: Some(DataSgment::load_dir(folder)),
-            folder: String::from(folder),
+        RustDB::load_with_options(folder, Options::default())
+    }
+
+    pub fn load_with_options(folder: &str, options: Options) -> RustDB {
+        RustDB::load_with_vfs(folder, options, Arc::new(DiskVfs))
+    }
+
+    pub fn load_with_vfs(folder: &str, options: Options, vfs: Arc<dyn Vfs>) -> RustDB {
+        let mut db = match options.storage_mode {
+            StorageMode::Log => {
+                let mut segment = DataSgment::load_dir(folder, &vfs, options.history_retention);
+                segment.set_durability(options.durability);
+                segment.build_missing_filters(options.bloom_false_positive_rate);
+                let sequence = segment.last_sequence();
+
+                RustDB {
+                    sequence,
+                    changes: RustDB::load_changes(folder, &options, &vfs, sequence),
+                    segment: Some(segment),
+                    leveled: None,
+                    folder: String::from(folder),
+                    options,
+                    expirations: Expirations::load(folder, &vfs).unwrap(),
+                    flags: KeyLog::load(folder, FLAGS_FILE, &vfs).unwrap(),
+                    content_types: KeyLog::load(folder, CONTENT_TYPES_FILE, &vfs).unwrap(),
+                    collection_names: KeyLog::load(folder, COLLECTIONS_FILE, &vfs).unwrap(),
+                    indexes: Indexes::load(folder, &vfs).unwrap(),
+                    schemas: Schemas::load(folder, &vfs).unwrap(),
+                    vfs,
+                    bloom_counters: BloomCounters::default(),
+                    read_only: false,
+                    collections: BTreeMap::new(),
+                }
+            }
+            StorageMode::Leveled => RustDB {
+                sequence: 0,
+                segment: None,
+                leveled: Some(LsmStore::load(folder, options.clone(), &vfs)),
+                folder: String::from(folder),
+                // events are read from the segments, which leveled storage
+                // does not have
+                changes: None,
+                options,
+                expirations: Expirations::load(folder, &vfs).unwrap(),
+                flags: KeyLog::load(folder, FLAGS_FILE, &vfs).unwrap(),
+                content_types: KeyLog::load(folder, CONTENT_TYPES_FILE, &vfs).unwrap(),
+                collection_names: KeyLog::load(folder, COLLECTIONS_FILE, &vfs).unwrap(),
+                indexes: Indexes::load(folder, &vfs).unwrap(),
+                schemas: Schemas::load(folder, &vfs).unwrap(),
+                vfs,
+                bloom_counters: BloomCounters::default(),
+                read_only: false,
+                collections: BTreeMap::new(),
+            },
+        };
+
+        let names: Vec<ByteString> = db
+            .collection_names
+            .iter()
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in names {
+            let name = String::from_utf8_lossy(&name).into_owned();
+            let collection = db.load_collection(&name);
+            db.collections.insert(name, collection);
         }
+        db.build_index(None).unwrap();
+        db
     }
 
-    fn new(folder: &str) -> RustDB {
-        RustDB {
-            segment: Some(DataSgment::new(folder)),
+    fn new(folder: &str, options: Options, vfs: &Arc<dyn Vfs>) -> Result<RustDB> {
+        let mut segment = DataSgment::try_new(folder, vfs)?;
+        segment.set_durability(options.durability);
+        segment.set_history_retention(options.history_retention);
+
+        Ok(RustDB {
+            segment: Some(segment),
+            leveled: None,
             folder: String::from(folder),
+            options,
+            vfs: Arc::clone(vfs),
+            bloom_counters: BloomCounters::default(),
+            expirations: Expirations::in_memory(),
+            flags: KeyLog::in_memory(),
+            content_types: KeyLog::in_memory(),
+            changes: None,
+            read_only: false,
+            collections: BTreeMap::new(),
+            collection_names: KeyLog::in_memory(),
+            indexes: Indexes::in_memory(),
+            schemas: Schemas::in_memory(),
+            sequence: 0,
+        })
+    }
+
+    fn load_changes(
+        folder: &str,
+        options: &Options,
+        vfs: &Arc<dyn Vfs>,
+        last_stamped: u64,
+    ) -> Option<ChangeLog> {
+        match options.change_log_size {
+            0 => None,
+            _ => Some(ChangeLog::load(folder, last_stamped, vfs).unwrap()),
+        }
+    }
+
+    fn collection_folder(&self, name: &str) -> String {
+        format!("{}/{}/{}", self.folder, COLLECTIONS_FOLDER, name)
+    }
+
+    fn load_collection(&self, name: &str) -> RustDB {
+        RustDB::load_with_vfs(
+            &self.collection_folder(name),
+            self.options.clone(),
+            Arc::clone(&self.vfs),
+        )
+    }
+
+    /// Creates the collection `name`, returning false when it already
+    /// exists. Names are up to 64 letters, digits, `-` and `_`, as they are
+    /// used for folders.
+    pub fn create_collection(&mut self, name: &str) -> Result<bool> {
+        self.check_writable()?;
+        check_name("collection", name)?;
+        if self.collections.contains_key(name) {
+            return Ok(false);
+        }
+
+        self.add_collection(name)?;
+        self.publish_collections();
+        Ok(true)
+    }
+
+    fn add_collection(&mut self, name: &str) -> Result<()> {
+        // files left by a drop cut short must not come back
+        self.vfs
+            .remove_dir_all(&folder_path(&self.collection_folder(name)))?;
+        let mut collection = self.load_collection(name);
+        collection.set_read_only(self.read_only);
+        self.collection_names
+            .set(name.as_bytes().to_vec(), now_millis())?;
+        self.collections.insert(String::from(name), collection);
+        Ok(())
+    }
+
+    /// Deletes the collection `name` with every key in it, returning false
+    /// when it does not exist.
+    pub fn drop_collection(&mut self, name: &str) -> Result<bool> {
+        self.check_writable()?;
+        if !self.collections.contains_key(name) {
+            return Ok(false);
+        }
+
+        self.remove_collection(name)?;
+        self.publish_collections();
+        Ok(true)
+    }
+
+    fn remove_collection(&mut self, name: &str) -> Result<()> {
+        self.collections.remove(name);
+        self.collection_names.clear(name.as_bytes())?;
+        self.vfs
+            .remove_dir_all(&folder_path(&self.collection_folder(name)))
+    }
+
+    /// Names of the collections, sorted.
+    pub fn list_collections(&self) -> Vec<String> {
+        self.collections.keys().cloned().collect()
+    }
+
+    /// The collection `name`, which is used like any database and compacted
+    /// on its own.
+    pub fn collection(&self, name: &str) -> Option<&RustDB> {
+        self.collections.get(name)
+    }
+
+    pub fn collection_mut(&mut self, name: &str) -> Option<&mut RustDB> {
+        self.collections.get_mut(name)
+    }
+
+    /// Indexes the values that are JSON objects by their field at `path`,
+    /// such as `email` or `address.city`, returning false when there is an
+    /// index `name` already. Names follow the rules of collection names.
+    /// The index is built from the records there are, then kept up to date
+    /// on every write.
+    pub fn create_index(&mut self, name: &str, path: &str) -> Result<bool> {
+        self.check_writable()?;
+        check_name("index", name)?;
+        if !self.indexes.create(name, path)? {
+            return Ok(false);
+        }
+
+        self.build_index(Some(name))?;
+        Ok(true)
+    }
+
+    pub fn drop_index(&mut self, name: &str) -> Result<bool> {
+        self.check_writable()?;
+        self.indexes.remove(name)
+    }
+
+    /// Names and paths of the indexes, sorted by name.
+    pub fn list_indexes(&self) -> Vec<(String, String)> {
+        self.indexes.list()
+    }
+
+    /// Records whose value at the path of the index `name` is `value`, in
+    /// order of their keys. Strings match their text, while numbers and
+    /// booleans match their JSON text, such as `42` or `true`.
+    pub fn find_by(&self, index: &str, value: &str) -> Result<Vec<KeyValue>> {
+        let mut records = Vec::new();
+        for key in self.indexes.find(index, value)? {
+            records.extend(self.get_record(key)?);
+        }
+        Ok(records)
+    }
+
+    /// Adds a version of the JSON Schema that values are validated against
+    /// on every write from then on, or one without a schema to stop
+    /// validating, and returns its number. Records already stored are not
+    /// checked. Schemas with keywords that are not supported fail as
+    /// `InvalidInput`.
+    pub fn set_schema(&mut self, schema: Option<Value>) -> Result<u64> {
+        self.check_writable()?;
+        self.schemas.set(schema)
+    }
+
+    /// Every version of the schema, `None` where validation was removed, in
+    /// order. The last one is in force.
+    pub fn schema_versions(&self) -> Vec<(u64, Option<Value>)> {
+        self.schemas.list()
+    }
+
+    /// Fills every index, or only `only`, from the records there are.
+    fn build_index(&mut self, only: Option<&str>) -> Result<()> {
+        if self.indexes.is_empty() {
+            return Ok(());
+        }
+
+        for key in self.get_keys()? {
+            if let Some(record) = self.get_record(key)? {
+                self.indexes.update(&record.key, &record.value, only);
+            }
         }
+        Ok(())
     }
 
-    pub fn get_record(&self, key: String) -> Result<Option<KeyValue>> {
+    pub fn get_vfs(&self) -> Arc<dyn Vfs> {
+        Arc::clone(&self.vfs)
+    }
+
+    /// Finds the value of `key`, which may be any sequence of bytes.
+    pub fn get_record<K: Into<ByteString>>(&self, key: K) -> Result<Option<KeyValue>> {
+        let key = key.into();
+        if self.expirations.is_expired(&key) {
+            return Ok(None);
+        }
+
+        if let Some(store) = &self.leveled {
+            return store.get_record(&key);
+        }
+
         match &self.segment {
             Some(value) => {
                 let result = self.get_record_from_segment(&key, value)?;
                 if let Some(v) = &result {
-                    if v.get_value_as_string().len() == 0 {
+                    if v.value.is_empty() {
                         return Ok(None);
                     }
                     return Ok(result);
                 }
-                return Ok(None);
+                Ok(None)
             }
-            None => return Ok(None),
+            None => Ok(None),
         }
     }
 
-    fn get_record_from_segment(&self, key: &str, segment: &DataSgment) -> Result<Option<KeyValue>> {
-        let record = segment.get_record(String::from(key))?;
+    fn get_record_from_segment(
+        &self,
+        key: &[u8],
+        segment: &DataSgment,
+    ) -> Result<Option<KeyValue>> {
+        let record = match segment.may_contain(key) {
+            Some(false) => {
+                self.bloom_counters.record_check(false);
+                None
+            }
+            Some(true) => {
+                self.bloom_counters.record_check(true);
+                let record = segment.get_record(key)?;
+                if record.is_none() {
+                    self.bloom_counters.record_false_positive();
+                }
+                record
+            }
+            None => segment.get_record(key)?,
+        };
 
         match record {
-            Some(_) => return Ok(record),
+            Some(_) => Ok(record),
             None => {
                 if let Some(next) = segment.get_previous() {
-                    return self.get_record_from_segment(key, &next);
+                    return self.get_record_from_segment(key, next);
                 }
-                return Ok(None);
+                Ok(None)
             }
         }
     }
 
-    pub fn delete_record(&mut self, key: String) -> Result<()> {
-        match &mut self.segment {
-            Some(value) => value.delete_record(key),
-            None => return Ok(()),
+    pub fn delete_record<K: Into<ByteString>>(&mut self, key: K) -> Result<()> {
+        self.check_writable()?;
+        let key = key.into();
+        let stamp = self.next_stamp();
+        self.delete_from_storage(&key, stamp)?;
+        self.record_change(key, None);
+        Ok(())
+    }
+
+    fn delete_from_storage(&mut self, key: &[u8], stamp: VersionStamp) -> Result<()> {
+        if let Some(store) = &mut self.leveled {
+            store.delete_record(key.to_vec())?;
+        } else {
+            self.write_version(KeyValue::new(key.to_vec(), Vec::new()), stamp)?;
+        }
+
+        self.expirations.clear(key)?;
+        self.flags.clear(key)?;
+        self.content_types.clear(key)?;
+        self.indexes.update(key, &[], None);
+        Ok(())
+    }
+
+    /// Refuses writes other than those replicated from a leader, until the
+    /// database is made writable again when promoted.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+        for collection in self.collections.values_mut() {
+            collection.set_read_only(read_only);
+        }
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        match self.read_only {
+            true => Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "Read-only replica, writes go to the leader",
+            )),
+            false => Ok(()),
+        }
+    }
+
+    /// Applies a write replicated from a leader, keeping its sequence, even
+    /// when read-only. Events already applied are skipped, returning false,
+    /// while a gap in the sequences fails.
+    pub fn apply_change(&mut self, event: ChangeEvent) -> Result<bool> {
+        let last_sequence = self.get_changes()?.last_sequence();
+        if event.sequence <= last_sequence {
+            return Ok(false);
+        }
+        if event.sequence != last_sequence + 1 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Expected the change {}, got {}",
+                    last_sequence + 1,
+                    event.sequence
+                ),
+            ));
+        }
+
+        let stamp = self.next_stamp();
+        match &event.value {
+            Some(value) => {
+                self.save_to_storage(KeyValue::new(event.key.clone(), value.clone()), stamp)?
+            }
+            None => self.delete_from_storage(&event.key, stamp)?,
+        }
+        self.record_change(event.key, event.value);
+        Ok(true)
+    }
+
+    /// Replaces every record with `records`, the state of a leader at
+    /// `sequence`, from which changes go on. The change log, when kept, is
+    /// emptied, as the events before it did not happen here.
+    pub fn install_snapshot(&mut self, sequence: u64, records: Vec<KeyValue>) -> Result<()> {
+        // the writes of the snapshot get no sequence, as they are not
+        // events of the leader
+        let stamp = match self.is_stamped() {
+            true => VersionStamp {
+                sequence: 0,
+                time: now_millis(),
+            },
+            false => VersionStamp::default(),
+        };
+        let kept: HashSet<&ByteString> = records.iter().map(|record| &record.key).collect();
+        for key in self.get_keys()? {
+            if !kept.contains(&key) {
+                self.delete_from_storage(&key, stamp)?;
+            }
+        }
+        for record in records {
+            self.save_to_storage(record, stamp)?;
+        }
+
+        match &mut self.changes {
+            Some(changes) => changes.reset(sequence),
+            None => Ok(()),
+        }
+    }
+
+    /// Applies what a leader replicated, even when read-only: a change as
+    /// `apply_change` does, the metadata of keys, or the collections to
+    /// create and drop, which are then followed on their own.
+    pub fn apply_replicated(&mut self, event: ReplicationEvent) -> Result<()> {
+        match event {
+            ReplicationEvent::Change(event) => self.apply_change(event).map(|_| ()),
+            ReplicationEvent::Metadata(metadata) => self.apply_metadata(metadata),
+            ReplicationEvent::AllMetadata(all) => {
+                let kept: HashSet<ByteString> =
+                    all.iter().map(|metadata| metadata.key.clone()).collect();
+                let mut keys: HashSet<ByteString> =
+                    self.flags.iter().map(|(key, _)| key.clone()).collect();
+                keys.extend(self.content_types.iter().map(|(key, _)| key.clone()));
+                keys.extend(self.expirations.iter().map(|(key, _)| key.clone()));
+                for key in keys.difference(&kept) {
+                    self.apply_metadata(KeyMetadata {
+                        key: key.clone(),
+                        ..KeyMetadata::default()
+                    })?;
+                }
+                for metadata in all {
+                    self.apply_metadata(metadata)?;
+                }
+                Ok(())
+            }
+            ReplicationEvent::Collections(names) => {
+                for name in self.list_collections() {
+                    if !names.contains(&name) {
+                        self.remove_collection(&name)?;
+                    }
+                }
+                for name in names {
+                    if !self.collections.contains_key(&name) {
+                        check_name("collection", &name)?;
+                        self.add_collection(&name)?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn apply_metadata(&mut self, metadata: KeyMetadata) -> Result<()> {
+        let key = metadata.key;
+        match metadata.flags {
+            0 => self.flags.clear(&key).map(|_| ())?,
+            flags => self.flags.set(key.clone(), flags as u64)?,
+        }
+        match metadata.content_type {
+            Some(content_type) => self
+                .content_types
+                .set(key.clone(), content_type.into_bytes())?,
+            None => self.content_types.clear(&key).map(|_| ())?,
+        }
+        match metadata.expires_at {
+            Some(deadline) => self.expirations.set(key, deadline),
+            None => self.expirations.clear(&key).map(|_| ()),
+        }
+    }
+
+    fn key_metadata(&self, key: &[u8]) -> KeyMetadata {
+        KeyMetadata {
+            key: key.to_vec(),
+            flags: self.get_flags(key),
+            content_type: self.get_content_type(key),
+            expires_at: self.expirations.deadline(key),
+        }
+    }
+
+    /// Sends the metadata of `key` to the followers, once it changed.
+    fn publish_metadata(&mut self, key: &[u8]) {
+        if !self.changes.as_ref().is_some_and(ChangeLog::has_replicas) {
+            return;
+        }
+        let event = ReplicationEvent::Metadata(self.key_metadata(key));
+        self.changes.as_mut().unwrap().publish_to_replicas(event);
+    }
+
+    fn publish_collections(&mut self) {
+        let event = ReplicationEvent::Collections(self.list_collections());
+        if let Some(changes) = &mut self.changes {
+            changes.publish_to_replicas(event);
+        }
+    }
+
+    /// Delivers what a follower needs to go on from sequence `from`, in the
+    /// order the database applied it: the changes still in the segments,
+    /// then the metadata of every key and the names of the collections as
+    /// they are after them, then each change and change of metadata as it
+    /// is applied. Collections are followed on their own. Fails as
+    /// `changes` does when the changes from `from` on were discarded.
+    pub fn replicate(&mut self, from: u64) -> Result<Subscription<ReplicationEvent>> {
+        let mut backlog: Vec<ReplicationEvent> = self
+            .changes(&ChangeFilter::from_sequence(from), usize::MAX)?
+            .into_iter()
+            .map(ReplicationEvent::Change)
+            .collect();
+
+        let mut keys: Vec<&ByteString> = self.flags.iter().map(|(key, _)| key).collect();
+        keys.extend(self.content_types.iter().map(|(key, _)| key));
+        keys.extend(self.expirations.iter().map(|(key, _)| key));
+        keys.sort();
+        keys.dedup();
+        let all = keys.into_iter().map(|key| self.key_metadata(key)).collect();
+        backlog.push(ReplicationEvent::AllMetadata(all));
+        backlog.push(ReplicationEvent::Collections(self.list_collections()));
+
+        Ok(self.changes.as_mut().unwrap().subscribe_replica(backlog))
+    }
+
+    /// Publishes the event of a write saved to the storage, stamped with
+    /// the next sequence, when changes are kept.
+    fn record_change(&mut self, key: ByteString, value: Option<ByteString>) {
+        if let Some(changes) = &mut self.changes {
+            changes.publish(key, value);
+        }
+    }
+
+    fn get_changes(&self) -> Result<&ChangeLog> {
+        if self.leveled.is_some() {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "Changes are only kept on log storage",
+            ));
+        }
+        self.changes.as_ref().ok_or_else(|| {
+            Error::new(
+                ErrorKind::Unsupported,
+                "Changes are not kept, as the change log size is 0",
+            )
+        })
+    }
+
+    /// Sequence of the latest write, 0 before the first one or when changes
+    /// are not kept.
+    pub fn last_sequence(&self) -> u64 {
+        self.changes
+            .as_ref()
+            .map_or(0, |changes| changes.last_sequence())
+    }
+
+    /// Up to `limit` events of writes matching `filter`, read from the
+    /// records of the segments. Fails when the events from `filter.from` on
+    /// were already discarded by compaction, or when changes are not kept.
+    pub fn changes(&self, filter: &ChangeFilter, limit: usize) -> Result<Vec<ChangeEvent>> {
+        let from = filter.from.max(1);
+        self.get_changes()?.check_kept(from)?;
+
+        let mut events = Vec::new();
+        let segment = match &self.segment {
+            Some(segment) => segment,
+            None => return Ok(events),
+        };
+        for (sequence, segment, position) in segment.sequenced_from(from) {
+            if events.len() >= limit {
+                break;
+            }
+            let record = segment.read_record_at(position)?;
+            let event = ChangeEvent {
+                sequence,
+                key: record.key,
+                value: Some(record.value).filter(|value| !value.is_empty()),
+            };
+            if filter.matches(&event) {
+                events.push(event);
+            }
+        }
+        Ok(events)
+    }
+
+    /// Delivers the events of writes matching `filter`: those still in the
+    /// segments first, then those of later writes as they are applied. A
+    /// subscriber that disconnects, or falls too far behind and gets
+    /// dropped, resumes by subscribing again from the sequence after the
+    /// last event it got.
+    pub fn subscribe(&mut self, filter: ChangeFilter) -> Result<Subscription> {
+        let backlog = self.changes(&filter, usize::MAX)?;
+        Ok(self.changes.as_mut().unwrap().subscribe(filter, backlog))
+    }
+
+    /// Attaches opaque `flags` to `key`, as memcached clients do to tell how
+    /// a value was serialized. Like expirations, flags are kept when the key
+    /// is saved again and removed when it is deleted.
+    pub fn set_flags<K: Into<ByteString>>(&mut self, key: K, flags: u32) -> Result<()> {
+        self.check_writable()?;
+        let key = key.into();
+        match flags {
+            0 => self.flags.clear(&key).map(|_| ())?,
+            flags => self.flags.set(key.clone(), flags as u64)?,
+        }
+        self.publish_metadata(&key);
+        Ok(())
+    }
+
+    pub fn get_flags<K: Into<ByteString>>(&self, key: K) -> u32 {
+        self.flags.get(&key.into()).copied().unwrap_or(0) as u32
+    }
+
+    /// Records the media type the value of `key` was written with, as the
+    /// HTTP API does to answer with it, or forgets it when `None`. It is
+    /// removed when the key is deleted.
+    pub fn set_content_type<K: Into<ByteString>>(
+        &mut self,
+        key: K,
+        content_type: Option<&str>,
+    ) -> Result<()> {
+        self.check_writable()?;
+        let key = key.into();
+        match content_type {
+            Some(content_type) if !content_type.is_empty() => self
+                .content_types
+                .set(key.clone(), content_type.as_bytes().to_vec())?,
+            _ => self.content_types.clear(&key).map(|_| ())?,
+        }
+        self.publish_metadata(&key);
+        Ok(())
+    }
+
+    pub fn get_content_type<K: Into<ByteString>>(&self, key: K) -> Option<String> {
+        self.content_types
+            .get(&key.into())
+            .map(|content_type| String::from_utf8_lossy(content_type).into_owned())
+    }
+
+    /// Makes `key` expire after `ttl`, returning false when it does not
+    /// exist. Saving the key again keeps its expiration, while deleting it
+    /// removes it.
+    pub fn expire<K: Into<ByteString>>(&mut self, key: K, ttl: Duration) -> Result<bool> {
+        self.check_writable()?;
+        let key = key.into();
+        if self.get_record(key.clone())?.is_none() {
+            return Ok(false);
+        }
+
+        let deadline = now_millis().saturating_add(ttl.as_millis() as u64);
+        self.expirations.set(key.clone(), deadline)?;
+        self.publish_metadata(&key);
+        Ok(true)
+    }
+
+    /// Saves the record to expire after `ttl`, or never when `None`. The
+    /// expiration is written before the value, so that a value is never
+    /// stored without it.
+    pub fn save_record_with_ttl(
+        &mut self,
+        key_value: KeyValue,
+        ttl: Option<Duration>,
+    ) -> Result<()> {
+        self.check_writable()?;
+        if !key_value.value.is_empty() {
+            self.schemas.check(&key_value.value)?;
+        }
+
+        let expiring = match ttl {
+            Some(ttl) => {
+                let deadline = now_millis().saturating_add(ttl.as_millis() as u64);
+                self.expirations.set(key_value.key.clone(), deadline)?;
+                true
+            }
+            None => self.expirations.clear(&key_value.key)?,
+        };
+        if expiring {
+            self.publish_metadata(&key_value.key);
+        }
+        self.save_record(key_value)
+    }
+
+    /// Removes the expiration of `key`, returning whether it had one.
+    pub fn persist<K: Into<ByteString>>(&mut self, key: K) -> Result<bool> {
+        self.check_writable()?;
+        let key = key.into();
+        if self.get_record(key.clone())?.is_none() {
+            return Ok(false);
+        }
+
+        let persisted = self.expirations.clear(&key)?;
+        if persisted {
+            self.publish_metadata(&key);
+        }
+        Ok(persisted)
+    }
+
+    /// Time left before `key` expires, or `None` when it never does.
+    pub fn time_to_live<K: Into<ByteString>>(&self, key: K) -> Option<Duration> {
+        self.expirations.time_left(&key.into())
+    }
+
+    /// Deletes the keys whose expiration passed, which are already hidden
+    /// from reads, in every collection too, returning how many were deleted.
+    /// Followers leave it to their leader.
+    pub fn remove_expired(&mut self) -> Result<usize> {
+        // a follower gets the deletes of its leader
+        if self.read_only {
+            return Ok(0);
+        }
+
+        let keys = self.expirations.expired_keys();
+        let mut count = keys.len();
+
+        for key in keys {
+            self.delete_record(key)?;
+        }
+        for collection in self.collections.values_mut() {
+            count += collection.remove_expired()?;
+        }
+
+        Ok(count)
+    }
+
+    /// Every live key, sorted.
+    pub fn get_keys(&self) -> Result<Vec<ByteString>> {
+        let mut keys = match (&self.leveled, &self.segment) {
+            (Some(store), _) => store.get_keys()?,
+            (None, Some(segment)) => segment.get_live_keys(),
+            (None, None) => Vec::new(),
+        };
+
+        keys.retain(|key| !self.expirations.is_expired(key));
+        keys.sort();
+        Ok(keys)
+    }
+
+    /// Up to `limit` live keys starting with `prefix`, in order, from `start`
+    /// on. The ordered index of every segment of the chain, or every table
+    /// of the leveled storage, is read from `start` on and merged, leaving
+    /// out deleted, expired and superseded entries.
+    pub fn scan_keys(&self, prefix: &[u8], start: &[u8], limit: usize) -> Result<KeyPage> {
+        let start = start.max(prefix);
+        let scanned: Box<dyn Iterator<Item = Result<ByteString>>> =
+            match (&self.leveled, &self.segment) {
+                (Some(store), _) => Box::new(store.keys_from(start)),
+                (None, Some(segment)) => Box::new(segment.live_keys_from(start).map(Ok)),
+                (None, None) => Box::new(std::iter::empty()),
+            };
+
+        // reads only the keys of the page and the one after it
+        let mut keys = Vec::new();
+        for key in scanned {
+            let key = key?;
+            if !key.starts_with(prefix) || keys.len() > limit {
+                break;
+            }
+            if !self.expirations.is_expired(&key) {
+                keys.push(key);
+            }
         }
+
+        let next = match keys.len() > limit {
+            true => keys.pop(),
+            false => None,
+        };
+        Ok(KeyPage { keys, next })
     }
 
     pub fn save_record(&mut self, key_value: KeyValue) -> Result<()> {
+        self.check_writable()?;
+        if !key_value.value.is_empty() {
+            self.schemas.check(&key_value.value)?;
+        }
+        let change = self
+            .changes
+            .as_ref()
+            .map(|_| (key_value.key.clone(), key_value.value.clone()));
+
+        let stamp = self.next_stamp();
+        self.save_to_storage(key_value, stamp)?;
+
+        if let Some((key, value)) = change {
+            // an empty value marks the key as deleted
+            self.record_change(key, Some(value).filter(|v| !v.is_empty()));
+        }
+        Ok(())
+    }
+
+    /// Adds `delta` to the counter at `key`, starting from 0 when missing,
+    /// and returns its new value. Counters are stored as decimal text, such
+    /// as `42`, so they read as JSON numbers, and keep their expiration and
+    /// content type. Values that are not integers and results that overflow
+    /// fail as `InvalidData`, leaving the value as it was.
+    pub fn increment<K: Into<ByteString>>(&mut self, key: K, delta: i64) -> Result<i64> {
+        self.check_writable()?;
+        let key = key.into();
+        let current = self.get_record(key.clone())?;
+        let value = incremented(current.as_ref().map(|kv| &kv.value[..]), delta)?;
+        self.save_record(KeyValue::new(key, value.to_string().into_bytes()))?;
+        Ok(value)
+    }
+
+    fn save_to_storage(&mut self, key_value: KeyValue, stamp: VersionStamp) -> Result<()> {
+        let indexed = match self.indexes.is_empty() {
+            true => None,
+            false => Some(key_value.clone()),
+        };
+        self.write_to_storage(key_value, stamp)?;
+
+        if let Some(record) = indexed {
+            self.indexes.update(&record.key, &record.value, None);
+        }
+        Ok(())
+    }
+
+    fn write_to_storage(&mut self, key_value: KeyValue, stamp: VersionStamp) -> Result<()> {
+        if let Some(store) = &mut self.leveled {
+            return store.save_record(key_value);
+        }
+
+        self.write_version(key_value, stamp)
+    }
+
+    /// Whether writes are stamped with their sequence and time, as they are
+    /// on log storage while history or changes are kept.
+    fn is_stamped(&self) -> bool {
+        self.leveled.is_none()
+            && (!self.options.history_retention.is_zero() || self.changes.is_some())
+    }
+
+    /// Stamp of the write about to be applied, unknown while writes are not
+    /// stamped. Sequences follow those of the change log when there is one,
+    /// and only count once the write is saved.
+    fn next_stamp(&mut self) -> VersionStamp {
+        if !self.is_stamped() {
+            return VersionStamp::default();
+        }
+
+        self.sequence = match &self.changes {
+            Some(changes) => changes.next_sequence(),
+            None => self.sequence + 1,
+        };
+        VersionStamp {
+            sequence: self.sequence,
+            time: now_millis(),
+        }
+    }
+
+    fn write_version(&mut self, key_value: KeyValue, stamp: VersionStamp) -> Result<()> {
         match &mut self.segment {
             Some(value) => {
-                value.save_record(key_value)?;
+                value.save_version(key_value, stamp)?;
 
-                if value.get_size() > MAX_SIZE_FILE {
-                    let new_segment = DataSgment::new(&self.folder);
-                    let current_segment = self.segment.replace(new_segment);
-                    self.segment.as_mut().unwrap().set_previous(current_segment);
+                if value.get_size() > self.options.segment_size {
+                    let mut new_segment = DataSgment::try_new(&self.folder, &self.vfs)?;
+                    new_segment.set_durability(self.options.durability);
+                    new_segment.set_history_retention(self.options.history_retention);
+                    value.update_next_file(new_segment.name)?;
+                    new_segment.set_previous(self.segment.take());
+                    new_segment.build_missing_filters(self.options.bloom_false_positive_rate);
+                    self.segment.replace(new_segment);
                 }
             }
             None => return Ok(()),
@@ -79,6 +955,75 @@ impl RustDB {
         Ok(())
     }
 
+    /// Versions of `key` still in the log, oldest first, deletes included.
+    /// Overwritten versions last until compaction, which keeps those younger
+    /// than `Options::history_retention`, while versions written before a
+    /// retention was set have unknown sequences and times. Fails as
+    /// `Unsupported` when the retention is zero, as writes are not stamped,
+    /// and on leveled storage.
+    pub fn history<K: Into<ByteString>>(&self, key: K) -> Result<Vec<Version>> {
+        if self.leveled.is_some() {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "History is only kept on log storage",
+            ));
+        }
+        if self.options.history_retention.is_zero() {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "History is not kept, as the history retention is 0",
+            ));
+        }
+
+        let key = key.into();
+        let mut segments = Vec::new();
+        let mut current = self.segment.as_ref();
+        while let Some(segment) = current {
+            segments.push(segment);
+            current = segment.get_previous().as_deref();
+        }
+
+        let mut versions = Vec::new();
+        for segment in segments.into_iter().rev() {
+            for (position, stamp) in segment.get_versions(&key) {
+                let record = segment.read_record_at(*position)?;
+                versions.push(Version {
+                    sequence: stamp.sequence,
+                    time: stamp.time,
+                    value: Some(record.value).filter(|value| !value.is_empty()),
+                });
+            }
+        }
+        Ok(versions)
+    }
+
+    /// Value `key` had at `at`, `None` when it was missing or deleted then,
+    /// or when the versions of that time were already discarded by
+    /// compaction. Expirations are not taken into account, and it fails as
+    /// `history` does when no history is kept.
+    pub fn get_record_at<K: Into<ByteString>>(
+        &self,
+        key: K,
+        at: PointInTime,
+    ) -> Result<Option<KeyValue>> {
+        let key = key.into();
+        let value = self
+            .history(key.clone())?
+            .into_iter()
+            .take_while(|version| match at {
+                PointInTime::Sequence(sequence) => version.sequence <= sequence,
+                PointInTime::Time(time) => version.time <= time,
+            })
+            .last()
+            .and_then(|version| version.value);
+
+        Ok(value.map(|value| KeyValue::new(key, value)))
+    }
+
+    pub fn get_options(&self) -> Options {
+        self.options.clone()
+    }
+
     pub fn get_closed_segment_names(&self) -> Vec<String> {
         let mut result = Vec::new();
 
@@ -101,34 +1046,358 @@ impl RustDB {
         result
     }
 
+    /// Live and dead space of every segment, from the active one to the oldest.
+    pub fn stats(&self) -> Vec<SegmentStats> {
+        let mut result = Vec::new();
+        let mut current = self.segment.as_ref();
+
+        while let Some(s) = current {
+            result.push(s.get_stats());
+            current = s.get_previous().as_deref();
+        }
+
+        result
+    }
+
+    /// Closed segments worth compressing, from the newest to the oldest one
+    /// with at least `MIN_DEAD_RATIO` of dead space. Older segments are left
+    /// untouched, and an empty list means compression is not worthwhile.
+    pub fn get_segments_to_compress(&self) -> Vec<String> {
+        let closed_names: HashSet<String> = self.get_closed_segment_names().into_iter().collect();
+        let closed: Vec<SegmentStats> = self
+            .stats()
+            .into_iter()
+            .filter(|s| closed_names.contains(&s.name))
+            .collect();
+
+        match closed
+            .iter()
+            .rposition(|s| s.dead_ratio() >= MIN_DEAD_RATIO)
+        {
+            Some(oldest) => closed[..=oldest].iter().map(|s| s.name.clone()).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Compresses the segments worth it in place, holding the database
+    /// meanwhile. Servers rather compress without holding it, as in
+    /// `LogCompressor`.
+    pub fn compress_segments(&mut self) -> Result<()> {
+        let segment_names = self.get_segments_to_compress();
+        if segment_names.is_empty() {
+            return Ok(());
+        }
+
+        let (active_segment, new_segment) = LogCompressor::new(
+            &self.folder,
+            segment_names.clone(),
+            self.get_active_segment_name(),
+        )
+        .with_vfs(Arc::clone(&self.vfs))
+        .with_active_keys(self.get_active_keys())
+        .with_options(self.options.clone())
+        .compress()?;
+
+        self.replace_segments(active_segment, new_segment);
+        LogCompressor::clean_with_vfs(&self.vfs, &self.folder, segment_names);
+        Ok(())
+    }
+
+    /// Copies the files of the database, and of its collections, to the
+    /// folder `to` once its segments are compressed and its records are
+    /// durable, so that another database can be loaded from the copy.
+    /// Returns the path of every file within `to`, with its size.
+    pub fn copy_files(&mut self, to: &str) -> Result<Vec<(String, u64)>> {
+        self.compress_segments()?;
+        self.flush()?;
+
+        let from_path = folder_path(&self.folder);
+        let to_path = folder_path(to);
+        self.vfs.create_dir_all(&to_path)?;
+        let mut names = self.vfs.list(&from_path)?;
+        names.retain(|name| name != COLLECTIONS_FOLDER && !name.ends_with(".tmp"));
+        names.sort();
+
+        let mut files = Vec::new();
+        for name in names {
+            let from = build_path(&from_path, &name);
+            let size = copy_file(&*self.vfs, &from, &build_path(&to_path, &name))?;
+            files.push((name, size));
+        }
+        for (name, collection) in &mut self.collections {
+            let folder = format!("{}/{}", COLLECTIONS_FOLDER, name);
+            for (path, size) in collection.copy_files(&format!("{}/{}", to, folder))? {
+                files.push((format!("{}/{}", folder, path), size));
+            }
+        }
+        Ok(files)
+    }
+
+    /// How often Bloom filters spared a segment or table lookup.
+    pub fn bloom_stats(&self) -> BloomStats {
+        match &self.leveled {
+            Some(store) => store.get_bloom_stats(),
+            None => self.bloom_counters.get_stats(),
+        }
+    }
+
+    /// Makes every saved record durable, of every collection too, writing
+    /// the memtable to a table on leveled storage.
+    pub fn flush(&mut self) -> Result<()> {
+        for collection in self.collections.values_mut() {
+            collection.flush()?;
+        }
+
+        if let Some(store) = &mut self.leveled {
+            return store.flush();
+        }
+
+        match &mut self.segment {
+            Some(segment) => segment.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Number of tables on each level, empty unless on leveled storage.
+    pub fn get_level_table_counts(&self) -> Vec<usize> {
+        match &self.leveled {
+            Some(store) => store.get_table_counts(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn get_active_keys(&self) -> HashSet<ByteString> {
+        match &self.segment {
+            Some(s) => s.index.keys().cloned().collect(),
+            None => HashSet::new(),
+        }
+    }
+
     pub fn get_active_segment_name(&self) -> u64 {
         self.segment.as_ref().unwrap().name
     }
 
     pub fn replace_segments(&mut self, replace_segment: u64, new_segment: DataSgment) {
-        RustDB::recursive(self.segment.as_mut().unwrap(), replace_segment, new_segment);
+        let segment = self.segment.as_mut().unwrap();
+        RustDB::recursive(segment, replace_segment, new_segment);
+        segment.refresh_stats();
+        segment.build_missing_filters(self.options.bloom_false_positive_rate);
     }
 
     fn recursive(current_segment: &mut DataSgment, replace_segment: u64, new_segment: DataSgment) {
         if current_segment.name == replace_segment {
-            current_segment.previous.replace(Box::from(new_segment));
-        } else {
-            if current_segment.previous.is_some() {
-                RustDB::recursive(
-                    current_segment.previous.as_mut().unwrap(),
-                    replace_segment,
-                    new_segment,
-                );
+            let replaced = current_segment.previous.replace(Box::from(new_segment));
+            let oldest = RustDB::oldest(current_segment.previous.as_mut().unwrap());
+            oldest.previous = RustDB::retained_segments(replaced, &oldest.get_name());
+        } else if let Some(previous) = current_segment.previous.as_mut() {
+            RustDB::recursive(previous, replace_segment, new_segment);
+        }
+    }
+
+    fn oldest(segment: &mut DataSgment) -> &mut DataSgment {
+        match segment.previous {
+            Some(ref mut previous) => RustDB::oldest(previous),
+            None => segment,
+        }
+    }
+
+    /// Segments older than the compressed ones were linked on disk to the
+    /// oldest compressed segment, so they are the ones to keep in the chain.
+    fn retained_segments(
+        mut replaced: Option<Box<DataSgment>>,
+        oldest_name: &str,
+    ) -> Option<Box<DataSgment>> {
+        while let Some(mut segment) = replaced {
+            if segment.read_next_file().as_deref() == Some(oldest_name) {
+                return Some(segment);
             }
+            replaced = segment.previous.take();
         }
+
+        None
+    }
+}
+
+/// The counter `current` plus `delta`, where a missing counter is 0.
+pub(crate) fn incremented(current: Option<&[u8]>, delta: i64) -> Result<i64> {
+    let current = match current {
+        Some(value) => std::str::from_utf8(value)
+            .ok()
+            .and_then(|value| value.parse::<i64>().ok())
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    "value is not an integer or out of range",
+                )
+            })?,
+        None => 0,
+    };
+
+    current.checked_add(delta).ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidData,
+            "increment or decrement would overflow",
+        )
+    })
+}
+
+/// Names of collections and indexes are up to 64 letters, digits, `-` and
+/// `_`, so they can be used in paths.
+fn check_name(kind: &str, name: &str) -> Result<()> {
+    let valid = name.len() <= MAX_NAME_SIZE
+        && !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+
+    match valid {
+        true => Ok(()),
+        false => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid {} name: {}", kind, name),
+        )),
+    }
+}
+
+/// Operations the servers need from a database, so that a `RustDB` or a
+/// `ShardedDB` can be served alike.
+pub trait Store: Send {
+    fn get_record(&self, key: &[u8]) -> Result<Option<KeyValue>>;
+    fn save_record(&mut self, key_value: KeyValue) -> Result<()>;
+    fn delete_record(&mut self, key: &[u8]) -> Result<()>;
+    fn increment(&mut self, key: &[u8], delta: i64) -> Result<i64>;
+    fn get_content_type(&self, key: &[u8]) -> Option<String>;
+    fn set_content_type(&mut self, key: &[u8], content_type: Option<&str>) -> Result<()>;
+    fn scan_keys(&self, prefix: &[u8], start: &[u8], limit: usize) -> Result<KeyPage>;
+    fn last_sequence(&self) -> u64;
+    fn changes(&self, filter: &ChangeFilter, limit: usize) -> Result<Vec<ChangeEvent>>;
+    fn subscribe(&mut self, filter: ChangeFilter) -> Result<Subscription>;
+    fn replicate(&mut self, from: u64) -> Result<Subscription<ReplicationEvent>>;
+    fn is_read_only(&self) -> bool;
+    fn set_read_only(&mut self, read_only: bool);
+    fn remove_expired(&mut self) -> Result<usize>;
+    fn flush(&mut self) -> Result<()>;
+    fn list_collections(&self) -> Vec<String>;
+    fn create_collection(&mut self, name: &str) -> Result<bool>;
+    fn drop_collection(&mut self, name: &str) -> Result<bool>;
+    fn collection_mut(&mut self, name: &str) -> Option<&mut dyn Store>;
+    fn create_index(&mut self, name: &str, path: &str) -> Result<bool>;
+    fn drop_index(&mut self, name: &str) -> Result<bool>;
+    fn list_indexes(&self) -> Vec<(String, String)>;
+    fn find_by(&self, index: &str, value: &str) -> Result<Vec<KeyValue>>;
+    fn set_schema(&mut self, schema: Option<Value>) -> Result<u64>;
+    fn schema_versions(&self) -> Vec<(u64, Option<Value>)>;
+}
+
+impl Store for RustDB {
+    fn get_record(&self, key: &[u8]) -> Result<Option<KeyValue>> {
+        RustDB::get_record(self, key)
+    }
+
+    fn save_record(&mut self, key_value: KeyValue) -> Result<()> {
+        RustDB::save_record(self, key_value)
+    }
+
+    fn delete_record(&mut self, key: &[u8]) -> Result<()> {
+        RustDB::delete_record(self, key)
+    }
+
+    fn increment(&mut self, key: &[u8], delta: i64) -> Result<i64> {
+        RustDB::increment(self, key, delta)
+    }
+
+    fn get_content_type(&self, key: &[u8]) -> Option<String> {
+        RustDB::get_content_type(self, key)
+    }
+
+    fn set_content_type(&mut self, key: &[u8], content_type: Option<&str>) -> Result<()> {
+        RustDB::set_content_type(self, key, content_type)
+    }
+
+    fn scan_keys(&self, prefix: &[u8], start: &[u8], limit: usize) -> Result<KeyPage> {
+        RustDB::scan_keys(self, prefix, start, limit)
+    }
+
+    fn last_sequence(&self) -> u64 {
+        RustDB::last_sequence(self)
+    }
+
+    fn changes(&self, filter: &ChangeFilter, limit: usize) -> Result<Vec<ChangeEvent>> {
+        RustDB::changes(self, filter, limit)
+    }
+
+    fn subscribe(&mut self, filter: ChangeFilter) -> Result<Subscription> {
+        RustDB::subscribe(self, filter)
+    }
+
+    fn replicate(&mut self, from: u64) -> Result<Subscription<ReplicationEvent>> {
+        RustDB::replicate(self, from)
+    }
+
+    fn is_read_only(&self) -> bool {
+        RustDB::is_read_only(self)
+    }
+
+    fn set_read_only(&mut self, read_only: bool) {
+        RustDB::set_read_only(self, read_only)
+    }
+
+    fn remove_expired(&mut self) -> Result<usize> {
+        RustDB::remove_expired(self)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        RustDB::flush(self)
+    }
+
+    fn list_collections(&self) -> Vec<String> {
+        RustDB::list_collections(self)
+    }
+
+    fn create_collection(&mut self, name: &str) -> Result<bool> {
+        RustDB::create_collection(self, name)
+    }
+
+    fn drop_collection(&mut self, name: &str) -> Result<bool> {
+        RustDB::drop_collection(self, name)
+    }
+
+    fn collection_mut(&mut self, name: &str) -> Option<&mut dyn Store> {
+        RustDB::collection_mut(self, name).map(|collection| collection as &mut dyn Store)
+    }
+
+    fn create_index(&mut self, name: &str, path: &str) -> Result<bool> {
+        RustDB::create_index(self, name, path)
+    }
+
+    fn drop_index(&mut self, name: &str) -> Result<bool> {
+        RustDB::drop_index(self, name)
+    }
+
+    fn list_indexes(&self) -> Vec<(String, String)> {
+        RustDB::list_indexes(self)
+    }
+
+    fn find_by(&self, index: &str, value: &str) -> Result<Vec<KeyValue>> {
+        RustDB::find_by(self, index, value)
+    }
+
+    fn set_schema(&mut self, schema: Option<Value>) -> Result<u64> {
+        RustDB::set_schema(self, schema)
+    }
+
+    fn schema_versions(&self) -> Vec<(u64, Option<Value>)> {
+        RustDB::schema_versions(self)
     }
 }
 
 pub struct LogCompressor {
-    db: RustDB,
     folder: String,
     closed_segments: Vec<String>,
     active_segment_name: u64,
+    active_keys: HashSet<ByteString>,
+    options: Options,
+    vfs: Arc<dyn Vfs>,
 }
 
 impl LogCompressor {
@@ -138,27 +1407,115 @@ impl LogCompressor {
         active_segment_name: u64,
     ) -> LogCompressor {
         LogCompressor {
-            db: RustDB::new(folder),
             folder: String::from(folder),
             closed_segments,
             active_segment_name,
+            active_keys: HashSet::new(),
+            options: Options::default(),
+            vfs: Arc::new(DiskVfs),
         }
     }
 
-    pub fn compress(mut self) -> (u64, DataSgment) {
-        for segment_name in self.closed_segments {
-            let data_segment = DataSgment::open(&format!("{}/{}", self.folder, segment_name));
+    /// File system holding the segments, which must be the one of the
+    /// database being compressed.
+    pub fn with_vfs(mut self, vfs: Arc<dyn Vfs>) -> LogCompressor {
+        self.vfs = vfs;
+        self
+    }
+
+    /// Keys already rewritten on the active segment, whose older versions
+    /// can be dropped instead of copied to the compressed segments.
+    pub fn with_active_keys(mut self, active_keys: HashSet<ByteString>) -> LogCompressor {
+        self.active_keys = active_keys;
+        self
+    }
+
+    /// Options of the database being compressed, so the compressed
+    /// segments are written as its own are, and the versions within its
+    /// `history_retention` are kept besides the latest ones.
+    pub fn with_options(mut self, options: Options) -> LogCompressor {
+        self.options = options;
+        self
+    }
+
+    /// Rewrites the closed segments into new ones, returning them with the
+    /// name of the active segment they link to. The storage is only switched
+    /// to them once they are complete, so a failure leaves it unchanged.
+    pub fn compress(self) -> Result<(u64, DataSgment)> {
+        let mut db = RustDB::new(&self.folder, self.options.clone(), &self.vfs)?;
+        let retained_segment = self.find_previous_segment();
+        let segments: Vec<DataSgment> = self
+            .closed_segments
+            .iter()
+            .map(|name| {
+                let path = build_path(&folder_path(&self.folder), name);
+                DataSgment::open(&path, &self.vfs, self.options.history_retention)
+            })
+            .collect();
+        let retention = self.options.history_retention;
+        let cutoff = match retention.is_zero() {
+            true => u64::MAX,
+            false => now_millis().saturating_sub(retention.as_millis() as u64),
+        };
+
+        let mut written = HashSet::new();
+        // every record of each key, from the oldest segment to the newest
+        let mut versions: HashMap<&ByteString, Vec<(&DataSgment, u64, VersionStamp)>> =
+            HashMap::new();
+        for segment in segments.iter().rev() {
+            for (key, records) in segment.iter_versions() {
+                versions.entry(key).or_default().extend(
+                    records
+                        .iter()
+                        .map(|(position, stamp)| (segment, *position, *stamp)),
+                );
+            }
+        }
+
+        for (key, records) in versions {
+            let (newest, older) = records.split_last().unwrap();
+            let kept: Vec<_> = older
+                .iter()
+                .filter(|(_, _, stamp)| stamp.time >= cutoff)
+                .collect();
+            for (segment, position, stamp) in &kept {
+                db.write_version(segment.read_record_at(*position)?, *stamp)?;
+                written.insert(stamp.sequence);
+            }
 
-            for key in data_segment.index.keys() {
-                let key = String::from_utf8(key.clone()).unwrap();
-                if self.db.get_record(key.clone()).unwrap().is_none() {
-                    let key_value = data_segment.get_record(key).unwrap().unwrap();
-                    self.db.save_record(key_value).unwrap();
+            let (segment, position, stamp) = newest;
+            if self.active_keys.contains(key) {
+                if stamp.time >= cutoff {
+                    db.write_version(segment.read_record_at(*position)?, *stamp)?;
+                    written.insert(stamp.sequence);
                 }
+                continue;
+            }
+
+            let key_value = segment.read_record_at(*position)?;
+
+            // deletes only matter while older segments or kept versions may
+            // hold the key
+            if !key_value.value.is_empty() || retained_segment.is_some() || !kept.is_empty() {
+                db.write_version(key_value, *stamp)?;
+                written.insert(stamp.sequence);
             }
         }
 
-        let mut current_segment = self.db.segment.unwrap();
+        // the change feed is read from the records, so it must not serve
+        // the sequences around those dropped, which is made durable before
+        // the segments are switched
+        let dropped = segments
+            .iter()
+            .flat_map(|segment| segment.get_sequenced())
+            .map(|(_, stamp)| stamp.sequence)
+            .filter(|sequence| !written.contains(sequence))
+            .max();
+        if let (Some(sequence), true) = (dropped, self.options.change_log_size > 0) {
+            ChangeLog::discard_before(&self.vfs, &self.folder, sequence + 1)?;
+        }
+
+        let mut current_segment = db.segment.unwrap();
         let mut latest_segment_name = current_segment.name;
         let mut previous_segment = current_segment.get_previous();
 
@@ -167,17 +1524,44 @@ impl LogCompressor {
             previous_segment = seg.get_previous();
         }
 
-        current_segment.update_next_file(self.active_segment_name);
+        current_segment.update_next_file(self.active_segment_name)?;
+
+        match retained_segment {
+            Some(previous) => {
+                DataSgment::link(&self.vfs, &self.folder, &previous, latest_segment_name)?
+            }
+            None => InitialSegmentReference::load_with_vfs(&self.folder, &self.vfs)
+                .update(latest_segment_name)?,
+        }
+
+        Ok((self.active_segment_name, current_segment))
+    }
+
+    /// Finds the segment pointing to the oldest one being compressed, which
+    /// exists when only the newest closed segments are compressed.
+    fn find_previous_segment(&self) -> Option<String> {
+        let oldest = self.closed_segments.last()?;
+        let reference = InitialSegmentReference::load_with_vfs(&self.folder, &self.vfs);
+        let mut current = reference.initial_segment.map(parse_file_name)?;
 
-        let reference = InitialSegmentReference::load(&self.folder);
-        reference.update(latest_segment_name);
+        while &current != oldest {
+            match DataSgment::read_next_segment(&self.vfs, &self.folder, &current) {
+                Some(next) if &next == oldest => return Some(current),
+                Some(next) => current = next,
+                None => return None,
+            }
+        }
 
-        (self.active_segment_name, current_segment)
+        None
     }
 
     pub fn clean(folder: &str, segments: Vec<String>) {
+        LogCompressor::clean_with_vfs(&(Arc::new(DiskVfs) as Arc<dyn Vfs>), folder, segments);
+    }
+
+    pub fn clean_with_vfs(vfs: &Arc<dyn Vfs>, folder: &str, segments: Vec<String>) {
         for segment_name in segments {
-            DataSgment::remove(folder, &segment_name);
+            DataSgment::remove(vfs, folder, &segment_name);
         }
     }
 }
@@ -0,0 +1,818 @@
+use std::collections::BTreeMap;
+use std::io::{BufReader, Error, ErrorKind, Result};
+use std::sync::Arc;
+
+use crc::crc64;
+use serde_json::Value;
+
+use crate::changes::{ChangeEvent, ChangeFilter, ReplicationEvent, Subscription};
+use crate::core::{ByteString, KeyValue};
+use crate::options::Options;
+use crate::service::{incremented, KeyPage, RustDB, Store};
+use crate::store::{build_path, folder_path, read_record, write_record};
+use crate::vfs::{DiskVfs, FileReader, Vfs};
+
+static SHARDS_FILE: &str = "shards";
+static LAYOUT_KEY: &[u8] = b"shards";
+/// Points each shard gets on the hash ring, so that keys spread evenly and
+/// a new shard takes a share of every other one.
+static VIRTUAL_NODES: u32 = 64;
+
+/// Keys hash-partitioned across `RustDB` instances, each in a `shard-{id}`
+/// subfolder, with the layout of the shards saved in the file `shards`.
+///
+/// Keys go to a shard by consistent hashing, so adding or removing a shard
+/// only moves the keys it takes or gives. Those keys are moved online by
+/// `migrate`, a batch at a time: until it is done, reads fall back to the
+/// other shards and writes move a key to its own shard first.
+///
+/// Changes, collections, secondary indexes and schemas are kept per
+/// `RustDB`, so a sharded store does not support them: as a `Store`, it
+/// fails them as `Unsupported` and lists none.
+pub struct ShardedDB {
+    folder: String,
+    options: Options,
+    vfs: Arc<dyn Vfs>,
+    shards: BTreeMap<u32, RustDB>,
+    /// Shards that take keys, the others being emptied before they are
+    /// dropped.
+    active: Vec<u32>,
+    /// Points of the active shards on the hash ring, sorted.
+    ring: Vec<(u64, u32)>,
+    /// Whether some keys may still be on another shard than theirs.
+    migrating: bool,
+}
+
+impl ShardedDB {
+    pub fn load(folder: &str, shard_count: u32) -> Result<ShardedDB> {
+        ShardedDB::load_with_options(folder, shard_count, Options::default())
+    }
+
+    pub fn load_with_options(
+        folder: &str,
+        shard_count: u32,
+        options: Options,
+    ) -> Result<ShardedDB> {
+        ShardedDB::load_with_vfs(folder, shard_count, options, Arc::new(DiskVfs))
+    }
+
+    /// Loads the shards found in `folder`, or starts `shard_count` of them
+    /// when there are none yet. Use `resize` to change the count of an
+    /// existing sharded store. Fails when the layout of the shards cannot
+    /// be read or saved.
+    pub fn load_with_vfs(
+        folder: &str,
+        shard_count: u32,
+        options: Options,
+        vfs: Arc<dyn Vfs>,
+    ) -> Result<ShardedDB> {
+        let path = build_path(&folder_path(folder), SHARDS_FILE);
+        let (shards, migrating) = match vfs.exists(&path) {
+            true => load_layout(&vfs, &path)?,
+            false => (
+                (1..=shard_count.max(1)).map(|id| (id, true)).collect(),
+                false,
+            ),
+        };
+
+        let mut db = ShardedDB {
+            folder: String::from(folder),
+            options,
+            vfs,
+            shards: BTreeMap::new(),
+            active: Vec::new(),
+            ring: Vec::new(),
+            migrating,
+        };
+        for (id, active) in shards {
+            db.open_shard(id);
+            if active {
+                db.active.push(id);
+            }
+        }
+        db.build_ring();
+        db.save_layout()?;
+        Ok(db)
+    }
+
+    fn open_shard(&mut self, id: u32) {
+        let shard = RustDB::load_with_vfs(
+            &shard_folder(&self.folder, id),
+            self.options.clone(),
+            Arc::clone(&self.vfs),
+        );
+        self.shards.insert(id, shard);
+    }
+
+    fn build_ring(&mut self) {
+        self.ring = self
+            .active
+            .iter()
+            .flat_map(|id| {
+                (0..VIRTUAL_NODES).map(move |point| {
+                    let name = format!("shard-{}-{}", id, point);
+                    (crc64::checksum_ecma(name.as_bytes()), *id)
+                })
+            })
+            .collect();
+        self.ring.sort_unstable();
+    }
+
+    /// Every shard with whether it is active, after whether a migration is
+    /// going on, in a single record replaced at once.
+    fn save_layout(&self) -> Result<()> {
+        let mut value = vec![self.migrating as u8];
+        for id in self.shards.keys() {
+            value.extend_from_slice(&id.to_be_bytes());
+            value.push(self.active.contains(id) as u8);
+        }
+        let mut data = Vec::new();
+        write_record(&mut data, &KeyValue::new(LAYOUT_KEY.to_vec(), value))?;
+
+        let folder = folder_path(&self.folder);
+        let path = build_path(&folder, SHARDS_FILE);
+        let temp_path = format!("{}.tmp", path);
+        self.vfs.create_dir_all(&folder)?;
+        let mut file = self.vfs.create(&temp_path)?;
+        file.append(&data)?;
+        file.sync()?;
+        self.vfs.rename(&temp_path, &path)
+    }
+
+    /// Shards taking keys, in order.
+    pub fn shard_ids(&self) -> Vec<u32> {
+        self.active.clone()
+    }
+
+    pub fn shard(&self, id: u32) -> Option<&RustDB> {
+        self.shards.get(&id)
+    }
+
+    /// Shard `key` belongs to: the first point of the ring from its hash on.
+    pub fn shard_of(&self, key: &[u8]) -> u32 {
+        let hash = crc64::checksum_ecma(key);
+        let index = self.ring.partition_point(|(point, _)| *point < hash);
+        self.ring[index % self.ring.len()].1
+    }
+
+    /// Whether keys are still being moved to the shards they belong to.
+    pub fn is_migrating(&self) -> bool {
+        self.migrating
+    }
+
+    /// Shard holding the record of `key`, which is the one it belongs to
+    /// unless it was not moved there yet.
+    fn locate(&self, key: &[u8]) -> Result<u32> {
+        let id = self.shard_of(key);
+        if !self.migrating || self.shards[&id].get_record(key)?.is_some() {
+            return Ok(id);
+        }
+
+        for (other, shard) in &self.shards {
+            if *other != id && shard.get_record(key)?.is_some() {
+                return Ok(*other);
+            }
+        }
+        Ok(id)
+    }
+
+    /// Removes the copies of `key` left on other shards than its own while
+    /// migrating, so that they do not come back once it is deleted.
+    fn remove_stale_copies(&mut self, key: &[u8]) -> Result<()> {
+        if !self.migrating {
+            return Ok(());
+        }
+
+        let id = self.shard_of(key);
+        for (other, shard) in self.shards.iter_mut() {
+            if *other != id && shard.get_record(key)?.is_some() {
+                shard.delete_record(key)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_record<K: Into<ByteString>>(&self, key: K) -> Result<Option<KeyValue>> {
+        let key = key.into();
+        self.shards[&self.locate(&key)?].get_record(key)
+    }
+
+    /// Values of `keys`, in the same order, looked up shard by shard.
+    pub fn get_records(&self, keys: &[ByteString]) -> Result<Vec<Option<KeyValue>>> {
+        let mut by_shard: BTreeMap<u32, Vec<usize>> = BTreeMap::new();
+        for (index, key) in keys.iter().enumerate() {
+            by_shard.entry(self.locate(key)?).or_default().push(index);
+        }
+
+        let mut records = vec![None; keys.len()];
+        for (id, indexes) in by_shard {
+            let shard = &self.shards[&id];
+            for index in indexes {
+                records[index] = shard.get_record(keys[index].clone())?;
+            }
+        }
+        Ok(records)
+    }
+
+    pub fn save_record(&mut self, key_value: KeyValue) -> Result<()> {
+        self.check_writable()?;
+        let id = self.shard_of(&key_value.key);
+        let from = self.locate(&key_value.key)?;
+        if from != id {
+            // moved first so its content type, flags and expiration follow
+            self.move_record(key_value.key.clone(), from, id)?;
+        }
+        self.shards.get_mut(&id).unwrap().save_record(key_value)
+    }
+
+    /// Adds `delta` to the counter at `key`, as `RustDB::increment` does.
+    pub fn increment<K: Into<ByteString>>(&mut self, key: K, delta: i64) -> Result<i64> {
+        self.check_writable()?;
+        let key = key.into();
+        let current = self.get_record(key.clone())?;
+        let value = incremented(current.as_ref().map(|kv| &kv.value[..]), delta)?;
+        self.save_record(KeyValue::new(key, value.to_string().into_bytes()))?;
+        Ok(value)
+    }
+
+    pub fn delete_record<K: Into<ByteString>>(&mut self, key: K) -> Result<()> {
+        self.check_writable()?;
+        let key = key.into();
+        let id = self.shard_of(&key);
+        self.shards
+            .get_mut(&id)
+            .unwrap()
+            .delete_record(key.clone())?;
+        self.remove_stale_copies(&key)
+    }
+
+    pub fn set_content_type<K: Into<ByteString>>(
+        &mut self,
+        key: K,
+        content_type: Option<&str>,
+    ) -> Result<()> {
+        self.check_writable()?;
+        let key = key.into();
+        let id = self.locate(&key)?;
+        self.shards
+            .get_mut(&id)
+            .unwrap()
+            .set_content_type(key, content_type)
+    }
+
+    pub fn get_content_type<K: Into<ByteString>>(&self, key: K) -> Option<String> {
+        let key = key.into();
+        let id = self.locate(&key).ok()?;
+        self.shards[&id].get_content_type(key)
+    }
+
+    /// Every live key of every shard, sorted.
+    pub fn get_keys(&self) -> Result<Vec<ByteString>> {
+        let mut keys = Vec::new();
+        for shard in self.shards.values() {
+            keys.extend(shard.get_keys()?);
+        }
+        keys.sort();
+        keys.dedup();
+        Ok(keys)
+    }
+
+    /// Same as `RustDB::scan_keys`, merging a page of every shard. The keys
+    /// of the merged page are among those of the shard pages, along with
+    /// the key they would each go on from.
+    pub fn scan_keys(&self, prefix: &[u8], start: &[u8], limit: usize) -> Result<KeyPage> {
+        let mut keys = Vec::new();
+        for shard in self.shards.values() {
+            let page = shard.scan_keys(prefix, start, limit)?;
+            keys.extend(page.keys);
+            keys.extend(page.next);
+        }
+        keys.sort();
+        keys.dedup();
+        keys.truncate(limit.saturating_add(1));
+
+        let next = match keys.len() > limit {
+            true => keys.pop(),
+            false => None,
+        };
+        Ok(KeyPage { keys, next })
+    }
+
+    pub fn remove_expired(&mut self) -> Result<usize> {
+        let mut count = 0;
+        for shard in self.shards.values_mut() {
+            count += shard.remove_expired()?;
+        }
+        Ok(count)
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        for shard in self.shards.values_mut() {
+            shard.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn set_read_only(&mut self, read_only: bool) {
+        for shard in self.shards.values_mut() {
+            shard.set_read_only(read_only);
+        }
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.shards.values().any(|shard| shard.is_read_only())
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        match self.is_read_only() {
+            true => Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "Read-only replica, writes go to the leader",
+            )),
+            false => Ok(()),
+        }
+    }
+
+    /// Splits the keys further with a new, empty shard, returning its id.
+    /// The keys it takes from the others are moved by `migrate`.
+    pub fn add_shard(&mut self) -> Result<u32> {
+        self.check_writable()?;
+        let id = self.shards.keys().last().map_or(1, |id| id + 1);
+        self.open_shard(id);
+        self.active.push(id);
+        self.build_ring();
+        self.migrating = true;
+        self.save_layout()?;
+        Ok(id)
+    }
+
+    /// Stops routing keys to shard `id`, which is dropped once `migrate`
+    /// has moved its keys to the others.
+    pub fn remove_shard(&mut self, id: u32) -> Result<()> {
+        self.check_writable()?;
+        if !self.active.contains(&id) {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("No active shard {}", id),
+            ));
+        }
+        if self.active.len() == 1 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "The last shard cannot be removed",
+            ));
+        }
+
+        self.active.retain(|active| *active != id);
+        self.build_ring();
+        self.migrating = true;
+        self.save_layout()
+    }
+
+    /// Adds or removes shards, the latest ones first, until `shard_count`
+    /// take keys.
+    pub fn resize(&mut self, shard_count: u32) -> Result<()> {
+        while self.active.len() < shard_count.max(1) as usize {
+            self.add_shard()?;
+        }
+        while self.active.len() > shard_count.max(1) as usize {
+            let id = *self.active.iter().max().unwrap();
+            self.remove_shard(id)?;
+        }
+        Ok(())
+    }
+
+    /// Moves up to `limit` keys to the shards they belong to, returning how
+    /// many were moved. Writes can go on between calls. Once every key is
+    /// in place, the shards that were removed are dropped along with their
+    /// files.
+    pub fn migrate(&mut self, limit: usize) -> Result<usize> {
+        if !self.migrating {
+            return Ok(0);
+        }
+        self.check_writable()?;
+
+        let mut moved = 0;
+        let ids: Vec<u32> = self.shards.keys().copied().collect();
+        for id in ids {
+            for key in self.shards[&id].get_keys()? {
+                if moved == limit {
+                    return Ok(moved);
+                }
+                let target = self.shard_of(&key);
+                if target != id {
+                    self.move_record(key, id, target)?;
+                    moved += 1;
+                }
+            }
+        }
+
+        self.drop_removed_shards()?;
+        self.migrating = false;
+        self.save_layout()?;
+        Ok(moved)
+    }
+
+    /// Copies the record of `key` along with its content type, flags and
+    /// expiration, before deleting it from the shard it leaves. A copy
+    /// already on the target, left by a move cut short, is the latest.
+    fn move_record(&mut self, key: ByteString, from: u32, to: u32) -> Result<()> {
+        if self.shards[&to].get_record(key.clone())?.is_some() {
+            return self.shards.get_mut(&from).unwrap().delete_record(key);
+        }
+
+        let source = &self.shards[&from];
+        let record = match source.get_record(key.clone())? {
+            Some(record) => record,
+            None => return Ok(()),
+        };
+        let content_type = source.get_content_type(key.clone());
+        let flags = source.get_flags(key.clone());
+        let ttl = source.time_to_live(key.clone());
+
+        let target = self.shards.get_mut(&to).unwrap();
+        target.save_record(record)?;
+        target.set_content_type(key.clone(), content_type.as_deref())?;
+        target.set_flags(key.clone(), flags)?;
+        if let Some(ttl) = ttl {
+            target.expire(key.clone(), ttl)?;
+        }
+
+        self.shards.get_mut(&from).unwrap().delete_record(key)
+    }
+
+    fn drop_removed_shards(&mut self) -> Result<()> {
+        let removed: Vec<u32> = self
+            .shards
+            .keys()
+            .filter(|id| !self.active.contains(id))
+            .copied()
+            .collect();
+
+        for id in removed {
+            self.shards.remove(&id);
+            self.vfs
+                .remove_dir_all(&folder_path(&shard_folder(&self.folder, id)))?;
+        }
+        Ok(())
+    }
+
+    /// Compresses the closed segments of every shard.
+    pub fn compress_segments(&mut self) -> Result<()> {
+        for shard in self.shards.values_mut() {
+            shard.compress_segments()?;
+        }
+        Ok(())
+    }
+}
+
+fn shard_folder(folder: &str, id: u32) -> String {
+    format!("{}/shard-{}", folder, id)
+}
+
+/// Shards with whether they are active, and whether a migration is going
+/// on, as saved by `ShardedDB::save_layout`.
+fn load_layout(vfs: &Arc<dyn Vfs>, path: &str) -> Result<(Vec<(u32, bool)>, bool)> {
+    let file = vfs.open(path)?;
+    let record = read_record(&mut BufReader::new(FileReader::new(&*file, 0)))?;
+    let invalid = || Error::new(ErrorKind::InvalidData, "Invalid shards layout");
+    if record.key != LAYOUT_KEY || record.value.is_empty() || record.value.len() % 5 != 1 {
+        return Err(invalid());
+    }
+
+    let shards = record.value[1..]
+        .chunks(5)
+        .map(|shard| {
+            let id = u32::from_be_bytes([shard[0], shard[1], shard[2], shard[3]]);
+            (id, shard[4] == 1)
+        })
+        .collect();
+    Ok((shards, record.value[0] == 1))
+}
+
+impl Store for ShardedDB {
+    fn get_record(&self, key: &[u8]) -> Result<Option<KeyValue>> {
+        ShardedDB::get_record(self, key)
+    }
+
+    fn save_record(&mut self, key_value: KeyValue) -> Result<()> {
+        ShardedDB::save_record(self, key_value)
+    }
+
+    fn delete_record(&mut self, key: &[u8]) -> Result<()> {
+        ShardedDB::delete_record(self, key)
+    }
+
+    fn increment(&mut self, key: &[u8], delta: i64) -> Result<i64> {
+        ShardedDB::increment(self, key, delta)
+    }
+
+    fn get_content_type(&self, key: &[u8]) -> Option<String> {
+        ShardedDB::get_content_type(self, key)
+    }
+
+    fn set_content_type(&mut self, key: &[u8], content_type: Option<&str>) -> Result<()> {
+        ShardedDB::set_content_type(self, key, content_type)
+    }
+
+    fn scan_keys(&self, prefix: &[u8], start: &[u8], limit: usize) -> Result<KeyPage> {
+        ShardedDB::scan_keys(self, prefix, start, limit)
+    }
+
+    /// Changes are numbered per shard, so none are told apart here.
+    fn last_sequence(&self) -> u64 {
+        0
+    }
+
+    fn changes(&self, _filter: &ChangeFilter, _limit: usize) -> Result<Vec<ChangeEvent>> {
+        Err(changes_unsupported())
+    }
+
+    fn subscribe(&mut self, _filter: ChangeFilter) -> Result<Subscription> {
+        Err(changes_unsupported())
+    }
+
+    fn replicate(&mut self, _from: u64) -> Result<Subscription<ReplicationEvent>> {
+        Err(changes_unsupported())
+    }
+
+    fn is_read_only(&self) -> bool {
+        ShardedDB::is_read_only(self)
+    }
+
+    fn set_read_only(&mut self, read_only: bool) {
+        ShardedDB::set_read_only(self, read_only)
+    }
+
+    fn remove_expired(&mut self) -> Result<usize> {
+        ShardedDB::remove_expired(self)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        ShardedDB::flush(self)
+    }
+
+    fn list_collections(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    fn create_collection(&mut self, _name: &str) -> Result<bool> {
+        Err(collections_unsupported())
+    }
+
+    fn drop_collection(&mut self, _name: &str) -> Result<bool> {
+        Err(collections_unsupported())
+    }
+
+    fn collection_mut(&mut self, _name: &str) -> Option<&mut dyn Store> {
+        None
+    }
+
+    fn create_index(&mut self, _name: &str, _path: &str) -> Result<bool> {
+        Err(indexes_unsupported())
+    }
+
+    fn drop_index(&mut self, _name: &str) -> Result<bool> {
+        Err(indexes_unsupported())
+    }
+
+    fn list_indexes(&self) -> Vec<(String, String)> {
+        Vec::new()
+    }
+
+    fn find_by(&self, _index: &str, _value: &str) -> Result<Vec<KeyValue>> {
+        Err(indexes_unsupported())
+    }
+
+    fn set_schema(&mut self, _schema: Option<Value>) -> Result<u64> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "Schemas are not supported by a sharded store",
+        ))
+    }
+
+    fn schema_versions(&self) -> Vec<(u64, Option<Value>)> {
+        Vec::new()
+    }
+}
+
+fn changes_unsupported() -> Error {
+    Error::new(
+        ErrorKind::Unsupported,
+        "Changes are kept per shard, not for a sharded store",
+    )
+}
+
+fn collections_unsupported() -> Error {
+    Error::new(
+        ErrorKind::Unsupported,
+        "Collections are not supported by a sharded store",
+    )
+}
+
+fn indexes_unsupported() -> Error {
+    Error::new(
+        ErrorKind::Unsupported,
+        "Indexes are not supported by a sharded store",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::MemoryVfs;
+
+    fn memory_db(vfs: &MemoryVfs, shard_count: u32) -> ShardedDB {
+        ShardedDB::load_with_vfs(
+            "sharded",
+            shard_count,
+            Options::default(),
+            Arc::new(vfs.clone()),
+        )
+        .unwrap()
+    }
+
+    fn save_keys(db: &mut ShardedDB, count: usize) {
+        for index in 0..count {
+            let key = format!("key{:03}", index);
+            let value = format!("value{}", index);
+            db.save_record(KeyValue::new(key.into_bytes(), value.into_bytes()))
+                .unwrap();
+        }
+    }
+
+    fn assert_keys(db: &ShardedDB, count: usize) {
+        for index in 0..count {
+            let key = format!("key{:03}", index);
+            let record = db.get_record(key.as_bytes()).unwrap().unwrap();
+            assert_eq!(record.value, format!("value{}", index).into_bytes());
+        }
+    }
+
+    fn misplaced_keys(db: &ShardedDB) -> usize {
+        db.shards
+            .iter()
+            .map(|(id, shard)| {
+                let keys = shard.get_keys().unwrap();
+                keys.iter().filter(|key| db.shard_of(key) != *id).count()
+            })
+            .sum()
+    }
+
+    #[test]
+    fn route_keys_to_shards_kept_across_loads() {
+        let vfs = MemoryVfs::default();
+        let mut db = memory_db(&vfs, 4);
+        save_keys(&mut db, 200);
+        db.delete_record(b"key007").unwrap();
+
+        assert_eq!(db.shard_ids(), vec![1, 2, 3, 4]);
+        for id in db.shard_ids() {
+            let keys = db.shard(id).unwrap().get_keys().unwrap();
+            assert!(keys.len() > 20, "shard {} has {} keys", id, keys.len());
+            assert!(keys.iter().all(|key| db.shard_of(key) == id));
+        }
+
+        // the layout found takes precedence over the count asked
+        let db = memory_db(&vfs, 2);
+        assert_eq!(db.shard_ids(), vec![1, 2, 3, 4]);
+        assert_eq!(db.get_record(b"key007").unwrap(), None);
+        assert_eq!(db.get_keys().unwrap().len(), 199);
+    }
+
+    #[test]
+    fn scan_and_get_across_shards() {
+        let vfs = MemoryVfs::default();
+        let mut db = memory_db(&vfs, 3);
+        save_keys(&mut db, 50);
+        db.save_record(KeyValue::new(b"other".to_vec(), b"value".to_vec()))
+            .unwrap();
+
+        let mut keys = Vec::new();
+        let mut start = Vec::new();
+        loop {
+            let page = db.scan_keys(b"key", &start, 7).unwrap();
+            assert!(page.keys.len() <= 7);
+            keys.extend(page.keys);
+            match page.next {
+                Some(next) => start = next,
+                None => break,
+            }
+        }
+        let expected: Vec<ByteString> = (0..50)
+            .map(|index| format!("key{:03}", index).into_bytes())
+            .collect();
+        assert_eq!(keys, expected);
+
+        let records = db
+            .get_records(&[b"key010".to_vec(), b"missing".to_vec(), b"other".to_vec()])
+            .unwrap();
+        let values: Vec<Option<ByteString>> = records
+            .into_iter()
+            .map(|record| record.map(|record| record.value))
+            .collect();
+        assert_eq!(
+            values,
+            vec![Some(b"value10".to_vec()), None, Some(b"value".to_vec())]
+        );
+    }
+
+    #[test]
+    fn fail_to_load_a_corrupt_layout() {
+        let vfs = MemoryVfs::default();
+        memory_db(&vfs, 2);
+        vfs.create("./sharded/shards")
+            .unwrap()
+            .append(b"corrupt")
+            .unwrap();
+
+        let result = ShardedDB::load_with_vfs("sharded", 2, Options::default(), Arc::new(vfs));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn refuse_every_write_while_read_only() {
+        let vfs = MemoryVfs::default();
+        let mut db = memory_db(&vfs, 2);
+        save_keys(&mut db, 10);
+        db.set_read_only(true);
+
+        for result in [
+            db.save_record(KeyValue::new(b"key001".to_vec(), b"value".to_vec())),
+            db.delete_record(b"key001"),
+            db.set_content_type(b"key001", Some("text/plain")),
+            db.increment(b"counter", 1).map(|_| ()),
+        ] {
+            assert_eq!(result.unwrap_err().kind(), ErrorKind::PermissionDenied);
+        }
+        assert_eq!(db.get_content_type(b"key001"), None);
+    }
+
+    #[test]
+    fn split_into_a_new_shard_online() {
+        let vfs = MemoryVfs::default();
+        let mut db = memory_db(&vfs, 2);
+        save_keys(&mut db, 100);
+        db.set_content_type(b"key001", Some("text/plain")).unwrap();
+
+        let id = db.add_shard().unwrap();
+        assert_eq!(id, 3);
+        assert!(db.is_migrating());
+        let misplaced = misplaced_keys(&db);
+        assert!(misplaced > 0);
+
+        // reads and writes go on while keys are moved a few at a time
+        assert_eq!(db.migrate(5).unwrap(), 5);
+        assert_keys(&db, 100);
+        save_keys(&mut db, 100);
+        db.delete_record(b"key002").unwrap();
+
+        let mut moved = 5;
+        while db.is_migrating() {
+            moved += db.migrate(5).unwrap();
+        }
+        assert!(moved <= misplaced);
+        assert_eq!(misplaced_keys(&db), 0);
+        assert!(!db.shard(3).unwrap().get_keys().unwrap().is_empty());
+        assert_eq!(db.get_record(b"key002").unwrap(), None);
+        assert_eq!(
+            db.get_content_type(b"key001"),
+            Some(String::from("text/plain"))
+        );
+        assert_eq!(db.get_keys().unwrap().len(), 99);
+
+        let db = memory_db(&vfs, 2);
+        assert!(!db.is_migrating());
+        assert_eq!(db.shard_ids(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn move_keys_off_a_removed_shard() {
+        let vfs = MemoryVfs::default();
+        let mut db = memory_db(&vfs, 3);
+        save_keys(&mut db, 60);
+
+        db.remove_shard(2).unwrap();
+        assert_eq!(db.shard_ids(), vec![1, 3]);
+        assert_keys(&db, 60);
+
+        // a restart in the middle of the migration goes on with it
+        db.migrate(3).unwrap();
+        let mut db = memory_db(&vfs, 3);
+        assert!(db.is_migrating());
+        assert_keys(&db, 60);
+
+        db.migrate(usize::MAX).unwrap();
+        assert!(!db.is_migrating());
+        assert!(db.shard(2).is_none());
+        assert!(vfs.list("./sharded/shard-2").unwrap().is_empty());
+        assert_keys(&db, 60);
+
+        assert_eq!(db.remove_shard(2).unwrap_err().kind(), ErrorKind::NotFound);
+        db.remove_shard(1).unwrap();
+        assert_eq!(
+            db.remove_shard(3).unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+    }
+}
@@ -1,26 +1,40 @@
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use crc::crc32;
 use rand::random;
-use std::collections::HashMap;
-use std::fs::{create_dir_all, remove_file, File, OpenOptions};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::io::{
-    prelude::*, BufReader, Error, ErrorKind, ErrorKind::UnexpectedEof, Result, SeekFrom,
+    prelude::*,
+    BufReader, Error,
+    ErrorKind::{self, InvalidData, UnexpectedEof},
+    Result,
 };
-use std::path::Path;
+use std::ops::Bound::{Included, Unbounded};
+use std::sync::Arc;
+use std::time::Duration;
 
+use crate::bloom::BloomFilter;
 use crate::core::{ByteString, KeyValue};
+use crate::expiry::now_millis;
+use crate::lsm::MergeIterator;
+use crate::options::Durability;
+use crate::vfs::{DiskVfs, FileReader, Vfs, VfsFile};
 
 pub struct InitialSegmentReference {
     pub initial_segment: Option<u64>,
     folder_name: String,
+    vfs: Arc<dyn Vfs>,
 }
 
 impl InitialSegmentReference {
     pub fn load(folder_name: &str) -> InitialSegmentReference {
+        InitialSegmentReference::load_with_vfs(folder_name, &(Arc::new(DiskVfs) as Arc<dyn Vfs>))
+    }
+
+    pub fn load_with_vfs(folder_name: &str, vfs: &Arc<dyn Vfs>) -> InitialSegmentReference {
         let initial_segment =
-            match File::open(InitialSegmentReference::initial_segment_file(folder_name)) {
-                Ok(mut f) => {
-                    let name = f.read_u64::<BigEndian>().unwrap();
+            match vfs.open(&InitialSegmentReference::initial_segment_file(folder_name)) {
+                Ok(f) => {
+                    let name = read_u64_at(&*f, 0).unwrap();
                     Some(name)
                 }
                 Err(_) => None,
@@ -29,27 +43,25 @@ impl InitialSegmentReference {
         InitialSegmentReference {
             initial_segment,
             folder_name: String::from(folder_name),
+            vfs: Arc::clone(vfs),
         }
     }
 
-    fn create(self, initial_segment_name: u64) {
-        let mut reference = File::create(Path::new(
-            &InitialSegmentReference::initial_segment_file(&self.folder_name),
-        ))
-        .unwrap();
-        reference
-            .write_u64::<BigEndian>(initial_segment_name)
-            .unwrap();
+    fn create(self, initial_segment_name: u64) -> Result<()> {
+        self.update(initial_segment_name)
     }
 
-    pub fn update(self, initial_segment_name: u64) {
-        let mut reference = File::create(Path::new(
-            &InitialSegmentReference::initial_segment_file(&self.folder_name),
-        ))
-        .unwrap();
-        reference
-            .write_u64::<BigEndian>(initial_segment_name)
-            .unwrap();
+    /// Writes the reference to a temporary file renamed over the current one,
+    /// so a crash leaves either the old or the new reference.
+    pub fn update(self, initial_segment_name: u64) -> Result<()> {
+        let path = InitialSegmentReference::initial_segment_file(&self.folder_name);
+        let temp_path = format!("{}.tmp", path);
+
+        let mut reference = self.vfs.create(&temp_path)?;
+        reference.append(&initial_segment_name.to_be_bytes())?;
+        reference.sync()?;
+
+        self.vfs.rename(&temp_path, &path)
     }
 
     fn initial_segment_file(folder_name: &str) -> String {
@@ -57,9 +69,110 @@ impl InitialSegmentReference {
     }
 }
 
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SegmentStats {
+    pub name: String,
+    pub live_keys: u64,
+    pub dead_keys: u64,
+    pub live_bytes: u64,
+    pub dead_bytes: u64,
+}
+
+impl SegmentStats {
+    pub fn total_bytes(&self) -> u64 {
+        self.live_bytes + self.dead_bytes
+    }
+
+    pub fn dead_ratio(&self) -> f64 {
+        match self.total_bytes() {
+            0 => 0.0,
+            total => self.dead_bytes as f64 / total as f64,
+        }
+    }
+
+    fn add(&mut self, entry: &IndexEntry) {
+        if entry.live {
+            self.live_keys += 1;
+            self.live_bytes += entry.size;
+        } else {
+            self.dead_keys += 1;
+            self.dead_bytes += entry.size;
+        }
+    }
+
+    fn kill(&mut self, entry: &mut IndexEntry) {
+        if entry.live {
+            entry.live = false;
+            self.live_keys -= 1;
+            self.live_bytes -= entry.size;
+            self.dead_keys += 1;
+            self.dead_bytes += entry.size;
+        }
+    }
+}
+
+pub struct IndexEntry {
+    pub position: u64,
+    size: u64,
+    live: bool,
+}
+
+impl IndexEntry {
+    fn new(key_value: &KeyValue, position: u64) -> IndexEntry {
+        IndexEntry {
+            position,
+            size: record_size(key_value),
+            live: !key_value.value.is_empty(),
+        }
+    }
+}
+
+pub(crate) fn record_size(key_value: &KeyValue) -> u64 {
+    (RECORD_HEADER_SIZE + key_value.key.len() + key_value.value.len()) as u64
+}
+
+static RECORD_HEADER_SIZE: usize = 12;
+static STAMP_SIZE: u64 = 16;
+
+/// When a record was written: the sequence of the write and its time in
+/// milliseconds since the Unix epoch, both 0 when unknown, as for records
+/// written while neither history nor changes were kept. Records saved from
+/// a snapshot have a time but no sequence.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct VersionStamp {
+    pub sequence: u64,
+    pub time: u64,
+}
+
+impl VersionStamp {
+    fn is_known(&self) -> bool {
+        *self != VersionStamp::default()
+    }
+}
+
 pub struct DataSgment {
-    database_file: File,
-    pub index: HashMap<ByteString, u64>,
+    database_file: Box<dyn VfsFile>,
+    vfs: Arc<dyn Vfs>,
+    /// Latest record of each key, ordered so that key scans can seek.
+    pub index: BTreeMap<ByteString, IndexEntry>,
+    /// Positions and stamps of the records of each key, oldest first,
+    /// tracked only while history is kept and pruned to the retention.
+    versions: HashMap<ByteString, Vec<(u64, VersionStamp)>>,
+    history_retention: Duration,
+    /// Positions and stamps of the records written with a sequence, in the
+    /// order they were written, which the change feed is read from.
+    sequenced: Vec<(u64, VersionStamp)>,
+    /// Whether `sequenced` is also ordered by sequence, as it is unless the
+    /// segment was written by compaction.
+    in_sequence: bool,
+    /// Stamps of the records in the order they were written, created with
+    /// the first stamped record.
+    stamps_file: Option<Box<dyn VfsFile>>,
+    records: u64,
+    durability: Durability,
+    stats: SegmentStats,
+    bloom: Option<BloomFilter>,
+    path: String,
     closed: bool,
     pub previous: Option<Box<DataSgment>>,
     size: u64,
@@ -67,7 +180,15 @@ pub struct DataSgment {
     pub next_segment_name: Option<String>,
 }
 
-fn folder_path(folder_name: &str) -> String {
+pub(crate) fn bloom_path(path: &str) -> String {
+    format!("{}.bloom", path)
+}
+
+fn stamps_path(path: &str) -> String {
+    format!("{}.versions", path)
+}
+
+pub(crate) fn folder_path(folder_name: &str) -> String {
     format!("./{}", folder_name)
 }
 
@@ -82,40 +203,150 @@ fn parse_next_segment_name(next_segment_name: u64) -> Option<String> {
     }
 }
 
-fn build_path(folder_path: &str, file: &str) -> String {
+pub(crate) fn build_path(folder_path: &str, file: &str) -> String {
     format!("{}/{}", folder_path, file)
 }
 
+pub(crate) fn read_u64_at(file: &dyn VfsFile, position: u64) -> Result<u64> {
+    FileReader::new(file, position).read_u64::<BigEndian>()
+}
+
+pub(crate) fn read_record<R: Read + Seek>(file: &mut R) -> Result<KeyValue> {
+    let (key_value, checksum, calculated_checksum) = read_unverified_record(file)?;
+
+    if checksum != calculated_checksum {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Invalid checksum at position: {}\nExpected: {}\nFound: {}",
+                file.stream_position()?,
+                calculated_checksum,
+                checksum
+            ),
+        ));
+    }
+
+    Ok(key_value)
+}
+
+/// Reads a record from a stream without a position, as a socket.
+pub(crate) fn read_streamed_record<R: Read>(stream: &mut R) -> Result<KeyValue> {
+    let (key_value, checksum, calculated_checksum) = read_unverified_record(stream)?;
+
+    if checksum != calculated_checksum {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Invalid checksum\nExpected: {}\nFound: {}",
+                calculated_checksum, checksum
+            ),
+        ));
+    }
+
+    Ok(key_value)
+}
+
+/// Record along with its stored and calculated checksums.
+fn read_unverified_record<R: Read>(file: &mut R) -> Result<(KeyValue, u32, u32)> {
+    let checksum = file.read_u32::<BigEndian>()?;
+    let key_size: usize = file.read_u32::<BigEndian>()? as usize;
+    let value_size: usize = file.read_u32::<BigEndian>()? as usize;
+    let total_size: usize = key_size + value_size;
+
+    let mut data = ByteString::with_capacity(total_size);
+
+    {
+        file.by_ref()
+            .take(total_size as u64)
+            .read_to_end(&mut data)?;
+    }
+
+    let calculated_checksum = crc32::checksum_ieee(&data);
+    let (key, value) = data.split_at(key_size.min(data.len()));
+
+    Ok((
+        KeyValue::new(key.to_vec(), value.to_vec()),
+        checksum,
+        calculated_checksum,
+    ))
+}
+
+pub(crate) fn write_record<W: Write>(file: &mut W, key_value: &KeyValue) -> Result<()> {
+    let key_size = key_value.key.len() as u32;
+    let value_size = key_value.value.len() as u32;
+    let total_size = key_size + value_size;
+    let mut data: Vec<u8> = Vec::with_capacity(total_size as usize);
+
+    data.extend_from_slice(&key_value.key);
+    data.extend_from_slice(&key_value.value);
+    let checksum = crc32::checksum_ieee(&data);
+
+    file.write_u32::<BigEndian>(checksum)?;
+    file.write_u32::<BigEndian>(key_size)?;
+    file.write_u32::<BigEndian>(value_size)?;
+    file.write_all(&data)?;
+
+    Ok(())
+}
+
 impl DataSgment {
-    pub fn update_next_file(&mut self, name: u64) {
-        self.database_file.seek(SeekFrom::Start(8)).unwrap();
-        self.database_file.write_u64::<BigEndian>(name).unwrap();
+    /// Reads the next segment reference straight from the file header, so
+    /// changes made through another handle (as the compressor does) are seen.
+    pub fn read_next_file(&mut self) -> Option<String> {
+        let next_segment_name = read_u64_at(&*self.database_file, 8).unwrap();
+        self.next_segment_name = parse_next_segment_name(next_segment_name);
+        self.next_segment_name.clone()
+    }
+
+    pub fn read_next_segment(vfs: &Arc<dyn Vfs>, folder: &str, segment: &str) -> Option<String> {
+        let database_file = vfs
+            .open(&build_path(&folder_path(folder), segment))
+            .unwrap();
+        let next_segment_name = read_u64_at(&*database_file, 8).unwrap();
+        parse_next_segment_name(next_segment_name)
+    }
+
+    pub fn link(vfs: &Arc<dyn Vfs>, folder: &str, segment: &str, name: u64) -> Result<()> {
+        let mut database_file = vfs.open(&build_path(&folder_path(folder), segment))?;
+        database_file.write_at(8, &name.to_be_bytes())?;
+        database_file.sync()
+    }
+
+    /// Links this segment to the next one, syncing it with its stamps, as
+    /// it is closed. Whatever a failed write left after the last record is
+    /// dropped, as only the last segment may end with a torn record.
+    pub fn update_next_file(&mut self, name: u64) -> Result<()> {
+        self.database_file.set_len(self.size)?;
+        self.database_file.write_at(8, &name.to_be_bytes())?;
+        self.flush()?;
         self.next_segment_name.replace(parse_file_name(name));
+        Ok(())
     }
 
-    pub fn load_dir(folder: &str) -> DataSgment {
+    /// Loads the chain of segments of `folder` under a new active segment,
+    /// keeping the versions of records within `history_retention`.
+    pub fn load_dir(folder: &str, vfs: &Arc<dyn Vfs>, history_retention: Duration) -> DataSgment {
         let folder_path = folder_path(folder);
-        create_dir_all(&folder_path).unwrap();
+        vfs.create_dir_all(&folder_path).unwrap();
 
-        let reference = InitialSegmentReference::load(folder);
+        let reference = InitialSegmentReference::load_with_vfs(folder, vfs);
 
         let mut data_segment_name = match reference.initial_segment {
             Some(value) => Some(parse_file_name(value)),
             None => {
-                let new_segment = DataSgment::new(folder);
-                reference.create(new_segment.name);
+                let mut new_segment = DataSgment::new(folder, vfs);
+                new_segment.set_history_retention(history_retention);
+                reference.create(new_segment.name).unwrap();
                 return new_segment;
             }
         };
 
         let mut loaded_segment = None;
         while let Some(next) = &data_segment_name {
-            let mut current = DataSgment::open(&build_path(&folder_path, next));
+            let mut current =
+                DataSgment::open(&build_path(&folder_path, next), vfs, history_retention);
 
-            data_segment_name = match &current.next_segment_name {
-                None => None,
-                Some(v) => Some(v.to_owned()),
-            };
+            data_segment_name = current.next_segment_name.as_ref().map(|v| v.to_owned());
 
             loaded_segment = match loaded_segment {
                 None => Some(current),
@@ -126,66 +357,96 @@ impl DataSgment {
             };
         }
 
-        let mut editable_segment = DataSgment::new(folder);
+        let mut editable_segment = DataSgment::new(folder, vfs);
+        editable_segment.set_history_retention(history_retention);
 
         if let Some(mut value) = loaded_segment {
-            value.update_next_file(editable_segment.name);
+            value.update_next_file(editable_segment.name).unwrap();
             editable_segment.previous.replace(Box::from(value));
         }
 
+        editable_segment.refresh_stats();
+
         editable_segment
     }
 
-    pub fn new(folder: &str) -> DataSgment {
+    pub fn new(folder: &str, vfs: &Arc<dyn Vfs>) -> DataSgment {
+        DataSgment::try_new(folder, vfs).unwrap()
+    }
+
+    pub fn try_new(folder: &str, vfs: &Arc<dyn Vfs>) -> Result<DataSgment> {
         let folder_path = folder_path(folder);
-        create_dir_all(&folder_path).unwrap();
+        vfs.create_dir_all(&folder_path)?;
 
         let name = random::<u64>();
+        let path = build_path(&folder_path, &parse_file_name(name));
 
-        let mut database_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(Path::new(&build_path(&folder_path, &parse_file_name(name))))
-            .unwrap();
+        let mut database_file = vfs.create(&path)?;
 
-        database_file.write_u64::<BigEndian>(name).unwrap();
-        database_file.write_u64::<BigEndian>(0).unwrap();
+        let mut header = Vec::with_capacity(16);
+        header.write_u64::<BigEndian>(name)?;
+        header.write_u64::<BigEndian>(0)?;
+        database_file.append(&header)?;
+        database_file.sync()?;
 
-        let size = database_file.seek(SeekFrom::End(0)).unwrap();
+        let size = database_file.len()?;
 
-        DataSgment {
+        Ok(DataSgment {
             database_file,
-            index: HashMap::new(),
+            vfs: Arc::clone(vfs),
+            index: BTreeMap::new(),
+            versions: HashMap::new(),
+            sequenced: Vec::new(),
+            in_sequence: true,
+            stamps_file: None,
+            records: 0,
+            durability: Durability::OnFlush,
+            history_retention: Duration::ZERO,
+            stats: SegmentStats::default(),
+            bloom: None,
+            path,
             closed: false,
             previous: None,
             size,
             name,
             next_segment_name: None,
-        }
+        })
     }
 
-    pub fn open(file_name: &str) -> DataSgment {
-        let path = Path::new(file_name);
-
-        let mut database_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(path)
-            .unwrap();
+    pub fn open(file_name: &str, vfs: &Arc<dyn Vfs>, history_retention: Duration) -> DataSgment {
+        DataSgment::try_open(file_name, vfs, history_retention).unwrap()
+    }
 
-        database_file.seek(SeekFrom::Start(0)).unwrap();
+    /// Opens the segment at `file_name`, keeping the versions of records
+    /// within `history_retention`, and failing when a record in it is
+    /// corrupt.
+    pub fn try_open(
+        file_name: &str,
+        vfs: &Arc<dyn Vfs>,
+        history_retention: Duration,
+    ) -> Result<DataSgment> {
+        let database_file = vfs.open(file_name)?;
 
-        let name = database_file.read_u64::<BigEndian>().unwrap();
+        let name = read_u64_at(&*database_file, 0)?;
 
-        let next_segment_name = database_file.read_u64::<BigEndian>().unwrap();
+        let next_segment_name = read_u64_at(&*database_file, 8)?;
 
-        let size = database_file.seek(SeekFrom::End(0)).unwrap();
+        let size = database_file.len()?;
 
         let mut segment = DataSgment {
             database_file,
-            index: HashMap::new(),
+            vfs: Arc::clone(vfs),
+            index: BTreeMap::new(),
+            versions: HashMap::new(),
+            sequenced: Vec::new(),
+            in_sequence: true,
+            stamps_file: vfs.open(&stamps_path(file_name)).ok(),
+            records: 0,
+            durability: Durability::OnFlush,
+            history_retention,
+            stats: SegmentStats::default(),
+            bloom: BloomFilter::load(vfs, &bloom_path(file_name)).ok(),
+            path: String::from(file_name),
             closed: true,
             previous: None,
             size,
@@ -193,121 +454,406 @@ impl DataSgment {
             next_segment_name: parse_next_segment_name(next_segment_name),
         };
 
-        segment.load().unwrap();
+        segment.load()?;
 
-        segment
+        Ok(segment)
     }
 
+    /// Indexes every record of the file. A record cut short or failing its
+    /// checksum at the end of the last segment of the chain was torn by a
+    /// crash while it was written, so the file is truncated right before
+    /// it. Anywhere else, the segment is corrupt and fails to load.
     fn load(&mut self) -> Result<()> {
-        let mut database_buffer = BufReader::new(&self.database_file);
-        let _ = database_buffer.seek(SeekFrom::Start(16))?;
-
-        loop {
-            let current_position = database_buffer.seek(SeekFrom::Current(0))?;
+        let mut records = Vec::new();
+        let mut end = 16;
 
-            match DataSgment::load_record(&mut database_buffer) {
-                Ok(key_value) => {
-                    DataSgment::update_index(&mut self.index, &key_value, current_position)
-                }
-                Err(err) => match err.kind() {
-                    UnexpectedEof => {
+        {
+            let mut database_buffer = BufReader::new(FileReader::new(&*self.database_file, 16));
+
+            loop {
+                match read_unverified_record(&mut database_buffer) {
+                    Ok((key_value, checksum, calculated)) if checksum == calculated => {
+                        let position = end;
+                        end += record_size(&key_value);
+                        records.push((key_value, position));
+                    }
+                    Ok((key_value, _, _)) => {
+                        let torn = end + record_size(&key_value) >= self.size;
+                        if !torn || self.next_segment_name.is_some() {
+                            return Err(Error::new(
+                                InvalidData,
+                                format!("Corrupt record at position {} of {}", end, self.path),
+                            ));
+                        }
                         break;
                     }
-                    _ => return Err(err),
-                },
-            };
+                    Err(err) if err.kind() == UnexpectedEof => break,
+                    Err(err) => return Err(err),
+                };
+            }
+        }
+
+        if self.size > end {
+            if self.next_segment_name.is_some() {
+                return Err(Error::new(
+                    InvalidData,
+                    format!("Record cut short at position {} of {}", end, self.path),
+                ));
+            }
+            self.database_file.set_len(end)?;
+            self.database_file.sync()?;
+            self.size = end;
+        }
+
+        let stamps = self.read_stamps()?;
+        for (key_value, position) in records {
+            let stamp = stamps
+                .get(self.records as usize)
+                .copied()
+                .unwrap_or_default();
+            self.update_index(&key_value, position, stamp);
         }
 
         Ok(())
     }
 
-    fn update_index(index: &mut HashMap<ByteString, u64>, key_value: &KeyValue, position: u64) {
-        index.insert(key_value.key.to_owned(), position);
+    /// Stamps of the records in the order they were written, as far as they
+    /// were persisted.
+    fn read_stamps(&self) -> Result<Vec<VersionStamp>> {
+        let file = match &self.stamps_file {
+            Some(file) => file,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut reader = BufReader::new(FileReader::new(&**file, 0));
+        let mut stamps = Vec::with_capacity((file.len()? / STAMP_SIZE) as usize);
+        for _ in 0..file.len()? / STAMP_SIZE {
+            stamps.push(VersionStamp {
+                sequence: reader.read_u64::<BigEndian>()?,
+                time: reader.read_u64::<BigEndian>()?,
+            });
+        }
+        Ok(stamps)
+    }
+
+    /// Writes the stamp of the record being saved, at the same place in the
+    /// stamps file as the record in the segment, to be synced along with
+    /// it. Unknown stamps are not written, so a segment without stamped
+    /// records has no stamps file.
+    fn write_stamp(&mut self, stamp: VersionStamp) -> Result<()> {
+        if !stamp.is_known() {
+            return Ok(());
+        }
+
+        if self.stamps_file.is_none() {
+            self.stamps_file = Some(self.vfs.create(&stamps_path(&self.path))?);
+        }
+        let file = self.stamps_file.as_mut().unwrap();
+        let mut data = Vec::with_capacity(STAMP_SIZE as usize);
+        data.write_u64::<BigEndian>(stamp.sequence)?;
+        data.write_u64::<BigEndian>(stamp.time)?;
+        file.write_at(self.records * STAMP_SIZE, &data)
     }
 
-    fn load_record(file: &mut BufReader<&File>) -> Result<KeyValue> {
-        let checksum = file.read_u32::<BigEndian>()?;
-        let key_size: usize = file.read_u32::<BigEndian>()? as usize;
-        let value_size: usize = file.read_u32::<BigEndian>()? as usize;
-        let total_size: usize = key_size + value_size;
+    fn update_index(&mut self, key_value: &KeyValue, position: u64, stamp: VersionStamp) {
+        let entry = IndexEntry::new(key_value, position);
+        self.stats.add(&entry);
+        self.records += 1;
+        if stamp.sequence > 0 {
+            if let Some((_, last)) = self.sequenced.last() {
+                self.in_sequence &= last.sequence < stamp.sequence;
+            }
+            self.sequenced.push((position, stamp));
+        }
+        if !self.history_retention.is_zero() {
+            let cutoff = now_millis().saturating_sub(self.history_retention.as_millis() as u64);
+            let versions = self.versions.entry(key_value.key.to_owned()).or_default();
+            // older versions out of the retention window are dropped, as
+            // compaction would drop them
+            versions.retain(|(_, stamp)| stamp.time >= cutoff);
+            versions.push((position, stamp));
+        }
 
-        let mut data = ByteString::with_capacity(total_size);
+        if let Some(mut previous) = self.index.insert(key_value.key.to_owned(), entry) {
+            self.stats.kill(&mut previous);
+        }
+    }
 
-        {
-            file.by_ref()
-                .take(total_size as u64)
-                .read_to_end(&mut data)?;
-        }
-
-        let calculated_checksum = crc32::checksum_ieee(&data);
-
-        if checksum != calculated_checksum {
-            return Err(Error::new(
-                ErrorKind::InvalidData,
-                format!(
-                    "Invalid checksum at position: {}\nExpected: {}\nFound: {}",
-                    file.seek(SeekFrom::Current(0))?,
-                    calculated_checksum,
-                    checksum
-                ),
-            ));
+    /// Marks every record shadowed by a newer segment as dead, walking the
+    /// chain from this segment to the oldest one. Killing an entry is
+    /// idempotent, so it is safe to run after loads and compactions.
+    pub fn refresh_stats(&mut self) {
+        let mut seen: HashSet<ByteString> = HashSet::new();
+        let mut current = Some(self);
+
+        while let Some(segment) = current {
+            for (key, entry) in segment.index.iter_mut() {
+                if seen.contains(key) {
+                    segment.stats.kill(entry);
+                }
+            }
+            seen.extend(segment.index.keys().cloned());
+
+            current = segment.previous.as_deref_mut();
         }
+    }
 
-        let (key, value) = data.split_at(key_size);
+    /// Marks the current version of `key` as dead in the newest previous
+    /// segment holding it, as it is about to be replaced by a new record.
+    fn kill_previous(&mut self, key: &[u8]) {
+        let mut previous = self.previous.as_deref_mut();
 
-        Ok(KeyValue::new(key.to_vec(), value.to_vec()))
+        while let Some(segment) = previous {
+            if let Some(entry) = segment.index.get_mut(key) {
+                segment.stats.kill(entry);
+                return;
+            }
+            previous = segment.previous.as_deref_mut();
+        }
     }
 
-    pub fn get_record(&self, key: String) -> Result<Option<KeyValue>> {
-        let key: Vec<u8> = Vec::from(key);
-        let key_position = match self.index.get(&key) {
-            Some(position) => position,
-            None => return Ok(None),
-        };
+    /// Builds and persists the Bloom filter of every previous segment still
+    /// missing one. The active segment never has a filter, as it keeps
+    /// changing.
+    pub fn build_missing_filters(&mut self, false_positive_rate: f64) {
+        let mut previous = self.previous.as_deref_mut();
+
+        while let Some(segment) = previous {
+            if segment.bloom.is_none() {
+                let filter = BloomFilter::from_keys(segment.index.keys(), false_positive_rate);
+                // a filter failing to persist is rebuilt on the next load
+                let _ = filter.save(&segment.vfs, &bloom_path(&segment.path));
+                segment.bloom.replace(filter);
+            }
+            previous = segment.previous.as_deref_mut();
+        }
+    }
+
+    /// Whether the segment may hold `key`, or `None` when it has no filter.
+    pub fn may_contain(&self, key: &[u8]) -> Option<bool> {
+        self.bloom.as_ref().map(|filter| filter.may_contain(key))
+    }
+
+    pub fn get_stats(&self) -> SegmentStats {
+        SegmentStats {
+            name: self.get_name(),
+            ..self.stats.clone()
+        }
+    }
+
+    pub fn get_record(&self, key: &[u8]) -> Result<Option<KeyValue>> {
+        match self.index.get(key) {
+            Some(entry) => self.read_record_at(entry.position).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn read_record_at(&self, position: u64) -> Result<KeyValue> {
+        read_record(&mut BufReader::new(FileReader::new(
+            &*self.database_file,
+            position,
+        )))
+    }
+
+    /// Positions and stamps of the records of `key` in this segment within
+    /// the history retention, oldest first, deletes included. Empty when no
+    /// history is kept.
+    pub fn get_versions(&self, key: &[u8]) -> &[(u64, VersionStamp)] {
+        self.versions.get(key).map_or(&[], Vec::as_slice)
+    }
+
+    /// Keys with at least one record in this segment, each with the
+    /// positions and stamps of its records, oldest first. Without history,
+    /// only the latest record of each key.
+    pub fn iter_versions(&self) -> Vec<(&ByteString, Vec<(u64, VersionStamp)>)> {
+        match self.history_retention.is_zero() {
+            true => self
+                .index
+                .iter()
+                .map(|(key, entry)| (key, vec![(entry.position, self.stamp_at(entry.position))]))
+                .collect(),
+            false => self
+                .versions
+                .iter()
+                .map(|(key, versions)| (key, versions.clone()))
+                .collect(),
+        }
+    }
+
+    /// Stamp of the record at `position`, unknown when it has no sequence.
+    fn stamp_at(&self, position: u64) -> VersionStamp {
+        match self
+            .sequenced
+            .binary_search_by_key(&position, |(position, _)| *position)
+        {
+            Ok(found) => self.sequenced[found].1,
+            Err(_) => VersionStamp::default(),
+        }
+    }
+
+    /// Positions and stamps of the records of this segment written with a
+    /// sequence, in the order they were written.
+    pub fn get_sequenced(&self) -> &[(u64, VersionStamp)] {
+        &self.sequenced
+    }
+
+    /// Highest sequence stamped on a record, from this segment to the
+    /// oldest one.
+    pub fn last_sequence(&self) -> u64 {
+        let mut sequence = 0;
+        let mut current = Some(self);
+
+        while let Some(segment) = current {
+            for (_, stamp) in &segment.sequenced {
+                sequence = sequence.max(stamp.sequence);
+            }
+            current = segment.previous.as_deref();
+        }
 
-        let mut buffer = BufReader::new(&self.database_file);
-        let _ = buffer.seek(SeekFrom::Start(*key_position))?;
+        sequence
+    }
 
-        match DataSgment::load_record(&mut buffer) {
-            Ok(data) => Ok(Some(data)),
-            Err(err) => Err(err),
+    /// Records written with a sequence from `from` on, from this segment to
+    /// the oldest one, as their sequence, segment and position, ordered by
+    /// sequence.
+    pub fn sequenced_from(&self, from: u64) -> Vec<(u64, &DataSgment, u64)> {
+        let mut found = Vec::new();
+        let mut current = Some(self);
+
+        while let Some(segment) = current {
+            let start = match segment.in_sequence {
+                true => segment
+                    .sequenced
+                    .partition_point(|(_, stamp)| stamp.sequence < from),
+                false => 0,
+            };
+            found.extend(
+                segment.sequenced[start..]
+                    .iter()
+                    .filter(|(_, stamp)| stamp.sequence >= from)
+                    .map(|(position, stamp)| (stamp.sequence, segment, *position)),
+            );
+            current = segment.previous.as_deref();
         }
+
+        found.sort_unstable_by_key(|(sequence, _, _)| *sequence);
+        found
     }
 
-    pub fn delete_record(&mut self, key: String) -> Result<()> {
-        let key: Vec<u8> = Vec::from(key);
+    pub fn delete_record(&mut self, key: ByteString) -> Result<()> {
         self.save_record(KeyValue::new(key, Vec::new()))?;
         Ok(())
     }
 
     pub fn save_record(&mut self, key_value: KeyValue) -> Result<()> {
-        let position = self.database_file.seek(SeekFrom::End(0))?;
+        self.save_version(key_value, VersionStamp::default())
+    }
 
-        let key_size = key_value.key.len() as u32;
-        let value_size = key_value.value.len() as u32;
-        let total_size = key_size + value_size;
-        let mut data: Vec<u8> = Vec::with_capacity(total_size as usize);
+    /// Saves `key_value` as written at `stamp`, kept for the history of the
+    /// key as long as the record is.
+    pub fn save_version(&mut self, key_value: KeyValue, stamp: VersionStamp) -> Result<()> {
+        if !self.index.contains_key(&key_value.key) {
+            self.kill_previous(&key_value.key);
+        }
 
-        data.append(&mut key_value.key.clone());
-        data.append(&mut key_value.value.clone());
-        let checksum = crc32::checksum_ieee(&data);
+        let mut data = Vec::with_capacity(record_size(&key_value) as usize);
+        write_record(&mut data, &key_value)?;
 
-        self.database_file.write_u32::<BigEndian>(checksum)?;
-        self.database_file.write_u32::<BigEndian>(key_size)?;
-        self.database_file.write_u32::<BigEndian>(value_size)?;
-        self.database_file.write(&data)?;
+        // written at the end of the last complete record, and dropped when
+        // it fails, so that only the end of the file may hold a torn record
+        let position = self.size;
+        if let Err(err) = self.write_version(position, &data, stamp) {
+            self.database_file.set_len(position)?;
+            return Err(err);
+        }
 
-        DataSgment::update_index(&mut self.index, &key_value, position);
+        self.update_index(&key_value, position, stamp);
 
-        self.size = self.database_file.seek(SeekFrom::End(0))?;
+        self.size = position + data.len() as u64;
 
         Ok(())
     }
 
+    fn write_version(&mut self, position: u64, data: &[u8], stamp: VersionStamp) -> Result<()> {
+        self.database_file.write_at(position, data)?;
+        self.write_stamp(stamp)?;
+        // the record and its stamp are synced together
+        if self.durability == Durability::EveryWrite {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// How long overwritten versions are kept, none being tracked when it
+    /// is zero.
+    pub fn set_history_retention(&mut self, history_retention: Duration) {
+        self.history_retention = history_retention;
+    }
+
+    /// Whether records are synced as they are saved, or only on `flush`.
+    pub fn set_durability(&mut self, durability: Durability) {
+        self.durability = durability;
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        if let Some(file) = &mut self.stamps_file {
+            file.sync()?;
+        }
+        self.database_file.sync()
+    }
+
     pub fn get_size(&self) -> u64 {
         self.size
     }
 
+    /// Keys whose newest record, from this segment to the oldest one, is not
+    /// a delete.
+    pub fn get_live_keys(&self) -> Vec<ByteString> {
+        let mut seen: HashSet<&ByteString> = HashSet::new();
+        let mut result = Vec::new();
+        let mut current = Some(self);
+
+        while let Some(segment) = current {
+            for (key, entry) in segment.index.iter() {
+                if seen.insert(key) && entry.live {
+                    result.push(key.clone());
+                }
+            }
+            current = segment.previous.as_deref();
+        }
+
+        result
+    }
+
+    /// Live keys from `start` on, in order, merging the index of this
+    /// segment with those of the older ones, where the newest entry of each
+    /// key wins.
+    pub fn live_keys_from<'a>(&'a self, start: &'a [u8]) -> impl Iterator<Item = ByteString> + 'a {
+        let mut sources: Vec<Box<dyn Iterator<Item = Result<KeyValue>> + 'a>> = Vec::new();
+        let mut current = Some(self);
+        while let Some(segment) = current {
+            sources.push(Box::new(
+                segment
+                    .index
+                    .range::<[u8], _>((Included(start), Unbounded))
+                    .map(|(key, entry)| {
+                        // only whether the key is live matters, not its value
+                        let value = match entry.live {
+                            true => vec![1],
+                            false => Vec::new(),
+                        };
+                        Ok(KeyValue::new(key.clone(), value))
+                    }),
+            ));
+            current = segment.previous.as_deref();
+        }
+
+        MergeIterator::new(sources)
+            .filter_map(|record| record.ok())
+            .filter(|record| !record.value.is_empty())
+            .map(|record| record.key)
+    }
+
     pub fn get_previous(&self) -> &Option<Box<DataSgment>> {
         &self.previous
     }
@@ -316,12 +862,11 @@ impl DataSgment {
         &self.closed
     }
 
+    /// Chains `segment` before this one, once it was linked to this one on
+    /// disk with `update_next_file`.
     pub fn set_previous(&mut self, segment: Option<DataSgment>) {
         if let Some(mut value) = segment {
             value.closed = true;
-            value.database_file.seek(SeekFrom::Start(8)).unwrap();
-            value.update_next_file(self.name);
-
             self.previous.replace(Box::from(value));
         }
     }
@@ -330,25 +875,35 @@ impl DataSgment {
         parse_file_name(self.name)
     }
 
-    pub fn remove(folder: &str, segment: &str) {
-        remove_file(build_path(&folder_path(folder), segment)).unwrap();
+    pub fn remove(vfs: &Arc<dyn Vfs>, folder: &str, segment: &str) {
+        let path = build_path(&folder_path(folder), segment);
+        vfs.remove(&path).unwrap();
+        BloomFilter::remove(vfs, &bloom_path(&path)).unwrap();
+        if vfs.exists(&stamps_path(&path)) {
+            vfs.remove(&stamps_path(&path)).unwrap();
+        }
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::assertions_on_constants, clippy::unused_unit)]
 mod tests {
     use super::*;
-    use std::fs::{copy, read_dir, remove_dir_all};
+    use std::fs::{copy, create_dir_all, read_dir, remove_dir_all};
 
     fn get_folder_name() -> String {
         format!("storage_test_{}", random::<u64>())
     }
 
+    fn disk() -> Arc<dyn Vfs> {
+        Arc::new(DiskVfs)
+    }
+
     #[test]
     fn create_empty_segment_on_new_db() {
         let folder_name = &get_folder_name();
 
-        let segment = DataSgment::new(folder_name);
+        let segment = DataSgment::new(folder_name, &disk());
 
         assert!(!segment.closed);
         assert_eq!(segment.size, 16);
@@ -359,7 +914,11 @@ mod tests {
 
     #[test]
     fn open_existing_segment() {
-        let segment = DataSgment::open("./readonly_storage_test/53e155bcbdeb560f");
+        let segment = DataSgment::open(
+            "./readonly_storage_test/53e155bcbdeb560f",
+            &disk(),
+            Duration::ZERO,
+        );
 
         assert!(segment.closed);
         assert_eq!(segment.size, 1058);
@@ -370,7 +929,7 @@ mod tests {
     fn update_size_on_save_data() {
         let folder_name = &get_folder_name();
 
-        let mut segment = DataSgment::new(folder_name);
+        let mut segment = DataSgment::new(folder_name, &disk());
         segment
             .save_record(KeyValue::new_from_strings(
                 String::from("123"),
@@ -385,6 +944,160 @@ mod tests {
         remove_dir_all(folder_path(folder_name)).unwrap();
     }
 
+    #[test]
+    fn keep_stamps_of_every_version_across_opens() {
+        let vfs: Arc<dyn Vfs> = Arc::new(crate::vfs::MemoryVfs::new());
+        let mut segment = DataSgment::new("storage", &vfs);
+        segment.set_history_retention(Duration::from_secs(3_600));
+        let stamp = |sequence| VersionStamp {
+            sequence,
+            time: now_millis(),
+        };
+        segment
+            .save_version(
+                KeyValue::new_from_strings(String::from("a"), String::from("1")),
+                stamp(6),
+            )
+            .unwrap();
+        segment
+            .save_version(
+                KeyValue::new_from_strings(String::from("a"), String::from("2")),
+                stamp(7),
+            )
+            .unwrap();
+        segment.delete_record(b"a".to_vec()).unwrap();
+
+        let segment = DataSgment::open(&segment.path, &vfs, Duration::from_secs(3_600));
+        let versions = segment.get_versions(b"a");
+
+        assert_eq!(versions.len(), 3);
+        assert_eq!(versions[0].1.sequence, 6);
+        assert_eq!(versions[1].1.sequence, 7);
+        assert_eq!(versions[2].1, VersionStamp::default());
+        assert_eq!(
+            segment.read_record_at(versions[1].0).unwrap().value,
+            b"2".to_vec()
+        );
+        assert_eq!(segment.last_sequence(), 7);
+    }
+
+    #[test]
+    fn track_versions_only_within_the_history_retention() {
+        let vfs: Arc<dyn Vfs> = Arc::new(crate::vfs::MemoryVfs::new());
+        let mut segment = DataSgment::new("storage", &vfs);
+        let stamp = |sequence, time| VersionStamp { sequence, time };
+        segment
+            .save_version(
+                KeyValue::new_from_strings(String::from("a"), String::from("1")),
+                stamp(1, now_millis()),
+            )
+            .unwrap();
+        assert!(segment.get_versions(b"a").is_empty());
+
+        segment.set_history_retention(Duration::from_secs(3_600));
+        segment
+            .save_version(
+                KeyValue::new_from_strings(String::from("b"), String::from("1")),
+                stamp(2, 1_700_000_000_000),
+            )
+            .unwrap();
+        segment
+            .save_version(
+                KeyValue::new_from_strings(String::from("b"), String::from("2")),
+                stamp(3, now_millis()),
+            )
+            .unwrap();
+
+        let versions = segment.get_versions(b"b");
+        assert_eq!(versions.len(), 1);
+        assert_eq!(versions[0].1.sequence, 3);
+    }
+
+    #[test]
+    fn fail_writes_whose_stamp_fails_to_persist() {
+        let faulty = crate::fault::FaultyVfs::new(7);
+        let vfs: Arc<dyn Vfs> = Arc::new(faulty.clone());
+        let mut segment = DataSgment::new("storage", &vfs);
+        segment.set_history_retention(Duration::from_secs(3_600));
+        let time = now_millis();
+        let stamp = |sequence| VersionStamp { sequence, time };
+        segment
+            .save_version(
+                KeyValue::new_from_strings(String::from("a"), String::from("1")),
+                stamp(1),
+            )
+            .unwrap();
+
+        faulty.fail_writes_after(1);
+        let result = segment.save_version(
+            KeyValue::new_from_strings(String::from("a"), String::from("2")),
+            stamp(2),
+        );
+        faulty.clear_faults();
+        segment
+            .save_version(
+                KeyValue::new_from_strings(String::from("a"), String::from("3")),
+                stamp(3),
+            )
+            .unwrap();
+
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::StorageFull);
+        let segment = DataSgment::open(&segment.path, &vfs, Duration::from_secs(3_600));
+        let versions = segment.get_versions(b"a");
+        assert_eq!(versions.len(), 2);
+        assert_eq!(versions[1].1, stamp(3));
+        assert_eq!(
+            segment.read_record_at(versions[1].0).unwrap().value,
+            b"3".to_vec()
+        );
+    }
+
+    fn segment_of_three_records(vfs: &Arc<dyn Vfs>) -> DataSgment {
+        let mut segment = DataSgment::new("storage", vfs);
+        for (key, value) in [("a", "1"), ("b", "2"), ("c", "3")] {
+            segment
+                .save_record(KeyValue::new_from_strings(
+                    String::from(key),
+                    String::from(value),
+                ))
+                .unwrap();
+        }
+        segment
+    }
+
+    #[test]
+    fn refuse_to_open_a_segment_corrupt_in_the_middle() {
+        let vfs: Arc<dyn Vfs> = Arc::new(crate::vfs::MemoryVfs::new());
+        let segment = segment_of_three_records(&vfs);
+
+        // the value of the second record, after the header and the first one
+        let mut file = vfs.open(&segment.path).unwrap();
+        file.write_at(16 + 14 + 12 + 1, b"x").unwrap();
+        let result = DataSgment::try_open(&segment.path, &vfs, Duration::ZERO);
+
+        assert_eq!(result.err().unwrap().kind(), ErrorKind::InvalidData);
+        assert_eq!(file.len().unwrap(), 16 + 14 * 3);
+    }
+
+    #[test]
+    fn truncate_a_torn_record_at_the_end_of_the_last_segment() {
+        let vfs: Arc<dyn Vfs> = Arc::new(crate::vfs::MemoryVfs::new());
+        let segment = segment_of_three_records(&vfs);
+        let mut file = vfs.open(&segment.path).unwrap();
+        file.write_at(16 + 14 * 2 + 13, b"x").unwrap();
+
+        let torn = DataSgment::open(&segment.path, &vfs, Duration::ZERO);
+        let mut closed = segment_of_three_records(&vfs);
+        closed.update_next_file(torn.name).unwrap();
+        let mut file = vfs.open(&closed.path).unwrap();
+        file.write_at(16 + 14 * 2 + 13, b"x").unwrap();
+
+        assert_eq!(torn.get_size(), 16 + 14 * 2);
+        assert!(torn.get_record(b"c").unwrap().is_none());
+        assert_eq!(torn.get_record(b"b").unwrap().unwrap().value, b"2".to_vec());
+        assert!(DataSgment::try_open(&closed.path, &vfs, Duration::ZERO).is_err());
+    }
+
     #[test]
     fn load_segments() {
         let folder_name = &get_folder_name();
@@ -411,7 +1124,7 @@ mod tests {
         )
         .unwrap();
 
-        let segment = DataSgment::load_dir(folder_name);
+        let segment = DataSgment::load_dir(folder_name, &disk(), Duration::ZERO);
 
         // first segment is always a neew open one
         assert!(!segment.closed);
@@ -473,7 +1186,7 @@ mod tests {
         let folder_name = &get_folder_name();
 
         // act
-        let segment = DataSgment::load_dir(folder_name);
+        let segment = DataSgment::load_dir(folder_name, &disk(), Duration::ZERO);
 
         // assert
         let paths: Vec<String> = read_dir(folder_path(folder_name))
@@ -0,0 +1,346 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{prelude::*, Error, ErrorKind, Result, SeekFrom};
+use std::sync::{Arc, Mutex};
+
+/// Handle to a file of a `Vfs`. Reads take a position so they can be shared
+/// by readers, while writes go either to a position or to the end of the file.
+pub trait VfsFile: Send {
+    fn read_at(&self, position: u64, buffer: &mut [u8]) -> Result<usize>;
+    fn write_at(&mut self, position: u64, data: &[u8]) -> Result<()>;
+    /// Writes `data` at the end of the file, returning where it was written.
+    fn append(&mut self, data: &[u8]) -> Result<u64>;
+    fn len(&self) -> Result<u64>;
+    fn set_len(&mut self, size: u64) -> Result<()>;
+    /// Makes every write done so far durable.
+    fn sync(&mut self) -> Result<()>;
+
+    fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+}
+
+/// File system used by the storage, so it can live on disk or in memory.
+pub trait Vfs: Send + Sync {
+    /// Creates an empty file, truncating it when it already exists.
+    fn create(&self, path: &str) -> Result<Box<dyn VfsFile>>;
+    /// Opens an existing file for reading and writing.
+    fn open(&self, path: &str) -> Result<Box<dyn VfsFile>>;
+    fn exists(&self, path: &str) -> bool;
+    fn rename(&self, from: &str, to: &str) -> Result<()>;
+    fn remove(&self, path: &str) -> Result<()>;
+    fn create_dir_all(&self, path: &str) -> Result<()>;
+    /// Removes the folder at `path` with everything in it, including its
+    /// subfolders. Succeeds when there is no such folder.
+    fn remove_dir_all(&self, path: &str) -> Result<()>;
+    /// Names of the files inside `folder`.
+    fn list(&self, folder: &str) -> Result<Vec<String>>;
+}
+
+/// Copies the file at `from` to `to`, a block at a time, returning its size.
+pub(crate) fn copy_file(vfs: &dyn Vfs, from: &str, to: &str) -> Result<u64> {
+    let source = vfs.open(from)?;
+    let mut copy = vfs.create(to)?;
+    let mut buffer = vec![0; COPY_BLOCK_SIZE];
+    let mut size = 0;
+    loop {
+        let read = source.read_at(size, &mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        copy.append(&buffer[..read])?;
+        size += read as u64;
+    }
+    copy.sync()?;
+    Ok(size)
+}
+
+static COPY_BLOCK_SIZE: usize = 65_536;
+
+/// Reads a `VfsFile` as a stream, starting at any position.
+pub struct FileReader<'a> {
+    file: &'a dyn VfsFile,
+    position: u64,
+}
+
+impl<'a> FileReader<'a> {
+    pub fn new(file: &'a dyn VfsFile, position: u64) -> FileReader<'a> {
+        FileReader { file, position }
+    }
+}
+
+impl<'a> Read for FileReader<'a> {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        let size = self.file.read_at(self.position, buffer)?;
+        self.position += size as u64;
+        Ok(size)
+    }
+}
+
+impl<'a> Seek for FileReader<'a> {
+    fn seek(&mut self, position: SeekFrom) -> Result<u64> {
+        let position = match position {
+            SeekFrom::Start(value) => Some(value),
+            SeekFrom::Current(value) => self.position.checked_add_signed(value),
+            SeekFrom::End(value) => self.file.len()?.checked_add_signed(value),
+        };
+
+        match position {
+            Some(value) => {
+                self.position = value;
+                Ok(value)
+            }
+            None => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Invalid seek to a negative position",
+            )),
+        }
+    }
+}
+
+/// Appends everything written to the end of a `VfsFile`.
+pub struct FileWriter<'a> {
+    file: &'a mut dyn VfsFile,
+}
+
+impl<'a> FileWriter<'a> {
+    pub fn new(file: &'a mut dyn VfsFile) -> FileWriter<'a> {
+        FileWriter { file }
+    }
+}
+
+impl<'a> Write for FileWriter<'a> {
+    fn write(&mut self, data: &[u8]) -> Result<usize> {
+        self.file.append(data)?;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub struct DiskVfs;
+
+struct DiskFile {
+    file: File,
+}
+
+impl VfsFile for DiskFile {
+    fn read_at(&self, position: u64, buffer: &mut [u8]) -> Result<usize> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(position))?;
+        file.read(buffer)
+    }
+
+    fn write_at(&mut self, position: u64, data: &[u8]) -> Result<()> {
+        self.file.seek(SeekFrom::Start(position))?;
+        self.file.write_all(data)
+    }
+
+    fn append(&mut self, data: &[u8]) -> Result<u64> {
+        let position = self.file.seek(SeekFrom::End(0))?;
+        self.file.write_all(data)?;
+        Ok(position)
+    }
+
+    fn len(&self) -> Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    fn set_len(&mut self, size: u64) -> Result<()> {
+        self.file.set_len(size)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.file.sync_all()
+    }
+}
+
+impl Vfs for DiskVfs {
+    fn create(&self, path: &str) -> Result<Box<dyn VfsFile>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(Box::new(DiskFile { file }))
+    }
+
+    fn open(&self, path: &str) -> Result<Box<dyn VfsFile>> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(Box::new(DiskFile { file }))
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        fs::metadata(path).is_ok()
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn remove(&self, path: &str) -> Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn create_dir_all(&self, path: &str) -> Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn remove_dir_all(&self, path: &str) -> Result<()> {
+        match fs::remove_dir_all(path) {
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    fn list(&self, folder: &str) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(folder)? {
+            if let Some(name) = entry?.file_name().to_str() {
+                names.push(String::from(name));
+            }
+        }
+        Ok(names)
+    }
+}
+
+type MemoryData = Arc<Mutex<Vec<u8>>>;
+
+/// File system kept in memory. Clones share the same files, so a storage
+/// can be loaded again from what a previous instance wrote.
+#[derive(Clone, Default)]
+pub struct MemoryVfs {
+    files: Arc<Mutex<HashMap<String, MemoryData>>>,
+}
+
+impl MemoryVfs {
+    pub fn new() -> MemoryVfs {
+        MemoryVfs::default()
+    }
+
+    /// Copies a file from disk, to load existing storages in memory.
+    pub fn copy_from_disk(&self, from: &str, to: &str) -> Result<()> {
+        let data = fs::read(from)?;
+        self.create(to)?.append(&data)?;
+        Ok(())
+    }
+}
+
+struct MemoryFile {
+    data: MemoryData,
+}
+
+fn not_found(path: &str) -> Error {
+    Error::new(ErrorKind::NotFound, format!("File not found: {}", path))
+}
+
+impl VfsFile for MemoryFile {
+    fn read_at(&self, position: u64, buffer: &mut [u8]) -> Result<usize> {
+        let data = self.data.lock().unwrap();
+        let start = (position as usize).min(data.len());
+        let size = buffer.len().min(data.len() - start);
+        buffer[..size].copy_from_slice(&data[start..start + size]);
+        Ok(size)
+    }
+
+    fn write_at(&mut self, position: u64, data: &[u8]) -> Result<()> {
+        let mut file = self.data.lock().unwrap();
+        let end = position as usize + data.len();
+        if file.len() < end {
+            file.resize(end, 0);
+        }
+        file[position as usize..end].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn append(&mut self, data: &[u8]) -> Result<u64> {
+        let mut file = self.data.lock().unwrap();
+        let position = file.len() as u64;
+        file.extend_from_slice(data);
+        Ok(position)
+    }
+
+    fn len(&self) -> Result<u64> {
+        Ok(self.data.lock().unwrap().len() as u64)
+    }
+
+    fn set_len(&mut self, size: u64) -> Result<()> {
+        self.data.lock().unwrap().resize(size as usize, 0);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Vfs for MemoryVfs {
+    fn create(&self, path: &str) -> Result<Box<dyn VfsFile>> {
+        let data = MemoryData::default();
+        self.files
+            .lock()
+            .unwrap()
+            .insert(String::from(path), Arc::clone(&data));
+        Ok(Box::new(MemoryFile { data }))
+    }
+
+    fn open(&self, path: &str) -> Result<Box<dyn VfsFile>> {
+        match self.files.lock().unwrap().get(path) {
+            Some(data) => Ok(Box::new(MemoryFile {
+                data: Arc::clone(data),
+            })),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        self.files.lock().unwrap().contains_key(path)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let mut files = self.files.lock().unwrap();
+        match files.remove(from) {
+            Some(data) => {
+                files.insert(String::from(to), data);
+                Ok(())
+            }
+            None => Err(not_found(from)),
+        }
+    }
+
+    fn remove(&self, path: &str) -> Result<()> {
+        match self.files.lock().unwrap().remove(path) {
+            Some(_) => Ok(()),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn create_dir_all(&self, _path: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn remove_dir_all(&self, path: &str) -> Result<()> {
+        let prefix = format!("{}/", path);
+        self.files
+            .lock()
+            .unwrap()
+            .retain(|file, _| !file.starts_with(&prefix));
+        Ok(())
+    }
+
+    fn list(&self, folder: &str) -> Result<Vec<String>> {
+        let prefix = format!("{}/", folder);
+        Ok(self
+            .files
+            .lock()
+            .unwrap()
+            .keys()
+            .filter_map(|path| path.strip_prefix(&prefix))
+            .filter(|name| !name.contains('/'))
+            .map(String::from)
+            .collect())
+    }
+}
@@ -0,0 +1,68 @@
+#![cfg(feature = "async")]
+
+use rustdb::{AsyncRustDB, KeyValue, MemoryVfs, Options, RustDB};
+use std::sync::Arc;
+
+fn memory_db() -> AsyncRustDB {
+    AsyncRustDB::new(RustDB::load_with_vfs(
+        "storage",
+        Options::default(),
+        Arc::new(MemoryVfs::new()),
+    ))
+}
+
+#[tokio::test]
+async fn save_get_and_delete_asynchronously() {
+    // arrange
+    let db = memory_db();
+
+    // act
+    db.save_record(KeyValue::new_from_strings(
+        String::from("ABC"),
+        String::from("{\"id\":\"ABC\"}"),
+    ))
+    .await
+    .unwrap();
+    let saved = db.get_record("ABC").await.unwrap();
+    db.delete_record("ABC").await.unwrap();
+    let deleted = db.get_record("ABC").await.unwrap();
+    db.flush().await.unwrap();
+
+    // assert
+    assert_eq!(saved.unwrap().get_value_as_string(), "{\"id\":\"ABC\"}");
+    assert!(deleted.is_none());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn clones_share_the_database() {
+    // arrange
+    let db = memory_db();
+
+    // act
+    let writers: Vec<_> = (0..20)
+        .map(|i| {
+            let db = db.clone();
+            tokio::spawn(async move {
+                db.save_record(KeyValue::new_from_strings(
+                    format!("{:04}", i),
+                    format!("{{\"id\":\"{}\"}}", i),
+                ))
+                .await
+            })
+        })
+        .collect();
+
+    for writer in writers {
+        writer.await.unwrap().unwrap();
+    }
+
+    // assert
+    for i in 0..20 {
+        let record = db.get_record(format!("{:04}", i)).await.unwrap();
+        assert_eq!(
+            record.unwrap().get_value_as_string(),
+            format!("{{\"id\":\"{}\"}}", i)
+        );
+    }
+    assert_eq!(db.shared().lock().unwrap().get_active_keys().len(), 20);
+}
@@ -1,6 +1,6 @@
 use rand::random;
-use rustdb::{InitialSegmentReference, KeyValue, LogCompressor, RustDB};
-use std::fs::{read_dir, remove_dir_all};
+use rustdb::{InitialSegmentReference, KeyValue, LogCompressor, MemoryVfs, Options, RustDB, Vfs};
+use std::sync::Arc;
 
 static STORAGE_TEST_FOLDER: &str = "storage_test";
 
@@ -12,11 +12,29 @@ fn path_to_folder(path: &str) -> String {
     format!("./{}", path)
 }
 
+fn copy_read_only_files(folder_name: &str) -> Arc<dyn Vfs> {
+    let vfs = MemoryVfs::new();
+    for file in &[
+        "53e155bcbdeb560f",
+        "4da053f2db81bb26",
+        "e0c515663f0ea931",
+        "initial_segment",
+    ] {
+        vfs.copy_from_disk(
+            &format!("./readonly_storage_test/{}", file),
+            &format!("./{}/{}", folder_name, file),
+        )
+        .unwrap();
+    }
+    Arc::new(vfs)
+}
+
 #[test]
 fn compress_closed_files() {
     // arrange
     let path = &folder_name();
-    let mut db = RustDB::load(path);
+    let vfs: Arc<dyn Vfs> = Arc::new(MemoryVfs::new());
+    let mut db = RustDB::load_with_vfs(path, Options::default(), Arc::clone(&vfs));
 
     for i in 0..200 {
         let id = i % 3;
@@ -30,12 +48,13 @@ fn compress_closed_files() {
     // act
     let segment_names = db.get_closed_segment_names();
     let current_segment_name = db.segment.unwrap().name;
-    let compressor = LogCompressor::new(path, segment_names, current_segment_name);
+    let compressor =
+        LogCompressor::new(path, segment_names, current_segment_name).with_vfs(Arc::clone(&vfs));
 
-    let (active_segment, new_segment) = compressor.compress();
+    let (active_segment, new_segment) = compressor.compress().unwrap();
 
     // assert
-    let reference = InitialSegmentReference::load(path);
+    let reference = InitialSegmentReference::load_with_vfs(path, &vfs);
 
     assert_eq!(current_segment_name, active_segment);
     assert_eq!(
@@ -43,15 +62,14 @@ fn compress_closed_files() {
         new_segment.next_segment_name.unwrap()
     );
     assert_eq!(reference.initial_segment.unwrap(), new_segment.name);
-
-    remove_dir_all(path_to_folder(path)).unwrap();
 }
 
 #[test]
 fn delete_compressed_files() {
     // arrange
     let path = &folder_name();
-    let mut db = RustDB::load(path);
+    let vfs: Arc<dyn Vfs> = Arc::new(MemoryVfs::new());
+    let mut db = RustDB::load_with_vfs(path, Options::default(), Arc::clone(&vfs));
 
     for i in 0..200 {
         let id = i % 3;
@@ -65,30 +83,26 @@ fn delete_compressed_files() {
     // act
     let segment_names = db.get_closed_segment_names();
     let current_segment_name = db.segment.unwrap().name;
-    let compressor = LogCompressor::new(path, segment_names.clone(), current_segment_name);
+    let compressor = LogCompressor::new(path, segment_names.clone(), current_segment_name)
+        .with_vfs(Arc::clone(&vfs));
 
-    let (_, new_segment) = compressor.compress();
-    LogCompressor::clean(&path, segment_names);
+    let (_, new_segment) = compressor.compress().unwrap();
+    LogCompressor::clean_with_vfs(&vfs, path, segment_names);
 
     // assert
-    let paths: Vec<String> = read_dir(path_to_folder(path))
-        .unwrap()
-        .map(|r| r.unwrap())
-        .map(|r| String::from(r.file_name().to_str().unwrap()))
-        .collect();
+    let paths = vfs.list(&path_to_folder(path)).unwrap();
 
     assert_eq!(3, paths.len());
     assert!(paths.contains(&new_segment.get_name()));
     assert!(paths.contains(&String::from("initial_segment")));
-
-    remove_dir_all(path_to_folder(path)).unwrap();
 }
 
 #[test]
 fn compress_and_replace() {
     // arrange
     let path = &folder_name();
-    let mut db = RustDB::load(path);
+    let vfs: Arc<dyn Vfs> = Arc::new(MemoryVfs::new());
+    let mut db = RustDB::load_with_vfs(path, Options::default(), Arc::clone(&vfs));
 
     for i in 0..200 {
         let id = i % 3;
@@ -102,13 +116,14 @@ fn compress_and_replace() {
     // act
     let segment_names = db.get_closed_segment_names();
     let current_segment_name = db.get_active_segment_name();
-    let compressor = LogCompressor::new(path, segment_names.clone(), current_segment_name);
+    let compressor = LogCompressor::new(path, segment_names.clone(), current_segment_name)
+        .with_vfs(Arc::clone(&vfs));
 
-    let (active_segment, new_segment) = compressor.compress();
+    let (active_segment, new_segment) = compressor.compress().unwrap();
 
     let new_segment_name = new_segment.name;
     db.replace_segments(active_segment, new_segment);
-    LogCompressor::clean(&path, segment_names);
+    LogCompressor::clean_with_vfs(&vfs, path, segment_names);
 
     // assert
     assert_eq!(active_segment, db.get_active_segment_name());
@@ -116,6 +131,101 @@ fn compress_and_replace() {
         new_segment_name,
         db.segment.unwrap().get_previous().as_ref().unwrap().name
     );
+}
+
+#[test]
+fn compress_only_segments_with_dead_space() {
+    // arrange
+    let path = &folder_name();
+    let vfs = copy_read_only_files(path);
+    let mut db = RustDB::load_with_vfs(path, Options::default(), Arc::clone(&vfs));
+
+    for i in 31..37 {
+        db.delete_record(format!("{:04}", i)).unwrap();
+    }
+
+    // act
+    let segment_names = db.get_segments_to_compress();
+    let current_segment_name = db.get_active_segment_name();
+    let compressor = LogCompressor::new(path, segment_names.clone(), current_segment_name)
+        .with_active_keys(db.get_active_keys())
+        .with_vfs(Arc::clone(&vfs));
+
+    let (active_segment, new_segment) = compressor.compress().unwrap();
+
+    let new_segment_name = new_segment.get_name();
+    db.replace_segments(active_segment, new_segment);
+    LogCompressor::clean_with_vfs(&vfs, path, segment_names.clone());
+
+    // assert
+    assert_eq!(segment_names, vec![String::from("4da053f2db81bb26")]);
+    assert_eq!(
+        db.get_closed_segment_names(),
+        vec![
+            new_segment_name,
+            String::from("e0c515663f0ea931"),
+            String::from("53e155bcbdeb560f")
+        ]
+    );
+
+    let stats = db.stats();
+    assert_eq!(stats[1].live_keys, 3);
+    assert_eq!(stats[1].dead_keys, 0);
+    assert!(db.get_segments_to_compress().is_empty());
+
+    let reference = InitialSegmentReference::load_with_vfs(path, &vfs);
+    assert_eq!(
+        format!("{:016x}", reference.initial_segment.unwrap()),
+        "53e155bcbdeb560f"
+    );
+
+    let db = RustDB::load_with_vfs(path, Options::default(), Arc::clone(&vfs));
+    assert!(db.get_record(String::from("0031")).unwrap().is_none());
+    assert!(db.get_record(String::from("0037")).unwrap().is_some());
+    assert!(db.get_record(String::from("0020")).unwrap().is_some());
+    assert!(db.get_record(String::from("0001")).unwrap().is_some());
+}
+
+#[test]
+fn compress_segments_in_memory() {
+    // arrange
+    let path = &folder_name();
+    let vfs = MemoryVfs::new();
+    for file in &[
+        "53e155bcbdeb560f",
+        "4da053f2db81bb26",
+        "e0c515663f0ea931",
+        "initial_segment",
+    ] {
+        vfs.copy_from_disk(
+            &format!("./readonly_storage_test/{}", file),
+            &format!("./{}/{}", path, file),
+        )
+        .unwrap();
+    }
+    let vfs: Arc<dyn Vfs> = Arc::new(vfs);
+    let mut db = RustDB::load_with_vfs(path, Options::default(), Arc::clone(&vfs));
+
+    for i in 31..37 {
+        db.delete_record(format!("{:04}", i)).unwrap();
+    }
+
+    // act
+    let segment_names = db.get_segments_to_compress();
+    let compressor = LogCompressor::new(path, segment_names.clone(), db.get_active_segment_name())
+        .with_active_keys(db.get_active_keys())
+        .with_vfs(db.get_vfs());
+
+    let (active_segment, new_segment) = compressor.compress().unwrap();
+    db.replace_segments(active_segment, new_segment);
+    LogCompressor::clean_with_vfs(&vfs, path, segment_names);
+
+    // assert
+    let files = vfs.list(&path_to_folder(path)).unwrap();
+    assert!(!files.contains(&String::from("4da053f2db81bb26")));
 
-    remove_dir_all(path_to_folder(path)).unwrap();
+    let db = RustDB::load_with_vfs(path, Options::default(), vfs);
+    assert!(db.get_record(String::from("0031")).unwrap().is_none());
+    assert!(db.get_record(String::from("0037")).unwrap().is_some());
+    assert!(db.get_record(String::from("0001")).unwrap().is_some());
 }
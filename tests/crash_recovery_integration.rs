@@ -0,0 +1,354 @@
+use rand::rngs::StdRng;
+use rand::{random, Rng, SeedableRng};
+use rustdb::{ChangeFilter, Durability, FaultyVfs, KeyValue, LogCompressor, Options, RustDB, Vfs};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+static STORAGE_TEST_FOLDER: &str = "storage_test";
+
+fn folder_name() -> String {
+    format!("{}{}", STORAGE_TEST_FOLDER, random::<u64>())
+}
+
+fn options() -> Options {
+    Options {
+        segment_size: 2_000,
+        durability: Durability::EveryWrite,
+        ..Options::default()
+    }
+}
+
+fn load(path: &str, vfs: &FaultyVfs) -> RustDB {
+    RustDB::load_with_vfs(path, options(), Arc::new(vfs.clone()))
+}
+
+fn copy_read_only_files(path: &str, vfs: &FaultyVfs) {
+    for file in &[
+        "53e155bcbdeb560f",
+        "4da053f2db81bb26",
+        "e0c515663f0ea931",
+        "initial_segment",
+    ] {
+        vfs.copy_from_disk(
+            &format!("./readonly_storage_test/{}", file),
+            &format!("./{}/{}", path, file),
+        )
+        .unwrap();
+    }
+}
+
+fn value(key: u32, version: u32) -> String {
+    format!("{{\"id\":\"{}\",\"version\":{}}}", key, version)
+}
+
+/// Values each key may have after a crash: the last acknowledged one, plus
+/// those of the writes that failed after it, as they may have reached the
+/// disk anyway.
+struct Model {
+    keys: HashMap<String, Vec<Option<String>>>,
+}
+
+impl Model {
+    fn new() -> Model {
+        Model {
+            keys: HashMap::new(),
+        }
+    }
+
+    fn write(&mut self, key: &str, value: Option<String>, acknowledged: bool) {
+        let values = self.keys.entry(String::from(key)).or_insert(vec![None]);
+        if acknowledged {
+            values.clear();
+        }
+        values.push(value);
+    }
+
+    fn verify(&mut self, db: &RustDB) {
+        for (key, values) in self.keys.iter_mut() {
+            let found = db
+                .get_record(key.clone())
+                .unwrap()
+                .map(|r| r.get_value_as_string());
+
+            assert!(
+                values.contains(&found),
+                "key {} recovered as {:?}, expected one of {:?}",
+                key,
+                found,
+                values
+            );
+
+            *values = vec![found];
+        }
+    }
+}
+
+fn compress(path: &str, db: &mut RustDB) {
+    let segment_names = db.get_segments_to_compress();
+    if segment_names.is_empty() {
+        return;
+    }
+
+    let compressor = LogCompressor::new(path, segment_names.clone(), db.get_active_segment_name())
+        .with_active_keys(db.get_active_keys())
+        .with_vfs(db.get_vfs());
+
+    if let Ok((active_segment, new_segment)) = compressor.compress() {
+        db.replace_segments(active_segment, new_segment);
+        LogCompressor::clean_with_vfs(&db.get_vfs(), path, segment_names);
+    }
+}
+
+fn run_workload(seed: u64) {
+    let path = &folder_name();
+    let vfs = FaultyVfs::new(seed);
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut model = Model::new();
+    let mut version = 0;
+
+    for _ in 0..4 {
+        let mut db = load(path, &vfs);
+        model.verify(&db);
+
+        let operations = rng.gen_range(50, 300);
+        let fault_at = rng.gen_range(0, operations);
+
+        for operation in 0..operations {
+            if operation == fault_at {
+                match rng.gen_range(0, 3) {
+                    0 => vfs.fail_writes_after(rng.gen_range(0, 20)),
+                    1 => vfs.fail_renames(),
+                    _ => {}
+                }
+            }
+
+            let key = rng.gen_range(0, 40);
+            version += 1;
+
+            match rng.gen_range(0, 10) {
+                0 => compress(path, &mut db),
+                1 | 2 => {
+                    let result = db.delete_record(format!("{:04}", key));
+                    model.write(&format!("{:04}", key), None, result.is_ok());
+                }
+                _ => {
+                    let result = db.save_record(KeyValue::new_from_strings(
+                        format!("{:04}", key),
+                        value(key, version),
+                    ));
+                    model.write(
+                        &format!("{:04}", key),
+                        Some(value(key, version)),
+                        result.is_ok(),
+                    );
+                }
+            }
+        }
+
+        drop(db);
+        vfs.crash();
+    }
+
+    let db = load(path, &vfs);
+    model.verify(&db);
+}
+
+#[test]
+fn acknowledged_writes_survive_random_crashes() {
+    for seed in 0..40 {
+        run_workload(seed);
+    }
+}
+
+#[test]
+fn torn_write_is_discarded_on_recovery() {
+    // arrange
+    let path = &folder_name();
+    let vfs = FaultyVfs::new(1);
+    let mut db = load(path, &vfs);
+    db.save_record(KeyValue::new_from_strings(
+        String::from("0001"),
+        value(1, 1),
+    ))
+    .unwrap();
+
+    // act
+    vfs.fail_writes_after(0);
+    let result = db.save_record(KeyValue::new_from_strings(
+        String::from("0002"),
+        value(2, 1),
+    ));
+    drop(db);
+    vfs.crash();
+
+    // assert
+    assert!(result.is_err());
+
+    let mut db = load(path, &vfs);
+    assert_eq!(
+        db.get_record(String::from("0001"))
+            .unwrap()
+            .unwrap()
+            .get_value_as_string(),
+        value(1, 1)
+    );
+
+    db.save_record(KeyValue::new_from_strings(
+        String::from("0003"),
+        value(3, 1),
+    ))
+    .unwrap();
+    drop(db);
+    vfs.crash();
+
+    let db = load(path, &vfs);
+    assert!(db.get_record(String::from("0001")).unwrap().is_some());
+    assert!(db.get_record(String::from("0003")).unwrap().is_some());
+}
+
+#[test]
+fn write_failing_mid_segment_is_overwritten() {
+    // arrange
+    let path = &folder_name();
+    let vfs = FaultyVfs::new(2);
+    let mut db = load(path, &vfs);
+
+    // act
+    vfs.fail_writes_after(0);
+    let failed = db.save_record(KeyValue::new_from_strings(
+        String::from("0001"),
+        value(1, 1),
+    ));
+    vfs.clear_faults();
+    let saved = db.save_record(KeyValue::new_from_strings(
+        String::from("0002"),
+        value(2, 1),
+    ));
+    drop(db);
+    vfs.crash();
+
+    // assert
+    assert!(failed.is_err());
+    assert!(saved.is_ok());
+
+    let db = load(path, &vfs);
+    assert!(db.get_record(String::from("0002")).unwrap().is_some());
+}
+
+#[test]
+fn failed_rename_leaves_compressed_segments_unused() {
+    // arrange
+    let path = &folder_name();
+    let vfs = FaultyVfs::new(3);
+    copy_read_only_files(path, &vfs);
+    let mut db = load(path, &vfs);
+
+    for i in 1..30 {
+        db.delete_record(format!("{:04}", i)).unwrap();
+    }
+
+    let segment_names = db.get_closed_segment_names();
+    let compressor = LogCompressor::new(path, segment_names, db.get_active_segment_name())
+        .with_vfs(db.get_vfs());
+
+    // act
+    vfs.fail_renames();
+    let result = compressor.compress();
+    drop(db);
+    vfs.crash();
+
+    // assert
+    assert!(result.is_err());
+
+    let db = load(path, &vfs);
+    let files = vfs.list(&format!("./{}", path)).unwrap();
+    assert!(files.contains(&String::from("initial_segment")));
+    assert!(files.contains(&String::from("53e155bcbdeb560f")));
+
+    assert!(db.get_record(String::from("0001")).unwrap().is_none());
+    assert!(db.get_record(String::from("0029")).unwrap().is_none());
+    assert!(db.get_record(String::from("0030")).unwrap().is_some());
+    assert!(db.get_record(String::from("0037")).unwrap().is_some());
+}
+
+#[test]
+fn acknowledged_version_stamps_survive_crashes() {
+    // arrange
+    let path = &folder_name();
+    let vfs = FaultyVfs::new(4);
+    let options = Options {
+        history_retention: Duration::from_secs(3_600),
+        ..options()
+    };
+    let mut db = RustDB::load_with_vfs(path, options.clone(), Arc::new(vfs.clone()));
+
+    // act
+    for version in 1..=100 {
+        db.save_record(KeyValue::new_from_strings(
+            String::from("0001"),
+            value(1, version),
+        ))
+        .unwrap();
+    }
+    drop(db);
+    vfs.crash();
+
+    // assert
+    let db = RustDB::load_with_vfs(path, options, Arc::new(vfs.clone()));
+    let history = db.history(String::from("0001")).unwrap();
+    assert_eq!(history.len(), 100);
+    for (sequence, version) in (1..).zip(&history) {
+        assert_eq!(version.sequence, sequence);
+        assert_eq!(version.value, Some(value(1, sequence as u32).into_bytes()));
+    }
+}
+
+#[test]
+fn failed_writes_publish_no_change() {
+    // arrange
+    let path = &folder_name();
+    let vfs = FaultyVfs::new(5);
+    let options = Options {
+        change_log_size: 10_000,
+        ..options()
+    };
+    let mut db = RustDB::load_with_vfs(path, options.clone(), Arc::new(vfs.clone()));
+    let mut subscription = db.subscribe(ChangeFilter::from_sequence(1)).unwrap();
+
+    // act
+    db.save_record(KeyValue::new_from_strings(
+        String::from("0001"),
+        value(1, 1),
+    ))
+    .unwrap();
+    vfs.fail_writes_after(0);
+    let failed = db.save_record(KeyValue::new_from_strings(
+        String::from("0002"),
+        value(2, 1),
+    ));
+    vfs.clear_faults();
+    db.delete_record(String::from("0001")).unwrap();
+    let live: Vec<u64> = (0..2)
+        .map(|_| {
+            subscription
+                .recv_timeout(Duration::from_millis(100))
+                .unwrap()
+                .sequence
+        })
+        .collect();
+    drop(db);
+    vfs.crash();
+
+    // assert
+    assert!(failed.is_err());
+    assert_eq!(live, vec![1, 2]);
+    assert!(subscription.next().is_none());
+
+    let db = RustDB::load_with_vfs(path, options, Arc::new(vfs.clone()));
+    let changes = db.changes(&ChangeFilter::from_sequence(1), 10).unwrap();
+    assert_eq!(db.last_sequence(), 2);
+    assert_eq!(changes.len(), 2);
+    assert_eq!(changes[0].key, b"0001".to_vec());
+    assert_eq!((changes[1].sequence, &changes[1].value), (2, &None));
+}
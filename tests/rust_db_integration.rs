@@ -1,6 +1,14 @@
+#![allow(clippy::assertions_on_constants, clippy::get_first)]
+
 use rand::random;
-use rustdb::{KeyValue, RustDB};
-use std::fs::{copy, create_dir_all, read_dir, remove_dir_all};
+use rustdb::{
+    ChangeFilter, KeyValue, LogCompressor, MemoryVfs, Options, PointInTime, Query, RustDB,
+    SchemaViolation, ShardedDB, Vfs,
+};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
 static STORAGE_TEST_FOLDER: &str = "storage_test";
 
@@ -11,36 +19,29 @@ fn folder_name() -> String {
     format!("{}{}", STORAGE_TEST_FOLDER, random::<u64>())
 }
 
-fn copy_read_only_files(folder_name: &str) {
-    create_dir_all(format!("./{}", folder_name)).unwrap();
-    copy(
-        "./readonly_storage_test/53e155bcbdeb560f",
-        format!("./{}/53e155bcbdeb560f", folder_name),
-    )
-    .unwrap();
-    copy(
-        "./readonly_storage_test/4da053f2db81bb26",
-        format!("./{}/4da053f2db81bb26", folder_name),
-    )
-    .unwrap();
-    copy(
-        "./readonly_storage_test/e0c515663f0ea931",
-        format!("./{}/e0c515663f0ea931", folder_name),
-    )
-    .unwrap();
-    copy(
-        "./readonly_storage_test/initial_segment",
-        format!("./{}/initial_segment", folder_name),
-    )
-    .unwrap();
+fn copy_read_only_files(folder_name: &str) -> Arc<dyn Vfs> {
+    let vfs = MemoryVfs::new();
+    for file in &[
+        "53e155bcbdeb560f",
+        "4da053f2db81bb26",
+        "e0c515663f0ea931",
+        "initial_segment",
+    ] {
+        vfs.copy_from_disk(
+            &format!("./readonly_storage_test/{}", file),
+            &format!("./{}/{}", folder_name, file),
+        )
+        .unwrap();
+    }
+    Arc::new(vfs)
 }
 
 #[test]
 fn open_existing_segment_and_find_record() {
     // arrange
     let path = &folder_name();
-    copy_read_only_files(path);
-    let db = RustDB::load(path);
+    let vfs = copy_read_only_files(path);
+    let db = RustDB::load_with_vfs(path, Options::default(), Arc::clone(&vfs));
 
     // act
     let data = db.get_record(String::from("0001"));
@@ -57,17 +58,15 @@ fn open_existing_segment_and_find_record() {
         data.get_value_as_string(),
         "{\"email\":\"1@test1.com\",\"id\":\"1\",\"name\":\"nome 1\"}"
     );
-
-    remove_dir_all(format!("./{}", path)).unwrap();
 }
 
 #[test]
 fn load_folder_and_find_all_records() {
     // arrange
     let path = &folder_name();
-    copy_read_only_files(path);
+    let vfs = copy_read_only_files(path);
 
-    let db = RustDB::load(path);
+    let db = RustDB::load_with_vfs(path, Options::default(), Arc::clone(&vfs));
 
     // act
     let data1 = db.get_record(String::from("0028"));
@@ -97,8 +96,6 @@ fn load_folder_and_find_all_records() {
         data4.unwrap(),
         "{\"email\":\"34@test1.com\",\"id\":\"34\",\"name\":\"nome 34\"}",
     );
-
-    remove_dir_all(format!("./{}", path)).unwrap();
 }
 
 fn validate_value(result: Option<KeyValue>, content: &str) {
@@ -113,8 +110,9 @@ fn validate_value(result: Option<KeyValue>, content: &str) {
 fn open_new_file_and_add_item() {
     // arrange
     let path = &folder_name();
+    let vfs: Arc<dyn Vfs> = Arc::new(MemoryVfs::new());
 
-    let mut db = RustDB::load(path);
+    let mut db = RustDB::load_with_vfs(path, Options::default(), Arc::clone(&vfs));
     let key_value = KeyValue::new_from_strings(String::from(KEY), String::from(VALUE));
 
     // act
@@ -130,19 +128,18 @@ fn open_new_file_and_add_item() {
     let data = data.unwrap();
     assert_eq!(data.get_key_as_string(), KEY);
     assert_eq!(data.get_value_as_string(), VALUE);
-
-    remove_dir_all(format!("./{}", path)).unwrap();
 }
 
 #[test]
 fn open_new_file_and_update_item() {
     // arrange
     let path = &&folder_name();
+    let vfs: Arc<dyn Vfs> = Arc::new(MemoryVfs::new());
 
     let updated_value =
         "{\"email\":\"tiago@test.com\",\"id\":\"1234\",\"name\":\"Tiago updated name\"}";
 
-    let mut db = RustDB::load(path);
+    let mut db = RustDB::load_with_vfs(path, Options::default(), Arc::clone(&vfs));
     let key_value_original = KeyValue::new_from_strings(String::from(KEY), String::from(VALUE));
     let key_value_updated =
         KeyValue::new_from_strings(String::from(KEY), String::from(updated_value));
@@ -161,16 +158,15 @@ fn open_new_file_and_update_item() {
     let data = data.unwrap();
     assert_eq!(data.get_key_as_string(), KEY);
     assert_eq!(data.get_value_as_string(), updated_value);
-
-    remove_dir_all(format!("./{}", path)).unwrap();
 }
 
 #[test]
 fn open_new_file_and_delete_item() {
     // arrange
     let path = &&folder_name();
+    let vfs: Arc<dyn Vfs> = Arc::new(MemoryVfs::new());
 
-    let mut db = RustDB::load(path);
+    let mut db = RustDB::load_with_vfs(path, Options::default(), Arc::clone(&vfs));
     let key_value = KeyValue::new_from_strings(String::from(KEY), String::from(VALUE));
 
     // act
@@ -183,16 +179,15 @@ fn open_new_file_and_delete_item() {
 
     let data = data.unwrap();
     assert!(data.is_none());
-
-    remove_dir_all(format!("./{}", path)).unwrap();
 }
 
 #[test]
 fn create_multiple_files() {
     // arrange
     let path = &&folder_name();
+    let vfs: Arc<dyn Vfs> = Arc::new(MemoryVfs::new());
 
-    let mut db = RustDB::load(path);
+    let mut db = RustDB::load_with_vfs(path, Options::default(), Arc::clone(&vfs));
 
     // act
     for i in 0..200 {
@@ -207,18 +202,17 @@ fn create_multiple_files() {
     }
 
     // assert
-    let paths = read_dir(path).unwrap();
-    assert_eq!(2, paths.count());
-
-    remove_dir_all(format!("./{}", path)).unwrap();
+    let paths = vfs.list(&format!("./{}", path)).unwrap();
+    assert_eq!(2, paths.len());
 }
 
 #[test]
 fn read_from_multiple_files() {
     // arrange
     let path = &&folder_name();
+    let vfs: Arc<dyn Vfs> = Arc::new(MemoryVfs::new());
 
-    let mut db = RustDB::load(path);
+    let mut db = RustDB::load_with_vfs(path, Options::default(), Arc::clone(&vfs));
 
     // act
     for i in 0..80 {
@@ -276,16 +270,15 @@ fn read_from_multiple_files() {
         data4.unwrap(),
         "{\"email\":\"130@test2.com\",\"id\":\"130\",\"name\":\"nome 130\"}",
     );
-
-    remove_dir_all(format!("./{}", path)).unwrap();
 }
 
 #[test]
 fn delete_item_that_exists_on_previous_segment() {
     // arrange
     let path = &&folder_name();
+    let vfs: Arc<dyn Vfs> = Arc::new(MemoryVfs::new());
 
-    let mut db = RustDB::load(path);
+    let mut db = RustDB::load_with_vfs(path, Options::default(), Arc::clone(&vfs));
 
     // create enough records to have more than on file
     for i in 0..30 {
@@ -306,20 +299,18 @@ fn delete_item_that_exists_on_previous_segment() {
     let result = db.get_record(String::from("0001")).unwrap();
 
     // assert
-    let paths = read_dir(path).unwrap();
-    assert!(paths.count() > 1); // check if we have more than on file
+    let paths = vfs.list(&format!("./{}", path)).unwrap();
+    assert!(paths.len() > 1); // check if we have more than on file
 
     assert!(result.is_none());
-
-    remove_dir_all(format!("./{}", path)).unwrap();
 }
 
 #[test]
 fn get_closed_segment_names() {
     // arrange
     let path = &folder_name();
-    copy_read_only_files(path);
-    let db = RustDB::load(path);
+    let vfs = copy_read_only_files(path);
+    let db = RustDB::load_with_vfs(path, Options::default(), Arc::clone(&vfs));
 
     // act
     let data: Vec<String> = db.get_closed_segment_names();
@@ -330,6 +321,1067 @@ fn get_closed_segment_names() {
     assert_eq!(data.get(0).unwrap(), "4da053f2db81bb26");
     assert_eq!(data.get(1).unwrap(), "e0c515663f0ea931");
     assert_eq!(data.get(2).unwrap(), "53e155bcbdeb560f");
+}
+
+#[test]
+fn stats_track_overwrites_and_deletes() {
+    // arrange
+    let path = &folder_name();
+    let vfs: Arc<dyn Vfs> = Arc::new(MemoryVfs::new());
+    let mut db = RustDB::load_with_vfs(path, Options::default(), Arc::clone(&vfs));
+
+    // act
+    db.save_record(KeyValue::new_from_strings(
+        String::from(KEY),
+        String::from(VALUE),
+    ))
+    .unwrap();
+    db.save_record(KeyValue::new_from_strings(
+        String::from(KEY),
+        String::from(VALUE),
+    ))
+    .unwrap();
+    db.save_record(KeyValue::new_from_strings(
+        String::from("DEF"),
+        String::from(VALUE),
+    ))
+    .unwrap();
+    db.delete_record(String::from("DEF")).unwrap();
+
+    // assert
+    let stats = db.stats();
+    assert_eq!(stats.len(), 1);
+
+    let record_size = (12 + KEY.len() + VALUE.len()) as u64;
+    assert_eq!(stats[0].live_keys, 1);
+    assert_eq!(stats[0].dead_keys, 3);
+    assert_eq!(stats[0].live_bytes, record_size);
+    assert_eq!(stats[0].dead_bytes, record_size * 2 + 12 + 3);
+}
+
+#[test]
+fn stats_rebuilt_on_load() {
+    // arrange
+    let path = &folder_name();
+    let vfs = copy_read_only_files(path);
+    let mut db = RustDB::load_with_vfs(path, Options::default(), Arc::clone(&vfs));
+
+    // act
+    db.delete_record(String::from("0001")).unwrap();
+    db.save_record(KeyValue::new_from_strings(
+        String::from("0020"),
+        String::from(VALUE),
+    ))
+    .unwrap();
+    let stats_before_reload = db.stats();
+
+    let db = RustDB::load_with_vfs(path, Options::default(), Arc::clone(&vfs));
+    let stats = db.stats();
+
+    // assert
+    assert_eq!(stats_before_reload.len(), 4);
+    assert_eq!(stats_before_reload[0].live_keys, 1);
+    assert_eq!(stats_before_reload[0].dead_keys, 1);
+
+    assert_eq!(stats_before_reload[1].name, "4da053f2db81bb26");
+    assert_eq!(stats_before_reload[1].live_keys, 9);
+    assert_eq!(stats_before_reload[1].dead_keys, 0);
+    assert_eq!(stats_before_reload[1].live_bytes, 619 - 16);
+
+    assert_eq!(stats_before_reload[2].name, "e0c515663f0ea931");
+    assert_eq!(stats_before_reload[2].live_keys, 14);
+    assert_eq!(stats_before_reload[2].dead_keys, 1);
+    assert_eq!(stats_before_reload[2].dead_bytes, 12 + 4 + 51);
+
+    assert_eq!(stats_before_reload[3].name, "53e155bcbdeb560f");
+    assert_eq!(stats_before_reload[3].live_keys, 15);
+    assert_eq!(stats_before_reload[3].dead_keys, 1);
+    assert_eq!(stats_before_reload[3].dead_bytes, 12 + 4 + 48);
+
+    // reloading adds a new empty active segment on top of the same data
+    assert_eq!(stats.len(), 5);
+    assert_eq!(stats[0].total_bytes(), 0);
+    assert_eq!(&stats[1..], &stats_before_reload[..]);
+}
+
+#[test]
+fn leveled_storage_save_update_and_delete() {
+    // arrange
+    let path = &folder_name();
+    let vfs: Arc<dyn Vfs> = Arc::new(MemoryVfs::new());
+    let options = Options {
+        memtable_size: 2_000,
+        level0_file_limit: 2,
+        level_base_size: 8_000,
+        table_size: 4_000,
+        ..Options::leveled()
+    };
+    let mut db = RustDB::load_with_vfs(path, options.clone(), Arc::clone(&vfs));
+
+    // act
+    for i in 0..300 {
+        db.save_record(KeyValue::new_from_strings(
+            format!("{:04}", i),
+            format!("{{\"id\":\"{}\",\"name\":\"nome {}\"}}", i, i),
+        ))
+        .unwrap();
+    }
+    for i in 0..100 {
+        db.save_record(KeyValue::new_from_strings(
+            format!("{:04}", i),
+            format!("{{\"id\":\"{}\",\"name\":\"updated {}\"}}", i, i),
+        ))
+        .unwrap();
+    }
+    db.delete_record(String::from("0200")).unwrap();
+
+    let db = RustDB::load_with_vfs(path, options, vfs);
+
+    // assert
+    assert!(db.get_level_table_counts().len() > 1);
+    assert!(db.get_closed_segment_names().is_empty());
+
+    validate_value(
+        db.get_record(String::from("0050")).unwrap(),
+        "{\"id\":\"50\",\"name\":\"updated 50\"}",
+    );
+    validate_value(
+        db.get_record(String::from("0250")).unwrap(),
+        "{\"id\":\"250\",\"name\":\"nome 250\"}",
+    );
+    assert!(db.get_record(String::from("0200")).unwrap().is_none());
+    assert!(db.get_record(String::from("0300")).unwrap().is_none());
+    assert!(db.bloom_stats().checks > 0);
+}
+
+#[test]
+fn bloom_filters_skip_closed_segments() {
+    // arrange
+    let path = &folder_name();
+    let vfs = copy_read_only_files(path);
+    let db = RustDB::load_with_vfs(path, Options::default(), Arc::clone(&vfs));
+
+    // act
+    let missing = db.get_record(String::from("9999")).unwrap();
+    let found = db.get_record(String::from("0001")).unwrap();
+
+    // assert
+    assert!(missing.is_none());
+    assert!(found.is_some());
+
+    let stats = db.bloom_stats();
+    assert_eq!(stats.checks, 6);
+    assert_eq!(stats.skipped, 5);
+    assert_eq!(stats.false_positives, 0);
+
+    let paths = vfs.list(&format!("./{}", path)).unwrap();
+    assert!(paths.contains(&String::from("53e155bcbdeb560f.bloom")));
+    assert!(paths.contains(&String::from("e0c515663f0ea931.bloom")));
+    assert!(paths.contains(&String::from("4da053f2db81bb26.bloom")));
+}
+
+#[test]
+fn memory_vfs_keeps_records_across_loads() {
+    // arrange
+    let path = &folder_name();
+    let vfs: Arc<dyn Vfs> = Arc::new(MemoryVfs::new());
+    let mut db = RustDB::load_with_vfs(path, Options::default(), Arc::clone(&vfs));
+
+    // act
+    for i in 0..20_000 {
+        db.save_record(KeyValue::new_from_strings(
+            format!("{:05}", i),
+            format!("{{\"id\":\"{}\",\"name\":\"nome {}\"}}", i, i),
+        ))
+        .unwrap();
+    }
+    db.delete_record(String::from("00010")).unwrap();
+
+    // assert
+    let db = RustDB::load_with_vfs(path, Options::default(), Arc::clone(&vfs));
+    assert!(!db.get_closed_segment_names().is_empty());
+    assert!(db.get_record(String::from("00010")).unwrap().is_none());
+    assert_eq!(
+        db.get_record(String::from("00011"))
+            .unwrap()
+            .unwrap()
+            .get_value_as_string(),
+        "{\"id\":\"11\",\"name\":\"nome 11\"}"
+    );
+
+    let files = vfs.list(&format!("./{}", path)).unwrap();
+    assert!(files.contains(&String::from("initial_segment")));
+    assert!(!Path::new(&format!("./{}", path)).exists());
+}
+
+#[test]
+fn memory_vfs_with_leveled_storage() {
+    // arrange
+    let path = &folder_name();
+    let vfs: Arc<dyn Vfs> = Arc::new(MemoryVfs::new());
+    let options = Options {
+        memtable_size: 10_000,
+        ..Options::leveled()
+    };
+    let mut db = RustDB::load_with_vfs(path, options.clone(), Arc::clone(&vfs));
+
+    // act
+    for i in 0..1_000 {
+        db.save_record(KeyValue::new_from_strings(
+            format!("{:04}", i),
+            format!("{{\"id\":\"{}\"}}", i),
+        ))
+        .unwrap();
+    }
+
+    // assert
+    let db = RustDB::load_with_vfs(path, options, vfs);
+    assert!(db.get_level_table_counts().iter().sum::<usize>() > 0);
+    assert_eq!(
+        db.get_record(String::from("0500"))
+            .unwrap()
+            .unwrap()
+            .get_value_as_string(),
+        "{\"id\":\"500\"}"
+    );
+    assert!(!Path::new(&format!("./{}", path)).exists());
+}
+
+#[test]
+fn binary_keys_survive_reload() {
+    // arrange
+    let path = &folder_name();
+    let vfs: Arc<dyn Vfs> = Arc::new(MemoryVfs::new());
+    let mut db = RustDB::load_with_vfs(path, Options::default(), Arc::clone(&vfs));
+    let key = vec![0, 255, b'/', 10];
+
+    // act
+    db.save_record(KeyValue::new(key.clone(), b"binary".to_vec()))
+        .unwrap();
+
+    // assert
+    let db = RustDB::load_with_vfs(path, Options::default(), vfs);
+    assert_eq!(
+        db.get_record(key.clone()).unwrap().unwrap().value,
+        b"binary".to_vec()
+    );
+    assert!(db.get_record(vec![0, 255]).unwrap().is_none());
+}
+
+#[test]
+fn expirations_survive_reload() {
+    // arrange
+    let path = &folder_name();
+    let vfs: Arc<dyn Vfs> = Arc::new(MemoryVfs::new());
+    let mut db = RustDB::load_with_vfs(path, Options::default(), Arc::clone(&vfs));
+    for key in &["a", "b", "c"] {
+        db.save_record(KeyValue::new_from_strings(
+            key.to_string(),
+            String::from("1"),
+        ))
+        .unwrap();
+    }
+
+    // act
+    db.expire("a", Duration::from_millis(20)).unwrap();
+    db.expire("b", Duration::from_secs(60)).unwrap();
+    db.expire("c", Duration::from_secs(60)).unwrap();
+    db.persist("c").unwrap();
+    let missing = db.expire("x", Duration::from_secs(60)).unwrap();
+    thread::sleep(Duration::from_millis(40));
+
+    // assert
+    assert!(!missing);
+    let mut db = RustDB::load_with_vfs(path, Options::default(), vfs);
+    assert!(db.get_record("a").unwrap().is_none());
+    assert!(db.time_to_live("b").unwrap() > Duration::from_secs(59));
+    assert!(db.time_to_live("c").is_none());
+    assert_eq!(db.get_keys().unwrap(), vec![b"b".to_vec(), b"c".to_vec()]);
+
+    db.set_read_only(true);
+    assert_eq!(db.remove_expired().unwrap(), 0);
+    db.set_read_only(false);
+    assert_eq!(db.remove_expired().unwrap(), 1);
+    assert!(db.time_to_live("a").is_none());
+}
+
+#[test]
+fn list_live_keys_in_order() {
+    for options in [
+        Options {
+            segment_size: 500,
+            ..Options::default()
+        },
+        Options {
+            memtable_size: 500,
+            ..Options::leveled()
+        },
+    ] {
+        // arrange
+        let path = &folder_name();
+        let vfs: Arc<dyn Vfs> = Arc::new(MemoryVfs::new());
+        let mut db = RustDB::load_with_vfs(path, options.clone(), Arc::clone(&vfs));
+
+        // act
+        for i in (0..100).rev() {
+            db.save_record(KeyValue::new_from_strings(
+                format!("{:04}", i),
+                String::from("value"),
+            ))
+            .unwrap();
+        }
+        for i in (0..100).step_by(2) {
+            db.delete_record(format!("{:04}", i)).unwrap();
+        }
+        db.save_record(KeyValue::new_from_strings(
+            String::from("0000"),
+            String::from("again"),
+        ))
+        .unwrap();
+
+        // assert
+        let expected: Vec<Vec<u8>> = std::iter::once(0)
+            .chain((1..100).step_by(2))
+            .map(|i| format!("{:04}", i).into_bytes())
+            .collect();
+        assert_eq!(db.get_keys().unwrap(), expected);
+        let db = RustDB::load_with_vfs(path, options, vfs);
+        assert_eq!(db.get_keys().unwrap(), expected);
+    }
+}
+
+#[test]
+fn flags_survive_reload_until_deleted() {
+    // arrange
+    let path = &folder_name();
+    let vfs: Arc<dyn Vfs> = Arc::new(MemoryVfs::new());
+    let mut db = RustDB::load_with_vfs(path, Options::default(), Arc::clone(&vfs));
+    let first = KeyValue::new(b"a".to_vec(), b"1".to_vec());
+    let second = KeyValue::new(b"a".to_vec(), b"2".to_vec());
+    let (first_version, second_version) = (first.get_version(), second.get_version());
+
+    // act
+    db.save_record(first).unwrap();
+    db.set_flags("a", 42).unwrap();
+    db.save_record(second).unwrap();
+    let mut db = RustDB::load_with_vfs(path, Options::default(), Arc::clone(&vfs));
+    let flags = db.get_flags("a");
+    let version = db.get_record("a").unwrap().unwrap().get_version();
+    db.delete_record("a").unwrap();
+
+    // assert
+    assert_eq!(flags, 42);
+    assert_ne!(first_version, second_version);
+    assert_eq!(version, second_version);
+    let db = RustDB::load_with_vfs(path, Options::default(), vfs);
+    assert_eq!(db.get_flags("a"), 0);
+}
+
+#[test]
+fn content_types_survive_reload_until_deleted() {
+    // arrange
+    let path = &folder_name();
+    let vfs: Arc<dyn Vfs> = Arc::new(MemoryVfs::new());
+    let mut db = RustDB::load_with_vfs(path, Options::default(), Arc::clone(&vfs));
+
+    // act
+    db.save_record(KeyValue::new(b"a".to_vec(), vec![0xff]))
+        .unwrap();
+    db.set_content_type("a", Some("image/png")).unwrap();
+    db.save_record(KeyValue::new(b"b".to_vec(), b"1".to_vec()))
+        .unwrap();
+    db.set_content_type("b", Some("text/plain")).unwrap();
+    db.set_content_type("b", None).unwrap();
+    let mut db = RustDB::load_with_vfs(path, Options::default(), Arc::clone(&vfs));
+    let content_types = (db.get_content_type("a"), db.get_content_type("b"));
+    db.delete_record("a").unwrap();
+
+    // assert
+    assert_eq!(content_types, (Some(String::from("image/png")), None));
+    let db = RustDB::load_with_vfs(path, Options::default(), vfs);
+    assert_eq!(db.get_content_type("a"), None);
+}
+
+#[test]
+fn scan_keys_by_prefix_and_page() {
+    // keys spread over several segments or tables, so scans merge them
+    let storages = [
+        Options {
+            segment_size: 40,
+            ..Options::default()
+        },
+        Options {
+            memtable_size: 40,
+            ..Options::leveled()
+        },
+    ];
+    for options in storages {
+        // arrange
+        let path = &folder_name();
+        let vfs: Arc<dyn Vfs> = Arc::new(MemoryVfs::new());
+        let mut db = RustDB::load_with_vfs(path, options, vfs);
+        for key in &["a", "b/1", "b/2", "b/3", "b/4", "c"] {
+            db.save_record(KeyValue::new_from_strings(
+                key.to_string(),
+                String::from("1"),
+            ))
+            .unwrap();
+        }
+        db.save_record(KeyValue::new_from_strings(
+            String::from("b/2"),
+            String::from("2"),
+        ))
+        .unwrap();
+        db.delete_record("b/3").unwrap();
+
+        // act
+        let first = db.scan_keys(b"b/", b"", 2).unwrap();
+        let second = db
+            .scan_keys(b"b/", &first.next.clone().unwrap(), 2)
+            .unwrap();
+        let from_start = db.scan_keys(b"", b"b/2", 10).unwrap();
+
+        // assert
+        assert_eq!(first.keys, vec![b"b/1".to_vec(), b"b/2".to_vec()]);
+        assert_eq!(first.next, Some(b"b/4".to_vec()));
+        assert_eq!(second.keys, vec![b"b/4".to_vec()]);
+        assert_eq!(second.next, None);
+        assert_eq!(
+            from_start.keys,
+            vec![b"b/2".to_vec(), b"b/4".to_vec(), b"c".to_vec()]
+        );
+    }
+}
+
+#[test]
+fn resume_changes_after_reload() {
+    // arrange
+    let path = &folder_name();
+    let vfs: Arc<dyn Vfs> = Arc::new(MemoryVfs::new());
+    let options = Options {
+        change_log_size: 10_000,
+        ..Options::default()
+    };
+    let mut db = RustDB::load_with_vfs(path, options.clone(), Arc::clone(&vfs));
+    let mut subscription = db.subscribe(ChangeFilter::from_sequence(1)).unwrap();
+
+    // act
+    db.save_record(KeyValue::new(b"a".to_vec(), b"1".to_vec()))
+        .unwrap();
+    db.delete_record("a").unwrap();
+    let live: Vec<u64> = (0..2)
+        .map(|_| subscription.next().unwrap().sequence)
+        .collect();
+    drop(db);
+    let mut db = RustDB::load_with_vfs(path, options, Arc::clone(&vfs));
+    db.save_record(KeyValue::new(b"b".to_vec(), b"2".to_vec()))
+        .unwrap();
+    let resumed = db.changes(&ChangeFilter::from_sequence(2), 10).unwrap();
+
+    // assert
+    assert_eq!(live, vec![1, 2]);
+    assert!(subscription.next().is_none());
+    assert_eq!(db.last_sequence(), 3);
+    assert_eq!(resumed.len(), 2);
+    assert_eq!((resumed[0].sequence, &resumed[0].value), (2, &None));
+    assert_eq!(resumed[1].key, b"b".to_vec());
+    assert_eq!(resumed[1].value, Some(b"2".to_vec()));
+}
+
+#[test]
+fn discard_changes_dropped_by_compaction() {
+    // arrange
+    let vfs: Arc<dyn Vfs> = Arc::new(MemoryVfs::new());
+    let options = Options {
+        segment_size: 100,
+        change_log_size: 10_000,
+        ..Options::default()
+    };
+    let mut db = RustDB::load_with_vfs(&folder_name(), options, vfs);
+    for version in 0..10 {
+        for key in &["a", "b"] {
+            db.save_record(KeyValue::new_from_strings(
+                String::from(*key),
+                version.to_string(),
+            ))
+            .unwrap();
+        }
+    }
+    let before = db.changes(&ChangeFilter::from_sequence(1), 100).unwrap();
+
+    // act
+    db.compress_segments().unwrap();
+    let discarded = db.changes(&ChangeFilter::from_sequence(1), 100);
+    let latest = db.changes(&ChangeFilter::from_sequence(19), 100).unwrap();
+
+    // assert
+    assert_eq!(before.len(), 20);
+    assert_eq!(
+        discarded.unwrap_err().kind(),
+        std::io::ErrorKind::InvalidInput
+    );
+    assert_eq!(latest.len(), 2);
+    assert_eq!(latest[0].sequence, 19);
+    assert_eq!(latest[1].value, Some(b"9".to_vec()));
+}
+
+#[test]
+fn refuse_subscriptions_without_change_log() {
+    let vfs: Arc<dyn Vfs> = Arc::new(MemoryVfs::new());
+    let mut db = RustDB::load_with_vfs(&folder_name(), Options::default(), Arc::clone(&vfs));
+    db.save_record(KeyValue::new(b"a".to_vec(), b"1".to_vec()))
+        .unwrap();
+    let leveled = Options {
+        change_log_size: 10_000,
+        ..Options::leveled()
+    };
+    let mut leveled = RustDB::load_with_vfs(&folder_name(), leveled, vfs);
+
+    assert!(db.subscribe(ChangeFilter::default()).is_err());
+    assert_eq!(db.last_sequence(), 0);
+    assert_eq!(
+        leveled
+            .changes(&ChangeFilter::default(), 10)
+            .unwrap_err()
+            .kind(),
+        std::io::ErrorKind::Unsupported
+    );
+    assert!(leveled.subscribe(ChangeFilter::default()).is_err());
+}
+
+#[test]
+fn apply_leader_changes_to_a_read_only_follower() {
+    let options = Options {
+        change_log_size: 100_000,
+        ..Options::default()
+    };
+    let vfs: Arc<dyn Vfs> = Arc::new(MemoryVfs::new());
+    let mut leader = RustDB::load_with_vfs("leader", options.clone(), Arc::clone(&vfs));
+    let mut follower = RustDB::load_with_vfs("follower", options.clone(), Arc::clone(&vfs));
+    follower.set_read_only(true);
+    leader
+        .save_record(KeyValue::new(b"a".to_vec(), b"1".to_vec()))
+        .unwrap();
+    leader
+        .save_record(KeyValue::new(b"b".to_vec(), b"2".to_vec()))
+        .unwrap();
+    leader.delete_record(b"a".to_vec()).unwrap();
+
+    follower
+        .install_snapshot(1, vec![KeyValue::new(b"a".to_vec(), b"1".to_vec())])
+        .unwrap();
+    for event in leader.changes(&ChangeFilter::from_sequence(1), 10).unwrap() {
+        follower.apply_change(event).unwrap();
+    }
+    let refused = follower.save_record(KeyValue::new(b"c".to_vec(), b"3".to_vec()));
+    let follower = RustDB::load_with_vfs("follower", options, vfs);
+
+    assert_eq!(follower.last_sequence(), 3);
+    assert_eq!(follower.get_keys().unwrap(), vec![b"b".to_vec()]);
+    assert_eq!(
+        refused.unwrap_err().kind(),
+        std::io::ErrorKind::PermissionDenied
+    );
+    assert!(follower
+        .changes(&ChangeFilter::from_sequence(1), 10)
+        .is_err());
+}
+
+#[test]
+fn reshard_and_reload() {
+    let vfs: Arc<dyn Vfs> = Arc::new(MemoryVfs::new());
+    let mut db =
+        ShardedDB::load_with_vfs("sharded", 3, Options::default(), Arc::clone(&vfs)).unwrap();
+    for n in 0..40 {
+        db.save_record(KeyValue::new(
+            format!("key{}", n).into_bytes(),
+            n.to_string().into_bytes(),
+        ))
+        .unwrap();
+    }
+
+    db.resize(2).unwrap();
+    db.migrate(10).unwrap();
+    let mut db =
+        ShardedDB::load_with_vfs("sharded", 2, Options::default(), Arc::clone(&vfs)).unwrap();
+    while db.is_migrating() {
+        db.migrate(10).unwrap();
+    }
+    db.flush().unwrap();
+    let db = ShardedDB::load_with_vfs("sharded", 2, Options::default(), vfs).unwrap();
+
+    assert_eq!(db.shard_ids(), vec![1, 2]);
+    assert!(db.shard(3).is_none());
+    assert_eq!(db.get_keys().unwrap().len(), 40);
+    let record = db.get_record(b"key17".to_vec()).unwrap().unwrap();
+    assert_eq!(record.value, b"17".to_vec());
+}
+
+#[test]
+fn keep_collections_apart_across_reloads() {
+    let vfs: Arc<dyn Vfs> = Arc::new(MemoryVfs::new());
+    let mut db = RustDB::load_with_vfs("storage", Options::default(), Arc::clone(&vfs));
+    db.save_record(KeyValue::new(b"a".to_vec(), b"root".to_vec()))
+        .unwrap();
+
+    assert!(db.create_collection("users").unwrap());
+    assert!(db.create_collection("orders").unwrap());
+    assert!(!db.create_collection("users").unwrap());
+    let users = db.collection_mut("users").unwrap();
+    users
+        .save_record(KeyValue::new(b"a".to_vec(), b"user".to_vec()))
+        .unwrap();
+    users.delete_record(b"a".to_vec()).unwrap();
+    users
+        .save_record(KeyValue::new(b"a".to_vec(), b"user again".to_vec()))
+        .unwrap();
+    let invalid = db.create_collection("../escape");
+    let db = RustDB::load_with_vfs("storage", Options::default(), vfs);
+
+    assert_eq!(
+        invalid.unwrap_err().kind(),
+        std::io::ErrorKind::InvalidInput
+    );
+    assert_eq!(db.list_collections(), vec!["orders", "users"]);
+    assert_eq!(db.get_keys().unwrap(), vec![b"a".to_vec()]);
+    let record = db.collection("users").unwrap().get_record(b"a".to_vec());
+    assert_eq!(record.unwrap().unwrap().value, b"user again".to_vec());
+    assert!(db
+        .collection("orders")
+        .unwrap()
+        .get_keys()
+        .unwrap()
+        .is_empty());
+    assert!(db.collection("missing").is_none());
+}
+
+#[test]
+fn keep_collections_on_disk() {
+    let path = &folder_name();
+    let mut db = RustDB::load(path);
+    db.create_collection("users").unwrap();
+    db.collection_mut("users")
+        .unwrap()
+        .save_record(KeyValue::new(b"a".to_vec(), b"user".to_vec()))
+        .unwrap();
+    drop(db);
+
+    let db = RustDB::load(path);
+    let record = db.collection("users").unwrap().get_record(b"a".to_vec());
+    std::fs::remove_dir_all(format!("./{}", path)).unwrap();
+
+    assert_eq!(db.list_collections(), vec!["users"]);
+    assert_eq!(record.unwrap().unwrap().value, b"user".to_vec());
+}
+
+#[test]
+fn load_a_copy_of_the_files() {
+    let vfs: Arc<dyn Vfs> = Arc::new(MemoryVfs::new());
+    let options = Options {
+        segment_size: 100,
+        ..Options::default()
+    };
+    let mut db = RustDB::load_with_vfs("storage", options.clone(), Arc::clone(&vfs));
+    for n in 0..20 {
+        db.save_record(KeyValue::new(b"key".to_vec(), n.to_string().into_bytes()))
+            .unwrap();
+    }
+    db.set_content_type(b"key".to_vec(), Some("text/plain"))
+        .unwrap();
+    db.create_collection("users").unwrap();
+    db.collection_mut("users")
+        .unwrap()
+        .save_record(KeyValue::new(b"a".to_vec(), b"user".to_vec()))
+        .unwrap();
+
+    let files = db.copy_files("copy").unwrap();
+    db.save_record(KeyValue::new(b"key".to_vec(), b"after".to_vec()))
+        .unwrap();
+    let copy = RustDB::load_with_vfs("copy", options, vfs);
+
+    assert!(files.iter().any(|(path, _)| path == "content_types"));
+    assert!(files
+        .iter()
+        .any(|(path, _)| path == "collections/users/initial_segment"));
+    assert_eq!(db.stats().len(), 2);
+    assert_eq!(
+        copy.get_record(b"key".to_vec()).unwrap().unwrap().value,
+        b"19".to_vec()
+    );
+    assert_eq!(
+        copy.get_content_type(b"key".to_vec()),
+        Some(String::from("text/plain"))
+    );
+    let user = copy.collection("users").unwrap().get_record(b"a".to_vec());
+    assert_eq!(user.unwrap().unwrap().value, b"user".to_vec());
+}
+
+#[test]
+fn drop_and_compress_one_collection() {
+    let vfs: Arc<dyn Vfs> = Arc::new(MemoryVfs::new());
+    let options = Options {
+        segment_size: 200,
+        ..Options::default()
+    };
+    let mut db = RustDB::load_with_vfs("storage", options.clone(), Arc::clone(&vfs));
+    db.create_collection("logs").unwrap();
+    db.create_collection("events").unwrap();
+    for collection in ["logs", "events"] {
+        let collection = db.collection_mut(collection).unwrap();
+        for n in 0..50 {
+            collection
+                .save_record(KeyValue::new(b"key".to_vec(), n.to_string().into_bytes()))
+                .unwrap();
+        }
+    }
 
-    remove_dir_all(format!("./{}", path)).unwrap();
+    db.collection_mut("events")
+        .unwrap()
+        .compress_segments()
+        .unwrap();
+    let events_segments = db.collection("events").unwrap().stats().len();
+    let logs_segments = db.collection("logs").unwrap().stats().len();
+    assert!(db.drop_collection("logs").unwrap());
+    assert!(!db.drop_collection("logs").unwrap());
+    db.create_collection("logs").unwrap();
+    let db = RustDB::load_with_vfs("storage", options, vfs);
+
+    assert!(events_segments < logs_segments);
+    let events = db.collection("events").unwrap();
+    assert_eq!(
+        events.get_record(b"key".to_vec()).unwrap().unwrap().value,
+        b"49".to_vec()
+    );
+    assert!(db
+        .collection("logs")
+        .unwrap()
+        .get_keys()
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn drop_nested_collections_and_recreate_empty() {
+    let vfs: Arc<dyn Vfs> = Arc::new(MemoryVfs::new());
+    let mut db = RustDB::load_with_vfs("storage", Options::default(), Arc::clone(&vfs));
+    db.create_collection("users").unwrap();
+    let users = db.collection_mut("users").unwrap();
+    users
+        .save_record(KeyValue::new(b"a".to_vec(), b"user".to_vec()))
+        .unwrap();
+    users.create_collection("archive").unwrap();
+    users
+        .collection_mut("archive")
+        .unwrap()
+        .save_record(KeyValue::new(b"a".to_vec(), b"old user".to_vec()))
+        .unwrap();
+
+    assert!(db.drop_collection("users").unwrap());
+    assert!(vfs
+        .list("./storage/collections/users/collections/archive")
+        .unwrap()
+        .is_empty());
+    assert!(db.create_collection("users").unwrap());
+    let db = RustDB::load_with_vfs("storage", Options::default(), vfs);
+
+    let users = db.collection("users").unwrap();
+    assert!(users.get_keys().unwrap().is_empty());
+    assert!(users.list_collections().is_empty());
+}
+
+#[test]
+fn find_records_by_indexed_fields_after_reload() {
+    let vfs: Arc<dyn Vfs> = Arc::new(MemoryVfs::new());
+    let mut db = RustDB::load_with_vfs("storage", Options::default(), Arc::clone(&vfs));
+    db.save_record(KeyValue::new(
+        b"1".to_vec(),
+        br#"{"id":"1","email":"a@test.com"}"#.to_vec(),
+    ))
+    .unwrap();
+
+    assert!(db.create_index("email", "email").unwrap());
+    db.save_record(KeyValue::new(
+        b"2".to_vec(),
+        br#"{"id":"2","email":"a@test.com"}"#.to_vec(),
+    ))
+    .unwrap();
+    db.save_record(KeyValue::new(
+        b"3".to_vec(),
+        br#"{"id":"3","email":"b@test.com"}"#.to_vec(),
+    ))
+    .unwrap();
+    db.save_record(KeyValue::new(
+        b"1".to_vec(),
+        br#"{"id":"1","email":"c@test.com"}"#.to_vec(),
+    ))
+    .unwrap();
+    db.delete_record(b"3".to_vec()).unwrap();
+    let db = RustDB::load_with_vfs("storage", Options::default(), vfs);
+
+    let keys = |email: &str| -> Vec<Vec<u8>> {
+        let records = db.find_by("email", email).unwrap();
+        records.into_iter().map(|record| record.key).collect()
+    };
+    assert_eq!(keys("a@test.com"), vec![b"2".to_vec()]);
+    assert_eq!(keys("c@test.com"), vec![b"1".to_vec()]);
+    assert!(keys("b@test.com").is_empty());
+    assert_eq!(
+        db.list_indexes(),
+        vec![(String::from("email"), String::from("email"))]
+    );
+    assert_eq!(
+        db.find_by("name", "a").unwrap_err().kind(),
+        std::io::ErrorKind::NotFound
+    );
+}
+
+#[test]
+fn query_the_load_test_export_by_job_title() {
+    let mut db = RustDB::load_with_vfs("storage", Options::default(), Arc::new(MemoryVfs::new()));
+    let export = std::fs::read_to_string("./load_test/ExportCSV.csv").unwrap();
+    let mut bakers = Vec::new();
+    for line in export.lines().take(1_000) {
+        let fields: Vec<&str> = line.split(',').collect();
+        if fields[1] == "Baker" {
+            bakers.push(fields[3].to_string());
+        }
+        let document = format!(
+            "{{\"id\":{},\"job_title\":\"{}\",\"email\":\"{}\",\"name\":\"{}\"}}",
+            fields[0], fields[1], fields[2], fields[3]
+        );
+        db.save_record(KeyValue::new(
+            fields[0].as_bytes().to_vec(),
+            document.into_bytes(),
+        ))
+        .unwrap();
+    }
+    bakers.sort();
+
+    let query = Query::parse(
+        br#"{"filter":{"job_title":"Baker","id":{"$lte":1000}},"fields":["name"],"sort":["name"]}"#,
+    )
+    .unwrap();
+    let names = |db: &RustDB| -> Vec<String> {
+        let matches = query.run(db).unwrap();
+        matches
+            .into_iter()
+            .map(|(_, document)| document["name"].as_str().unwrap().to_string())
+            .collect()
+    };
+    assert!(!bakers.is_empty());
+    assert_eq!(names(&db), bakers);
+
+    db.create_index("job_title", "job_title").unwrap();
+    assert_eq!(names(&db), bakers);
+}
+
+#[test]
+fn keep_counters_across_rollover_compaction_and_reload() {
+    let vfs: Arc<dyn Vfs> = Arc::new(MemoryVfs::new());
+    let options = Options {
+        segment_size: 200,
+        ..Options::default()
+    };
+    let mut db = RustDB::load_with_vfs("storage", options.clone(), Arc::clone(&vfs));
+    for _ in 0..100 {
+        db.increment(b"visits".to_vec(), 1).unwrap();
+        db.increment(b"stock".to_vec(), -2).unwrap();
+    }
+    let segments = db.stats().len();
+
+    db.compress_segments().unwrap();
+    assert!(db.stats().len() < segments);
+    assert_eq!(db.increment(b"visits".to_vec(), 0).unwrap(), 100);
+    let stock = db.increment(b"stock".to_vec(), 50).unwrap();
+    let mut db = RustDB::load_with_vfs("storage", options, vfs);
+
+    assert!(segments > 1);
+    assert_eq!(stock, -150);
+    assert_eq!(
+        db.get_record(b"stock".to_vec()).unwrap().unwrap().value,
+        b"-150".to_vec()
+    );
+    db.save_record(KeyValue::new(b"name".to_vec(), b"Lana".to_vec()))
+        .unwrap();
+    assert_eq!(
+        db.increment(b"name".to_vec(), 1).unwrap_err().kind(),
+        std::io::ErrorKind::InvalidData
+    );
+    assert_eq!(db.increment(b"visits".to_vec(), -100).unwrap(), 0);
+}
+
+#[test]
+fn validate_collection_writes_after_reload() {
+    let vfs: Arc<dyn Vfs> = Arc::new(MemoryVfs::new());
+    let mut db = RustDB::load_with_vfs("storage", Options::default(), Arc::clone(&vfs));
+    db.create_collection("users").unwrap();
+    let users = db.collection_mut("users").unwrap();
+    let schema = serde_json::json!({"type": "object", "required": ["email"]});
+    assert_eq!(users.set_schema(Some(schema.clone())).unwrap(), 1);
+    let mut db = RustDB::load_with_vfs("storage", Options::default(), vfs);
+
+    db.save_record(KeyValue::new(b"1".to_vec(), b"no schema here".to_vec()))
+        .unwrap();
+    let users = db.collection_mut("users").unwrap();
+    assert_eq!(users.schema_versions(), vec![(1, Some(schema))]);
+    users
+        .save_record(KeyValue::new(
+            b"1".to_vec(),
+            br#"{"email":"a@test.com"}"#.to_vec(),
+        ))
+        .unwrap();
+    let err = users
+        .save_record(KeyValue::new(b"2".to_vec(), br#"{"name":"Lana"}"#.to_vec()))
+        .unwrap_err();
+    let violation = err.get_ref().unwrap().downcast_ref::<SchemaViolation>();
+    assert_eq!(
+        violation.unwrap().errors[0].message,
+        "missing required property email"
+    );
+    assert!(users.get_record(b"2".to_vec()).unwrap().is_none());
+}
+
+#[test]
+fn read_old_versions_across_compaction_and_reload() {
+    let vfs: Arc<dyn Vfs> = Arc::new(MemoryVfs::new());
+    let options = Options {
+        segment_size: 500,
+        change_log_size: 1_000_000,
+        history_retention: Duration::from_secs(3_600),
+        ..Options::default()
+    };
+    let mut db = RustDB::load_with_vfs("storage", options.clone(), Arc::clone(&vfs));
+    for i in 0..100 {
+        db.save_record(KeyValue::new_from_strings(
+            String::from("price"),
+            i.to_string(),
+        ))
+        .unwrap();
+    }
+    db.delete_record(String::from("price")).unwrap();
+    db.save_record(KeyValue::new_from_strings(
+        String::from("name"),
+        String::from("Lana"),
+    ))
+    .unwrap();
+    let segments = db.stats().len();
+    db.compress_segments().unwrap();
+    let db = RustDB::load_with_vfs("storage", options.clone(), Arc::clone(&vfs));
+
+    let history = db.history(String::from("price")).unwrap();
+    assert!(segments > 1);
+    assert_eq!(history.len(), 101);
+    assert_eq!(history[0].sequence, 1);
+    assert_eq!(history[0].value, Some(b"0".to_vec()));
+    assert_eq!(history[100].sequence, 101);
+    assert_eq!(history[100].value, None);
+    assert!(history.windows(2).all(|pair| pair[0].time <= pair[1].time));
+    assert_eq!(
+        db.get_record_at(String::from("price"), PointInTime::Sequence(42))
+            .unwrap()
+            .unwrap()
+            .value,
+        b"41".to_vec()
+    );
+    assert!(db
+        .get_record_at(String::from("price"), PointInTime::Sequence(101))
+        .unwrap()
+        .is_none());
+    assert!(db
+        .get_record_at(
+            String::from("price"),
+            PointInTime::Time(history[0].time - 1)
+        )
+        .unwrap()
+        .is_none());
+    assert_eq!(
+        db.get_record_at(String::from("name"), PointInTime::Time(u64::MAX))
+            .unwrap()
+            .unwrap()
+            .value,
+        b"Lana".to_vec()
+    );
+
+    // versions older than the retention window are discarded
+    let options = Options {
+        history_retention: Duration::from_millis(1),
+        ..options
+    };
+    let mut db = RustDB::load_with_vfs("storage", options, vfs);
+    db.save_record(KeyValue::new_from_strings(
+        String::from("price"),
+        String::from("100"),
+    ))
+    .unwrap();
+    thread::sleep(Duration::from_millis(10));
+    db.compress_segments().unwrap();
+
+    let history = db.history(String::from("price")).unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].sequence, 103);
+    assert_eq!(history[0].value, Some(b"100".to_vec()));
+    assert_eq!(
+        db.get_record(String::from("name")).unwrap().unwrap().value,
+        b"Lana".to_vec()
+    );
+}
+
+#[test]
+fn refuse_history_reads_without_retention() {
+    let vfs: Arc<dyn Vfs> = Arc::new(MemoryVfs::new());
+    let mut db = RustDB::load_with_vfs("storage", Options::default(), vfs);
+    for price in ["1", "2"] {
+        db.save_record(KeyValue::new_from_strings(
+            String::from("price"),
+            String::from(price),
+        ))
+        .unwrap();
+    }
+
+    assert_eq!(
+        db.history(String::from("price")).unwrap_err().kind(),
+        std::io::ErrorKind::Unsupported
+    );
+    assert_eq!(
+        db.get_record_at(String::from("price"), PointInTime::Sequence(1))
+            .unwrap_err()
+            .kind(),
+        std::io::ErrorKind::Unsupported
+    );
+}
+
+#[test]
+fn keep_history_when_compressing_with_the_database_options() {
+    let vfs: Arc<dyn Vfs> = Arc::new(MemoryVfs::new());
+    let options = Options {
+        segment_size: 500,
+        history_retention: Duration::from_secs(3_600),
+        ..Options::default()
+    };
+    let mut db = RustDB::load_with_vfs("storage", options.clone(), Arc::clone(&vfs));
+    for i in 0..100 {
+        db.save_record(KeyValue::new_from_strings(
+            String::from("price"),
+            i.to_string(),
+        ))
+        .unwrap();
+    }
+
+    let segment_names = db.get_segments_to_compress();
+    let compressor = LogCompressor::new(
+        "storage",
+        segment_names.clone(),
+        db.get_active_segment_name(),
+    )
+    .with_active_keys(db.get_active_keys())
+    .with_vfs(db.get_vfs())
+    .with_options(db.get_options());
+    let (active_segment, new_segment) = compressor.compress().unwrap();
+    db.replace_segments(active_segment, new_segment);
+    LogCompressor::clean_with_vfs(&vfs, "storage", segment_names);
+    let db = RustDB::load_with_vfs("storage", options, vfs);
+
+    let history = db.history(String::from("price")).unwrap();
+    assert_eq!(history.len(), 100);
+    assert_eq!(history[0].value, Some(b"0".to_vec()));
 }